@@ -3,25 +3,65 @@ use std::time::Instant;
 
 use crate::{
     strategy_pool::bb_swing::BBSwing,
-    types::{self, BacktestMetric, BbBandConfig},
+    types::{self, BacktestMetric, BbBandConfig, EntryOrderType},
 };
 use types::Kline;
 
-pub fn backtest(config: &BbBandConfig, klines: &Vec<Kline>) -> BacktestMetric {
+pub fn backtest(config: &BbBandConfig, klines: &[Kline]) -> BacktestMetric {
     // Variables
     let mut metric = BacktestMetric::new(config);
     let timer = Instant::now();
     metric.bb_width = config.bb_width;
 
-    let mut bb_swing = BBSwing::new(&config);
+    let mut bb_swing = BBSwing::new(config);
 
-    for index in 0..klines.len() {
-        bb_swing.strategy(&mut metric, &klines[index]);
+    for kline in klines {
+        bb_swing.strategy(&mut metric, kline);
     }
     info!(
-        "total_fee: {}, total_profit: {}, usd_balance: {}, max_usd: {}",
-        metric.total_fee, metric.total_profit, metric.usd_balance, metric.max_usd
+        "total_fee: {}, total_profit: {}, usd_balance: {}, max_usd: {}, risk_halts: {}",
+        metric.total_fee,
+        metric.total_profit,
+        metric.usd_balance,
+        metric.max_usd,
+        metric.risk_halts
     );
     info!("elapsed: {}", timer.elapsed().as_secs());
     metric
 }
+
+// Reruns a limit-entry backtest with market entries on the same klines and logs both
+pub fn compare_entries(config: &BbBandConfig, klines: &[Kline], limit: &BacktestMetric) {
+    let mut market_config = config.clone();
+    market_config.entry.order_type = EntryOrderType::Market;
+    let market = backtest(&market_config, klines);
+    info!("{:<14}{:>14}{:>14}", "", "limit", "market");
+    let rows = [
+        ("usd_balance", limit.usd_balance, market.usd_balance),
+        ("total_profit", limit.total_profit, market.total_profit),
+        ("total_fee", limit.total_fee, market.total_fee),
+        ("max_drawdown", limit.max_drawdown(), market.max_drawdown()),
+        ("win_rate", win_rate(limit), win_rate(&market)),
+    ];
+    for (name, limit, market) in rows {
+        info!("{:<14}{:>14.4}{:>14.4}", name, limit, market);
+    }
+    info!(
+        "{:<14}{:>14}{:>14}",
+        "trades",
+        limit.trades.len(),
+        market.trades.len()
+    );
+    let mut msg = "".to_string();
+    msg += &format!("limit_quotes: {}, ", limit.limit_quotes);
+    msg += &format!("limit_fills: {}, ", limit.limit_fills);
+    msg += &format!(
+        "fill_rate: {:.4}",
+        limit.limit_fills as f64 / limit.limit_quotes.max(1) as f64
+    );
+    info!("{}", msg);
+}
+
+fn win_rate(metric: &BacktestMetric) -> f64 {
+    metric.win as f64 / (metric.win + metric.lose).max(1) as f64
+}
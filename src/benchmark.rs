@@ -0,0 +1,384 @@
+use std::{fs::File, path::Path};
+
+use anyhow::Result;
+use log::info;
+use serde::Serialize;
+
+use crate::{
+    types::{BacktestMetric, BbBandConfig, Fill, FillKind, Kline, TradeLog},
+    utils::{calculate_fee, mean_std},
+    DAY_MS,
+};
+
+// Statistics of one equity curve, sampled at the end of every day
+#[derive(Debug, Serialize, Clone)]
+pub struct EquityStats {
+    pub usd_balance: f64,
+    pub total_return: f64,
+    pub volatility: f64, // annualised, of daily returns
+    pub sharpe: f64,     // annualised
+    pub max_drawdown: f64,
+    pub trades: usize,
+    pub total_fee: f64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Comparison {
+    pub days: usize,
+    pub strategy: EquityStats,
+    pub benchmark: EquityStats,
+    pub alpha: f64, // annualised, daily regression of strategy on benchmark returns
+    pub beta: f64,
+    pub correlation: f64,
+    #[serde(skip)]
+    pub equity: Vec<(i64, f64, f64)>, // day end, strategy, benchmark
+}
+
+// Buys at the open of the first kline and sells at the close of the last one, sized
+// like a strategy entry at the same leverage and fee rate. max_usd and min_usd follow
+// the marked equity. A close that marks the equity at or below zero liquidates the
+// position: the account ends flat at zero and pays no exit fee.
+pub fn buy_and_hold(config: &BbBandConfig, klines: &[Kline]) -> BacktestMetric {
+    let mut metric = BacktestMetric::new(config);
+    let (Some(first), Some(mut last)) = (klines.first(), klines.last()) else {
+        return metric;
+    };
+    let size = config.initial_captial * config.entry_protion / first.open;
+    let entry_fee = calculate_fee(config.fee_rate, first.open, size, config.leverage);
+    let mut liquidated = false;
+    for kline in klines {
+        let equity = mark(config, first.open, size, entry_fee, kline.close);
+        metric.max_usd = metric.max_usd.max(equity);
+        metric.min_usd = metric.min_usd.min(equity.max(0.));
+        if equity <= 0. {
+            last = kline;
+            liquidated = true;
+            break;
+        }
+    }
+    let (profit, exit_fee) = if liquidated {
+        (entry_fee - config.initial_captial, 0.)
+    } else {
+        (
+            (last.close - first.open) * size * config.leverage as f64,
+            calculate_fee(config.fee_rate, last.close, size, config.leverage),
+        )
+    };
+    metric.total_fee = entry_fee + exit_fee;
+    metric.total_profit = profit;
+    metric.usd_balance += profit - metric.total_fee;
+    if profit - metric.total_fee >= 0. {
+        metric.win = 1;
+    } else {
+        metric.lose = 1;
+    }
+    metric.trades.push(TradeLog {
+        entry_side: 1,
+        entry_price: first.open,
+        entry_size: size,
+        exit_price: last.close,
+        entry_time: first.open_time,
+        exit_time: last.close_time,
+        entry_balance: config.initial_captial,
+        profit,
+        fee: metric.total_fee,
+        fills: vec![
+            Fill {
+                time: first.open_time,
+                kind: FillKind::Entry,
+                side: 1,
+                price: first.open,
+                size,
+                fee: entry_fee,
+            },
+            Fill {
+                time: last.close_time,
+                kind: FillKind::Exit,
+                side: -1,
+                price: last.close,
+                size,
+                fee: exit_fee,
+            },
+        ],
+    });
+    metric
+}
+
+fn mark(config: &BbBandConfig, entry_price: f64, size: f64, entry_fee: f64, price: f64) -> f64 {
+    config.initial_captial - entry_fee + (price - entry_price) * size * config.leverage as f64
+}
+
+// Both equity curves are sampled at every day's close, see daily_equity
+pub fn compare(
+    config: &BbBandConfig,
+    klines: &[Kline],
+    metric: &BacktestMetric,
+    benchmark: &BacktestMetric,
+) -> Comparison {
+    let start = klines.first().map_or(0, |kline| kline.open_time);
+    let end = klines.last().map_or(0, |kline| kline.close_time);
+    let days = ((end - start) / DAY_MS + 1) as usize;
+
+    let strategy_equity = daily_equity(config, klines, metric, start, days);
+    let benchmark_equity = daily_equity(config, klines, benchmark, start, days);
+
+    let strategy_returns = returns(config.initial_captial, &strategy_equity);
+    let benchmark_returns = returns(config.initial_captial, &benchmark_equity);
+    let (strategy_mean, strategy_std) = mean_std(&strategy_returns);
+    let (benchmark_mean, benchmark_std) = mean_std(&benchmark_returns);
+    let covariance = strategy_returns
+        .iter()
+        .zip(&benchmark_returns)
+        .map(|(s, b)| (s - strategy_mean) * (b - benchmark_mean))
+        .sum::<f64>()
+        / (days as f64 - 1.).max(1.);
+    let beta = if benchmark_std > 0. {
+        covariance / benchmark_std.powi(2)
+    } else {
+        0.
+    };
+    let correlation = if strategy_std > 0. && benchmark_std > 0. {
+        covariance / (strategy_std * benchmark_std)
+    } else {
+        0.
+    };
+
+    Comparison {
+        days,
+        strategy: stats(config, metric, &strategy_equity, &strategy_returns),
+        benchmark: stats(config, benchmark, &benchmark_equity, &benchmark_returns),
+        alpha: (strategy_mean - beta * benchmark_mean) * 365.,
+        beta,
+        correlation,
+        equity: (0..days)
+            .map(|day| {
+                (
+                    start + (day as i64 + 1) * DAY_MS,
+                    strategy_equity[day],
+                    benchmark_equity[day],
+                )
+            })
+            .collect(),
+    }
+}
+
+// The balance after the closed trades, or the open trade marked at the day's last close
+// on its largest size and average entry price. Days without klines keep the last close,
+// a liquidated account stays flat at zero.
+fn daily_equity(
+    config: &BbBandConfig,
+    klines: &[Kline],
+    metric: &BacktestMetric,
+    start: i64,
+    days: usize,
+) -> Vec<f64> {
+    let mut closes = vec![None; days];
+    for kline in klines {
+        let day = ((kline.close_time - start) / DAY_MS).clamp(0, days as i64 - 1);
+        closes[day as usize] = Some(kline.close);
+    }
+    let mut trades = metric.trades.iter().peekable();
+    let mut balance = config.initial_captial;
+    let mut close = klines.first().map_or(0., |kline| kline.open);
+    let mut equity = Vec::new();
+    for (day, day_close) in closes.into_iter().enumerate() {
+        let day_end = start + (day as i64 + 1) * DAY_MS;
+        close = day_close.unwrap_or(close);
+        while let Some(trade) = trades.next_if(|trade| trade.exit_time < day_end) {
+            balance = trade.balance_after();
+        }
+        equity.push(match trades.peek() {
+            Some(trade) if trade.entry_time < day_end => mark_trade(config, trade, close, day_end),
+            _ => balance,
+        });
+    }
+    equity
+}
+
+// Entry fees paid before time are taken off, the exit fee is not paid yet
+fn mark_trade(config: &BbBandConfig, trade: &TradeLog, price: f64, time: i64) -> f64 {
+    let entry_fee: f64 = trade
+        .fills
+        .iter()
+        .filter(|fill| fill.time < time && matches!(fill.kind, FillKind::Entry | FillKind::ScaleIn))
+        .map(|fill| fill.fee)
+        .sum();
+    trade.entry_balance - entry_fee
+        + (price - trade.entry_price)
+            * trade.entry_side as f64
+            * trade.entry_size
+            * config.leverage as f64
+}
+
+fn returns(initial: f64, equity: &[f64]) -> Vec<f64> {
+    let mut prev = initial;
+    equity
+        .iter()
+        .map(|equity| {
+            let ret = if prev > 0. { equity / prev - 1. } else { 0. };
+            prev = *equity;
+            ret
+        })
+        .collect()
+}
+
+fn stats(
+    config: &BbBandConfig,
+    metric: &BacktestMetric,
+    equity: &[f64],
+    returns: &[f64],
+) -> EquityStats {
+    let (mean, std) = mean_std(returns);
+    let mut peak = config.initial_captial;
+    let mut max_drawdown: f64 = 0.;
+    for equity in equity {
+        peak = peak.max(*equity);
+        max_drawdown = max_drawdown.max((peak - equity) / peak);
+    }
+    EquityStats {
+        usd_balance: metric.usd_balance,
+        total_return: metric.usd_balance / config.initial_captial - 1.,
+        volatility: std * 365f64.sqrt(),
+        sharpe: if std > 0. {
+            mean / std * 365f64.sqrt()
+        } else {
+            0.
+        },
+        max_drawdown,
+        trades: metric.trades.len(),
+        total_fee: metric.total_fee,
+    }
+}
+
+pub fn log_comparison(comparison: &Comparison) {
+    info!("{:<14}{:>14}{:>14}", "", "strategy", "buy_and_hold");
+    let (strategy, benchmark) = (&comparison.strategy, &comparison.benchmark);
+    let rows = [
+        ("usd_balance", strategy.usd_balance, benchmark.usd_balance),
+        (
+            "total_return",
+            strategy.total_return,
+            benchmark.total_return,
+        ),
+        ("volatility", strategy.volatility, benchmark.volatility),
+        ("sharpe", strategy.sharpe, benchmark.sharpe),
+        (
+            "max_drawdown",
+            strategy.max_drawdown,
+            benchmark.max_drawdown,
+        ),
+        ("total_fee", strategy.total_fee, benchmark.total_fee),
+    ];
+    for (name, strategy, benchmark) in rows {
+        info!("{:<14}{:>14.4}{:>14.4}", name, strategy, benchmark);
+    }
+    info!(
+        "{:<14}{:>14}{:>14}",
+        "trades", comparison.strategy.trades, comparison.benchmark.trades
+    );
+    let mut msg = "".to_string();
+    msg += &format!("alpha: {:.4}, ", comparison.alpha);
+    msg += &format!("beta: {:.4}, ", comparison.beta);
+    msg += &format!("correlation: {:.4}", comparison.correlation);
+    info!("{}", msg);
+}
+
+pub fn write_comparison(comparison: &Comparison, path: &Path) -> Result<()> {
+    serde_json::to_writer_pretty(File::create(path)?, comparison)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config(leverage: u64) -> BbBandConfig {
+        crate::test_utils::config(json!({"leverage": leverage, "entry_protion": 1.}))
+    }
+
+    // One kline per day
+    fn kline(day: i64, price: f64) -> Kline {
+        Kline {
+            open_time: day * DAY_MS,
+            close_time: (day + 1) * DAY_MS - 1,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn benchmark_equity_is_carried_over_missing_days() {
+        let config = config(1);
+        let klines = vec![kline(0, 100.), kline(1, 110.), kline(4, 120.)];
+        let benchmark = buy_and_hold(&config, &klines);
+        let comparison = compare(&config, &klines, &BacktestMetric::new(&config), &benchmark);
+        let equity: Vec<f64> = comparison.equity.iter().map(|day| day.2).collect();
+        assert_eq!(equity.len(), 5);
+        assert_eq!(equity[2], equity[1]);
+        assert_eq!(equity[3], equity[1]);
+        assert!(equity[1] > config.initial_captial);
+        assert_eq!(equity[4], benchmark.usd_balance);
+    }
+
+    #[test]
+    fn buy_and_hold_is_liquidated_and_stays_flat() {
+        let config = config(10);
+        let klines = vec![kline(0, 100.), kline(1, 95.), kline(2, 89.), kline(3, 150.)];
+        let benchmark = buy_and_hold(&config, &klines);
+        assert_eq!(benchmark.usd_balance, 0.);
+        assert_eq!(benchmark.min_usd, 0.);
+        assert_eq!(benchmark.lose, 1);
+        let trade = &benchmark.trades[0];
+        assert_eq!(trade.exit_time, klines[2].close_time);
+        assert_eq!(trade.fills[1].fee, 0.);
+
+        let comparison = compare(&config, &klines, &BacktestMetric::new(&config), &benchmark);
+        let equity: Vec<f64> = comparison.equity.iter().map(|day| day.2).collect();
+        assert!(equity[1] > 0.);
+        assert_eq!(&equity[2..], &[0., 0.]);
+        assert_eq!(comparison.benchmark.max_drawdown, 1.);
+    }
+
+    #[test]
+    fn open_trades_are_marked_at_the_day_close() {
+        let config = config(2);
+        let klines = vec![kline(0, 100.), kline(1, 90.), kline(2, 80.), kline(4, 95.)];
+        let mut metric = BacktestMetric::new(&config);
+        // Short 10 from the open of day 1 to the close of day 4
+        metric.trades.push(TradeLog {
+            entry_side: -1,
+            entry_price: 90.,
+            entry_size: 10.,
+            exit_price: 95.,
+            entry_time: klines[1].open_time,
+            exit_time: klines[3].close_time,
+            entry_balance: 10000.,
+            profit: -100.,
+            fee: 1.5,
+            fills: vec![Fill {
+                time: klines[1].open_time,
+                kind: FillKind::Entry,
+                side: -1,
+                price: 90.,
+                size: 10.,
+                fee: 0.7,
+            }],
+        });
+        metric.usd_balance = 9898.5;
+        let benchmark = buy_and_hold(&config, &klines);
+        let comparison = compare(&config, &klines, &metric, &benchmark);
+        let equity: Vec<f64> = comparison.equity.iter().map(|day| day.1).collect();
+        assert_eq!(equity[0], 10000.);
+        assert_eq!(equity[1], 10000. - 0.7);
+        // 10 points in favour at 2x on day 2, carried over the missing day 3
+        assert_eq!(equity[2], 10000. - 0.7 + 200.);
+        assert_eq!(equity[3], equity[2]);
+        assert_eq!(equity[4], 9898.5);
+        assert!(comparison.strategy.max_drawdown > 0.);
+    }
+}
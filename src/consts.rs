@@ -1,12 +1,19 @@
+use serde::{Deserialize, Serialize};
+
 pub const BTCUSDT_15M: &str = "BTCUSDT_15m";
 pub const KLINE_DB: &str = "klines";
+pub const RESULTS_DB: &str = "bb_band";
 pub const LOCAL_MONGO_CONNECTION_STRING: &str = "mongodb://localhost:27017";
+pub const KLINE_INTERVAL_MS: i64 = 15 * 60 * 1000;
+pub const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+pub const KLINE_CACHE_DIR: &str = "kline_cache";
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
 pub enum TradeSide {
     Sell,
     Buy,
     Stop,
+    #[default]
     None,
 }
 
@@ -18,10 +25,13 @@ impl TradeSide {
             TradeSide::Stop | TradeSide::None => 0.,
         }
     }
-}
 
-impl Default for TradeSide {
-    fn default() -> Self {
-        TradeSide::None
+    pub fn opposite(&self) -> TradeSide {
+        match *self {
+            TradeSide::Sell => TradeSide::Buy,
+            TradeSide::Buy => TradeSide::Sell,
+            TradeSide::Stop => TradeSide::Stop,
+            TradeSide::None => TradeSide::None,
+        }
     }
 }
@@ -0,0 +1,307 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+use chrono::DateTime;
+
+use crate::types::{DataQualityPolicy, Kline};
+
+const MAX_REPORTED_SAMPLES: usize = 5;
+
+#[derive(Debug, Clone, Default)]
+pub struct DataQualityReport {
+    pub total: usize,
+    pub duplicates: Vec<i64>,
+    pub non_monotonic: Vec<i64>,
+    pub off_grid: Vec<i64>,
+    pub invalid_prices: Vec<i64>,
+    pub high_low_anomalies: Vec<i64>,
+    pub gaps: Vec<(i64, i64)>, // (last open_time before the gap, first open_time after it)
+    pub missing_bars: usize,
+    pub dropped: usize,
+    pub filled: usize,
+}
+
+impl DataQualityReport {
+    pub fn is_clean(&self) -> bool {
+        self.duplicates.is_empty()
+            && self.non_monotonic.is_empty()
+            && self.off_grid.is_empty()
+            && self.invalid_prices.is_empty()
+            && self.high_low_anomalies.is_empty()
+            && self.gaps.is_empty()
+    }
+}
+
+impl fmt::Display for DataQualityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "data quality report: {} klines", self.total)?;
+        write_samples(f, "duplicate open_time", &self.duplicates)?;
+        write_samples(f, "non-monotonic open_time", &self.non_monotonic)?;
+        write_samples(f, "off-grid open/close_time", &self.off_grid)?;
+        write_samples(f, "zero/negative price", &self.invalid_prices)?;
+        write_samples(f, "high < low", &self.high_low_anomalies)?;
+        writeln!(
+            f,
+            "  gaps: {} ({} missing bars)",
+            self.gaps.len(),
+            self.missing_bars
+        )?;
+        for (before, after) in self.gaps.iter().take(MAX_REPORTED_SAMPLES) {
+            writeln!(f, "    {} -> {}", format_ts(*before), format_ts(*after))?;
+        }
+        write!(f, "  dropped: {}, filled: {}", self.dropped, self.filled)
+    }
+}
+
+fn write_samples(f: &mut fmt::Formatter<'_>, label: &str, timestamps: &[i64]) -> fmt::Result {
+    writeln!(f, "  {}: {}", label, timestamps.len())?;
+    for ts in timestamps.iter().take(MAX_REPORTED_SAMPLES) {
+        writeln!(f, "    {}", format_ts(*ts))?;
+    }
+    Ok(())
+}
+
+fn format_ts(ts_ms: i64) -> String {
+    match DateTime::from_timestamp_millis(ts_ms) {
+        Some(date) => date.naive_utc().to_string(),
+        None => ts_ms.to_string(),
+    }
+}
+
+// Checks the klines against the interval grid and applies the policy to whatever is found.
+// Drop and ForwardFill remove malformed bars; ForwardFill also fills gaps with flat bars
+// at the previous close so that BBSwing's bar-counted window stays aligned with time.
+pub fn validate_klines(
+    klines: Vec<Kline>,
+    interval_ms: i64,
+    policy: DataQualityPolicy,
+) -> Result<(Vec<Kline>, DataQualityReport)> {
+    let mut report = DataQualityReport {
+        total: klines.len(),
+        ..Default::default()
+    };
+    let mut output = Vec::with_capacity(klines.len());
+    let mut prev: Option<Kline> = None;
+    // Fail and Warn keep malformed bars in the output, so gaps are measured from them too
+    let keeps_bad = matches!(policy, DataQualityPolicy::Fail | DataQualityPolicy::Warn);
+
+    for kline in klines {
+        let mut is_bad = false;
+        let prices = [kline.open, kline.high, kline.low, kline.close];
+        if prices
+            .iter()
+            .any(|price| !price.is_finite() || *price <= 0.)
+        {
+            report.invalid_prices.push(kline.open_time);
+            is_bad = true;
+        }
+        if kline.high < kline.low {
+            report.high_low_anomalies.push(kline.open_time);
+            is_bad = true;
+        }
+        if kline.open_time % interval_ms != 0
+            || kline.close_time != kline.open_time + interval_ms - 1
+        {
+            report.off_grid.push(kline.open_time);
+            is_bad = true;
+        }
+        if let Some(prev_kline) = &prev {
+            if kline.open_time == prev_kline.open_time {
+                report.duplicates.push(kline.open_time);
+                is_bad = true;
+            } else if kline.open_time < prev_kline.open_time {
+                report.non_monotonic.push(kline.open_time);
+                is_bad = true;
+            } else if (!is_bad || keeps_bad) && kline.open_time > prev_kline.open_time + interval_ms
+            {
+                let missing = ((kline.open_time - prev_kline.open_time) / interval_ms - 1) as usize;
+                report.gaps.push((prev_kline.open_time, kline.open_time));
+                report.missing_bars += missing;
+                if policy == DataQualityPolicy::ForwardFill {
+                    for step in 1..=missing as i64 {
+                        let open_time = prev_kline.open_time + step * interval_ms;
+                        output.push(Kline {
+                            open_time,
+                            close_time: open_time + interval_ms - 1,
+                            open: prev_kline.close,
+                            high: prev_kline.close,
+                            low: prev_kline.close,
+                            close: prev_kline.close,
+                            volume: prev_kline.volume.map(|_| 0.),
+                            quote_volume: prev_kline.quote_volume.map(|_| 0.),
+                            trade_count: prev_kline.trade_count.map(|_| 0),
+                            taker_buy_volume: prev_kline.taker_buy_volume.map(|_| 0.),
+                            taker_buy_quote_volume: prev_kline.taker_buy_quote_volume.map(|_| 0.),
+                        });
+                    }
+                    report.filled += missing;
+                }
+            }
+        }
+
+        if is_bad && !keeps_bad {
+            report.dropped += 1;
+            continue;
+        }
+        // A kept bar that goes back in time does not move the grid
+        if prev
+            .as_ref()
+            .is_none_or(|prev_kline| kline.open_time > prev_kline.open_time)
+        {
+            prev = Some(kline.clone());
+        }
+        output.push(kline);
+    }
+
+    if policy == DataQualityPolicy::Fail && !report.is_clean() {
+        bail!("kline validation failed\n{}", report);
+    }
+    Ok((output, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: i64 = 60_000;
+
+    fn kline(bar: i64, price: f64) -> Kline {
+        Kline {
+            open_time: bar * INTERVAL,
+            close_time: (bar + 1) * INTERVAL - 1,
+            open: price,
+            high: price + 1.,
+            low: price - 1.,
+            close: price,
+            ..Default::default()
+        }
+    }
+
+    fn open_times(klines: &[Kline]) -> Vec<i64> {
+        klines
+            .iter()
+            .map(|kline| kline.open_time / INTERVAL)
+            .collect()
+    }
+
+    #[test]
+    fn clean_klines_pass_every_policy() {
+        let klines: Vec<Kline> = (0..5).map(|bar| kline(bar, 100.)).collect();
+        for policy in [
+            DataQualityPolicy::Fail,
+            DataQualityPolicy::Drop,
+            DataQualityPolicy::ForwardFill,
+            DataQualityPolicy::Warn,
+        ] {
+            let (output, report) = validate_klines(klines.clone(), INTERVAL, policy).unwrap();
+            assert!(report.is_clean());
+            assert_eq!(output.len(), 5);
+        }
+    }
+
+    #[test]
+    fn gaps_are_reported_and_filled() {
+        let klines = vec![kline(0, 100.), kline(1, 101.), kline(4, 102.)];
+
+        let (output, report) =
+            validate_klines(klines.clone(), INTERVAL, DataQualityPolicy::Warn).unwrap();
+        assert_eq!(report.gaps, vec![(INTERVAL, 4 * INTERVAL)]);
+        assert_eq!(report.missing_bars, 2);
+        assert_eq!(open_times(&output), vec![0, 1, 4]);
+
+        let (output, report) =
+            validate_klines(klines.clone(), INTERVAL, DataQualityPolicy::Drop).unwrap();
+        assert_eq!(report.missing_bars, 2);
+        assert_eq!(open_times(&output), vec![0, 1, 4]);
+
+        let (output, report) =
+            validate_klines(klines.clone(), INTERVAL, DataQualityPolicy::ForwardFill).unwrap();
+        assert_eq!(report.filled, 2);
+        assert_eq!(open_times(&output), vec![0, 1, 2, 3, 4]);
+        assert_eq!(output[2].close, 101.);
+        assert_eq!(output[3].high, 101.);
+
+        assert!(validate_klines(klines, INTERVAL, DataQualityPolicy::Fail).is_err());
+    }
+
+    #[test]
+    fn duplicates_are_reported_and_dropped() {
+        let klines = vec![
+            kline(0, 100.),
+            kline(1, 101.),
+            kline(1, 101.),
+            kline(2, 102.),
+        ];
+
+        let (output, report) =
+            validate_klines(klines.clone(), INTERVAL, DataQualityPolicy::Warn).unwrap();
+        assert_eq!(report.duplicates, vec![INTERVAL]);
+        assert!(report.gaps.is_empty());
+        assert_eq!(open_times(&output), vec![0, 1, 1, 2]);
+
+        for policy in [DataQualityPolicy::Drop, DataQualityPolicy::ForwardFill] {
+            let (output, report) = validate_klines(klines.clone(), INTERVAL, policy).unwrap();
+            assert_eq!(report.dropped, 1);
+            assert_eq!(open_times(&output), vec![0, 1, 2]);
+        }
+
+        assert!(validate_klines(klines, INTERVAL, DataQualityPolicy::Fail).is_err());
+    }
+
+    #[test]
+    fn non_monotonic_bars_do_not_move_the_grid() {
+        let klines = vec![
+            kline(0, 100.),
+            kline(2, 101.),
+            kline(1, 101.),
+            kline(3, 102.),
+        ];
+        let (output, report) = validate_klines(klines, INTERVAL, DataQualityPolicy::Warn).unwrap();
+        assert_eq!(report.non_monotonic, vec![INTERVAL]);
+        assert_eq!(report.gaps, vec![(0, 2 * INTERVAL)]);
+        assert_eq!(report.missing_bars, 1);
+        assert_eq!(output.len(), 4);
+    }
+
+    #[test]
+    fn price_anomalies_are_reported_and_dropped() {
+        let mut inverted = kline(1, 101.);
+        inverted.high = 99.;
+        let mut zero = kline(2, 102.);
+        zero.low = 0.;
+        let klines = vec![kline(0, 100.), inverted, zero, kline(3, 103.)];
+
+        // Kept bad bars move the grid, so the next good bar is not a gap
+        let (output, report) =
+            validate_klines(klines.clone(), INTERVAL, DataQualityPolicy::Warn).unwrap();
+        assert_eq!(report.high_low_anomalies, vec![INTERVAL]);
+        assert_eq!(report.invalid_prices, vec![2 * INTERVAL]);
+        assert!(report.gaps.is_empty());
+        assert_eq!(output.len(), 4);
+
+        let (output, report) =
+            validate_klines(klines.clone(), INTERVAL, DataQualityPolicy::Drop).unwrap();
+        assert_eq!(report.dropped, 2);
+        assert_eq!(report.missing_bars, 2);
+        assert_eq!(open_times(&output), vec![0, 3]);
+
+        let (output, report) =
+            validate_klines(klines.clone(), INTERVAL, DataQualityPolicy::ForwardFill).unwrap();
+        assert_eq!(report.dropped, 2);
+        assert_eq!(report.filled, 2);
+        assert_eq!(open_times(&output), vec![0, 1, 2, 3]);
+        assert_eq!(output[1].close, 100.);
+
+        assert!(validate_klines(klines, INTERVAL, DataQualityPolicy::Fail).is_err());
+    }
+
+    #[test]
+    fn off_grid_bars_are_reported() {
+        let mut shifted = kline(1, 101.);
+        shifted.close_time += 5;
+        let klines = vec![kline(0, 100.), shifted, kline(2, 102.)];
+        let (output, report) = validate_klines(klines, INTERVAL, DataQualityPolicy::Drop).unwrap();
+        assert_eq!(report.off_grid, vec![INTERVAL]);
+        assert_eq!(open_times(&output), vec![0, 2]);
+    }
+}
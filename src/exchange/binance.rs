@@ -0,0 +1,839 @@
+use std::{
+    env, fmt, io,
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+use super::{Exchange, ExchangePosition};
+use crate::{
+    order::{Order, OrderId, OrderRequest, OrderStatus, OrderType},
+    types::{BinanceConfig, Kline},
+    TradeSide,
+};
+
+const KLINE_LIMIT: usize = 1500;
+const LISTEN_KEY_KEEPALIVE: Duration = Duration::from_secs(30 * 60);
+// Wakes the user data stream on a quiet account so the keepalive is not missed
+const USER_DATA_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Binance USDⓈ-M futures connector, e.g. base_url https://fapi.binance.com.
+// Point base_url/ws_url at exchange::stub to run it without network access.
+pub struct BinanceFutures {
+    config: BinanceConfig,
+    agent: ureq::Agent,
+}
+
+#[derive(Debug, Deserialize)]
+struct BinanceError {
+    code: i64,
+    msg: String,
+}
+
+// Error answered by the API itself, as opposed to transport failures where the
+// outcome of the request is unknown
+#[derive(Debug)]
+pub struct BinanceApiError {
+    pub status: u16,
+    pub code: i64,
+    pub msg: String,
+}
+
+impl fmt::Display for BinanceApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "binance error {} ({}): {}",
+            self.code, self.status, self.msg
+        )
+    }
+}
+
+impl std::error::Error for BinanceApiError {}
+
+impl BinanceApiError {
+    // 4xx answers refuse the request itself (e.g. -2019 margin, -2022 reduce-only), while
+    // 5xx leaves the outcome unknown and 403/418/429 are WAF, ban and rate limit responses
+    pub fn is_rejection(&self) -> bool {
+        (400..500).contains(&self.status) && ![403, 418, 429].contains(&self.status)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OrderResponse {
+    order_id: OrderId,
+    status: String,
+    side: String,
+    #[serde(rename = "type")]
+    order_type: String,
+    orig_qty: String,
+    executed_qty: String,
+    avg_price: String,
+    #[serde(default)]
+    price: String,
+    #[serde(default)]
+    stop_price: String,
+    #[serde(default)]
+    reduce_only: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PositionRisk {
+    symbol: String,
+    position_amt: String,
+    entry_price: String,
+    #[serde(default = "one_way_side")]
+    position_side: String, // BOTH in one-way mode, LONG/SHORT in hedge mode
+}
+
+fn one_way_side() -> String {
+    "BOTH".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AssetBalance {
+    asset: String,
+    balance: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListenKey {
+    listen_key: String,
+}
+
+impl BinanceFutures {
+    pub fn new(config: &BinanceConfig) -> Self {
+        let mut config = config.clone();
+        if config.api_key.is_empty() {
+            config.api_key = env::var("BINANCE_API_KEY").unwrap_or_default();
+        }
+        if config.api_secret.is_empty() {
+            config.api_secret = env::var("BINANCE_API_SECRET").unwrap_or_default();
+        }
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+        BinanceFutures { config, agent }
+    }
+
+    pub fn start_user_data_stream(&mut self) -> Result<UserDataStream> {
+        let body = self.send("POST", "/fapi/v1/listenKey", &[], false)?;
+        let listen_key: ListenKey = serde_json::from_str(&body)?;
+        let url = format!("{}/ws/{}", self.config.ws_url, listen_key.listen_key);
+        let (socket, _) = tungstenite::connect(url.as_str())?;
+        let tcp_stream = match socket.get_ref() {
+            MaybeTlsStream::Plain(stream) => Some(stream),
+            MaybeTlsStream::Rustls(stream) => Some(stream.get_ref()),
+            _ => None,
+        };
+        if let Some(tcp_stream) = tcp_stream {
+            tcp_stream.set_read_timeout(Some(USER_DATA_READ_TIMEOUT))?;
+        }
+        info!("user data stream connected");
+        Ok(UserDataStream {
+            socket,
+            listen_key: listen_key.listen_key,
+            symbol: self.config.symbol.clone(),
+            last_keepalive: Instant::now(),
+        })
+    }
+
+    pub fn keepalive_user_data_stream(&mut self, listen_key: &str) -> Result<()> {
+        self.send(
+            "PUT",
+            "/fapi/v1/listenKey",
+            &[("listenKey", listen_key.to_string())],
+            false,
+        )?;
+        Ok(())
+    }
+
+    fn signed(&self, method: &str, path: &str, params: &[(&str, String)]) -> Result<String> {
+        let mut params = params.to_vec();
+        params.push(("recvWindow", self.config.recv_window.to_string()));
+        params.push(("timestamp", Utc::now().timestamp_millis().to_string()));
+        self.send(method, path, &params, true)
+    }
+
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        params: &[(&str, String)],
+        is_signed: bool,
+    ) -> Result<String> {
+        let mut query = params
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("&");
+        if is_signed {
+            let signature = sign(&self.config.api_secret, &query);
+            query += &format!("&signature={}", signature);
+        }
+        let url = if query.is_empty() {
+            format!("{}{}", self.config.base_url, path)
+        } else {
+            format!("{}{}?{}", self.config.base_url, path, query)
+        };
+        let response = self
+            .agent
+            .request(method, &url)
+            .set("X-MBX-APIKEY", &self.config.api_key)
+            .call();
+        match response {
+            Ok(response) => Ok(response.into_string()?),
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string()?;
+                let err = match serde_json::from_str::<BinanceError>(&body) {
+                    Ok(err) => BinanceApiError {
+                        status,
+                        code: err.code,
+                        msg: err.msg,
+                    },
+                    Err(_) => BinanceApiError {
+                        status,
+                        code: 0,
+                        msg: body,
+                    },
+                };
+                Err(err.into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn format_quantity(&self, quantity: f64) -> String {
+        format!("{:.*}", self.config.quantity_precision, quantity)
+    }
+
+    fn format_price(&self, price: f64) -> String {
+        format!("{:.*}", self.config.price_precision, price)
+    }
+}
+
+impl Exchange for BinanceFutures {
+    fn place_order(&mut self, request: OrderRequest) -> Result<Order> {
+        let mut params = vec![
+            ("symbol", self.config.symbol.clone()),
+            ("side", side_to_str(&request.side)?.to_string()),
+            ("quantity", self.format_quantity(request.size)),
+            ("newOrderRespType", "RESULT".to_string()),
+        ];
+        match request.order_type {
+            OrderType::Market => params.push(("type", "MARKET".to_string())),
+            OrderType::Limit { price } => {
+                params.push(("type", "LIMIT".to_string()));
+                params.push(("price", self.format_price(price)));
+                params.push(("timeInForce", "GTC".to_string()));
+            }
+            OrderType::StopMarket { stop_price } => {
+                params.push(("type", "STOP_MARKET".to_string()));
+                params.push(("stopPrice", self.format_price(stop_price)));
+            }
+            OrderType::TakeProfitMarket { stop_price } => {
+                params.push(("type", "TAKE_PROFIT_MARKET".to_string()));
+                params.push(("stopPrice", self.format_price(stop_price)));
+            }
+        }
+        if request.reduce_only {
+            params.push(("reduceOnly", "true".to_string()));
+        }
+        match self.signed("POST", "/fapi/v1/order", &params) {
+            Ok(body) => to_order(serde_json::from_str(&body)?),
+            // Rejections come back as API errors, surface them like the mock does
+            Err(err) => match err.downcast_ref::<BinanceApiError>() {
+                Some(api_err) if api_err.is_rejection() => {
+                    warn!("order rejected: {}", api_err);
+                    let mut order = Order::new(0, request);
+                    order.reject()?;
+                    Ok(order)
+                }
+                _ => Err(err),
+            },
+        }
+    }
+
+    fn cancel_order(&mut self, id: OrderId) -> Result<Order> {
+        let params = [
+            ("symbol", self.config.symbol.clone()),
+            ("orderId", id.to_string()),
+        ];
+        let body = self.signed("DELETE", "/fapi/v1/order", &params)?;
+        to_order(serde_json::from_str(&body)?)
+    }
+
+    fn order(&mut self, id: OrderId) -> Result<Order> {
+        let params = [
+            ("symbol", self.config.symbol.clone()),
+            ("orderId", id.to_string()),
+        ];
+        let body = self.signed("GET", "/fapi/v1/order", &params)?;
+        to_order(serde_json::from_str(&body)?)
+    }
+
+    fn position(&mut self) -> Result<ExchangePosition> {
+        let params = [("symbol", self.config.symbol.clone())];
+        let body = self.signed("GET", "/fapi/v2/positionRisk", &params)?;
+        let positions: Vec<PositionRisk> = serde_json::from_str(&body)?;
+        let positions: Vec<&PositionRisk> = positions
+            .iter()
+            .filter(|position| position.symbol == self.config.symbol)
+            .collect();
+        if positions
+            .iter()
+            .any(|position| position.position_side != "BOTH")
+        {
+            bail!(
+                "{} is in hedge mode, only one-way mode is supported",
+                self.config.symbol
+            );
+        }
+        let Some(position) = positions.first() else {
+            return Ok(ExchangePosition::default());
+        };
+        let amount: f64 = position.position_amt.parse()?;
+        let side = if amount > 0. {
+            TradeSide::Buy
+        } else if amount < 0. {
+            TradeSide::Sell
+        } else {
+            TradeSide::None
+        };
+        Ok(ExchangePosition {
+            side,
+            size: amount.abs(),
+            entry_price: position.entry_price.parse()?,
+        })
+    }
+
+    fn balance(&mut self) -> Result<f64> {
+        let body = self.signed("GET", "/fapi/v2/balance", &[])?;
+        let balances: Vec<AssetBalance> = serde_json::from_str(&body)?;
+        let usdt = balances
+            .iter()
+            .find(|balance| balance.asset == "USDT")
+            .ok_or_else(|| anyhow!("no USDT balance in account"))?;
+        Ok(usdt.balance.parse()?)
+    }
+
+    fn fetch_klines(&mut self, from_ts: i64, to_ts: i64) -> Result<Vec<Kline>> {
+        let mut klines: Vec<Kline> = Vec::new();
+        let mut start_time = from_ts;
+        while start_time <= to_ts {
+            let params = [
+                ("symbol", self.config.symbol.clone()),
+                ("interval", "15m".to_string()),
+                ("startTime", start_time.to_string()),
+                ("endTime", to_ts.to_string()),
+                ("limit", KLINE_LIMIT.to_string()),
+            ];
+            let body = self.send("GET", "/fapi/v1/klines", &params, false)?;
+            let rows: Vec<Vec<Value>> = serde_json::from_str(&body)?;
+            let count = rows.len();
+            for row in rows {
+                klines.push(parse_kline_row(&row)?);
+            }
+            match klines.last() {
+                Some(last) if count == KLINE_LIMIT => start_time = last.close_time + 1,
+                _ => break,
+            }
+        }
+        Ok(klines)
+    }
+}
+
+#[derive(Debug)]
+pub enum UserDataEvent {
+    OrderUpdate(Order),
+    AccountUpdate {
+        balance: Option<f64>,
+        position: Option<ExchangePosition>,
+    },
+    ListenKeyExpired,
+}
+
+pub struct UserDataStream {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    listen_key: String,
+    symbol: String,
+    last_keepalive: Instant,
+}
+
+impl UserDataStream {
+    // Blocks until the next order/account event, keeping the listen key alive on the way
+    pub fn next_event(&mut self, client: &mut BinanceFutures) -> Result<UserDataEvent> {
+        loop {
+            if self.last_keepalive.elapsed() >= LISTEN_KEY_KEEPALIVE {
+                client.keepalive_user_data_stream(&self.listen_key)?;
+                self.last_keepalive = Instant::now();
+            }
+            let text = match self.socket.read() {
+                Ok(Message::Text(text)) => text,
+                Ok(Message::Close(_)) => bail!("user data stream closed"),
+                Ok(_) => continue,
+                // The read timeout, go round to check the keepalive
+                Err(tungstenite::Error::Io(err))
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let event: Value = serde_json::from_str(&text)?;
+            match event["e"].as_str() {
+                // The account can trade other symbols, only ours are reported
+                Some("ORDER_TRADE_UPDATE") if event["o"]["s"] == self.symbol.as_str() => {
+                    return Ok(UserDataEvent::OrderUpdate(parse_order_update(&event["o"])?))
+                }
+                Some("ACCOUNT_UPDATE") => {
+                    let account = &event["a"];
+                    let balance = account["B"]
+                        .as_array()
+                        .and_then(|balances| balances.iter().find(|b| b["a"] == "USDT"))
+                        .and_then(|balance| balance["wb"].as_str())
+                        .and_then(|balance| balance.parse().ok());
+                    let positions: Vec<&Value> = account["P"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter(|position| position["s"] == self.symbol.as_str())
+                        .collect();
+                    let is_hedged = |position: &&Value| {
+                        position["ps"].as_str().is_some_and(|side| side != "BOTH")
+                    };
+                    if positions.iter().any(is_hedged) {
+                        bail!(
+                            "{} is in hedge mode, only one-way mode is supported",
+                            self.symbol
+                        );
+                    }
+                    let position = positions
+                        .first()
+                        .map(|position| parse_account_position(position))
+                        .transpose()?;
+                    return Ok(UserDataEvent::AccountUpdate { balance, position });
+                }
+                Some("listenKeyExpired") => return Ok(UserDataEvent::ListenKeyExpired),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn sign(secret: &str, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(payload.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn side_to_str(side: &TradeSide) -> Result<&'static str> {
+    match side {
+        TradeSide::Buy => Ok("BUY"),
+        TradeSide::Sell => Ok("SELL"),
+        _ => bail!("cannot place an order with side {:?}", side),
+    }
+}
+
+fn side_from_str(side: &str) -> TradeSide {
+    match side {
+        "BUY" => TradeSide::Buy,
+        "SELL" => TradeSide::Sell,
+        _ => TradeSide::None,
+    }
+}
+
+fn status_from_str(status: &str) -> OrderStatus {
+    match status {
+        "NEW" => OrderStatus::Pending,
+        "PARTIALLY_FILLED" => OrderStatus::PartiallyFilled,
+        "FILLED" => OrderStatus::Filled,
+        "REJECTED" => OrderStatus::Rejected,
+        // CANCELED, EXPIRED
+        _ => OrderStatus::Cancelled,
+    }
+}
+
+fn order_type_from_str(order_type: &str, price: f64, stop_price: f64) -> OrderType {
+    match order_type {
+        "LIMIT" => OrderType::Limit { price },
+        "STOP_MARKET" => OrderType::StopMarket { stop_price },
+        "TAKE_PROFIT_MARKET" => OrderType::TakeProfitMarket { stop_price },
+        _ => OrderType::Market,
+    }
+}
+
+fn parse_or_zero(value: &str) -> f64 {
+    value.parse().unwrap_or(0.)
+}
+
+// Commissions are not part of order responses, fee stays 0
+fn to_order(response: OrderResponse) -> Result<Order> {
+    Ok(Order {
+        status: status_from_str(&response.status),
+        filled_size: response.executed_qty.parse()?,
+        avg_fill_price: parse_or_zero(&response.avg_price),
+        ..Order::new(
+            response.order_id,
+            OrderRequest {
+                side: side_from_str(&response.side),
+                order_type: order_type_from_str(
+                    &response.order_type,
+                    parse_or_zero(&response.price),
+                    parse_or_zero(&response.stop_price),
+                ),
+                size: response.orig_qty.parse()?,
+                reduce_only: response.reduce_only,
+            },
+        )
+    })
+}
+
+fn parse_order_update(order: &Value) -> Result<Order> {
+    let field = |key: &str| order[key].as_str().unwrap_or_default();
+    let id = order["i"]
+        .as_u64()
+        .ok_or_else(|| anyhow!("order update without id"))?;
+    Ok(Order {
+        status: status_from_str(field("X")),
+        filled_size: parse_or_zero(field("z")),
+        avg_fill_price: parse_or_zero(field("ap")),
+        ..Order::new(
+            id,
+            OrderRequest {
+                side: side_from_str(field("S")),
+                order_type: order_type_from_str(
+                    field("o"),
+                    parse_or_zero(field("p")),
+                    parse_or_zero(field("sp")),
+                ),
+                size: parse_or_zero(field("q")),
+                reduce_only: order["R"].as_bool().unwrap_or(false),
+            },
+        )
+    })
+}
+
+fn parse_account_position(position: &Value) -> Result<ExchangePosition> {
+    let amount = parse_or_zero(position["pa"].as_str().unwrap_or_default());
+    let side = if amount > 0. {
+        TradeSide::Buy
+    } else if amount < 0. {
+        TradeSide::Sell
+    } else {
+        TradeSide::None
+    };
+    Ok(ExchangePosition {
+        side,
+        size: amount.abs(),
+        entry_price: parse_or_zero(position["ep"].as_str().unwrap_or_default()),
+    })
+}
+
+// [open_time, open, high, low, close, volume, close_time, quote_volume, trades,
+//  taker_buy_volume, taker_buy_quote_volume, ignore]
+fn parse_kline_row(row: &[Value]) -> Result<Kline> {
+    let number = |index: usize| -> Result<f64> {
+        row.get(index)
+            .and_then(|value| value.as_str())
+            .ok_or_else(|| anyhow!("malformed kline row: {:?}", row))?
+            .parse::<f64>()
+            .map_err(Into::into)
+    };
+    Ok(Kline {
+        open_time: row[0]
+            .as_i64()
+            .ok_or_else(|| anyhow!("malformed kline row"))?,
+        close_time: row[6]
+            .as_i64()
+            .ok_or_else(|| anyhow!("malformed kline row"))?,
+        open: number(1)?,
+        high: number(2)?,
+        low: number(3)?,
+        close: number(4)?,
+        volume: number(5).ok(),
+        quote_volume: number(7).ok(),
+        trade_count: row.get(8).and_then(|value| value.as_u64()),
+        taker_buy_volume: number(9).ok(),
+        taker_buy_quote_volume: number(10).ok(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::exchange::stub::{StubRecording, StubRoute, StubServer};
+
+    fn route(method: &str, path: &str, status: u16, body: Value) -> StubRoute {
+        StubRoute {
+            method: method.to_string(),
+            path: path.to_string(),
+            status,
+            body,
+        }
+    }
+
+    fn client(server: &StubServer) -> BinanceFutures {
+        BinanceFutures::new(&BinanceConfig {
+            base_url: server.base_url(),
+            ws_url: server.ws_url(),
+            api_key: "key".to_string(),
+            api_secret: "secret".to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn query_param<'a>(request: &'a str, key: &str) -> Option<&'a str> {
+        let query = request.split_whitespace().nth(1)?.split_once('?')?.1;
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+    }
+
+    #[test]
+    fn signs_like_the_api_docs() {
+        let secret = "NhqPtmdSJYdKjVHjA7PZj4Mge3R5YNiP1e3UZjInClVN65XAbvqqM6A7H5fATj0j";
+        let query = "symbol=LTCBTC&side=BUY&type=LIMIT&timeInForce=GTC&quantity=1&price=0.1\
+            &recvWindow=5000&timestamp=1499827319559";
+        assert_eq!(
+            sign(secret, query),
+            "c8db56825ae71d6d79447849e617115f4a920fa2acdcab2b053c4b2838bd6b71"
+        );
+    }
+
+    #[test]
+    fn places_signed_order_and_parses_the_result() {
+        let server = StubServer::start(StubRecording {
+            routes: vec![route(
+                "POST",
+                "/fapi/v1/order",
+                200,
+                json!({
+                    "orderId": 42,
+                    "status": "FILLED",
+                    "side": "SELL",
+                    "type": "STOP_MARKET",
+                    "origQty": "0.010",
+                    "executedQty": "0.010",
+                    "avgPrice": "29950.5",
+                    "price": "0",
+                    "stopPrice": "30000.0",
+                    "reduceOnly": true,
+                }),
+            )],
+            ws_messages: Vec::new(),
+        })
+        .unwrap();
+        let mut client = client(&server);
+        let order = client
+            .place_order(OrderRequest {
+                side: TradeSide::Sell,
+                order_type: OrderType::StopMarket { stop_price: 30000. },
+                size: 0.01,
+                reduce_only: true,
+            })
+            .unwrap();
+        assert_eq!(order.id, 42);
+        assert_eq!(order.status, OrderStatus::Filled);
+        assert_eq!(order.side, TradeSide::Sell);
+        assert_eq!(
+            order.order_type,
+            OrderType::StopMarket { stop_price: 30000. }
+        );
+        assert_eq!(order.filled_size, 0.01);
+        assert_eq!(order.avg_fill_price, 29950.5);
+        assert!(order.reduce_only);
+
+        let request = server.requests.lock().unwrap()[0].clone();
+        assert!(request.starts_with("POST /fapi/v1/order?"));
+        assert_eq!(query_param(&request, "symbol"), Some("BTCUSDT"));
+        assert_eq!(query_param(&request, "side"), Some("SELL"));
+        assert_eq!(query_param(&request, "type"), Some("STOP_MARKET"));
+        assert_eq!(query_param(&request, "quantity"), Some("0.010"));
+        assert_eq!(query_param(&request, "stopPrice"), Some("30000.0"));
+        assert_eq!(query_param(&request, "reduceOnly"), Some("true"));
+        let (payload, signature) = request
+            .split_whitespace()
+            .nth(1)
+            .and_then(|target| target.split_once('?'))
+            .and_then(|(_, query)| query.rsplit_once("&signature="))
+            .unwrap();
+        assert_eq!(signature, sign("secret", payload));
+    }
+
+    #[test]
+    fn api_errors_reject_the_order() {
+        let server = StubServer::start(StubRecording {
+            routes: vec![route(
+                "POST",
+                "/fapi/v1/order",
+                400,
+                json!({"code": -2019, "msg": "Margin is insufficient."}),
+            )],
+            ws_messages: Vec::new(),
+        })
+        .unwrap();
+        let order = client(&server)
+            .place_order(OrderRequest {
+                side: TradeSide::Buy,
+                order_type: OrderType::Market,
+                size: 1.,
+                reduce_only: false,
+            })
+            .unwrap();
+        assert_eq!(order.status, OrderStatus::Rejected);
+    }
+
+    #[test]
+    fn server_errors_and_limits_are_not_rejections() {
+        let responses = [
+            (503, json!({"code": -1000, "msg": "Unknown error."})),
+            (429, json!({"code": -1003, "msg": "Too many requests."})),
+            (418, json!({"code": -1003, "msg": "Way too many requests."})),
+        ];
+        let recording = StubRecording {
+            routes: responses
+                .iter()
+                .map(|(status, body)| route("POST", "/fapi/v1/order", *status, body.clone()))
+                .collect(),
+            ws_messages: Vec::new(),
+        };
+        let path = std::env::temp_dir().join(format!(
+            "bb_band_stub_recording_{}.json",
+            std::process::id()
+        ));
+        std::fs::write(&path, serde_json::to_string(&recording).unwrap()).unwrap();
+        let server = StubServer::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut client = client(&server);
+        for (status, _) in responses {
+            let err = client
+                .place_order(OrderRequest {
+                    side: TradeSide::Buy,
+                    order_type: OrderType::Market,
+                    size: 1.,
+                    reduce_only: false,
+                })
+                .unwrap_err();
+            let api_err = err.downcast_ref::<BinanceApiError>().unwrap();
+            assert_eq!(api_err.status, status);
+            assert!(!api_err.is_rejection());
+        }
+    }
+
+    #[test]
+    fn position_is_read_for_the_configured_symbol() {
+        let server = StubServer::start(StubRecording {
+            routes: vec![
+                route(
+                    "GET",
+                    "/fapi/v2/positionRisk",
+                    200,
+                    json!([
+                        {"symbol": "ETHUSDT", "positionAmt": "3.0", "entryPrice": "2000", "positionSide": "BOTH"},
+                        {"symbol": "BTCUSDT", "positionAmt": "-0.250", "entryPrice": "30100.5", "positionSide": "BOTH"},
+                    ]),
+                ),
+                route(
+                    "GET",
+                    "/fapi/v2/positionRisk",
+                    200,
+                    json!([{"symbol": "BTCUSDT", "positionAmt": "0.000", "entryPrice": "0.0"}]),
+                ),
+                route(
+                    "GET",
+                    "/fapi/v2/positionRisk",
+                    200,
+                    json!([
+                        {"symbol": "BTCUSDT", "positionAmt": "0.1", "entryPrice": "30000", "positionSide": "LONG"},
+                        {"symbol": "BTCUSDT", "positionAmt": "0.0", "entryPrice": "0", "positionSide": "SHORT"},
+                    ]),
+                ),
+            ],
+            ws_messages: Vec::new(),
+        })
+        .unwrap();
+        let mut client = client(&server);
+
+        let position = client.position().unwrap();
+        assert_eq!(position.side, TradeSide::Sell);
+        assert_eq!(position.size, 0.25);
+        assert_eq!(position.entry_price, 30100.5);
+        assert_eq!(client.position().unwrap(), ExchangePosition::default());
+        assert!(client.position().is_err());
+    }
+
+    #[test]
+    fn user_data_stream_parses_our_events() {
+        let server = StubServer::start(StubRecording {
+            routes: vec![route(
+                "POST",
+                "/fapi/v1/listenKey",
+                200,
+                json!({"listenKey": "stub-key"}),
+            )],
+            ws_messages: vec![
+                json!({"e": "ORDER_TRADE_UPDATE", "o": {"s": "ETHUSDT", "i": 1, "X": "NEW"}}),
+                json!({"e": "ORDER_TRADE_UPDATE", "o": {
+                    "s": "BTCUSDT", "i": 7, "X": "PARTIALLY_FILLED", "S": "BUY", "o": "LIMIT",
+                    "p": "29000.0", "sp": "0", "q": "0.020", "z": "0.005", "ap": "29000.0", "R": false,
+                }}),
+                json!({"e": "ACCOUNT_UPDATE", "a": {
+                    "B": [{"a": "BNB", "wb": "1.0"}, {"a": "USDT", "wb": "1234.5"}],
+                    "P": [
+                        {"s": "ETHUSDT", "pa": "2.0", "ep": "2000.0", "ps": "BOTH"},
+                        {"s": "BTCUSDT", "pa": "0.005", "ep": "29000.0", "ps": "BOTH"},
+                    ],
+                }}),
+                json!({"e": "listenKeyExpired"}),
+            ],
+        })
+        .unwrap();
+        let mut client = client(&server);
+        let mut stream = client.start_user_data_stream().unwrap();
+
+        let UserDataEvent::OrderUpdate(order) = stream.next_event(&mut client).unwrap() else {
+            panic!("expected an order update");
+        };
+        assert_eq!(order.id, 7);
+        assert_eq!(order.status, OrderStatus::PartiallyFilled);
+        assert_eq!(order.order_type, OrderType::Limit { price: 29000. });
+        assert_eq!(order.size, 0.02);
+        assert_eq!(order.filled_size, 0.005);
+
+        let UserDataEvent::AccountUpdate { balance, position } =
+            stream.next_event(&mut client).unwrap()
+        else {
+            panic!("expected an account update");
+        };
+        assert_eq!(balance, Some(1234.5));
+        let position = position.unwrap();
+        assert_eq!(position.side, TradeSide::Buy);
+        assert_eq!(position.size, 0.005);
+
+        assert!(matches!(
+            stream.next_event(&mut client).unwrap(),
+            UserDataEvent::ListenKeyExpired
+        ));
+        let requests = server.requests.lock().unwrap();
+        assert!(requests[0].starts_with("POST /fapi/v1/listenKey"));
+    }
+}
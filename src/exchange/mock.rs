@@ -0,0 +1,227 @@
+use anyhow::{anyhow, bail, Result};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{Exchange, ExchangePosition};
+use crate::{
+    order::{Order, OrderId, OrderRequest, OrderType},
+    types::{Kline, MockExchangeConfig},
+    utils::calculate_fee,
+    TradeSide,
+};
+
+const MIN_SIZE: f64 = 1e-12;
+
+struct MockOrder {
+    order: Order,
+    active_from: usize, // index of the first kline the order can fill on
+}
+
+// Deterministic exchange that matches orders against a fixed kline series.
+// Klines are released one by one with advance(); orders placed after a kline closed
+// can only fill from the next kline on, plus the configured latency.
+pub struct MockExchange {
+    klines: Vec<Kline>,
+    cursor: usize,
+    config: MockExchangeConfig,
+    fee_rate: f64,
+    leverage: u64,
+    balance: f64,
+    position: ExchangePosition,
+    orders: Vec<MockOrder>,
+    rng: ChaCha8Rng,
+    pub total_fee: f64,
+}
+
+impl MockExchange {
+    pub fn new(
+        klines: Vec<Kline>,
+        config: &MockExchangeConfig,
+        initial_balance: f64,
+        fee_rate: f64,
+        leverage: u64,
+    ) -> Self {
+        MockExchange {
+            klines,
+            cursor: 0,
+            config: config.clone(),
+            fee_rate,
+            leverage,
+            balance: initial_balance,
+            position: ExchangePosition::default(),
+            orders: Vec::new(),
+            rng: ChaCha8Rng::seed_from_u64(config.seed),
+            total_fee: 0.,
+        }
+    }
+
+    // Releases the next kline, filling any active orders it touches
+    pub fn advance(&mut self) -> Option<Kline> {
+        let kline = self.klines.get(self.cursor)?.clone();
+        for index in 0..self.orders.len() {
+            let is_active = self.orders[index].active_from <= self.cursor
+                && self.orders[index].order.status.is_open();
+            if is_active {
+                self.match_order(index, &kline);
+            }
+        }
+        if self.position.side == TradeSide::None {
+            self.cancel_reduce_only_orders();
+        }
+        self.cursor += 1;
+        Some(kline)
+    }
+
+    fn match_order(&mut self, index: usize, kline: &Kline) {
+        let request = self.orders[index].order.request();
+        let is_buy = request.side == TradeSide::Buy;
+        // Gaps through the trigger fill at the open, like a real market order would
+        let fill_price = match request.order_type {
+            OrderType::Market => Some(kline.open),
+            OrderType::Limit { price } => {
+                if is_buy && kline.low <= price {
+                    Some(price.min(kline.open))
+                } else if !is_buy && kline.high >= price {
+                    Some(price.max(kline.open))
+                } else {
+                    None
+                }
+            }
+            OrderType::StopMarket { stop_price } => {
+                if is_buy && kline.high >= stop_price {
+                    Some(stop_price.max(kline.open))
+                } else if !is_buy && kline.low <= stop_price {
+                    Some(stop_price.min(kline.open))
+                } else {
+                    None
+                }
+            }
+            OrderType::TakeProfitMarket { stop_price } => {
+                if is_buy && kline.low <= stop_price {
+                    Some(stop_price.min(kline.open))
+                } else if !is_buy && kline.high >= stop_price {
+                    Some(stop_price.max(kline.open))
+                } else {
+                    None
+                }
+            }
+        };
+        let Some(fill_price) = fill_price else {
+            return;
+        };
+
+        let order = &self.orders[index].order;
+        let mut fill_size = order
+            .remaining()
+            .min(order.size * self.config.partial_fill_ratio);
+        if request.reduce_only {
+            let is_reducing =
+                self.position.side != TradeSide::None && self.position.side != request.side;
+            if !is_reducing {
+                self.orders[index].order.cancel().unwrap();
+                return;
+            }
+            fill_size = fill_size.min(self.position.size);
+        }
+        if fill_size <= MIN_SIZE {
+            return;
+        }
+
+        let fee = self.apply_fill(&request.side, fill_size, fill_price);
+        let order = &mut self.orders[index].order;
+        order.fill(fill_size, fill_price, fee).unwrap();
+    }
+
+    // Returns the fee charged for the fill
+    fn apply_fill(&mut self, side: &TradeSide, size: f64, price: f64) -> f64 {
+        let fee = calculate_fee(self.fee_rate, price, size, self.leverage);
+        self.balance -= fee;
+        self.total_fee += fee;
+
+        let position = &mut self.position;
+        if position.side == TradeSide::None || position.side == *side {
+            position.entry_price =
+                (position.entry_price * position.size + price * size) / (position.size + size);
+            position.size += size;
+            position.side = side.clone();
+            return fee;
+        }
+        let close_size = size.min(position.size);
+        let profit = (price - position.entry_price)
+            * close_size
+            * position.side.value()
+            * self.leverage as f64;
+        self.balance += profit;
+        position.size -= close_size;
+        let flip_size = size - close_size;
+        if flip_size > MIN_SIZE {
+            position.side = side.clone();
+            position.size = flip_size;
+            position.entry_price = price;
+        } else if position.size <= MIN_SIZE {
+            *position = ExchangePosition::default();
+        }
+        fee
+    }
+
+    fn cancel_reduce_only_orders(&mut self) {
+        for mock_order in self.orders.iter_mut() {
+            if mock_order.order.reduce_only && mock_order.order.status.is_open() {
+                mock_order.order.cancel().unwrap();
+            }
+        }
+    }
+
+    fn find_order(&mut self, id: OrderId) -> Result<&mut MockOrder> {
+        self.orders
+            .get_mut((id as usize).wrapping_sub(1))
+            .ok_or_else(|| anyhow!("unknown order id {}", id))
+    }
+}
+
+impl Exchange for MockExchange {
+    fn place_order(&mut self, request: OrderRequest) -> Result<Order> {
+        if request.size <= 0. || request.side.value() == 0. {
+            bail!("invalid order request: {:?}", request);
+        }
+        let is_flat = self.position.side == TradeSide::None;
+        let is_rejected = self.rng.gen_bool(self.config.rejection_rate.clamp(0., 1.))
+            || (request.reduce_only && is_flat);
+        let mut order = Order::new(self.orders.len() as OrderId + 1, request);
+        if is_rejected {
+            order.reject()?;
+        }
+        self.orders.push(MockOrder {
+            order: order.clone(),
+            active_from: self.cursor + self.config.latency_bars,
+        });
+        Ok(order)
+    }
+
+    fn cancel_order(&mut self, id: OrderId) -> Result<Order> {
+        let mock_order = self.find_order(id)?;
+        mock_order.order.cancel()?;
+        Ok(mock_order.order.clone())
+    }
+
+    fn order(&mut self, id: OrderId) -> Result<Order> {
+        Ok(self.find_order(id)?.order.clone())
+    }
+
+    fn position(&mut self) -> Result<ExchangePosition> {
+        Ok(self.position.clone())
+    }
+
+    fn balance(&mut self) -> Result<f64> {
+        Ok(self.balance)
+    }
+
+    fn fetch_klines(&mut self, from_ts: i64, to_ts: i64) -> Result<Vec<Kline>> {
+        // Only klines that have already been released, so there is no lookahead
+        Ok(self.klines[..self.cursor]
+            .iter()
+            .filter(|kline| kline.close_time >= from_ts && kline.close_time <= to_ts)
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,29 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    order::{Order, OrderId, OrderRequest},
+    types::Kline,
+    TradeSide,
+};
+
+pub mod binance;
+pub mod mock;
+pub mod stub;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExchangePosition {
+    pub side: TradeSide,
+    pub size: f64,
+    pub entry_price: f64,
+}
+
+// Single-symbol view of an exchange account, implemented by the mock and by real connectors
+pub trait Exchange {
+    fn place_order(&mut self, request: OrderRequest) -> Result<Order>;
+    fn cancel_order(&mut self, id: OrderId) -> Result<Order>;
+    fn order(&mut self, id: OrderId) -> Result<Order>;
+    fn position(&mut self) -> Result<ExchangePosition>;
+    fn balance(&mut self) -> Result<f64>;
+    fn fetch_klines(&mut self, from_ts: i64, to_ts: i64) -> Result<Vec<Kline>>;
+}
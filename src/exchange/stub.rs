@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tungstenite::Message;
+
+// A recorded exchange session: HTTP responses per route and user data stream messages
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct StubRecording {
+    pub routes: Vec<StubRoute>,
+    #[serde(default)]
+    pub ws_messages: Vec<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StubRoute {
+    pub method: String,
+    pub path: String,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    pub body: Value,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+// Local HTTP + WebSocket server replaying a StubRecording, so connectors can be exercised
+// without network access. Responses for the same route are served in recorded order and
+// the last one repeats. Every request line received, followed by its body if any, is kept
+// in `requests`.
+pub struct StubServer {
+    pub http_addr: SocketAddr,
+    pub ws_addr: SocketAddr,
+    pub requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl StubServer {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let recording: StubRecording = serde_json::from_reader(File::open(path)?)?;
+        Self::start(recording)
+    }
+
+    pub fn start(recording: StubRecording) -> Result<Self> {
+        let http_listener = TcpListener::bind("127.0.0.1:0")?;
+        let ws_listener = TcpListener::bind("127.0.0.1:0")?;
+        let server = StubServer {
+            http_addr: http_listener.local_addr()?,
+            ws_addr: ws_listener.local_addr()?,
+            requests: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        let mut routes: HashMap<(String, String), Vec<StubRoute>> = HashMap::new();
+        for route in recording.routes {
+            let key = (route.method.to_uppercase(), route.path.clone());
+            routes.entry(key).or_default().push(route);
+        }
+        let requests = server.requests.clone();
+        thread::spawn(move || {
+            let mut served: HashMap<(String, String), usize> = HashMap::new();
+            for stream in http_listener.incoming().flatten() {
+                if let Err(err) = serve_http(stream, &routes, &mut served, &requests) {
+                    warn!("stub http error: {}", err);
+                }
+            }
+        });
+
+        let ws_messages = recording.ws_messages;
+        thread::spawn(move || {
+            for stream in ws_listener.incoming().flatten() {
+                if let Err(err) = serve_ws(stream, &ws_messages) {
+                    warn!("stub ws error: {}", err);
+                }
+            }
+        });
+        Ok(server)
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.http_addr)
+    }
+
+    pub fn ws_url(&self) -> String {
+        format!("ws://{}", self.ws_addr)
+    }
+}
+
+fn serve_http(
+    stream: TcpStream,
+    routes: &HashMap<(String, String), Vec<StubRoute>>,
+    served: &mut HashMap<(String, String), usize>,
+    requests: &Mutex<Vec<String>>,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+    let mut request = format!("{} {}", method, target);
+    if !body.is_empty() {
+        request += &format!(" {}", String::from_utf8_lossy(&body));
+    }
+    requests.lock().unwrap().push(request);
+    let path = target.split('?').next().unwrap_or_default().to_string();
+
+    let key = (method, path);
+    let (status, body) = match routes.get(&key) {
+        Some(responses) => {
+            let count = served.entry(key).or_insert(0);
+            let route = &responses[(*count).min(responses.len() - 1)];
+            *count += 1;
+            (route.status, route.body.to_string())
+        }
+        None => (404, r#"{"code":-1,"msg":"no stub route"}"#.to_string()),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} STUB\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn serve_ws(stream: TcpStream, messages: &[Value]) -> Result<()> {
+    let mut socket = tungstenite::accept(stream)?;
+    for message in messages {
+        socket.send(Message::Text(message.to_string()))?;
+    }
+    // Keep the connection open until the client goes away
+    while socket.read().is_ok() {}
+    Ok(())
+}
@@ -0,0 +1,413 @@
+use std::{fs::File, path::Path};
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{
+    hypertune::output::TrialRow,
+    types::{AnalysisConfig, BacktestMetric, HypertuneConfig},
+    utils::{mean_std, normal_cdf, normal_quantile},
+    DAY_MS,
+};
+
+const EULER_MASCHERONI: f64 = 0.5772156649;
+const MAX_CSCV_BLOCKS: usize = 20;
+
+#[derive(Debug, Serialize)]
+pub struct Analysis {
+    pub trials: usize,
+    pub days: usize,
+    pub best_trial: usize, // highest Sharpe ratio, index into the trial output
+    pub best_sharpe: f64,  // annualised
+    pub expected_max_sharpe: f64, // annualised, best Sharpe expected from luck alone
+    pub deflated_sharpe: f64, // probability the best Sharpe beats that
+    pub pbo: Option<f64>,
+    pub cscv_splits: usize,
+    pub best_is_fragile: bool,
+    pub fragile: Vec<FragilePeak>,
+}
+
+// A trial doing much better than the trials one grid step away from it
+#[derive(Debug, Serialize)]
+pub struct FragilePeak {
+    pub trial: usize,
+    pub take_profit_percentage: f64,
+    pub stop_loss_percentage: f64,
+    pub bb_width: f64,
+    pub total_return: f64,
+    pub neighbour_return: f64, // mean over the neighbours
+    pub neighbours: usize,
+}
+
+// Net trade returns summed per day since start, days without exits are 0
+pub fn daily_returns(metric: &BacktestMetric, start: i64, days: usize) -> Vec<f64> {
+    let mut returns = vec![0.; days];
+    if days == 0 {
+        return returns;
+    }
+    for trade in &metric.trades {
+        let day = (trade.exit_time - start)
+            .div_euclid(DAY_MS)
+            .clamp(0, days as i64 - 1);
+        returns[day as usize] += trade.net_return();
+    }
+    returns
+}
+
+// Deflated Sharpe ratio of the best trial, probability of backtest overfitting through
+// combinatorially symmetric cross-validation, and fragile peaks in the grid.
+// Written to <base>.analysis.json.
+pub fn analyse(rows: &[TrialRow], hypertune_config: &HypertuneConfig, path: &Path) -> Result<()> {
+    let analysis_config = &hypertune_config.analysis;
+    let returns: Vec<&[f64]> = rows.iter().map(|row| row.returns.as_slice()).collect();
+    let days = returns
+        .iter()
+        .map(|returns| returns.len())
+        .min()
+        .unwrap_or(0);
+    if rows.len() < 2 || days < 2 {
+        warn!("hypertune analysis needs at least 2 trials with 2 days of returns");
+        return Ok(());
+    }
+    let returns: Vec<&[f64]> = returns.iter().map(|returns| &returns[..days]).collect();
+
+    let sharpes: Vec<f64> = returns.iter().map(|returns| sharpe(returns)).collect();
+    let best_trial = (0..sharpes.len())
+        .max_by(|a, b| sharpes[*a].total_cmp(&sharpes[*b]))
+        .unwrap();
+    let (expected_max_sharpe, deflated_sharpe) = deflated_sharpe(&sharpes, returns[best_trial]);
+    let (pbo, cscv_splits) = match probability_of_overfitting(&returns, analysis_config) {
+        Some((pbo, splits)) => (Some(pbo), splits),
+        None => (None, 0),
+    };
+    let fragile = fragile_peaks(rows, hypertune_config);
+    let annualise = 365f64.sqrt();
+    let analysis = Analysis {
+        trials: rows.len(),
+        days,
+        best_trial,
+        best_sharpe: sharpes[best_trial] * annualise,
+        expected_max_sharpe: expected_max_sharpe * annualise,
+        deflated_sharpe,
+        pbo,
+        cscv_splits,
+        best_is_fragile: fragile.iter().any(|peak| peak.trial == best_trial),
+        fragile,
+    };
+    serde_json::to_writer_pretty(File::create(path)?, &analysis)?;
+
+    let mut msg = "".to_string();
+    msg += &format!("hypertune analysis: trials: {}, ", analysis.trials);
+    msg += &format!("days: {}, ", analysis.days);
+    msg += &format!("best_trial: {}, ", analysis.best_trial);
+    msg += &format!("best_sharpe: {:.4}, ", analysis.best_sharpe);
+    msg += &format!("expected_max_sharpe: {:.4}, ", analysis.expected_max_sharpe);
+    msg += &format!("deflated_sharpe: {:.4}, ", analysis.deflated_sharpe);
+    msg += &format!(
+        "pbo: {}, ",
+        analysis
+            .pbo
+            .map_or("-".to_string(), |pbo| format!("{:.4}", pbo))
+    );
+    msg += &format!("fragile: {}, ", analysis.fragile.len());
+    msg += &format!("best_is_fragile: {}", analysis.best_is_fragile);
+    info!("{}", msg);
+    if analysis.deflated_sharpe < 0.95 || analysis.pbo.is_some_and(|pbo| pbo > 0.5) {
+        warn!("the best hypertune trial is likely overfit, see {:?}", path);
+    }
+    Ok(())
+}
+
+fn sharpe(returns: &[f64]) -> f64 {
+    let (mean, std) = mean_std(returns);
+    if std > 0. {
+        mean / std
+    } else {
+        0.
+    }
+}
+
+// Bailey and Lopez de Prado: the Sharpe ratio the best of N unskilled trials would reach,
+// and the probability the selected trial's Sharpe ratio exceeds it given its
+// track length, skewness and kurtosis. Both per day.
+fn deflated_sharpe(sharpes: &[f64], best_returns: &[f64]) -> (f64, f64) {
+    let trials = sharpes.len() as f64;
+    let (_, sharpe_std) = mean_std(sharpes);
+    let expected_max = sharpe_std
+        * ((1. - EULER_MASCHERONI) * normal_quantile(1. - 1. / trials)
+            + EULER_MASCHERONI * normal_quantile(1. - 1. / (trials * std::f64::consts::E)));
+
+    let (mean, std) = mean_std(best_returns);
+    let best = sharpe(best_returns);
+    let len = best_returns.len() as f64;
+    let (skew, kurtosis) = if std > 0. {
+        let moment = |power: i32| {
+            best_returns
+                .iter()
+                .map(|ret| ((ret - mean) / std).powi(power))
+                .sum::<f64>()
+                / len
+        };
+        (moment(3), moment(4))
+    } else {
+        (0., 3.)
+    };
+    let denominator = 1. - skew * best + (kurtosis - 1.) / 4. * best * best;
+    let deflated = if denominator > 0. {
+        normal_cdf((best - expected_max) * (len - 1.).sqrt() / denominator.sqrt())
+    } else {
+        0.
+    };
+    (expected_max, deflated)
+}
+
+// CSCV: split the days into S blocks, and for every half of the blocks pick the best
+// trial in sample and look at its out of sample rank. PBO is the share of splits where
+// it ranks below the median.
+fn probability_of_overfitting(
+    returns: &[&[f64]],
+    analysis_config: &AnalysisConfig,
+) -> Option<(f64, usize)> {
+    let days = returns[0].len();
+    let blocks = analysis_config.cscv_blocks.min(MAX_CSCV_BLOCKS).min(days) / 2 * 2;
+    if blocks < 2 {
+        return None;
+    }
+    // sum, sum of squares and count of each trial's returns per block
+    let block_stats: Vec<Vec<(f64, f64, f64)>> = returns
+        .iter()
+        .map(|returns| {
+            (0..blocks)
+                .map(|block| {
+                    let range = block * days / blocks..(block + 1) * days / blocks;
+                    returns[range]
+                        .iter()
+                        .fold((0., 0., 0.), |(sum, sq, n), ret| {
+                            (sum + ret, sq + ret * ret, n + 1.)
+                        })
+                })
+                .collect()
+        })
+        .collect();
+    let combined_sharpe = |stats: &[(f64, f64, f64)], mask: u32, in_sample: bool| {
+        let (sum, sq, n) = stats
+            .iter()
+            .enumerate()
+            .filter(|(block, _)| (mask >> block & 1 == 1) == in_sample)
+            .fold((0., 0., 0.), |(sum, sq, n), (_, stats)| {
+                (sum + stats.0, sq + stats.1, n + stats.2)
+            });
+        let mean = sum / n;
+        let variance = (sq / n - mean * mean) * n / (n - 1.);
+        if variance > 0. {
+            mean / variance.sqrt()
+        } else {
+            0.
+        }
+    };
+
+    let trials = returns.len();
+    let mut splits = 0;
+    let mut overfit = 0;
+    for mask in 0u32..(1 << blocks) {
+        if mask.count_ones() as usize != blocks / 2 {
+            continue;
+        }
+        let in_sample: Vec<f64> = block_stats
+            .iter()
+            .map(|stats| combined_sharpe(stats, mask, true))
+            .collect();
+        let out_of_sample: Vec<f64> = block_stats
+            .iter()
+            .map(|stats| combined_sharpe(stats, mask, false))
+            .collect();
+        let best = (0..trials)
+            .max_by(|a, b| in_sample[*a].total_cmp(&in_sample[*b]))
+            .unwrap();
+        let rank = out_of_sample
+            .iter()
+            .filter(|sharpe| **sharpe < out_of_sample[best])
+            .count()
+            + 1;
+        let relative_rank = rank as f64 / (trials + 1) as f64;
+        let logit = (relative_rank / (1. - relative_rank)).ln();
+        splits += 1;
+        if logit <= 0. {
+            overfit += 1;
+        }
+    }
+    Some((overfit as f64 / splits as f64, splits))
+}
+
+// Trials whose total return is well above the mean of the trials at most one step away
+// in take profit, stop loss and bb width
+fn fragile_peaks(rows: &[TrialRow], hypertune_config: &HypertuneConfig) -> Vec<FragilePeak> {
+    let steps = [
+        hypertune_config.take_profit_percentage_step,
+        hypertune_config.stop_loss_percentage_step,
+        hypertune_config.bb_width_step,
+    ];
+    let points: Vec<Option<([f64; 3], f64)>> = rows
+        .iter()
+        .map(|row| {
+            let param = |key: &str| row.config.get(key).and_then(|value| value.as_f64());
+            let params = [
+                param("take_profit_percentage")?,
+                param("stop_loss_percentage")?,
+                param("bb_width")?,
+            ];
+            let total_return = row.metric("usd_balance")? / row.metric("initial_capital")? - 1.;
+            Some((params, total_return))
+        })
+        .collect();
+    let is_neighbour = |a: &[f64; 3], b: &[f64; 3]| {
+        a != b
+            && a.iter().zip(b).zip(steps).all(|((a, b), step)| {
+                if step > 0. {
+                    (a - b).abs() / step <= 1. + 1e-6
+                } else {
+                    a == b
+                }
+            })
+    };
+
+    let mut fragile = Vec::new();
+    for (trial, point) in points.iter().enumerate() {
+        let Some((params, total_return)) = point else {
+            continue;
+        };
+        if *total_return <= 0. {
+            continue;
+        }
+        let neighbour_returns: Vec<f64> = points
+            .iter()
+            .flatten()
+            .filter(|(other, _)| is_neighbour(params, other))
+            .map(|(_, total_return)| *total_return)
+            .collect();
+        if neighbour_returns.is_empty() {
+            continue;
+        }
+        let neighbour_return =
+            neighbour_returns.iter().sum::<f64>() / neighbour_returns.len() as f64;
+        if (total_return - neighbour_return) / total_return
+            > hypertune_config.analysis.fragile_threshold
+        {
+            fragile.push(FragilePeak {
+                trial,
+                take_profit_percentage: params[0],
+                stop_loss_percentage: params[1],
+                bb_width: params[2],
+                total_return: *total_return,
+                neighbour_return,
+                neighbours: neighbour_returns.len(),
+            });
+        }
+    }
+    fragile.sort_by(|a, b| b.total_return.total_cmp(&a.total_return));
+    fragile
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+    use rand_distr::{Distribution, Normal};
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::hypertune_config;
+
+    fn noise(trials: usize, days: usize, seed: u64) -> Vec<Vec<f64>> {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let normal = Normal::new(0., 0.01).unwrap();
+        (0..trials)
+            .map(|_| (0..days).map(|_| normal.sample(&mut rng)).collect())
+            .collect()
+    }
+
+    fn pbo(returns: &[Vec<f64>]) -> f64 {
+        let returns: Vec<&[f64]> = returns.iter().map(|returns| returns.as_slice()).collect();
+        let analysis_config = AnalysisConfig {
+            cscv_blocks: 10,
+            ..Default::default()
+        };
+        let (pbo, splits) = probability_of_overfitting(&returns, &analysis_config).unwrap();
+        assert_eq!(splits, 252);
+        pbo
+    }
+
+    #[test]
+    fn pbo_is_low_for_a_dominant_trial() {
+        let mut returns = noise(10, 300, 1);
+        for ret in returns[3].iter_mut() {
+            *ret += 0.01;
+        }
+        assert!(pbo(&returns) < 0.05);
+    }
+
+    #[test]
+    fn pbo_is_about_half_for_noise() {
+        let mean = (0..5).map(|seed| pbo(&noise(10, 300, seed))).sum::<f64>() / 5.;
+        assert!((0.3..0.7).contains(&mean), "pbo {}", mean);
+    }
+
+    #[test]
+    fn deflated_sharpe_falls_with_the_trial_count() {
+        let best = &noise(1, 300, 2)[0];
+        let best: Vec<f64> = best.iter().map(|ret| ret + 0.002).collect();
+        let mut last = (0., 1.);
+        for trials in [4, 8, 16, 64, 256] {
+            // Same spread of Sharpe ratios, more of them
+            let sharpes: Vec<f64> = (0..trials)
+                .map(|trial| if trial % 2 == 0 { 0.05 } else { -0.05 })
+                .collect();
+            let (expected_max, deflated) = deflated_sharpe(&sharpes, &best);
+            assert!(expected_max > last.0 && deflated < last.1);
+            last = (expected_max, deflated);
+        }
+    }
+
+    fn row(take_profit_percentage: f64, total_return: f64) -> TrialRow {
+        TrialRow {
+            metrics: vec![
+                ("initial_capital".to_string(), json!(1000.)),
+                (
+                    "usd_balance".to_string(),
+                    json!(1000. * (1. + total_return)),
+                ),
+            ],
+            config: json!({
+                "take_profit_percentage": take_profit_percentage,
+                "stop_loss_percentage": 0.01,
+                "bb_width": 2.,
+            }),
+            returns: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn fragile_peaks_flag_spikes_not_plateaus() {
+        let hypertune_config = hypertune_config(json!({}));
+        let spike = [
+            row(0.01, 0.01),
+            row(0.02, 0.2),
+            row(0.03, 0.01),
+            row(0.05, 0.3),
+        ];
+        let fragile = fragile_peaks(&spike, &hypertune_config);
+        assert_eq!(fragile.len(), 1);
+        assert_eq!((fragile[0].trial, fragile[0].neighbours), (1, 2));
+        assert!((fragile[0].neighbour_return - 0.01).abs() < 1e-9);
+
+        // The trial two steps away is no neighbour, so a lone trial is never fragile
+        let plateau = [
+            row(0.01, 0.19),
+            row(0.02, 0.2),
+            row(0.03, 0.19),
+            row(0.05, 0.3),
+        ];
+        assert!(fragile_peaks(&plateau, &hypertune_config).is_empty());
+    }
+}
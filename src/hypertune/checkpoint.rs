@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    hypertune::output::TrialRow,
+    types::{BbBandConfig, HypertuneConfig, Kline},
+};
+
+// First line of a checkpoint, ties it to one config, one data set and one set of output files
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckpointHeader {
+    hash: String,
+    base: PathBuf,
+    run_id: Option<String>,
+}
+
+// One line per completed trial
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckpointEntry {
+    key: String,
+    row: TrialRow,
+}
+
+// Completed trials of a hypertune run, appended as they finish. A rerun with the same
+// configs and klines picks the checkpoint up, rewrites the outputs from it and skips its trials.
+pub struct Checkpoint {
+    path: PathBuf,
+    file: File,
+    pub base: PathBuf,
+    pub run_id: Option<String>,
+    pub rows: Vec<TrialRow>,
+    done: HashMap<String, TrialRow>,
+}
+
+impl Checkpoint {
+    // dir is where a new run would write its outputs
+    pub fn open(
+        dir: &Path,
+        config: &BbBandConfig,
+        hypertune_config: &HypertuneConfig,
+        klines: &[Kline],
+        base: PathBuf,
+        run_id: Option<String>,
+    ) -> Result<Self> {
+        let hash = checkpoint_hash(config, hypertune_config, klines)?;
+        let path = dir.join(format!("hypertune_{}.checkpoint.jsonl", &hash[..12]));
+        if path.exists() {
+            return Self::resume(path, &hash);
+        }
+        let header = CheckpointHeader { hash, base, run_id };
+        let mut file = File::create(&path)?;
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        info!("hypertune checkpoint: {:?}", path);
+        Ok(Checkpoint {
+            path,
+            file,
+            base: header.base,
+            run_id: header.run_id,
+            rows: Vec::new(),
+            done: HashMap::new(),
+        })
+    }
+
+    fn resume(path: PathBuf, hash: &str) -> Result<Self> {
+        let mut lines = BufReader::new(File::open(&path)?).lines();
+        let header: CheckpointHeader = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => bail!("empty hypertune checkpoint {:?}", path),
+        };
+        if header.hash != hash {
+            bail!("hypertune checkpoint {:?} belongs to other configs", path);
+        }
+        let mut entries = Vec::new();
+        for line in lines {
+            // A crash can leave the last line half written, that trial is simply rerun
+            match serde_json::from_str::<CheckpointEntry>(&line?) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => warn!("skipping broken checkpoint line: {}", err),
+            }
+        }
+        info!(
+            "resuming hypertune from {:?} with {} completed trials",
+            path,
+            entries.len()
+        );
+        // Rewritten so a broken line does not end up in the middle of the file
+        let mut file = File::create(&path)?;
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        for entry in &entries {
+            writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        }
+        Ok(Checkpoint {
+            path,
+            file,
+            base: header.base,
+            run_id: header.run_id,
+            rows: entries.iter().map(|entry| entry.row.clone()).collect(),
+            done: entries
+                .into_iter()
+                .map(|entry| (entry.key, entry.row))
+                .collect(),
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&TrialRow> {
+        self.done.get(key)
+    }
+
+    pub fn record(&mut self, key: &str, row: &TrialRow) -> Result<()> {
+        let entry = CheckpointEntry {
+            key: key.to_string(),
+            row: row.clone(),
+        };
+        writeln!(self.file, "{}", serde_json::to_string(&entry)?)?;
+        self.done.insert(key.to_string(), row.clone());
+        Ok(())
+    }
+
+    // The run is complete, nothing left to resume
+    pub fn remove(self) -> Result<()> {
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+// The klines are hashed too, the configs do not say which data set (e.g. which
+// synthetic seed) the trials ran on
+fn checkpoint_hash(
+    config: &BbBandConfig,
+    hypertune_config: &HypertuneConfig,
+    klines: &[Kline],
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(&config.redacted())?);
+    hasher.update(serde_json::to_vec(hypertune_config)?);
+    for kline in klines {
+        hasher.update(kline.open_time.to_le_bytes());
+        hasher.update(kline.close_time.to_le_bytes());
+        for value in [kline.open, kline.high, kline.low, kline.close] {
+            hasher.update(value.to_le_bytes());
+        }
+        hasher.update(kline.volume.unwrap_or(f64::NAN).to_le_bytes());
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::{config, hypertune_config, klines};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "bb_band_checkpoint_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn row(usd_balance: f64) -> TrialRow {
+        TrialRow {
+            metrics: vec![("usd_balance".to_string(), json!(usd_balance))],
+            config: json!({}),
+            returns: vec![0.01, -0.02],
+        }
+    }
+
+    fn open(dir: &Path, klines: &[Kline]) -> Result<Checkpoint> {
+        Checkpoint::open(
+            dir,
+            &config(json!({})),
+            &hypertune_config(json!({})),
+            klines,
+            dir.join("trials"),
+            Some("run".to_string()),
+        )
+    }
+
+    fn checkpoint_file(dir: &Path) -> PathBuf {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        files.remove(0)
+    }
+
+    #[test]
+    fn trials_survive_a_restart() {
+        let dir = temp_dir("round_trip");
+        let klines = klines(100);
+        let mut checkpoint = open(&dir, &klines).unwrap();
+        checkpoint.record("a", &row(1.)).unwrap();
+        checkpoint.record("b", &row(2.)).unwrap();
+        drop(checkpoint);
+
+        let checkpoint = open(&dir, &klines).unwrap();
+        assert_eq!(checkpoint.base, dir.join("trials"));
+        assert_eq!(checkpoint.run_id.as_deref(), Some("run"));
+        assert_eq!(checkpoint.rows.len(), 2);
+        assert_eq!(checkpoint.get("b").unwrap().metric("usd_balance"), Some(2.));
+        assert_eq!(checkpoint.get("a").unwrap().returns, [0.01, -0.02]);
+        assert!(checkpoint.get("c").is_none());
+
+        checkpoint.remove().unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn broken_trailing_line_is_dropped() {
+        let dir = temp_dir("broken");
+        let klines = klines(100);
+        let mut checkpoint = open(&dir, &klines).unwrap();
+        checkpoint.record("a", &row(1.)).unwrap();
+        drop(checkpoint);
+        let path = checkpoint_file(&dir);
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{{\"key\": \"b\", \"row\": {{\"met").unwrap();
+        drop(file);
+
+        let mut checkpoint = open(&dir, &klines).unwrap();
+        assert_eq!(checkpoint.rows.len(), 1);
+        assert!(checkpoint.get("b").is_none());
+        checkpoint.record("b", &row(2.)).unwrap();
+        drop(checkpoint);
+
+        let checkpoint = open(&dir, &klines).unwrap();
+        assert_eq!(checkpoint.rows.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_klines_or_configs_are_not_resumed() {
+        let dir = temp_dir("mismatch");
+        let klines = klines(100);
+        let mut checkpoint = open(&dir, &klines).unwrap();
+        checkpoint.record("a", &row(1.)).unwrap();
+        drop(checkpoint);
+
+        // Another data set gets its own checkpoint
+        let other = open(&dir, &klines[..99]).unwrap();
+        assert!(other.rows.is_empty());
+        other.remove().unwrap();
+
+        // A file under the same name with another hash is refused
+        let path = checkpoint_file(&dir);
+        let content = fs::read_to_string(&path).unwrap();
+        let hash =
+            checkpoint_hash(&config(json!({})), &hypertune_config(json!({})), &klines).unwrap();
+        fs::write(&path, content.replace(&hash, &"0".repeat(hash.len()))).unwrap();
+        assert!(open(&dir, &klines).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,383 @@
+use std::{cmp::Ordering, fs::File};
+
+use anyhow::Result;
+use log::info;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, StandardNormal};
+
+use crate::{
+    hypertune::{
+        output::suffixed,
+        params::{apply, grid_params, random_gene},
+        tournament, violation, Trials,
+    },
+    types::{BbBandConfig, GeneticConfig, HypertuneConfig, ParamRange},
+};
+
+#[derive(Clone)]
+struct Individual {
+    genes: Vec<f64>,
+    fitness: f64, // higher is better, minimised objectives are negated
+    violation: f64,
+}
+
+// Genetic search: tournament selection, blend crossover, gaussian mutation and elitism.
+// Generation statistics go to <base>.generations.csv.
+pub fn search(
+    trials: &mut Trials,
+    config: &BbBandConfig,
+    hypertune_config: &HypertuneConfig,
+    genetic_config: &GeneticConfig,
+) -> Result<()> {
+    let params = params(hypertune_config, genetic_config);
+    // Fail on a bad field before spending any time on backtests
+    apply(
+        config,
+        &params,
+        &params.iter().map(|param| param.min).collect::<Vec<_>>(),
+    )?;
+    info!(
+        "genetic search over {:?}",
+        params.iter().map(|param| &param.field).collect::<Vec<_>>()
+    );
+
+    let mut rng = ChaCha8Rng::seed_from_u64(genetic_config.seed);
+    let population_size = genetic_config.population.max(2);
+    let elitism = genetic_config.elitism.min(population_size);
+    let path = suffixed(trials.base(), "generations.csv");
+    let mut writer = csv::Writer::from_writer(File::create(&path)?);
+    let mut header = vec![
+        "generation".to_string(),
+        "evaluations".to_string(),
+        "feasible".to_string(),
+    ];
+    let metric = genetic_config.objective.metric();
+    for stat in ["best", "mean", "std", "worst"] {
+        header.push(format!("{}_{}", stat, metric));
+    }
+    header.extend(params.iter().map(|param| format!("best.{}", param.field)));
+    writer.write_record(&header)?;
+
+    let mut evaluations = 0;
+    let mut population = Vec::new();
+    for _ in 0..population_size {
+        if trials.is_interrupted() {
+            return Ok(());
+        }
+        let genes = params
+            .iter()
+            .map(|param| random_gene(param, &mut rng))
+            .collect();
+        population.push(evaluate(trials, config, &params, genetic_config, genes)?);
+        evaluations += 1;
+    }
+    population.sort_by(compare);
+    write_generation(&mut writer, 0, evaluations, &population, genetic_config)?;
+
+    let mut progress = Progress::new(&population[0]);
+    for generation in 1..=genetic_config.generations {
+        let mut next: Vec<Individual> = population[..elitism].to_vec();
+        while next.len() < population_size {
+            let size = genetic_config.tournament_size;
+            let parent_a = tournament(&population, size, compare, &mut rng);
+            let parent_b = tournament(&population, size, compare, &mut rng);
+            let (mut child_a, mut child_b) =
+                if rng.gen::<f64>() < genetic_config.crossover_probability {
+                    crossover(&params, &parent_a.genes, &parent_b.genes, &mut rng)
+                } else {
+                    (parent_a.genes.clone(), parent_b.genes.clone())
+                };
+            mutate(&params, &mut child_a, genetic_config, &mut rng);
+            mutate(&params, &mut child_b, genetic_config, &mut rng);
+            for child in [child_a, child_b] {
+                if next.len() == population_size {
+                    break;
+                }
+                if trials.is_interrupted() {
+                    return Ok(());
+                }
+                next.push(evaluate(trials, config, &params, genetic_config, child)?);
+                evaluations += 1;
+            }
+        }
+        population = next;
+        population.sort_by(compare);
+        write_generation(
+            &mut writer,
+            generation,
+            evaluations,
+            &population,
+            genetic_config,
+        )?;
+
+        progress.update(&population[0]);
+        if progress.is_stalled(genetic_config) {
+            info!(
+                "genetic search stopped at generation {}, no improvement for {} generations",
+                generation, progress.stalled
+            );
+            break;
+        }
+    }
+
+    let best = progress.best;
+    let mut msg = "".to_string();
+    msg += &format!(
+        "genetic best {}: {:.4}, ",
+        metric,
+        objective_value(&best, genetic_config)
+    );
+    msg += &format!("constraint_violation: {:.4}, ", best.violation);
+    for (param, gene) in params.iter().zip(&best.genes) {
+        msg += &format!("{}: {}, ", param.field, gene);
+    }
+    msg += &format!("generations: {:?}", path);
+    info!("{}", msg);
+    Ok(())
+}
+
+// Without explicit params the hypertune ranges are searched
+fn params(hypertune_config: &HypertuneConfig, genetic_config: &GeneticConfig) -> Vec<ParamRange> {
+    if !genetic_config.params.is_empty() {
+        return genetic_config.params.clone();
+    }
+    grid_params(hypertune_config)
+}
+
+fn evaluate(
+    trials: &mut Trials,
+    config: &BbBandConfig,
+    params: &[ParamRange],
+    genetic_config: &GeneticConfig,
+    genes: Vec<f64>,
+) -> Result<Individual> {
+    let (trial_config, genes) = apply(config, params, &genes)?;
+    let row = trials.run(&trial_config)?;
+    let objective = genetic_config.objective;
+    // A metric that is missing or not a number (NaN is written as null) counts as the worst
+    let fitness = match row.metric(objective.metric()) {
+        Some(value) if objective.maximize() => value,
+        Some(value) => -value,
+        None => f64::NEG_INFINITY,
+    };
+    Ok(Individual {
+        genes,
+        violation: violation(&row, &genetic_config.constraints),
+        fitness,
+    })
+}
+
+fn objective_value(individual: &Individual, genetic_config: &GeneticConfig) -> f64 {
+    if genetic_config.objective.maximize() {
+        individual.fitness
+    } else {
+        -individual.fitness
+    }
+}
+
+// Feasible first, then the fitter
+fn compare(a: &Individual, b: &Individual) -> Ordering {
+    a.violation
+        .total_cmp(&b.violation)
+        .then_with(|| b.fitness.total_cmp(&a.fitness))
+}
+
+// Best individual so far and for how many generations it has held
+struct Progress {
+    best: Individual,
+    stalled: usize,
+}
+
+impl Progress {
+    fn new(best: &Individual) -> Self {
+        Progress {
+            best: best.clone(),
+            stalled: 0,
+        }
+    }
+
+    fn update(&mut self, generation_best: &Individual) {
+        if compare(generation_best, &self.best) == Ordering::Less {
+            self.best = generation_best.clone();
+            self.stalled = 0;
+        } else {
+            self.stalled += 1;
+        }
+    }
+
+    fn is_stalled(&self, genetic_config: &GeneticConfig) -> bool {
+        genetic_config
+            .stall_generations
+            .is_some_and(|stall_generations| self.stalled >= stall_generations)
+    }
+}
+
+// Blend crossover (BLX-0.5), children can land a little outside their parents
+fn crossover(
+    params: &[ParamRange],
+    parent_a: &[f64],
+    parent_b: &[f64],
+    rng: &mut ChaCha8Rng,
+) -> (Vec<f64>, Vec<f64>) {
+    let mut child_a = Vec::new();
+    let mut child_b = Vec::new();
+    for ((param, a), b) in params.iter().zip(parent_a).zip(parent_b) {
+        let spread = (a - b).abs() * 0.5;
+        let low = (a.min(*b) - spread).max(param.min);
+        let high = (a.max(*b) + spread).min(param.max);
+        if high > low {
+            child_a.push(rng.gen_range(low..=high));
+            child_b.push(rng.gen_range(low..=high));
+        } else {
+            child_a.push(*a);
+            child_b.push(*b);
+        }
+    }
+    (child_a, child_b)
+}
+
+fn mutate(
+    params: &[ParamRange],
+    genes: &mut [f64],
+    genetic_config: &GeneticConfig,
+    rng: &mut ChaCha8Rng,
+) {
+    for (gene, param) in genes.iter_mut().zip(params) {
+        if rng.gen::<f64>() >= genetic_config.mutation_probability {
+            continue;
+        }
+        let z: f64 = StandardNormal.sample(rng);
+        let sigma = genetic_config.mutation_scale * (param.max - param.min);
+        *gene = (*gene + z * sigma).clamp(param.min, param.max);
+    }
+}
+
+fn write_generation(
+    writer: &mut csv::Writer<File>,
+    generation: usize,
+    evaluations: usize,
+    population: &[Individual],
+    genetic_config: &GeneticConfig,
+) -> Result<()> {
+    let values: Vec<f64> = population
+        .iter()
+        .map(|individual| objective_value(individual, genetic_config))
+        .filter(|value| value.is_finite())
+        .collect();
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<f64>()
+        / values.len() as f64;
+    let worst = if genetic_config.objective.maximize() {
+        values.iter().cloned().fold(f64::NAN, f64::min)
+    } else {
+        values.iter().cloned().fold(f64::NAN, f64::max)
+    };
+    let feasible = population
+        .iter()
+        .filter(|individual| individual.violation == 0.)
+        .count();
+    // The population is sorted, the first individual is the generation's best
+    let best = &population[0];
+    let mut record = vec![
+        generation.to_string(),
+        evaluations.to_string(),
+        feasible.to_string(),
+        objective_value(best, genetic_config).to_string(),
+        mean.to_string(),
+        variance.sqrt().to_string(),
+        worst.to_string(),
+    ];
+    for gene in &best.genes {
+        record.push(gene.to_string());
+    }
+    writer.write_record(&record)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> Vec<ParamRange> {
+        ["take_profit_percentage", "bb_width"]
+            .iter()
+            .map(|field| ParamRange {
+                field: field.to_string(),
+                min: 1.,
+                max: 2.,
+                step: None,
+            })
+            .collect()
+    }
+
+    fn individual(fitness: f64, violation: f64) -> Individual {
+        Individual {
+            genes: Vec::new(),
+            fitness,
+            violation,
+        }
+    }
+
+    #[test]
+    fn crossover_and_mutation_stay_in_range() {
+        let params = params();
+        let genetic_config = GeneticConfig {
+            mutation_probability: 1.,
+            mutation_scale: 10.,
+            ..Default::default()
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0..200 {
+            let (mut child_a, mut child_b) = crossover(&params, &[1., 1.9], &[1.2, 2.], &mut rng);
+            // BLX-0.5 reaches half the parents' distance past them
+            for child in [&child_a, &child_b] {
+                assert!((1. ..=1.3).contains(&child[0]));
+                assert!((1.85..=2.).contains(&child[1]));
+            }
+            mutate(&params, &mut child_a, &genetic_config, &mut rng);
+            mutate(&params, &mut child_b, &genetic_config, &mut rng);
+            for gene in child_a.iter().chain(&child_b) {
+                assert!((1. ..=2.).contains(gene));
+            }
+        }
+        // Equal parents have nothing to blend
+        let (child_a, _) = crossover(&params, &[1.5, 1.5], &[1.5, 1.5], &mut rng);
+        assert_eq!(child_a, [1.5, 1.5]);
+    }
+
+    #[test]
+    fn feasible_beats_fitter() {
+        let mut population = [individual(3., 0.5), individual(1., 0.), individual(2., 0.)];
+        population.sort_by(compare);
+        let order: Vec<f64> = population
+            .iter()
+            .map(|individual| individual.fitness)
+            .collect();
+        assert_eq!(order, [2., 1., 3.]);
+    }
+
+    #[test]
+    fn search_stalls_without_improvement() {
+        let genetic_config = GeneticConfig {
+            stall_generations: Some(2),
+            ..Default::default()
+        };
+        let mut progress = Progress::new(&individual(1., 0.));
+        progress.update(&individual(1., 0.));
+        assert!(!progress.is_stalled(&genetic_config));
+        progress.update(&individual(2., 0.));
+        assert_eq!(progress.stalled, 0);
+        progress.update(&individual(2., 0.));
+        progress.update(&individual(0., 0.));
+        assert!(progress.is_stalled(&genetic_config));
+        assert_eq!(progress.best.fitness, 2.);
+
+        let unlimited = GeneticConfig::default();
+        assert!(!progress.is_stalled(&unlimited));
+    }
+}
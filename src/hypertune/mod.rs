@@ -0,0 +1,243 @@
+use std::{
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    backtest::backtest,
+    hypertune::{
+        analysis::analyse,
+        checkpoint::Checkpoint,
+        output::{output_base, output_paths, suffixed, TrialRow, TrialWriter},
+    },
+    results::{config_hash, ResultStore},
+    types::{
+        BacktestMetric, BbBandConfig, HypertuneConfig, HypertuneConstraints, Kline, SearchConfig,
+    },
+    DAY_MS,
+};
+
+pub mod analysis;
+pub mod checkpoint;
+pub mod genetic;
+pub mod nsga2;
+pub mod output;
+pub mod params;
+pub mod sensitivity;
+
+pub fn hypertune(
+    config: &BbBandConfig,
+    hypertune_config: &HypertuneConfig,
+    klines: &[Kline],
+) -> Result<()> {
+    info!("hypertune_config: {:?}", hypertune_config);
+    let mut trials = Trials::new(config, hypertune_config, klines)?;
+    match &hypertune_config.search {
+        SearchConfig::Grid => grid(&mut trials, config, hypertune_config)?,
+        SearchConfig::Nsga2(nsga2_config) => {
+            nsga2::search(&mut trials, config, hypertune_config, nsga2_config)?
+        }
+        SearchConfig::Genetic(genetic_config) => {
+            genetic::search(&mut trials, config, hypertune_config, genetic_config)?
+        }
+    }
+    trials.finish(hypertune_config)
+}
+
+fn grid(
+    trials: &mut Trials,
+    config: &BbBandConfig,
+    hypertune_config: &HypertuneConfig,
+) -> Result<()> {
+    let take_profit_percentage_max = hypertune_config.take_profit_percentage_max;
+    let stop_loss_percentage_max = hypertune_config.stop_loss_percentage_max;
+    let bb_width_max = hypertune_config.bb_width_max;
+
+    let mut trial_config = config.clone();
+    trial_config.take_profit_percentage = hypertune_config.take_profit_percentage_min;
+    trial_config.bb_width = hypertune_config.bb_width_min;
+
+    while trial_config.take_profit_percentage <= take_profit_percentage_max {
+        trial_config.stop_loss_percentage = hypertune_config.stop_loss_percentage_min;
+
+        while trial_config.stop_loss_percentage <= stop_loss_percentage_max {
+            trial_config.bb_width = hypertune_config.bb_width_min;
+
+            while trial_config.bb_width <= bb_width_max {
+                if trials.is_interrupted() {
+                    return Ok(());
+                }
+                trials.run(&trial_config)?;
+                trial_config.bb_width += hypertune_config.bb_width_step;
+            }
+            trial_config.stop_loss_percentage += hypertune_config.stop_loss_percentage_step;
+        }
+        trial_config.take_profit_percentage += hypertune_config.take_profit_percentage_step;
+    }
+    Ok(())
+}
+
+// Runs each trial once: completed trials come from the checkpoint, new ones are
+// written to the outputs, stored and checkpointed
+pub struct Trials<'a> {
+    klines: &'a [Kline],
+    rows: Vec<TrialRow>, // every trial of the run, in output order
+    writer: TrialWriter,
+    checkpoint: Checkpoint,
+    store: Option<ResultStore>,
+    trial: usize,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl<'a> Trials<'a> {
+    fn new(
+        config: &BbBandConfig,
+        hypertune_config: &HypertuneConfig,
+        klines: &'a [Kline],
+    ) -> Result<Self> {
+        let mut store = ResultStore::new(&config.results);
+        let base = output_base(hypertune_config.output.as_deref())?;
+        let checkpoint_dir = base.parent().unwrap_or(Path::new("")).to_path_buf();
+        let mut checkpoint = Checkpoint::open(
+            &checkpoint_dir,
+            config,
+            hypertune_config,
+            klines,
+            base,
+            store.as_ref().map(|store| store.run_id().to_string()),
+        )?;
+        if let (Some(store), Some(run_id)) = (store.as_mut(), &checkpoint.run_id) {
+            store.resume(run_id);
+        }
+
+        let formats = &hypertune_config.formats;
+        let paths = output_paths(&checkpoint.base, formats);
+        info!("hypertune output: {:?}", paths);
+        let mut writer = TrialWriter::new(&paths, formats)?;
+        // Outputs are rewritten from the checkpoint, Parquet files cannot be appended to
+        let rows = std::mem::take(&mut checkpoint.rows);
+        for row in &rows {
+            writer.write(row)?;
+        }
+
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let handler_interrupted = interrupted.clone();
+        ctrlc::set_handler(move || {
+            if handler_interrupted.swap(true, Ordering::SeqCst) {
+                process::exit(130);
+            }
+            warn!("interrupted, finishing the current trial (Ctrl-C again to abort)");
+        })?;
+
+        Ok(Trials {
+            klines,
+            trial: rows.len(),
+            rows,
+            writer,
+            checkpoint,
+            store,
+            interrupted,
+        })
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+
+    // Base name of the output files, for extra outputs next to them
+    pub fn base(&self) -> &Path {
+        &self.checkpoint.base
+    }
+
+    pub fn run(&mut self, trial_config: &BbBandConfig) -> Result<TrialRow> {
+        let key = trial_key(trial_config)?;
+        if let Some(row) = self.checkpoint.get(&key) {
+            return Ok(row.clone());
+        }
+        let metric = backtest(trial_config, self.klines);
+        let mut row = TrialRow::new(&metric, trial_config)?;
+        row.returns = self.daily_returns(&metric);
+        self.writer.write(&row)?;
+        if let Some(store) = &self.store {
+            store.store("hypertune", Some(self.trial), trial_config, &metric)?;
+        }
+        self.checkpoint.record(&key, &row)?;
+        self.rows.push(row.clone());
+        self.trial += 1;
+        Ok(row)
+    }
+
+    fn daily_returns(&self, metric: &BacktestMetric) -> Vec<f64> {
+        let (Some(first), Some(last)) = (self.klines.first(), self.klines.last()) else {
+            return Vec::new();
+        };
+        let days = (last.close_time - first.open_time) / DAY_MS + 1;
+        analysis::daily_returns(metric, first.open_time, days as usize)
+    }
+
+    fn finish(self, hypertune_config: &HypertuneConfig) -> Result<()> {
+        let is_interrupted = self.is_interrupted();
+        self.writer.finish()?;
+        if !is_interrupted && hypertune_config.analysis.enabled {
+            analyse(
+                &self.rows,
+                hypertune_config,
+                &suffixed(&self.checkpoint.base, "analysis.json"),
+            )?;
+        }
+        if is_interrupted {
+            info!(
+                "hypertune stopped after {} trials, rerun to resume",
+                self.trial
+            );
+        } else {
+            self.checkpoint.remove()?;
+        }
+        Ok(())
+    }
+}
+
+// Identifies a trial across runs, whichever config fields the search varies
+pub fn trial_key(config: &BbBandConfig) -> Result<String> {
+    config_hash(&config.redacted())
+}
+
+// Best of `size` individuals drawn at random, the first drawn wins ties
+pub fn tournament<'a, T>(
+    population: &'a [T],
+    size: usize,
+    compare: impl Fn(&T, &T) -> std::cmp::Ordering,
+    rng: &mut ChaCha8Rng,
+) -> &'a T {
+    let mut winner = &population[rng.gen_range(0..population.len())];
+    for _ in 1..size.max(1) {
+        let challenger = &population[rng.gen_range(0..population.len())];
+        if compare(challenger, winner) == std::cmp::Ordering::Less {
+            winner = challenger;
+        }
+    }
+    winner
+}
+
+// Relative amount by which the constraints are broken, 0 when they all hold
+pub fn violation(row: &TrialRow, constraints: &HypertuneConstraints) -> f64 {
+    let mut violation = 0.;
+    if let Some(min_trades) = constraints.min_trades {
+        let trades = row.metric("trades").unwrap_or(0.);
+        violation += ((min_trades as f64 - trades) / min_trades.max(1) as f64).max(0.);
+    }
+    if let Some(max_drawdown) = constraints.max_drawdown {
+        let drawdown = row.metric("max_drawdown").unwrap_or(1.);
+        violation += (drawdown - max_drawdown).max(0.);
+    }
+    violation
+}
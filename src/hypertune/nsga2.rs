@@ -0,0 +1,446 @@
+use std::{cmp::Ordering, collections::HashSet, path::Path};
+
+use anyhow::Result;
+use log::info;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde_json::Value;
+
+use crate::{
+    hypertune::{
+        output::{suffixed, TrialRow},
+        params::{apply, grid_params, random_gene},
+        tournament, trial_key, violation, Trials,
+    },
+    types::{BbBandConfig, HypertuneConfig, Nsga2Config, ParamRange},
+};
+
+#[derive(Clone)]
+struct Individual {
+    genes: Vec<f64>,
+    key: String,
+    row: TrialRow,
+    objectives: Vec<f64>, // all minimised, maximised objectives are negated
+    violation: f64,
+    rank: usize,
+    crowding: f64,
+}
+
+// NSGA-II over the hypertune ranges. Every evaluated trial goes to the usual outputs,
+// the final non-dominated set goes to <base>.pareto.csv.
+pub fn search(
+    trials: &mut Trials,
+    config: &BbBandConfig,
+    hypertune_config: &HypertuneConfig,
+    nsga2_config: &Nsga2Config,
+) -> Result<()> {
+    let params = grid_params(hypertune_config);
+    let mut rng = ChaCha8Rng::seed_from_u64(nsga2_config.seed);
+    let population_size = nsga2_config.population.max(2);
+
+    let mut population = Vec::new();
+    for _ in 0..population_size {
+        if trials.is_interrupted() {
+            return Ok(());
+        }
+        let genes = params
+            .iter()
+            .map(|param| random_gene(param, &mut rng))
+            .collect();
+        population.push(evaluate(trials, config, &params, nsga2_config, genes)?);
+    }
+    rank(&mut population);
+
+    for generation in 0..nsga2_config.generations {
+        let mut offspring = Vec::new();
+        while offspring.len() < population_size {
+            let parent_a = tournament(&population, 2, compare, &mut rng);
+            let parent_b = tournament(&population, 2, compare, &mut rng);
+            let (mut child_a, mut child_b) = crossover(
+                &params,
+                &parent_a.genes,
+                &parent_b.genes,
+                nsga2_config,
+                &mut rng,
+            );
+            mutate(&params, &mut child_a, nsga2_config.mutation_eta, &mut rng);
+            mutate(&params, &mut child_b, nsga2_config.mutation_eta, &mut rng);
+            for child in [child_a, child_b] {
+                if offspring.len() == population_size {
+                    break;
+                }
+                if trials.is_interrupted() {
+                    return Ok(());
+                }
+                offspring.push(evaluate(trials, config, &params, nsga2_config, child)?);
+            }
+        }
+        population.extend(offspring);
+        population = select(population, population_size);
+        log_generation(generation, &population, nsga2_config);
+    }
+
+    let front = pareto_front(&population);
+    let path = suffixed(trials.base(), "pareto.csv");
+    write_pareto(&path, &front)?;
+    info!(
+        "pareto front: {} trials, written to {:?}",
+        front.len(),
+        path
+    );
+    Ok(())
+}
+
+fn evaluate(
+    trials: &mut Trials,
+    config: &BbBandConfig,
+    params: &[ParamRange],
+    nsga2_config: &Nsga2Config,
+    genes: Vec<f64>,
+) -> Result<Individual> {
+    // The unsnapped genes are kept so the search stays continuous
+    let (trial_config, _) = apply(config, params, &genes)?;
+    let row = trials.run(&trial_config)?;
+    // A metric that is missing or not a number (NaN is written as null) counts as the worst
+    let objectives = nsga2_config
+        .objectives
+        .iter()
+        .map(|objective| match row.metric(objective.metric()) {
+            Some(value) if objective.maximize() => -value,
+            Some(value) => value,
+            None => f64::INFINITY,
+        })
+        .collect();
+    Ok(Individual {
+        genes,
+        key: trial_key(&trial_config)?,
+        violation: violation(&row, &nsga2_config.constraints),
+        row,
+        objectives,
+        rank: 0,
+        crowding: 0.,
+    })
+}
+
+// Constrained domination: feasible beats infeasible, less violation beats more,
+// otherwise plain Pareto dominance
+fn dominates(a: &Individual, b: &Individual) -> bool {
+    if a.violation > 0. || b.violation > 0. {
+        return a.violation < b.violation;
+    }
+    a.objectives.iter().zip(&b.objectives).all(|(a, b)| a <= b)
+        && a.objectives.iter().zip(&b.objectives).any(|(a, b)| a < b)
+}
+
+// Fast non-dominated sort, sets rank and crowding distance
+fn rank(population: &mut [Individual]) {
+    let len = population.len();
+    let mut dominated: Vec<Vec<usize>> = vec![Vec::new(); len];
+    let mut domination_count = vec![0; len];
+    let mut fronts: Vec<Vec<usize>> = vec![Vec::new()];
+    for i in 0..len {
+        for j in 0..len {
+            if i == j {
+                continue;
+            }
+            if dominates(&population[i], &population[j]) {
+                dominated[i].push(j);
+            } else if dominates(&population[j], &population[i]) {
+                domination_count[i] += 1;
+            }
+        }
+        if domination_count[i] == 0 {
+            population[i].rank = 0;
+            fronts[0].push(i);
+        }
+    }
+    let mut current = 0;
+    while !fronts[current].is_empty() {
+        let mut next = Vec::new();
+        for &i in &fronts[current] {
+            for &j in &dominated[i] {
+                domination_count[j] -= 1;
+                if domination_count[j] == 0 {
+                    population[j].rank = current + 1;
+                    next.push(j);
+                }
+            }
+        }
+        current += 1;
+        fronts.push(next);
+    }
+    fronts.pop();
+    for front in &fronts {
+        crowding_distance(population, front);
+    }
+}
+
+fn crowding_distance(population: &mut [Individual], front: &[usize]) {
+    for &i in front {
+        population[i].crowding = 0.;
+    }
+    let objectives = population[front[0]].objectives.len();
+    for objective in 0..objectives {
+        let mut sorted = front.to_vec();
+        sorted.sort_by(|a, b| {
+            population[*a].objectives[objective].total_cmp(&population[*b].objectives[objective])
+        });
+        let first = sorted[0];
+        let last = sorted[sorted.len() - 1];
+        population[first].crowding = f64::INFINITY;
+        population[last].crowding = f64::INFINITY;
+        let span = population[last].objectives[objective] - population[first].objectives[objective];
+        if !span.is_finite() || span <= 0. {
+            continue;
+        }
+        for window in sorted.windows(3) {
+            let distance = (population[window[2]].objectives[objective]
+                - population[window[0]].objectives[objective])
+                / span;
+            population[window[1]].crowding += distance;
+        }
+    }
+}
+
+// Lower rank first, then the less crowded
+fn compare(a: &Individual, b: &Individual) -> Ordering {
+    a.rank
+        .cmp(&b.rank)
+        .then_with(|| b.crowding.total_cmp(&a.crowding))
+}
+
+// Simulated binary crossover
+fn crossover(
+    params: &[ParamRange],
+    parent_a: &[f64],
+    parent_b: &[f64],
+    nsga2_config: &Nsga2Config,
+    rng: &mut ChaCha8Rng,
+) -> (Vec<f64>, Vec<f64>) {
+    let mut child_a = parent_a.to_vec();
+    let mut child_b = parent_b.to_vec();
+    if rng.gen::<f64>() > nsga2_config.crossover_probability {
+        return (child_a, child_b);
+    }
+    for (gene, param) in params.iter().enumerate() {
+        if rng.gen::<f64>() > 0.5 {
+            continue;
+        }
+        let u: f64 = rng.gen();
+        let beta = if u <= 0.5 {
+            (2. * u).powf(1. / (nsga2_config.crossover_eta + 1.))
+        } else {
+            (1. / (2. * (1. - u))).powf(1. / (nsga2_config.crossover_eta + 1.))
+        };
+        let (a, b) = (parent_a[gene], parent_b[gene]);
+        child_a[gene] = (0.5 * ((1. + beta) * a + (1. - beta) * b)).clamp(param.min, param.max);
+        child_b[gene] = (0.5 * ((1. - beta) * a + (1. + beta) * b)).clamp(param.min, param.max);
+    }
+    (child_a, child_b)
+}
+
+// Polynomial mutation, one gene on average
+fn mutate(params: &[ParamRange], genes: &mut [f64], eta: f64, rng: &mut ChaCha8Rng) {
+    for (gene, param) in genes.iter_mut().zip(params) {
+        let (min, max) = (param.min, param.max);
+        if max <= min || rng.gen::<f64>() > 1. / params.len() as f64 {
+            continue;
+        }
+        let u: f64 = rng.gen();
+        let delta = if u < 0.5 {
+            (2. * u).powf(1. / (eta + 1.)) - 1.
+        } else {
+            1. - (2. * (1. - u)).powf(1. / (eta + 1.))
+        };
+        *gene = (*gene + delta * (max - min)).clamp(min, max);
+    }
+}
+
+// Fills the next population front by front, the last front by crowding distance
+fn select(mut population: Vec<Individual>, size: usize) -> Vec<Individual> {
+    rank(&mut population);
+    population.sort_by(compare);
+    population.truncate(size);
+    rank(&mut population);
+    population
+}
+
+// Rank 0 of the population, one entry per trial
+fn pareto_front(population: &[Individual]) -> Vec<Individual> {
+    let mut keys = HashSet::new();
+    let mut front: Vec<Individual> = population
+        .iter()
+        .filter(|individual| individual.rank == 0 && keys.insert(individual.key.clone()))
+        .cloned()
+        .collect();
+    crowding_distance_of(&mut front);
+    front.sort_by(|a, b| a.objectives[0].total_cmp(&b.objectives[0]));
+    front
+}
+
+fn crowding_distance_of(front: &mut [Individual]) {
+    if front.is_empty() {
+        return;
+    }
+    let indices: Vec<usize> = (0..front.len()).collect();
+    crowding_distance(front, &indices);
+}
+
+fn log_generation(generation: usize, population: &[Individual], nsga2_config: &Nsga2Config) {
+    let front: Vec<&Individual> = population
+        .iter()
+        .filter(|individual| individual.rank == 0)
+        .collect();
+    let feasible = population
+        .iter()
+        .filter(|individual| individual.violation == 0.)
+        .count();
+    let mut msg = "".to_string();
+    msg += &format!("nsga2 generation: {}, ", generation);
+    msg += &format!("front: {}, ", front.len());
+    msg += &format!("feasible: {}/{}", feasible, population.len());
+    for (index, objective) in nsga2_config.objectives.iter().enumerate() {
+        let best = front
+            .iter()
+            .map(|individual| individual.objectives[index])
+            .fold(f64::INFINITY, f64::min);
+        let best = if objective.maximize() { -best } else { best };
+        msg += &format!(", best {}: {:.4}", objective.metric(), best);
+    }
+    info!("{}", msg);
+}
+
+fn write_pareto(path: &Path, front: &[Individual]) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    let Some(first) = front.first() else {
+        return Ok(());
+    };
+    let mut header: Vec<String> = first
+        .row
+        .metrics
+        .iter()
+        .map(|(key, _)| key.clone())
+        .collect();
+    header.push("constraint_violation".to_string());
+    header.push("crowding_distance".to_string());
+    writer.write_record(&header)?;
+    for individual in front {
+        let mut record: Vec<String> = individual
+            .row
+            .metrics
+            .iter()
+            .map(|(_, value)| match value {
+                Value::Null => "".to_string(),
+                _ => value.to_string(),
+            })
+            .collect();
+        record.push(individual.violation.to_string());
+        record.push(individual.crowding.to_string());
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn individual(key: &str, objectives: &[f64], violation: f64) -> Individual {
+        Individual {
+            genes: Vec::new(),
+            key: key.to_string(),
+            row: TrialRow {
+                metrics: Vec::new(),
+                config: Value::Null,
+                returns: Vec::new(),
+            },
+            objectives: objectives.to_vec(),
+            violation,
+            rank: 0,
+            crowding: 0.,
+        }
+    }
+
+    fn keys(population: &[Individual]) -> Vec<&str> {
+        population
+            .iter()
+            .map(|individual| individual.key.as_str())
+            .collect()
+    }
+
+    // Three trade-offs, one trial behind them, one behind that and an infeasible one
+    fn population() -> Vec<Individual> {
+        vec![
+            individual("a", &[1., 4.], 0.),
+            individual("b", &[2., 2.], 0.),
+            individual("c", &[4., 1.], 0.),
+            individual("d", &[2., 4.], 0.),
+            individual("e", &[3., 5.], 0.),
+            individual("f", &[0., 0.], 1.),
+        ]
+    }
+
+    #[test]
+    fn dominance_needs_no_worse_and_one_better_objective() {
+        let a = individual("a", &[1., 1.], 0.);
+        let b = individual("b", &[2., 1.], 0.);
+        let c = individual("c", &[2., 0.], 0.);
+        assert!(dominates(&a, &b));
+        assert!(!dominates(&b, &a));
+        assert!(!dominates(&a, &a));
+        assert!(!dominates(&a, &c) && !dominates(&c, &a));
+    }
+
+    #[test]
+    fn constraints_decide_dominance_first() {
+        let feasible = individual("a", &[9., 9.], 0.);
+        let slightly = individual("b", &[0., 0.], 0.1);
+        let badly = individual("c", &[0., 0.], 0.5);
+        assert!(dominates(&feasible, &slightly));
+        assert!(dominates(&slightly, &badly));
+        assert!(!dominates(&badly, &feasible));
+        assert!(!dominates(&slightly, &slightly.clone()));
+    }
+
+    #[test]
+    fn rank_sorts_into_fronts() {
+        let mut population = population();
+        rank(&mut population);
+        let ranks: Vec<usize> = population
+            .iter()
+            .map(|individual| individual.rank)
+            .collect();
+        assert_eq!(ranks, [0, 0, 0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn front_ends_are_never_crowded() {
+        let mut population = population();
+        rank(&mut population);
+        assert_eq!(population[0].crowding, f64::INFINITY);
+        assert_eq!(population[2].crowding, f64::INFINITY);
+        // Neighbours span the whole range on both objectives
+        assert_eq!(population[1].crowding, 2.);
+        // Alone in their front
+        assert_eq!(population[3].crowding, f64::INFINITY);
+    }
+
+    #[test]
+    fn select_keeps_better_fronts_then_the_less_crowded() {
+        assert_eq!(keys(&select(population(), 4)), ["a", "c", "b", "d"]);
+        let selected = select(population(), 2);
+        assert_eq!(keys(&selected), ["a", "c"]);
+        assert!(selected.iter().all(|individual| individual.rank == 0));
+    }
+
+    #[test]
+    fn pareto_front_has_one_entry_per_trial() {
+        let mut population = population();
+        population.push(individual("b", &[2., 2.], 0.));
+        rank(&mut population);
+        let front = pareto_front(&population);
+        assert_eq!(keys(&front), ["a", "b", "c"]);
+        assert_eq!(front[1].crowding, 2.);
+    }
+}
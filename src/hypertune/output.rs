@@ -0,0 +1,534 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use chrono::Utc;
+use parquet::{
+    basic::{LogicalType, Repetition, Type as PhysicalType},
+    data_type::{BoolType, ByteArray, ByteArrayType, DataType, DoubleType, Int64Type},
+    file::{
+        properties::WriterProperties,
+        writer::{SerializedColumnWriter, SerializedFileWriter},
+    },
+    schema::types::Type,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::types::{BacktestMetric, BbBandConfig, OutputFormat};
+
+const PARQUET_ROW_GROUP_SIZE: usize = 256;
+
+// One hypertune trial: its metrics plus the full (redacted) trial config
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrialRow {
+    pub metrics: Vec<(String, Value)>,
+    pub config: Value,
+    #[serde(default)]
+    pub returns: Vec<f64>, // daily net returns over the backtest, for the overfitting analysis
+}
+
+impl TrialRow {
+    pub fn new(metric: &BacktestMetric, config: &BbBandConfig) -> Result<Self> {
+        let win_rate = metric.win as f64 / (metric.win + metric.lose).max(1) as f64;
+        let metrics = vec![
+            ("initial_capital", json!(metric.initial_captial)),
+            ("usd_balance", json!(metric.usd_balance)),
+            ("max_usd", json!(metric.max_usd)),
+            ("min_usd", json!(metric.min_usd)),
+            ("win", json!(metric.win)),
+            ("lose", json!(metric.lose)),
+            ("win_rate", json!(win_rate)),
+            ("trades", json!(metric.trades.len())),
+            ("max_drawdown", json!(metric.max_drawdown())),
+            ("total_fee", json!(metric.total_fee)),
+            ("total_profit", json!(metric.total_profit)),
+            (
+                "take_profit_percentage",
+                json!(config.take_profit_percentage),
+            ),
+            ("stop_loss_percentage", json!(config.stop_loss_percentage)),
+            ("bb_width", json!(metric.bb_width)),
+        ];
+        Ok(TrialRow {
+            metrics: metrics
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+            config: serde_json::to_value(config.redacted())?,
+            returns: Vec::new(),
+        })
+    }
+
+    pub fn metric(&self, key: &str) -> Option<f64> {
+        self.metrics
+            .iter()
+            .find(|(metric, _)| metric == key)
+            .and_then(|(_, value)| value.as_f64())
+    }
+
+    // Metrics first, then the config flattened into config.* columns
+    fn columns(&self) -> Vec<(String, Value)> {
+        let mut columns = self.metrics.clone();
+        flatten("config", &self.config, &mut columns);
+        columns
+    }
+
+    fn to_json(&self) -> Value {
+        let mut row: Map<String, Value> = self.metrics.iter().cloned().collect();
+        row.insert("config".to_string(), self.config.clone());
+        Value::Object(row)
+    }
+}
+
+fn flatten(prefix: &str, value: &Value, columns: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                flatten(&format!("{}.{}", prefix, key), value, columns);
+            }
+        }
+        // Lists stay one JSON column
+        Value::Array(_) => columns.push((prefix.to_string(), Value::String(value.to_string()))),
+        _ => columns.push((prefix.to_string(), value.clone())),
+    }
+}
+
+// Base name of the output files. A directory (or a path without extension) gets a
+// timestamped name inside it, a file path is used as the base name for every format.
+pub fn output_base(output: Option<&Path>) -> Result<PathBuf> {
+    let timestamped = format!("hypertune_{}", Utc::now().format("%Y%m%d_%H%M%S"));
+    Ok(match output {
+        None => PathBuf::from(timestamped),
+        Some(path) if path.is_dir() || path.extension().is_none() => {
+            fs::create_dir_all(path)?;
+            path.join(timestamped)
+        }
+        Some(path) => {
+            if let Some(parent) = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                fs::create_dir_all(parent)?;
+            }
+            path.with_extension("")
+        }
+    })
+}
+
+pub fn output_paths(base: &Path, formats: &[OutputFormat]) -> Vec<PathBuf> {
+    formats
+        .iter()
+        .map(|format| suffixed(base, format.extension()))
+        .collect()
+}
+
+// <base>.<suffix>, for outputs written next to the trial files
+pub fn suffixed(base: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(base.as_os_str());
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+enum FormatWriter {
+    Csv {
+        writer: csv::Writer<File>,
+        header: Vec<String>,
+    },
+    Jsonl(File),
+    Parquet(ParquetWriter),
+}
+
+pub struct TrialWriter {
+    writers: Vec<FormatWriter>,
+}
+
+impl TrialWriter {
+    pub fn new(paths: &[PathBuf], formats: &[OutputFormat]) -> Result<Self> {
+        let mut writers = Vec::new();
+        for (path, format) in paths.iter().zip(formats) {
+            writers.push(match format {
+                OutputFormat::Csv => FormatWriter::Csv {
+                    writer: csv::Writer::from_path(path)?,
+                    header: Vec::new(),
+                },
+                OutputFormat::Jsonl => FormatWriter::Jsonl(File::create(path)?),
+                OutputFormat::Parquet => FormatWriter::Parquet(ParquetWriter::new(path)),
+            });
+        }
+        Ok(TrialWriter { writers })
+    }
+
+    pub fn write(&mut self, row: &TrialRow) -> Result<()> {
+        for writer in self.writers.iter_mut() {
+            match writer {
+                FormatWriter::Csv { writer, header } => {
+                    let columns = row.columns();
+                    if header.is_empty() {
+                        *header = columns.iter().map(|(key, _)| key.clone()).collect();
+                        writer.write_record(header.iter())?;
+                    }
+                    // Columns follow the header, missing ones are left empty
+                    writer.write_record(header.iter().map(|key| {
+                        match columns.iter().find(|(column, _)| column == key) {
+                            Some((_, Value::String(value))) => value.clone(),
+                            Some((_, Value::Null)) | None => "".to_string(),
+                            Some((_, value)) => value.to_string(),
+                        }
+                    }))?;
+                    writer.flush()?;
+                }
+                FormatWriter::Jsonl(file) => {
+                    writeln!(file, "{}", row.to_json())?;
+                }
+                FormatWriter::Parquet(writer) => writer.push(row.columns())?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        for writer in self.writers {
+            if let FormatWriter::Parquet(writer) = writer {
+                writer.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ColumnKind {
+    Int64,
+    Double,
+    Boolean,
+    Utf8,
+}
+
+impl ColumnKind {
+    fn of(value: &Value) -> Self {
+        match value {
+            Value::Number(number) if number.is_i64() || number.is_u64() => ColumnKind::Int64,
+            Value::Number(_) => ColumnKind::Double,
+            Value::Bool(_) => ColumnKind::Boolean,
+            _ => ColumnKind::Utf8,
+        }
+    }
+}
+
+// Column types come from the first row, every column is optional.
+// Rows are buffered and written one row group at a time.
+struct ParquetWriter {
+    path: PathBuf,
+    writer: Option<SerializedFileWriter<File>>,
+    columns: Vec<(String, ColumnKind)>,
+    rows: Vec<Vec<(String, Value)>>,
+}
+
+impl ParquetWriter {
+    fn new(path: &Path) -> Self {
+        ParquetWriter {
+            path: path.to_path_buf(),
+            writer: None,
+            columns: Vec::new(),
+            rows: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, row: Vec<(String, Value)>) -> Result<()> {
+        if self.writer.is_none() {
+            self.columns = row
+                .iter()
+                .map(|(key, value)| (key.clone(), ColumnKind::of(value)))
+                .collect();
+            self.writer = Some(self.create_writer()?);
+        }
+        self.rows.push(row);
+        if self.rows.len() >= PARQUET_ROW_GROUP_SIZE {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn create_writer(&self) -> Result<SerializedFileWriter<File>> {
+        let mut fields = Vec::new();
+        for (name, kind) in &self.columns {
+            let (physical_type, logical_type) = match kind {
+                ColumnKind::Int64 => (PhysicalType::INT64, None),
+                ColumnKind::Double => (PhysicalType::DOUBLE, None),
+                ColumnKind::Boolean => (PhysicalType::BOOLEAN, None),
+                ColumnKind::Utf8 => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+            };
+            let field = Type::primitive_type_builder(name, physical_type)
+                .with_repetition(Repetition::OPTIONAL)
+                .with_logical_type(logical_type)
+                .build()?;
+            fields.push(Arc::new(field));
+        }
+        let schema = Type::group_type_builder("trial")
+            .with_fields(fields)
+            .build()?;
+        let properties = WriterProperties::builder().build();
+        Ok(SerializedFileWriter::new(
+            File::create(&self.path)?,
+            Arc::new(schema),
+            Arc::new(properties),
+        )?)
+    }
+
+    fn write_row_group(&mut self) -> Result<()> {
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let mut row_group = writer.next_row_group()?;
+        for (index, (name, kind)) in self.columns.iter().enumerate() {
+            let mut column = row_group.next_column()?.unwrap();
+            // Missing or mistyped values are written as nulls
+            let values: Vec<Option<&Value>> = self
+                .rows
+                .iter()
+                .map(|row| {
+                    row.get(index)
+                        .filter(|(key, value)| key == name && !value.is_null())
+                        .map(|(_, value)| value)
+                })
+                .collect();
+            match kind {
+                ColumnKind::Int64 => {
+                    let (values, def_levels) = present(&values, Value::as_i64);
+                    write_column::<Int64Type>(&mut column, &values, &def_levels)?;
+                }
+                ColumnKind::Double => {
+                    let (values, def_levels) = present(&values, Value::as_f64);
+                    write_column::<DoubleType>(&mut column, &values, &def_levels)?;
+                }
+                ColumnKind::Boolean => {
+                    let (values, def_levels) = present(&values, Value::as_bool);
+                    write_column::<BoolType>(&mut column, &values, &def_levels)?;
+                }
+                ColumnKind::Utf8 => {
+                    let (values, def_levels) = present(&values, |value| {
+                        Some(match value {
+                            Value::String(value) => ByteArray::from(value.as_str()),
+                            _ => ByteArray::from(value.to_string().as_str()),
+                        })
+                    });
+                    write_column::<ByteArrayType>(&mut column, &values, &def_levels)?;
+                }
+            }
+            column.close()?;
+        }
+        row_group.close()?;
+        self.rows.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.write_row_group()?;
+        if let Some(writer) = self.writer {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+// Non-null values and the definition levels marking which rows have one
+fn present<T>(
+    values: &[Option<&Value>],
+    convert: impl Fn(&Value) -> Option<T>,
+) -> (Vec<T>, Vec<i16>) {
+    let converted: Vec<Option<T>> = values
+        .iter()
+        .map(|value| value.and_then(&convert))
+        .collect();
+    let def_levels = converted
+        .iter()
+        .map(|value| value.is_some() as i16)
+        .collect();
+    (converted.into_iter().flatten().collect(), def_levels)
+}
+
+fn write_column<T: DataType>(
+    column: &mut SerializedColumnWriter<'_>,
+    values: &[T::T],
+    def_levels: &[i16],
+) -> Result<()> {
+    column
+        .typed::<T>()
+        .write_batch(values, Some(def_levels), None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::Field,
+    };
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bb_band_output_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn row(metrics: Value, config: Value) -> TrialRow {
+        TrialRow {
+            metrics: metrics.as_object().unwrap().clone().into_iter().collect(),
+            config,
+            returns: Vec::new(),
+        }
+    }
+
+    // The second row has a null, a mistyped and a missing value
+    fn rows() -> Vec<TrialRow> {
+        vec![
+            row(
+                json!({"trades": 3, "usd_balance": 1000.5}),
+                json!({"flag": true, "list": [1, 2], "name": "a"}),
+            ),
+            row(
+                json!({"trades": "x", "usd_balance": null}),
+                json!({"flag": false, "list": []}),
+            ),
+        ]
+    }
+
+    fn write(dir: &Path, formats: &[OutputFormat]) -> Vec<PathBuf> {
+        let paths = output_paths(&output_base(Some(&dir.join("trials"))).unwrap(), formats);
+        let mut writer = TrialWriter::new(&paths, formats).unwrap();
+        for row in rows() {
+            writer.write(&row).unwrap();
+        }
+        writer.finish().unwrap();
+        paths
+    }
+
+    #[test]
+    fn csv_and_jsonl_hold_every_row() {
+        let dir = temp_dir("text");
+        let paths = write(&dir, &[OutputFormat::Csv, OutputFormat::Jsonl]);
+
+        let csv = fs::read_to_string(&paths[0]).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "trades,usd_balance,config.flag,config.list,config.name",
+                "3,1000.5,true,\"[1,2]\",a",
+                "x,,false,[],",
+            ]
+        );
+
+        let jsonl = fs::read_to_string(&paths[1]).unwrap();
+        let rows: Vec<Value> = jsonl
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                json!({"trades": 3, "usd_balance": 1000.5,
+                    "config": {"flag": true, "list": [1, 2], "name": "a"}}),
+                json!({"trades": "x", "usd_balance": null,
+                    "config": {"flag": false, "list": []}}),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parquet_keeps_types_and_nulls() {
+        let dir = temp_dir("parquet");
+        let paths = write(&dir, &[OutputFormat::Parquet]);
+
+        let reader = SerializedFileReader::new(File::open(&paths[0]).unwrap()).unwrap();
+        let schema = reader.metadata().file_metadata().schema_descr_ptr();
+        let types: Vec<(String, PhysicalType)> = schema
+            .columns()
+            .iter()
+            .map(|column| (column.name().to_string(), column.physical_type()))
+            .collect();
+        assert_eq!(
+            types,
+            [
+                ("trades".to_string(), PhysicalType::INT64),
+                ("usd_balance".to_string(), PhysicalType::DOUBLE),
+                ("config.flag".to_string(), PhysicalType::BOOLEAN),
+                ("config.list".to_string(), PhysicalType::BYTE_ARRAY),
+                ("config.name".to_string(), PhysicalType::BYTE_ARRAY),
+            ]
+        );
+
+        let rows: Vec<Vec<Field>> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| {
+                row.unwrap()
+                    .get_column_iter()
+                    .map(|(_, field)| field.clone())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                vec![
+                    Field::Long(3),
+                    Field::Double(1000.5),
+                    Field::Bool(true),
+                    Field::Str("[1,2]".to_string()),
+                    Field::Str("a".to_string()),
+                ],
+                vec![
+                    Field::Null,
+                    Field::Null,
+                    Field::Bool(false),
+                    Field::Str("[]".to_string()),
+                    Field::Null,
+                ],
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn output_base_names_the_files() {
+        let dir = temp_dir("base");
+        fs::create_dir_all(&dir).unwrap();
+
+        // An existing directory and a name without extension both get a timestamped file
+        for path in [dir.clone(), dir.join("runs")] {
+            let base = output_base(Some(&path)).unwrap();
+            assert!(path.is_dir());
+            assert_eq!(base.parent(), Some(path.as_path()));
+            let name = base.file_name().unwrap().to_str().unwrap();
+            assert!(name.starts_with("hypertune_"));
+        }
+
+        let file = dir.join("nested").join("trials.csv");
+        let base = output_base(Some(&file)).unwrap();
+        assert_eq!(base, dir.join("nested").join("trials"));
+        assert!(dir.join("nested").is_dir());
+        assert_eq!(
+            output_paths(&base, &[OutputFormat::Csv, OutputFormat::Parquet]),
+            [
+                dir.join("nested").join("trials.csv"),
+                dir.join("nested").join("trials.parquet"),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
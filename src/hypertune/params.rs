@@ -0,0 +1,154 @@
+use anyhow::{bail, Result};
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use serde_json::json;
+
+use crate::types::{BbBandConfig, HypertuneConfig, ParamRange};
+
+// The take profit, stop loss and bb width ranges the grid sweeps
+pub fn grid_params(hypertune_config: &HypertuneConfig) -> Vec<ParamRange> {
+    vec![
+        ParamRange {
+            field: "take_profit_percentage".to_string(),
+            min: hypertune_config.take_profit_percentage_min,
+            max: hypertune_config.take_profit_percentage_max,
+            step: Some(hypertune_config.take_profit_percentage_step),
+        },
+        ParamRange {
+            field: "stop_loss_percentage".to_string(),
+            min: hypertune_config.stop_loss_percentage_min,
+            max: hypertune_config.stop_loss_percentage_max,
+            step: Some(hypertune_config.stop_loss_percentage_step),
+        },
+        ParamRange {
+            field: "bb_width".to_string(),
+            min: hypertune_config.bb_width_min,
+            max: hypertune_config.bb_width_max,
+            step: Some(hypertune_config.bb_width_step),
+        },
+    ]
+}
+
+// Sets each field through the config's JSON form, so any numeric field can be searched.
+// Genes are searched continuously and snapped to the steps, so nearby genes share a
+// trial and are only backtested once. Returns the config and the values actually set,
+// after snapping and rounding.
+pub fn apply(
+    config: &BbBandConfig,
+    params: &[ParamRange],
+    genes: &[f64],
+) -> Result<(BbBandConfig, Vec<f64>)> {
+    let mut value = serde_json::to_value(config)?;
+    let mut applied = Vec::new();
+    for (param, gene) in params.iter().zip(genes) {
+        let pointer = format!("/{}", param.field.replace('.', "/"));
+        let Some(slot) = value.pointer_mut(&pointer) else {
+            bail!("unknown hypertune param {}", param.field);
+        };
+        let gene = snap(param, *gene);
+        *slot = if slot.is_u64() {
+            json!(gene.round().max(0.) as u64)
+        } else if slot.is_i64() {
+            json!(gene.round() as i64)
+        } else if slot.is_number() || slot.is_null() {
+            json!(gene)
+        } else {
+            bail!("hypertune param {} is not numeric", param.field);
+        };
+        applied.push(slot.as_f64().unwrap_or(gene));
+    }
+    Ok((serde_json::from_value(value)?, applied))
+}
+
+// Nearest step within the range, or the gene clamped to the range without a step
+pub fn snap(param: &ParamRange, gene: f64) -> f64 {
+    match param.step {
+        Some(step) if step > 0. => {
+            let steps = ((gene - param.min) / step)
+                .round()
+                .min(((param.max - param.min) / step).floor());
+            param.min + steps.max(0.) * step
+        }
+        _ => gene.clamp(param.min, param.max),
+    }
+}
+
+pub fn random_gene(param: &ParamRange, rng: &mut ChaCha8Rng) -> f64 {
+    if param.max > param.min {
+        rng.gen_range(param.min..=param.max)
+    } else {
+        param.min
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::config;
+
+    fn param(field: &str, min: f64, max: f64, step: Option<f64>) -> ParamRange {
+        ParamRange {
+            field: field.to_string(),
+            min,
+            max,
+            step,
+        }
+    }
+
+    #[test]
+    fn snap_stays_on_the_steps_inside_the_range() {
+        let stepped = param("bb_width", 1., 2.2, Some(0.5));
+        assert_eq!(snap(&stepped, 1.2), 1.);
+        assert_eq!(snap(&stepped, 1.3), 1.5);
+        // 2.5 would be past the max
+        assert_eq!(snap(&stepped, 2.2), 2.);
+        assert_eq!(snap(&stepped, 0.), 1.);
+        assert_eq!(snap(&stepped, 9.), 2.);
+
+        let continuous = param("bb_width", 1., 2., None);
+        assert_eq!(snap(&continuous, 1.234), 1.234);
+        assert_eq!(snap(&continuous, 3.), 2.);
+        assert_eq!(snap(&param("bb_width", 1., 2., Some(0.)), 0.5), 1.);
+    }
+
+    #[test]
+    fn apply_sets_nested_and_integer_fields() {
+        let params = [
+            param("bb_width", 1., 3., Some(0.5)),
+            param("risk.max_drawdown", 0., 1., None),
+            param("timing.max_holding_bars", 0., 100., None),
+            param("leverage", 0., 10., None),
+        ];
+        let (config, applied) = apply(&config(json!({})), &params, &[2.2, 0.25, 7.6, -3.]).unwrap();
+        assert_eq!(applied, [2., 0.25, 8., 0.]);
+        assert_eq!(config.bb_width, 2.);
+        assert_eq!(config.risk.max_drawdown, Some(0.25));
+        assert_eq!(config.timing.max_holding_bars, 8);
+        assert_eq!(config.leverage, 0);
+    }
+
+    #[test]
+    fn apply_rejects_unknown_and_non_numeric_fields() {
+        let config = config(json!({}));
+        let err = apply(&config, &[param("bb_widht", 1., 2., None)], &[1.]).unwrap_err();
+        assert_eq!(err.to_string(), "unknown hypertune param bb_widht");
+        let err = apply(&config, &[param("strategy_type", 0., 1., None)], &[1.]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "hypertune param strategy_type is not numeric"
+        );
+    }
+
+    #[test]
+    fn grid_params_follow_the_hypertune_ranges() {
+        let hypertune_config = crate::test_utils::hypertune_config(json!({}));
+        let params = grid_params(&hypertune_config);
+        let (config, applied) = apply(&config(json!({})), &params, &[0.024, 0.016, 1.6]).unwrap();
+        assert_eq!(applied, [0.02, 0.02, 1.5]);
+        assert_eq!(config.take_profit_percentage, 0.02);
+        assert_eq!(config.stop_loss_percentage, 0.02);
+        assert_eq!(config.bb_width, 1.5);
+    }
+}
@@ -0,0 +1,477 @@
+use std::{collections::BTreeMap, fs};
+
+use anyhow::{bail, Result};
+use log::info;
+
+use crate::{
+    plot::{color, tick_label, Svg, MISSING_COLOR},
+    types::{Aggregate, PlotConfig},
+};
+
+// Columns of CSVs written before trial configs were flattened into config.* columns
+const LEGACY_PARAMS: [&str; 3] = ["take_profit_percentage", "stop_loss_percentage", "bb_width"];
+const MAX_TICKS: usize = 12;
+
+struct Table {
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    fn read(config: &PlotConfig) -> Result<Self> {
+        let mut reader = csv::Reader::from_path(&config.input)?;
+        let header = reader.headers()?.iter().map(str::to_string).collect();
+        let rows = reader
+            .records()
+            .map(|record| Ok(record?.iter().map(str::to_string).collect()))
+            .collect::<Result<Vec<Vec<String>>>>()?;
+        Ok(Table { header, rows })
+    }
+
+    // Params can be named with or without their config. prefix
+    fn column(&self, name: &str) -> Result<usize> {
+        let prefixed = format!("config.{}", name);
+        match self
+            .header
+            .iter()
+            .position(|column| column == name || *column == prefixed)
+        {
+            Some(index) => Ok(index),
+            None => bail!("no column {} in the hypertune output", name),
+        }
+    }
+
+    fn value(&self, row: usize, column: usize) -> Option<f64> {
+        self.rows[row].get(column)?.parse().ok()
+    }
+
+    fn distinct(&self, rows: &[usize], column: usize) -> Vec<f64> {
+        let mut values: Vec<f64> = rows
+            .iter()
+            .filter_map(|row| self.value(*row, column))
+            .collect();
+        values.sort_by(|a, b| a.total_cmp(b));
+        values.dedup_by(|a, b| same(*a, *b));
+        values
+    }
+
+    // Numeric config columns that take more than one value
+    fn swept_params(&self, rows: &[usize]) -> Vec<usize> {
+        let has_config = self
+            .header
+            .iter()
+            .any(|column| column.starts_with("config."));
+        (0..self.header.len())
+            .filter(|column| {
+                let name = &self.header[*column];
+                if has_config {
+                    name.starts_with("config.")
+                } else {
+                    LEGACY_PARAMS.contains(&name.as_str())
+                }
+            })
+            .filter(|column| {
+                rows.iter().all(|row| self.value(*row, *column).is_some())
+                    && self.distinct(rows, *column).len() > 1
+            })
+            .collect()
+    }
+
+    fn name(&self, column: usize) -> &str {
+        let name = &self.header[column];
+        name.strip_prefix("config.").unwrap_or(name)
+    }
+}
+
+// Renders a heatmap of the metric for every pair of params and a sensitivity plot for
+// every param. Trials are filtered to the fixed params, the rest are aggregated.
+pub fn plot(config: &PlotConfig) -> Result<()> {
+    let table = Table::read(config)?;
+    let metric = table.column(&config.metric)?;
+    let mut rows: Vec<usize> = (0..table.rows.len())
+        .filter(|row| table.value(*row, metric).is_some_and(f64::is_finite))
+        .collect();
+    let mut fixed = Vec::new();
+    for (name, value) in &config.fixed {
+        let column = table.column(name)?;
+        rows.retain(|row| table.value(*row, column).is_some_and(|v| same(v, *value)));
+        fixed.push(column);
+    }
+    if rows.is_empty() {
+        bail!("no trials in {:?} match {:?}", config.input, config.fixed);
+    }
+    let params: Vec<usize> = if config.params.is_empty() {
+        table.swept_params(&rows)
+    } else {
+        config
+            .params
+            .iter()
+            .map(|name| table.column(name))
+            .collect::<Result<_>>()?
+    };
+    let params: Vec<usize> = params
+        .into_iter()
+        .filter(|param| !fixed.contains(param) && *param != metric)
+        .collect();
+    info!(
+        "plotting {} over {:?} from {} trials",
+        config.metric,
+        params
+            .iter()
+            .map(|param| table.name(*param))
+            .collect::<Vec<_>>(),
+        rows.len()
+    );
+
+    fs::create_dir_all(&config.output_dir)?;
+    let mut paths = Vec::new();
+    for (index, x) in params.iter().enumerate() {
+        let svg = sensitivity(&table, &rows, *x, metric, config);
+        let base =
+            config
+                .output_dir
+                .join(format!("sensitivity_{}_{}", config.metric, table.name(*x)));
+        paths.extend(svg.save(&base, &config.formats)?);
+        for y in &params[index + 1..] {
+            let svg = heatmap(&table, &rows, *x, *y, metric, config);
+            let base = config.output_dir.join(format!(
+                "heatmap_{}_{}_{}",
+                config.metric,
+                table.name(*x),
+                table.name(*y)
+            ));
+            paths.extend(svg.save(&base, &config.formats)?);
+        }
+    }
+    info!("wrote {} plots to {:?}", paths.len(), config.output_dir);
+    Ok(())
+}
+
+fn same(a: f64, b: f64) -> bool {
+    (a - b).abs() <= 1e-9 * a.abs().max(b.abs()).max(1.)
+}
+
+fn aggregate(values: &mut [f64], aggregate: Aggregate) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    match aggregate {
+        Aggregate::Mean => values.iter().sum::<f64>() / values.len() as f64,
+        Aggregate::Median => {
+            let middle = values.len() / 2;
+            if values.len().is_multiple_of(2) {
+                (values[middle - 1] + values[middle]) / 2.
+            } else {
+                values[middle]
+            }
+        }
+        Aggregate::Max => values[values.len() - 1],
+        Aggregate::Min => values[0],
+    }
+}
+
+// Metric values grouped by the given columns
+fn group(
+    table: &Table,
+    rows: &[usize],
+    columns: &[usize],
+    metric: usize,
+) -> Vec<(Vec<f64>, Vec<f64>)> {
+    let mut groups: Vec<(Vec<f64>, Vec<f64>)> = Vec::new();
+    for row in rows {
+        let key: Vec<f64> = columns
+            .iter()
+            .map(|column| table.value(*row, *column).unwrap_or(f64::NAN))
+            .collect();
+        let value = table.value(*row, metric).unwrap();
+        match groups
+            .iter_mut()
+            .find(|(other, _)| other.iter().zip(&key).all(|(a, b)| same(*a, *b)))
+        {
+            Some((_, values)) => values.push(value),
+            None => groups.push((key, vec![value])),
+        }
+    }
+    groups
+}
+
+fn tick_step(count: usize) -> usize {
+    count.div_ceil(MAX_TICKS).max(1)
+}
+
+fn heatmap(
+    table: &Table,
+    rows: &[usize],
+    x: usize,
+    y: usize,
+    metric: usize,
+    config: &PlotConfig,
+) -> Svg {
+    let (width, height) = (760., 580.);
+    let (left, right, top, bottom) = (100., 130., 50., 80.);
+    let xs = table.distinct(rows, x);
+    let ys = table.distinct(rows, y);
+    let mut cells = BTreeMap::new();
+    for (key, mut values) in group(table, rows, &[x, y], metric) {
+        let column = xs.iter().position(|value| same(*value, key[0]));
+        let row = ys.iter().position(|value| same(*value, key[1]));
+        if let (Some(column), Some(row)) = (column, row) {
+            cells.insert((column, row), aggregate(&mut values, config.aggregate));
+        }
+    }
+    let min = cells.values().cloned().fold(f64::INFINITY, f64::min);
+    let max = cells.values().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let scale = |value: f64| {
+        if max > min {
+            (value - min) / (max - min)
+        } else {
+            0.5
+        }
+    };
+
+    let mut svg = Svg::new(width, height);
+    let plot_width = width - left - right;
+    let plot_height = height - top - bottom;
+    let cell_width = plot_width / xs.len() as f64;
+    let cell_height = plot_height / ys.len() as f64;
+    let show_values = cell_width >= 44. && cell_height >= 16.;
+    for column in 0..xs.len() {
+        for row in 0..ys.len() {
+            let cell_x = left + column as f64 * cell_width;
+            // Rows go upwards
+            let cell_y = top + plot_height - (row + 1) as f64 * cell_height;
+            match cells.get(&(column, row)) {
+                Some(value) => {
+                    svg.rect(
+                        cell_x,
+                        cell_y,
+                        cell_width,
+                        cell_height,
+                        &color(scale(*value)),
+                    );
+                    if show_values {
+                        // Light text on the dark end of the colour map
+                        let fill = if scale(*value) < 0.5 {
+                            "white"
+                        } else {
+                            "black"
+                        };
+                        svg.colored_text(
+                            cell_x + cell_width / 2.,
+                            cell_y + cell_height / 2. + 4.,
+                            10.,
+                            "middle",
+                            false,
+                            fill,
+                            &tick_label(*value),
+                        );
+                    }
+                }
+                None => svg.rect(cell_x, cell_y, cell_width, cell_height, MISSING_COLOR),
+            }
+        }
+    }
+    for (column, value) in xs.iter().enumerate().step_by(tick_step(xs.len())) {
+        let tick_x = left + (column as f64 + 0.5) * cell_width;
+        svg.text(
+            tick_x,
+            top + plot_height + 18.,
+            11.,
+            "middle",
+            false,
+            &tick_label(*value),
+        );
+    }
+    for (row, value) in ys.iter().enumerate().step_by(tick_step(ys.len())) {
+        let tick_y = top + plot_height - (row as f64 + 0.5) * cell_height + 4.;
+        svg.text(left - 8., tick_y, 11., "end", false, &tick_label(*value));
+    }
+    svg.text(
+        left + plot_width / 2.,
+        height - 30.,
+        13.,
+        "middle",
+        false,
+        table.name(x),
+    );
+    svg.text(
+        30.,
+        top + plot_height / 2.,
+        13.,
+        "middle",
+        true,
+        table.name(y),
+    );
+    svg.text(
+        left + plot_width / 2.,
+        30.,
+        15.,
+        "middle",
+        false,
+        &format!(
+            "{} ({:?} over {} trials)",
+            config.metric,
+            config.aggregate,
+            rows.len()
+        ),
+    );
+
+    // Colour bar
+    let bar_x = width - right + 30.;
+    let steps = 40;
+    for step in 0..steps {
+        let t = step as f64 / (steps - 1) as f64;
+        let bar_y = top + plot_height - (step + 1) as f64 * plot_height / steps as f64;
+        svg.rect(
+            bar_x,
+            bar_y,
+            20.,
+            plot_height / steps as f64 + 0.5,
+            &color(t),
+        );
+    }
+    svg.text(
+        bar_x + 26.,
+        top + 10.,
+        11.,
+        "start",
+        false,
+        &tick_label(max),
+    );
+    svg.text(
+        bar_x + 26.,
+        top + plot_height,
+        11.,
+        "start",
+        false,
+        &tick_label(min),
+    );
+    svg
+}
+
+// Aggregate of the metric per param value, with the min-max range of the other params
+// as a band: a flat line in a narrow band is a robust plateau
+fn sensitivity(table: &Table, rows: &[usize], x: usize, metric: usize, config: &PlotConfig) -> Svg {
+    let (width, height) = (760., 440.);
+    let (left, right, top, bottom) = (100., 40., 50., 80.);
+    let mut points: Vec<(f64, f64, f64, f64)> = group(table, rows, &[x], metric)
+        .into_iter()
+        .map(|(key, mut values)| {
+            let low = values.iter().cloned().fold(f64::INFINITY, f64::min);
+            let high = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            (key[0], aggregate(&mut values, config.aggregate), low, high)
+        })
+        .collect();
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let x_min = points.first().map_or(0., |point| point.0);
+    let x_max = points.last().map_or(1., |point| point.0);
+    let y_min = points
+        .iter()
+        .map(|point| point.2)
+        .fold(f64::INFINITY, f64::min);
+    let y_max = points
+        .iter()
+        .map(|point| point.3)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let plot_width = width - left - right;
+    let plot_height = height - top - bottom;
+    let to_x = |value: f64| {
+        if x_max > x_min {
+            left + (value - x_min) / (x_max - x_min) * plot_width
+        } else {
+            left + plot_width / 2.
+        }
+    };
+    let to_y = |value: f64| {
+        if y_max > y_min {
+            top + plot_height - (value - y_min) / (y_max - y_min) * plot_height
+        } else {
+            top + plot_height / 2.
+        }
+    };
+
+    let mut svg = Svg::new(width, height);
+    svg.line(
+        left,
+        top + plot_height,
+        left + plot_width,
+        top + plot_height,
+        "black",
+    );
+    svg.line(left, top, left, top + plot_height, "black");
+    let ticks = 5;
+    for tick in 0..=ticks {
+        let value = y_min + (y_max - y_min) * tick as f64 / ticks as f64;
+        let tick_y = to_y(value);
+        svg.line(left, tick_y, left + plot_width, tick_y, "#eeeeee");
+        svg.text(
+            left - 8.,
+            tick_y + 4.,
+            11.,
+            "end",
+            false,
+            &tick_label(value),
+        );
+    }
+    for point in points.iter().step_by(tick_step(points.len())) {
+        svg.text(
+            to_x(point.0),
+            top + plot_height + 18.,
+            11.,
+            "middle",
+            false,
+            &tick_label(point.0),
+        );
+    }
+
+    let mut band: Vec<(f64, f64)> = points
+        .iter()
+        .map(|point| (to_x(point.0), to_y(point.3)))
+        .collect();
+    band.extend(
+        points
+            .iter()
+            .rev()
+            .map(|point| (to_x(point.0), to_y(point.2))),
+    );
+    svg.polygon(&band, &color(0.5), 0.25);
+    let line: Vec<(f64, f64)> = points
+        .iter()
+        .map(|point| (to_x(point.0), to_y(point.1)))
+        .collect();
+    svg.polyline(&line, &color(0.));
+    for (point_x, point_y) in &line {
+        svg.circle(*point_x, *point_y, 3., &color(0.));
+    }
+
+    svg.text(
+        left + plot_width / 2.,
+        height - 30.,
+        13.,
+        "middle",
+        false,
+        table.name(x),
+    );
+    svg.text(
+        30.,
+        top + plot_height / 2.,
+        13.,
+        "middle",
+        true,
+        &config.metric,
+    );
+    svg.text(
+        left + plot_width / 2.,
+        30.,
+        15.,
+        "middle",
+        false,
+        &format!(
+            "{} vs {} ({:?}, band: min-max over {} trials)",
+            config.metric,
+            table.name(x),
+            config.aggregate,
+            rows.len()
+        ),
+    );
+    svg
+}
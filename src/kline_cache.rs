@@ -0,0 +1,363 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use async_std::task;
+use log::info;
+
+use crate::{
+    mongo_client::{KlineFingerprint, MongoClient},
+    types::Kline,
+};
+
+const MAGIC: &[u8; 4] = b"BBKC";
+const VERSION: u32 = 1;
+const NONE_COUNT: u64 = u64::MAX;
+
+// On-disk columnar cache of a kline collection.
+// One file per (collection, from_ts); the file remembers up to which close_time it is filled,
+// so longer ranges only query the missing tail from Mongo.
+pub struct KlineCache {
+    dir: PathBuf,
+}
+
+struct CacheFile {
+    from_ts: i64,
+    to_ts: i64,
+    klines: Vec<Kline>,
+}
+
+impl KlineCache {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        KlineCache {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn get_klines(
+        &self,
+        mongo_client: &MongoClient,
+        database_name: &str,
+        collection_name: &str,
+        from_ts: i64,
+        to_ts: i64,
+    ) -> Result<Vec<Kline>> {
+        let path = self.file_path(collection_name, from_ts);
+        let cached = match read_cache_file(&path) {
+            Ok(cached) if cached.from_ts == from_ts => {
+                let covered_to = cached.to_ts.min(to_ts);
+                let source = task::block_on(mongo_client.get_klines_fingerprint(
+                    database_name,
+                    collection_name,
+                    from_ts,
+                    covered_to,
+                ))?;
+                let local = fingerprint(
+                    cached
+                        .klines
+                        .iter()
+                        .filter(|kline| kline.close_time <= covered_to),
+                );
+                if source.matches(&local) {
+                    Some(cached)
+                } else {
+                    info!("kline cache {:?} is stale, refetching", path);
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        let cache = match cached {
+            None => {
+                let klines = task::block_on(mongo_client.get_klines(
+                    database_name,
+                    collection_name,
+                    from_ts,
+                    Some(to_ts),
+                ));
+                let cache = CacheFile {
+                    from_ts,
+                    to_ts,
+                    klines,
+                };
+                write_cache_file(&path, &cache)?;
+                cache
+            }
+            Some(mut cache) if cache.to_ts < to_ts => {
+                let top_up = task::block_on(mongo_client.get_klines(
+                    database_name,
+                    collection_name,
+                    cache.to_ts + 1,
+                    Some(to_ts),
+                ));
+                info!("kline cache {:?}: topped up {} klines", path, top_up.len());
+                cache.klines.extend(top_up);
+                cache.to_ts = to_ts;
+                write_cache_file(&path, &cache)?;
+                cache
+            }
+            Some(cache) => {
+                info!("kline cache hit: {:?}", path);
+                cache
+            }
+        };
+
+        let klines = cache
+            .klines
+            .into_iter()
+            .filter(|kline| kline.close_time <= to_ts)
+            .collect();
+        Ok(klines)
+    }
+
+    fn file_path(&self, collection_name: &str, from_ts: i64) -> PathBuf {
+        self.dir
+            .join(format!("{}_{}.klc", collection_name, from_ts))
+    }
+}
+
+fn fingerprint<'a>(klines: impl Iterator<Item = &'a Kline>) -> KlineFingerprint {
+    let mut fingerprint = KlineFingerprint::default();
+    for kline in klines {
+        fingerprint.count += 1;
+        fingerprint.open_sum += kline.open;
+        fingerprint.high_sum += kline.high;
+        fingerprint.low_sum += kline.low;
+        fingerprint.close_sum += kline.close;
+        fingerprint.volume_sum += kline.volume.unwrap_or(0.);
+        fingerprint.max_close_time = fingerprint.max_close_time.max(kline.close_time);
+    }
+    fingerprint
+}
+
+fn write_cache_file(path: &Path, cache: &CacheFile) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Write to a temporary file first so an interrupted run never leaves a truncated cache
+    let tmp_path = path.with_extension("klc.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&cache.from_ts.to_le_bytes())?;
+    writer.write_all(&cache.to_ts.to_le_bytes())?;
+    writer.write_all(&(cache.klines.len() as u64).to_le_bytes())?;
+
+    let klines = &cache.klines;
+    write_i64_column(&mut writer, klines.iter().map(|kline| kline.open_time))?;
+    write_i64_column(&mut writer, klines.iter().map(|kline| kline.close_time))?;
+    write_f64_column(&mut writer, klines.iter().map(|kline| kline.open))?;
+    write_f64_column(&mut writer, klines.iter().map(|kline| kline.high))?;
+    write_f64_column(&mut writer, klines.iter().map(|kline| kline.low))?;
+    write_f64_column(&mut writer, klines.iter().map(|kline| kline.close))?;
+    // Missing optional fields are stored as NaN / u64::MAX
+    write_f64_column(
+        &mut writer,
+        klines.iter().map(|kline| kline.volume.unwrap_or(f64::NAN)),
+    )?;
+    write_f64_column(
+        &mut writer,
+        klines
+            .iter()
+            .map(|kline| kline.quote_volume.unwrap_or(f64::NAN)),
+    )?;
+    for kline in klines {
+        writer.write_all(&kline.trade_count.unwrap_or(NONE_COUNT).to_le_bytes())?;
+    }
+    write_f64_column(
+        &mut writer,
+        klines
+            .iter()
+            .map(|kline| kline.taker_buy_volume.unwrap_or(f64::NAN)),
+    )?;
+    write_f64_column(
+        &mut writer,
+        klines
+            .iter()
+            .map(|kline| kline.taker_buy_quote_volume.unwrap_or(f64::NAN)),
+    )?;
+    writer.flush()?;
+    drop(writer);
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+fn read_cache_file(path: &Path) -> Result<CacheFile> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("{:?} is not a kline cache file", path);
+    }
+    let version = u32::from_le_bytes(read_array(&mut reader)?);
+    if version != VERSION {
+        bail!("{:?} has unsupported cache version {}", path, version);
+    }
+    let from_ts = i64::from_le_bytes(read_array(&mut reader)?);
+    let to_ts = i64::from_le_bytes(read_array(&mut reader)?);
+    let len = u64::from_le_bytes(read_array(&mut reader)?) as usize;
+
+    let open_times = read_column(&mut reader, len, i64::from_le_bytes)?;
+    let close_times = read_column(&mut reader, len, i64::from_le_bytes)?;
+    let opens = read_column(&mut reader, len, f64::from_le_bytes)?;
+    let highs = read_column(&mut reader, len, f64::from_le_bytes)?;
+    let lows = read_column(&mut reader, len, f64::from_le_bytes)?;
+    let closes = read_column(&mut reader, len, f64::from_le_bytes)?;
+    let volumes = read_column(&mut reader, len, f64::from_le_bytes)?;
+    let quote_volumes = read_column(&mut reader, len, f64::from_le_bytes)?;
+    let trade_counts = read_column(&mut reader, len, u64::from_le_bytes)?;
+    let taker_buy_volumes = read_column(&mut reader, len, f64::from_le_bytes)?;
+    let taker_buy_quote_volumes = read_column(&mut reader, len, f64::from_le_bytes)?;
+
+    let klines = (0..len)
+        .map(|index| Kline {
+            open_time: open_times[index],
+            close_time: close_times[index],
+            open: opens[index],
+            high: highs[index],
+            low: lows[index],
+            close: closes[index],
+            volume: non_nan(volumes[index]),
+            quote_volume: non_nan(quote_volumes[index]),
+            trade_count: Some(trade_counts[index]).filter(|count| *count != NONE_COUNT),
+            taker_buy_volume: non_nan(taker_buy_volumes[index]),
+            taker_buy_quote_volume: non_nan(taker_buy_quote_volumes[index]),
+        })
+        .collect();
+    Ok(CacheFile {
+        from_ts,
+        to_ts,
+        klines,
+    })
+}
+
+fn non_nan(value: f64) -> Option<f64> {
+    Some(value).filter(|value| !value.is_nan())
+}
+
+fn write_i64_column(writer: &mut impl Write, values: impl Iterator<Item = i64>) -> Result<()> {
+    for value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_f64_column(writer: &mut impl Write, values: impl Iterator<Item = f64>) -> Result<()> {
+    for value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_column<T>(
+    reader: &mut impl Read,
+    len: usize,
+    from_bytes: fn([u8; 8]) -> T,
+) -> Result<Vec<T>> {
+    let mut buf = vec![0u8; len * 8];
+    reader.read_exact(&mut buf)?;
+    Ok(buf
+        .chunks_exact(8)
+        .map(|chunk| from_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    fn kline(bar: i64, price: f64) -> Kline {
+        Kline {
+            open_time: bar * 60_000,
+            close_time: (bar + 1) * 60_000 - 1,
+            open: price,
+            high: price + 2.,
+            low: price - 1.,
+            close: price + 1.,
+            volume: Some(10. + bar as f64),
+            quote_volume: (bar % 2 == 0).then_some(1000.),
+            trade_count: (bar % 3 != 0).then_some(bar as u64),
+            taker_buy_volume: None,
+            taker_buy_quote_volume: Some(500.),
+        }
+    }
+
+    fn cache_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("bb_band_{}_{}.klc", name, process::id()))
+    }
+
+    #[test]
+    fn cache_file_round_trips() {
+        let path = cache_path("round_trip");
+        let cache = CacheFile {
+            from_ts: 0,
+            to_ts: 5 * 60_000,
+            klines: (0..5).map(|bar| kline(bar, 100. + bar as f64)).collect(),
+        };
+        write_cache_file(&path, &cache).unwrap();
+        let read = read_cache_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.from_ts, cache.from_ts);
+        assert_eq!(read.to_ts, cache.to_ts);
+        assert_eq!(read.klines, cache.klines);
+    }
+
+    #[test]
+    fn top_up_appends_to_the_cache() {
+        let path = cache_path("top_up");
+        let mut cache = CacheFile {
+            from_ts: 0,
+            to_ts: 3 * 60_000,
+            klines: (0..3).map(|bar| kline(bar, 100.)).collect(),
+        };
+        write_cache_file(&path, &cache).unwrap();
+        let mut read = read_cache_file(&path).unwrap();
+        read.klines.extend((3..6).map(|bar| kline(bar, 101.)));
+        read.to_ts = 6 * 60_000;
+        write_cache_file(&path, &read).unwrap();
+        let topped_up = read_cache_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        cache.klines.extend((3..6).map(|bar| kline(bar, 101.)));
+        assert_eq!(topped_up.to_ts, 6 * 60_000);
+        assert_eq!(topped_up.klines, cache.klines);
+    }
+
+    #[test]
+    fn rejects_foreign_files() {
+        let path = cache_path("foreign");
+        fs::write(&path, b"not a cache").unwrap();
+        assert!(read_cache_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fingerprint_covers_every_price_and_volume() {
+        let klines: Vec<Kline> = (0..4).map(|bar| kline(bar, 100.)).collect();
+        let base = fingerprint(klines.iter());
+        let edits: [fn(&mut Kline); 5] = [
+            |kline| kline.open += 0.5,
+            |kline| kline.high += 0.5,
+            |kline| kline.low -= 0.5,
+            |kline| kline.close += 0.5,
+            |kline| kline.volume = None,
+        ];
+        for edit in edits {
+            let mut edited = klines.clone();
+            edit(&mut edited[2]);
+            assert!(!base.matches(&fingerprint(edited.iter())));
+        }
+        assert!(base.matches(&fingerprint(klines.iter())));
+    }
+}
@@ -1,8 +1,27 @@
 pub mod backtest;
+pub mod benchmark;
 pub mod consts;
+pub mod data_quality;
+pub mod exchange;
 pub mod hypertune;
+pub mod kline_cache;
 pub mod mongo_client;
+pub mod monte_carlo;
+pub mod notify;
+pub mod order;
+pub mod paper;
+pub mod plot;
+pub mod portfolio;
+pub mod report;
+pub mod results;
+pub mod risk;
+pub mod sleeves;
 pub mod types;
 pub mod utils;
 pub use consts::*;
 pub mod strategy_pool;
+pub mod stream;
+pub mod synthetic;
+#[cfg(test)]
+mod test_utils;
+pub mod trader;
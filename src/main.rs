@@ -1,12 +1,26 @@
 use anyhow::Result;
 use bb_band::{
-    backtest::backtest,
-    hypertune::hypertune,
-    types::{BbBandConfig, Cli, HypertuneConfig, Mode},
+    backtest::{backtest, compare_entries},
+    benchmark::{buy_and_hold, compare, log_comparison, write_comparison},
+    data_quality::validate_klines,
+    hypertune::{hypertune, sensitivity::plot},
+    monte_carlo::monte_carlo,
+    paper::paper,
+    portfolio::{load_streams, portfolio_backtest, write_portfolio},
+    report::write_report,
+    results::{query, ResultStore},
+    sleeves::{sleeves_backtest, write_sleeves},
+    stream::serve_replay,
+    synthetic::get_synthetic_klines,
+    trader::{live_trade, mock_trade},
+    types::{
+        BbBandConfig, Cli, EntryOrderType, HypertuneConfig, Mode, PlotConfig, SyntheticConfig,
+    },
     utils::get_klines_from_db,
+    KLINE_INTERVAL_MS,
 };
 use clap::Parser;
-use log::{info, LevelFilter};
+use log::{info, warn, LevelFilter};
 use simplelog::*;
 use std::fs::File;
 
@@ -30,16 +44,114 @@ fn main() -> Result<()> {
     let config_file = File::open(args.config_path)?;
     let config: BbBandConfig = serde_json::from_reader(config_file)?;
     info!("config: {:#?}", config);
-    let klines = get_klines_from_db(&config);
+    if let Mode::Query = args.mode {
+        return query(&config, &args.run_ids, args.limit);
+    }
+    // Live trading warms up from the exchange, it needs no local klines
+    if let Mode::Live = args.mode {
+        return live_trade(&config);
+    }
+    if let Mode::Plot = args.mode {
+        let mut plot_config: PlotConfig = match &args.plot_config {
+            Some(plot_config_path) => serde_json::from_reader(File::open(plot_config_path)?)?,
+            None => PlotConfig::default(),
+        };
+        if let Some(output) = &args.output {
+            plot_config.output_dir = output.clone();
+        }
+        info!("plot_config: {:?}", plot_config);
+        return plot(&plot_config);
+    }
+    let synthetic_config = match &args.synthetic_config {
+        Some(synthetic_config_path) => {
+            let synthetic_config_file = File::open(synthetic_config_path)?;
+            let synthetic_config: SyntheticConfig = serde_json::from_reader(synthetic_config_file)?;
+            info!("synthetic_config: {:?}", synthetic_config);
+            Some(synthetic_config)
+        }
+        None => None,
+    };
+    if let Mode::Portfolio = args.mode {
+        let streams = load_streams(&config, synthetic_config.as_ref())?;
+        let metric = portfolio_backtest(&config, &streams);
+        if let Some(store) = ResultStore::new(&config.results) {
+            store.store("portfolio", None, &config, &metric.account)?;
+        }
+        if let Some(path) = &args.output {
+            write_portfolio(&metric, path)?;
+            info!("portfolio metrics: {:?}", path);
+        }
+        return Ok(());
+    }
+    let klines = match &synthetic_config {
+        Some(synthetic_config) => get_synthetic_klines(&config, synthetic_config)?,
+        None => get_klines_from_db(&config),
+    };
+    let (klines, report) = validate_klines(klines, KLINE_INTERVAL_MS, config.data_quality_policy)?;
+    if report.is_clean() {
+        info!("{}", report);
+    } else {
+        warn!("{}", report);
+    }
     match args.mode {
         Mode::Backtest => {
-            backtest(&config, &klines);
+            let metric = backtest(&config, &klines);
+            if let Some(store) = ResultStore::new(&config.results) {
+                store.store("backtest", None, &config, &metric)?;
+            }
+            if config.entry.order_type == EntryOrderType::Limit {
+                compare_entries(&config, &klines, &metric);
+            }
+            let benchmark = buy_and_hold(&config, &klines);
+            let comparison = compare(&config, &klines, &metric, &benchmark);
+            log_comparison(&comparison);
+            if let Some(path) = &args.output {
+                write_comparison(&comparison, path)?;
+                info!("benchmark comparison: {:?}", path);
+            }
+            if let Some(path) = &args.report {
+                write_report(&config, &klines, &metric, &comparison, path)?;
+                info!("backtest report: {:?}", path);
+            }
         }
         Mode::Hypertune => {
             let hypertune_config_file = File::open(args.hypertune_config.unwrap())?;
-            let hypertune_config: HypertuneConfig = serde_json::from_reader(hypertune_config_file)?;
-            let _ = hypertune(&config, &hypertune_config, &klines);
+            let mut hypertune_config: HypertuneConfig =
+                serde_json::from_reader(hypertune_config_file)?;
+            if args.output.is_some() {
+                hypertune_config.output = args.output.clone();
+            }
+            hypertune(&config, &hypertune_config, &klines)?;
+        }
+        Mode::Sleeves => {
+            let metric = sleeves_backtest(&config, &klines)?;
+            if let Some(store) = ResultStore::new(&config.results) {
+                store.store("sleeves", None, &config, &metric.account)?;
+            }
+            if let Some(path) = &args.output {
+                write_sleeves(&metric, path)?;
+                info!("sleeve metrics: {:?}", path);
+            }
+        }
+        Mode::MonteCarlo => {
+            let metric = backtest(&config, &klines);
+            monte_carlo(&config.monte_carlo, &metric)?;
+        }
+        Mode::Paper => {
+            paper(&config, &klines)?;
+        }
+        Mode::Replay => {
+            serve_replay(
+                &config.paper.replay_addr,
+                &klines,
+                "15m",
+                config.paper.replay_delay_ms,
+            )?;
+        }
+        Mode::Mock => {
+            mock_trade(&config, &klines)?;
         }
+        Mode::Query | Mode::Plot | Mode::Portfolio | Mode::Live => unreachable!(),
     }
     Ok(())
 }
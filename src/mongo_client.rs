@@ -1,3 +1,4 @@
+use anyhow::Result;
 use chrono::Utc;
 use futures::stream::TryStreamExt;
 
@@ -48,13 +49,128 @@ impl MongoClient {
                 high: parse_f64(doc.get("high")),
                 low: parse_f64(doc.get("low")),
                 close: parse_f64(doc.get("close")),
+                volume: parse_f64_opt(doc.get("volume")),
+                quote_volume: parse_f64_opt(doc.get("quote_asset_volume")),
+                trade_count: parse_u64_opt(doc.get("number_of_trades")),
+                taker_buy_volume: parse_f64_opt(doc.get("taker_buy_base_asset_volume")),
+                taker_buy_quote_volume: parse_f64_opt(doc.get("taker_buy_quote_asset_volume")),
             };
             klines.push(kline);
         }
         klines
     }
+
+    // Cheap server-side summary of a kline range, used to detect changes in the source data
+    pub async fn get_klines_fingerprint(
+        &self,
+        database_name: &str,
+        collection_name: &str,
+        from_ts: i64,
+        to_ts: i64,
+    ) -> Result<KlineFingerprint> {
+        let database = self.client.database(database_name);
+        let collection = database.collection::<Document>(collection_name);
+        let pipeline = vec![
+            doc! { "$match": { "close_time": {"$gte": from_ts, "$lte": to_ts} } },
+            doc! { "$group": {
+                "_id": Bson::Null,
+                "count": { "$sum": 1_i64 },
+                "open_sum": { "$sum": { "$toDouble": "$open" } },
+                "high_sum": { "$sum": { "$toDouble": "$high" } },
+                "low_sum": { "$sum": { "$toDouble": "$low" } },
+                "close_sum": { "$sum": { "$toDouble": "$close" } },
+                "volume_sum": { "$sum": { "$toDouble": "$volume" } },
+                "max_close_time": { "$max": "$close_time" },
+            } },
+        ];
+        let mut cursor = collection.aggregate(pipeline, None).await?;
+        let Some(doc) = cursor.try_next().await? else {
+            return Ok(KlineFingerprint::default());
+        };
+        // $sum yields an integer 0 when every value is missing
+        let sum = |key: &str| parse_f64_opt(doc.get(key)).unwrap_or(0.);
+        Ok(KlineFingerprint {
+            count: doc.get_i64("count")? as u64,
+            open_sum: sum("open_sum"),
+            high_sum: sum("high_sum"),
+            low_sum: sum("low_sum"),
+            close_sum: sum("close_sum"),
+            volume_sum: sum("volume_sum"),
+            max_close_time: doc.get_i64("max_close_time")?,
+        })
+    }
+
+    pub async fn insert_documents(
+        &self,
+        database_name: &str,
+        collection_name: &str,
+        documents: Vec<Document>,
+    ) -> Result<()> {
+        let database = self.client.database(database_name);
+        let collection = database.collection::<Document>(collection_name);
+        collection.insert_many(documents, None).await?;
+        Ok(())
+    }
+
+    pub async fn find_documents(
+        &self,
+        database_name: &str,
+        collection_name: &str,
+        filter: Document,
+        find_options: FindOptions,
+    ) -> Result<Vec<Document>> {
+        let database = self.client.database(database_name);
+        let collection = database.collection::<Document>(collection_name);
+        let cursor = collection.find(filter, find_options).await?;
+        Ok(cursor.try_collect().await?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KlineFingerprint {
+    pub count: u64,
+    pub open_sum: f64,
+    pub high_sum: f64,
+    pub low_sum: f64,
+    pub close_sum: f64,
+    pub volume_sum: f64, // missing volumes count as 0
+    pub max_close_time: i64,
+}
+
+impl KlineFingerprint {
+    pub fn matches(&self, other: &KlineFingerprint) -> bool {
+        let close = |a: f64, b: f64| (a - b).abs() <= 1e-9 * a.abs().max(1.);
+        self.count == other.count
+            && self.max_close_time == other.max_close_time
+            && close(self.open_sum, other.open_sum)
+            && close(self.high_sum, other.high_sum)
+            && close(self.low_sum, other.low_sum)
+            && close(self.close_sum, other.close_sum)
+            && close(self.volume_sum, other.volume_sum)
+    }
 }
 
 pub fn parse_f64(bson: Option<&Bson>) -> f64 {
     bson.unwrap().as_str().unwrap().parse::<f64>().unwrap()
 }
+
+// Volume fields are missing in older collections and may be stored as strings or numbers
+pub fn parse_f64_opt(bson: Option<&Bson>) -> Option<f64> {
+    match bson? {
+        Bson::String(value) => value.parse::<f64>().ok(),
+        Bson::Double(value) => Some(*value),
+        Bson::Int32(value) => Some(*value as f64),
+        Bson::Int64(value) => Some(*value as f64),
+        _ => None,
+    }
+}
+
+pub fn parse_u64_opt(bson: Option<&Bson>) -> Option<u64> {
+    match bson? {
+        Bson::String(value) => value.parse::<u64>().ok(),
+        Bson::Int32(value) => u64::try_from(*value).ok(),
+        Bson::Int64(value) => u64::try_from(*value).ok(),
+        Bson::Double(value) => Some(*value as u64),
+        _ => None,
+    }
+}
@@ -0,0 +1,342 @@
+use std::fs::File;
+
+use anyhow::{bail, Result};
+use log::info;
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    types::{BacktestMetric, MonteCarloConfig, ResampleMethod},
+    utils::normal_quantile,
+};
+
+#[derive(Debug, Clone)]
+pub struct PathResult {
+    pub final_balance: f64,
+    pub max_drawdown: f64,
+    pub ruined: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonteCarloSummary {
+    pub method: ResampleMethod,
+    pub iterations: usize,
+    pub final_balance_mean: f64,
+    pub final_balance_median: f64,
+    pub final_balance_ci: (f64, f64),
+    pub max_drawdown_mean: f64,
+    pub max_drawdown_median: f64,
+    pub max_drawdown_ci: (f64, f64),
+    pub risk_of_ruin: f64,
+    pub risk_of_ruin_ci: (f64, f64),
+}
+
+// Resamples the backtest trade sequence and reports the distribution of outcomes.
+// Trades are replayed as returns on the running balance, so position sizing compounds
+// the same way regardless of the order they are drawn in.
+pub fn monte_carlo(
+    mc_config: &MonteCarloConfig,
+    metric: &BacktestMetric,
+) -> Result<Vec<MonteCarloSummary>> {
+    let returns: Vec<f64> = metric
+        .trades
+        .iter()
+        .map(|trade| trade.net_return())
+        .collect();
+    if returns.is_empty() {
+        bail!("monte carlo: no trades to resample");
+    }
+    if mc_config.iterations == 0 {
+        bail!("monte carlo: iterations must be at least 1");
+    }
+    if !(mc_config.confidence > 0. && mc_config.confidence < 1.) {
+        bail!(
+            "monte carlo: confidence must be within (0, 1), got {}",
+            mc_config.confidence
+        );
+    }
+    if !(mc_config.ruin_threshold > 0. && mc_config.ruin_threshold <= 1.) {
+        bail!(
+            "monte carlo: ruin_threshold must be within (0, 1], got {}",
+            mc_config.ruin_threshold
+        );
+    }
+    let mut summaries = Vec::new();
+
+    let mut rng = ChaCha8Rng::seed_from_u64(mc_config.seed);
+    let ruin_balance = metric.initial_captial * (1. - mc_config.ruin_threshold);
+    let file = File::create(&mc_config.output_path)?;
+    let mut writer = csv::Writer::from_writer(file);
+    writer.write_record([
+        "method",
+        "iteration",
+        "final_balance",
+        "max_drawdown",
+        "ruined",
+    ])?;
+
+    let original = simulate_path(metric.initial_captial, ruin_balance, &returns);
+    info!(
+        "monte carlo: {} trades, original path final_balance: {:.4}, max_drawdown: {:.4}",
+        returns.len(),
+        original.final_balance,
+        original.max_drawdown
+    );
+
+    for method in &mc_config.methods {
+        let mut results = Vec::with_capacity(mc_config.iterations);
+        let mut sample = returns.clone();
+        for iteration in 0..mc_config.iterations {
+            resample(
+                *method,
+                &returns,
+                &mut sample,
+                mc_config.block_size,
+                &mut rng,
+            );
+            let result = simulate_path(metric.initial_captial, ruin_balance, &sample);
+            writer.write_record(&[
+                format!("{:?}", method),
+                iteration.to_string(),
+                result.final_balance.to_string(),
+                result.max_drawdown.to_string(),
+                result.ruined.to_string(),
+            ])?;
+            results.push(result);
+        }
+        let summary = summarize(*method, &results, mc_config.confidence);
+        log_summary(&summary, mc_config.confidence);
+        summaries.push(summary);
+    }
+    writer.flush()?;
+    Ok(summaries)
+}
+
+fn resample(
+    method: ResampleMethod,
+    returns: &[f64],
+    sample: &mut Vec<f64>,
+    block_size: usize,
+    rng: &mut ChaCha8Rng,
+) {
+    let len = returns.len();
+    sample.clear();
+    match method {
+        ResampleMethod::Shuffle => {
+            sample.extend_from_slice(returns);
+            sample.shuffle(rng);
+        }
+        ResampleMethod::Bootstrap => {
+            sample.extend((0..len).map(|_| returns[rng.gen_range(0..len)]));
+        }
+        ResampleMethod::BlockBootstrap => {
+            // Circular blocks keep short runs of consecutive wins/losses together
+            let block_size = block_size.clamp(1, len);
+            while sample.len() < len {
+                let start = rng.gen_range(0..len);
+                for offset in 0..block_size.min(len - sample.len()) {
+                    sample.push(returns[(start + offset) % len]);
+                }
+            }
+        }
+    }
+}
+
+fn simulate_path(initial_balance: f64, ruin_balance: f64, returns: &[f64]) -> PathResult {
+    let mut balance = initial_balance;
+    let mut peak = initial_balance;
+    let mut max_drawdown: f64 = 0.;
+    let mut ruined = false;
+    for ret in returns {
+        balance *= 1. + ret;
+        peak = peak.max(balance);
+        max_drawdown = max_drawdown.max((peak - balance) / peak);
+        ruined |= balance <= ruin_balance;
+    }
+    PathResult {
+        final_balance: balance,
+        max_drawdown,
+        ruined,
+    }
+}
+
+fn summarize(method: ResampleMethod, results: &[PathResult], confidence: f64) -> MonteCarloSummary {
+    let mut final_balances: Vec<f64> = results.iter().map(|result| result.final_balance).collect();
+    let mut max_drawdowns: Vec<f64> = results.iter().map(|result| result.max_drawdown).collect();
+    final_balances.sort_by(|a, b| a.total_cmp(b));
+    max_drawdowns.sort_by(|a, b| a.total_cmp(b));
+    let lower = (1. - confidence) / 2.;
+    let upper = 1. - lower;
+
+    let n = results.len() as f64;
+    let risk_of_ruin = results.iter().filter(|result| result.ruined).count() as f64 / n;
+    // Wilson score interval, which behaves at 0% and 100% unlike the normal approximation
+    let z = normal_quantile(upper);
+    let center = (risk_of_ruin + z * z / (2. * n)) / (1. + z * z / n);
+    let half_width = z / (1. + z * z / n)
+        * (risk_of_ruin * (1. - risk_of_ruin) / n + z * z / (4. * n * n)).sqrt();
+
+    MonteCarloSummary {
+        method,
+        iterations: results.len(),
+        final_balance_mean: mean(&final_balances),
+        final_balance_median: percentile(&final_balances, 0.5),
+        final_balance_ci: (
+            percentile(&final_balances, lower),
+            percentile(&final_balances, upper),
+        ),
+        max_drawdown_mean: mean(&max_drawdowns),
+        max_drawdown_median: percentile(&max_drawdowns, 0.5),
+        max_drawdown_ci: (
+            percentile(&max_drawdowns, lower),
+            percentile(&max_drawdowns, upper),
+        ),
+        risk_of_ruin,
+        risk_of_ruin_ci: ((center - half_width).max(0.), (center + half_width).min(1.)),
+    }
+}
+
+fn log_summary(summary: &MonteCarloSummary, confidence: f64) {
+    let mut msg = "".to_string();
+    msg += &format!("method: {:?}, ", summary.method);
+    msg += &format!("iterations: {}, ", summary.iterations);
+    msg += &format!(
+        "final_balance mean/median: {:.4}/{:.4} ",
+        summary.final_balance_mean, summary.final_balance_median
+    );
+    msg += &format!(
+        "[{:.0}% ci {:.4}, {:.4}], ",
+        confidence * 100.,
+        summary.final_balance_ci.0,
+        summary.final_balance_ci.1
+    );
+    msg += &format!(
+        "max_drawdown mean/median: {:.4}/{:.4} ",
+        summary.max_drawdown_mean, summary.max_drawdown_median
+    );
+    msg += &format!(
+        "[{:.0}% ci {:.4}, {:.4}], ",
+        confidence * 100.,
+        summary.max_drawdown_ci.0,
+        summary.max_drawdown_ci.1
+    );
+    msg += &format!(
+        "risk_of_ruin: {:.4} [{:.0}% ci {:.4}, {:.4}]",
+        summary.risk_of_ruin,
+        confidence * 100.,
+        summary.risk_of_ruin_ci.0,
+        summary.risk_of_ruin_ci.1
+    );
+    info!("{}", msg);
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+// Linear interpolation between closest ranks, values must be sorted
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    let rank = q.clamp(0., 1.) * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, process};
+
+    use super::*;
+    use crate::types::TradeLog;
+
+    fn metric(returns: &[f64]) -> BacktestMetric {
+        let mut metric = BacktestMetric {
+            initial_captial: 1000.,
+            ..Default::default()
+        };
+        let mut balance = 1000.;
+        for ret in returns {
+            metric.trades.push(TradeLog {
+                entry_balance: balance,
+                profit: balance * ret,
+                ..Default::default()
+            });
+            balance *= 1. + ret;
+        }
+        metric.usd_balance = balance;
+        metric
+    }
+
+    fn mc_config(name: &str, methods: Vec<ResampleMethod>) -> MonteCarloConfig {
+        MonteCarloConfig {
+            iterations: 200,
+            seed: 11,
+            methods,
+            output_path: output_path(name),
+            ..Default::default()
+        }
+    }
+
+    fn output_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("bb_band_mc_{}_{}.csv", name, process::id()))
+    }
+
+    const RETURNS: [f64; 8] = [0.05, -0.1, 0.02, -0.2, 0.08, -0.05, 0.1, -0.15];
+
+    #[test]
+    fn shuffle_keeps_the_final_balance() {
+        let metric = metric(&RETURNS);
+        let config = mc_config("shuffle", vec![ResampleMethod::Shuffle]);
+        let summary = &monte_carlo(&config, &metric).unwrap()[0];
+        fs::remove_file(&config.output_path).unwrap();
+        let (low, high) = summary.final_balance_ci;
+        assert!((low - metric.usd_balance).abs() < 1e-9);
+        assert!((high - metric.usd_balance).abs() < 1e-9);
+        assert!(summary.max_drawdown_ci.0 < summary.max_drawdown_ci.1);
+    }
+
+    #[test]
+    fn seeded_runs_repeat() {
+        let metric = metric(&RETURNS);
+        let methods = vec![ResampleMethod::Bootstrap, ResampleMethod::BlockBootstrap];
+        let config = mc_config("seeded", methods);
+        let first = monte_carlo(&config, &metric).unwrap();
+        let second = monte_carlo(&config, &metric).unwrap();
+        fs::remove_file(&config.output_path).unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn ruin_interval_brackets_the_rate() {
+        let metric = metric(&RETURNS);
+        for ruin_threshold in [0.05, 0.3, 1.] {
+            let mut config = mc_config("ruin", vec![ResampleMethod::Bootstrap]);
+            config.ruin_threshold = ruin_threshold;
+            let summary = &monte_carlo(&config, &metric).unwrap()[0];
+            let (low, high) = summary.risk_of_ruin_ci;
+            assert!(0. <= low && low <= summary.risk_of_ruin);
+            assert!(summary.risk_of_ruin <= high && high <= 1.);
+            assert!(high > low);
+        }
+        fs::remove_file(output_path("ruin")).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        let empty = metric(&[]);
+        let metric = metric(&RETURNS);
+        let settings: [fn(&mut MonteCarloConfig); 5] = [
+            |config| config.iterations = 0,
+            |config| config.confidence = 0.,
+            |config| config.confidence = 1.5,
+            |config| config.ruin_threshold = 0.,
+            |config| config.ruin_threshold = 1.2,
+        ];
+        for setting in settings {
+            let mut config = mc_config("rejected", vec![ResampleMethod::Shuffle]);
+            setting(&mut config);
+            assert!(monte_carlo(&config, &metric).is_err());
+        }
+        assert!(monte_carlo(&mc_config("rejected", vec![]), &empty).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::mpsc::{self, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use chrono::DateTime;
+use log::warn;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    types::{NotifierConfig, SinkConfig, TradeLog, WebhookFormat},
+    TradeSide,
+};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Entry {
+        time: i64,
+        side: TradeSide,
+        size: f64,
+        price: f64,
+        stop_loss: f64,
+        take_profit: f64,
+    },
+    ScaleIn {
+        time: i64,
+        side: TradeSide,
+        size: f64,
+        price: f64,
+        entry_price: f64, // average after the add
+        stop_loss: f64,
+        take_profit: f64,
+    },
+    PartialExit {
+        time: i64,
+        side: TradeSide,
+        size: f64,
+        price: f64,
+        profit: f64,
+        fee: f64,
+        remaining: f64,
+    },
+    Exit(TradeLog),
+    StopOut(TradeLog),
+    Guardrail {
+        time: i64,
+        reason: String,
+        action: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl Event {
+    // One line for chat sinks
+    pub fn summary(&self) -> String {
+        let date = |time: i64| DateTime::from_timestamp_millis(time).unwrap().naive_utc();
+        match self {
+            Event::Entry {
+                time,
+                side,
+                size,
+                price,
+                stop_loss,
+                take_profit,
+            } => format!(
+                "[entry] {}: {:?} {:.4} @ {:.4}, stop_loss: {:.4}, take_profit: {:.4}",
+                date(*time),
+                side,
+                size,
+                price,
+                stop_loss,
+                take_profit
+            ),
+            Event::ScaleIn {
+                time,
+                side,
+                size,
+                price,
+                entry_price,
+                stop_loss,
+                take_profit,
+            } => format!(
+                "[scale_in] {}: {:?} {:.4} @ {:.4}, entry_price: {:.4}, stop_loss: {:.4}, take_profit: {:.4}",
+                date(*time),
+                side,
+                size,
+                price,
+                entry_price,
+                stop_loss,
+                take_profit
+            ),
+            Event::PartialExit {
+                time,
+                side,
+                size,
+                price,
+                profit,
+                fee,
+                remaining,
+            } => format!(
+                "[partial_exit] {}: {:?} {:.4} @ {:.4}, profit: {:.4}, fee: {:.4}, remaining: {:.4}",
+                date(*time),
+                side,
+                size,
+                price,
+                profit,
+                fee,
+                remaining
+            ),
+            Event::Exit(trade) | Event::StopOut(trade) => format!(
+                "[{}] {}: {} {:.4} @ {:.4} -> {:.4}, profit: {:.4}, fee: {:.4}",
+                if matches!(self, Event::StopOut(_)) {
+                    "stop_out"
+                } else {
+                    "exit"
+                },
+                date(trade.exit_time),
+                if trade.entry_side > 0 { "Buy" } else { "Sell" },
+                trade.entry_size,
+                trade.entry_price,
+                trade.exit_price,
+                trade.profit,
+                trade.fee
+            ),
+            Event::Guardrail {
+                time,
+                reason,
+                action,
+            } => format!(
+                "[guardrail] {}: {}, action: {}",
+                date(*time),
+                reason,
+                action
+            ),
+            Event::Error { message } => format!("[error] {}", message),
+        }
+    }
+}
+
+pub trait Sink {
+    fn send(&mut self, event: &Event) -> Result<()>;
+}
+
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn send(&mut self, event: &Event) -> Result<()> {
+        println!("{}", serde_json::to_string(event)?);
+        Ok(())
+    }
+}
+
+pub struct JsonlSink {
+    file: File,
+}
+
+impl JsonlSink {
+    pub fn new(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonlSink { file })
+    }
+}
+
+impl Sink for JsonlSink {
+    fn send(&mut self, event: &Event) -> Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(event)?)?;
+        Ok(())
+    }
+}
+
+// Posts from a background thread so a slow webhook never holds up order handling.
+// Dropping the sink waits for the queued payloads to go out.
+pub struct WebhookSink {
+    format: WebhookFormat,
+    sender: Option<Sender<(String, Value)>>, // summary for the log, payload
+    worker: Option<JoinHandle<()>>,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str, format: &WebhookFormat) -> Self {
+        let (sender, receiver) = mpsc::channel::<(String, Value)>();
+        let agent = ureq::AgentBuilder::new().timeout(WEBHOOK_TIMEOUT).build();
+        let url = url.to_string();
+        let worker = thread::spawn(move || {
+            for (summary, payload) in receiver {
+                if let Err(err) = post(&agent, &url, &payload) {
+                    warn!("notifier failed to send {}: {}", summary, err);
+                }
+            }
+        });
+        WebhookSink {
+            format: format.clone(),
+            sender: Some(sender),
+            worker: Some(worker),
+        }
+    }
+
+    fn payload(&self, event: &Event) -> Result<Value> {
+        Ok(match &self.format {
+            WebhookFormat::Generic => serde_json::to_value(event)?,
+            WebhookFormat::Slack => json!({ "text": event.summary() }),
+            WebhookFormat::Discord => json!({ "content": event.summary() }),
+            WebhookFormat::Telegram { chat_id } => {
+                json!({ "chat_id": chat_id, "text": event.summary() })
+            }
+        })
+    }
+}
+
+impl Sink for WebhookSink {
+    fn send(&mut self, event: &Event) -> Result<()> {
+        let payload = self.payload(event)?;
+        let Some(sender) = &self.sender else {
+            bail!("webhook sink is closed");
+        };
+        if sender.send((event.summary(), payload)).is_err() {
+            bail!("webhook worker stopped");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WebhookSink {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn post(agent: &ureq::Agent, url: &str, payload: &Value) -> Result<()> {
+    // ureq errors include the url, which holds the webhook token
+    let request = agent.post(url).set("Content-Type", "application/json");
+    match request.send_string(&payload.to_string()) {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(status, _)) => bail!("webhook returned status {}", status),
+        Err(ureq::Error::Transport(transport)) => {
+            bail!("webhook transport error: {}", transport.kind())
+        }
+    }
+}
+
+// Fans events out to the configured sinks. A failing sink is logged and never stops trading.
+pub struct Notifier {
+    sinks: Vec<Box<dyn Sink + Send>>,
+}
+
+impl Notifier {
+    pub fn new(config: &NotifierConfig) -> Self {
+        let mut sinks: Vec<Box<dyn Sink + Send>> = Vec::new();
+        for sink_config in &config.sinks {
+            match sink_config {
+                SinkConfig::Stdout => sinks.push(Box::new(StdoutSink)),
+                SinkConfig::Jsonl { path } => match JsonlSink::new(path) {
+                    Ok(sink) => sinks.push(Box::new(sink)),
+                    Err(err) => warn!("notifier sink {:?} unavailable: {}", path, err),
+                },
+                SinkConfig::Webhook { url, format } => {
+                    sinks.push(Box::new(WebhookSink::new(url, format)))
+                }
+            }
+        }
+        Notifier { sinks }
+    }
+
+    pub fn notify(&mut self, event: &Event) {
+        for sink in self.sinks.iter_mut() {
+            if let Err(err) = sink.send(event) {
+                warn!("notifier failed to send {}: {}", event.summary(), err);
+            }
+        }
+    }
+
+    pub fn notify_all(&mut self, events: Vec<Event>) {
+        for event in events {
+            self.notify(&event);
+        }
+    }
+
+    // Passes the result through, publishing an error event on the way
+    pub fn report<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(err) = &result {
+            self.notify(&Event::Error {
+                message: format!("{:#}", err),
+            });
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+        sync::mpsc::Receiver,
+    };
+
+    use super::*;
+
+    // Local webhook endpoint answering 200, every request body is passed on
+    fn listener() -> (String, Receiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook/token", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                sender.send(serde_json::from_slice(&body).unwrap()).unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn guardrail() -> Event {
+        Event::Guardrail {
+            time: 0,
+            reason: "drawdown 5.00% >= max_drawdown 5.00%".to_string(),
+            action: "HaltEntries".to_string(),
+        }
+    }
+
+    fn sent(format: WebhookFormat) -> Value {
+        let (url, receiver) = listener();
+        let mut sink = WebhookSink::new(&url, &format);
+        sink.send(&guardrail()).unwrap();
+        drop(sink);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn chat_payloads_carry_the_summary() {
+        let summary = guardrail().summary();
+        assert_eq!(sent(WebhookFormat::Slack), json!({ "text": summary }));
+        assert_eq!(sent(WebhookFormat::Discord), json!({ "content": summary }));
+        assert_eq!(
+            sent(WebhookFormat::Telegram {
+                chat_id: "-100123".to_string()
+            }),
+            json!({ "chat_id": "-100123", "text": summary })
+        );
+    }
+
+    #[test]
+    fn generic_payload_is_the_tagged_event() {
+        assert_eq!(
+            sent(WebhookFormat::Generic),
+            json!({
+                "event": "guardrail",
+                "time": 0,
+                "reason": "drawdown 5.00% >= max_drawdown 5.00%",
+                "action": "HaltEntries",
+            })
+        );
+    }
+
+    #[test]
+    fn slow_webhook_does_not_block_send() {
+        // Accepts the connection but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let mut sink = WebhookSink::new(&url, &WebhookFormat::Slack);
+        let start = std::time::Instant::now();
+        for _ in 0..3 {
+            sink.send(&guardrail()).unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // Leak the sink rather than wait for the timeouts on drop
+        std::mem::forget(sink);
+        drop(listener);
+    }
+}
@@ -0,0 +1,630 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    types::{Fill, FillKind, Kline},
+    TradeSide,
+};
+
+pub type OrderId = u64;
+
+const MIN_SIZE: f64 = 1e-12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum OrderType {
+    Market,
+    Limit { price: f64 },
+    StopMarket { stop_price: f64 },
+    TakeProfitMarket { stop_price: f64 },
+}
+
+impl OrderType {
+    pub fn price(&self) -> Option<f64> {
+        match *self {
+            OrderType::Market => None,
+            OrderType::Limit { price } => Some(price),
+            OrderType::StopMarket { stop_price } | OrderType::TakeProfitMarket { stop_price } => {
+                Some(stop_price)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderRequest {
+    pub side: TradeSide,
+    pub order_type: OrderType,
+    pub size: f64,
+    pub reduce_only: bool,
+}
+
+// pending -> partially filled -> filled -> closed
+//        \-> cancelled / rejected
+// Closed means the filled quantity has been fully exited again.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum OrderStatus {
+    Pending,
+    PartiallyFilled,
+    Filled,
+    Closed,
+    Cancelled,
+    Rejected,
+}
+
+impl OrderStatus {
+    pub fn is_open(&self) -> bool {
+        matches!(self, OrderStatus::Pending | OrderStatus::PartiallyFilled)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Order {
+    pub id: OrderId,
+    pub side: TradeSide,
+    pub order_type: OrderType,
+    pub size: f64,
+    pub reduce_only: bool,
+    pub status: OrderStatus,
+    pub filled_size: f64,
+    pub avg_fill_price: f64,
+    pub fee: f64,
+}
+
+impl Order {
+    pub fn new(id: OrderId, request: OrderRequest) -> Self {
+        Order {
+            id,
+            side: request.side,
+            order_type: request.order_type,
+            size: request.size,
+            reduce_only: request.reduce_only,
+            status: OrderStatus::Pending,
+            filled_size: 0.,
+            avg_fill_price: 0.,
+            fee: 0.,
+        }
+    }
+
+    pub fn request(&self) -> OrderRequest {
+        OrderRequest {
+            side: self.side.clone(),
+            order_type: self.order_type,
+            size: self.size,
+            reduce_only: self.reduce_only,
+        }
+    }
+
+    pub fn remaining(&self) -> f64 {
+        self.size - self.filled_size
+    }
+
+    pub fn fill(&mut self, size: f64, price: f64, fee: f64) -> Result<()> {
+        if !self.status.is_open() {
+            bail!("order {} cannot fill in status {:?}", self.id, self.status);
+        }
+        if size > self.remaining() + MIN_SIZE {
+            bail!(
+                "order {} overfilled: {} > remaining {}",
+                self.id,
+                size,
+                self.remaining()
+            );
+        }
+        self.avg_fill_price =
+            (self.avg_fill_price * self.filled_size + price * size) / (self.filled_size + size);
+        self.filled_size += size;
+        self.fee += fee;
+        self.status = if self.remaining() <= MIN_SIZE {
+            OrderStatus::Filled
+        } else {
+            OrderStatus::PartiallyFilled
+        };
+        Ok(())
+    }
+
+    pub fn cancel(&mut self) -> Result<()> {
+        if !self.status.is_open() {
+            bail!(
+                "order {} cannot be cancelled in status {:?}",
+                self.id,
+                self.status
+            );
+        }
+        self.status = OrderStatus::Cancelled;
+        Ok(())
+    }
+
+    pub fn reject(&mut self) -> Result<()> {
+        if self.status != OrderStatus::Pending {
+            bail!(
+                "order {} cannot be rejected in status {:?}",
+                self.id,
+                self.status
+            );
+        }
+        self.status = OrderStatus::Rejected;
+        Ok(())
+    }
+
+    pub fn close(&mut self) -> Result<()> {
+        if self.status != OrderStatus::Filled {
+            bail!(
+                "order {} cannot be closed in status {:?}",
+                self.id,
+                self.status
+            );
+        }
+        self.status = OrderStatus::Closed;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bracket {
+    StopLoss,
+    TakeProfit,
+}
+
+// An open position with its entry order and the attached reduce-only brackets
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    pub side: TradeSide,
+    pub size: f64,
+    pub entry_price: f64, // average over the scale-ins
+    pub entry_time: i64,
+    pub entry_balance: f64, // usd_balance before the entry fee
+    pub entry_order: Order,
+    pub stop_loss: Order,
+    pub take_profit: Order,
+    #[serde(default)]
+    pub exit_order: Option<Order>, // market exit outside the brackets
+    #[serde(default)]
+    pub scale_ins: Vec<Order>,
+    #[serde(default)]
+    pub partial_exits: Vec<Order>, // take profits that closed part of the size
+    #[serde(default)]
+    pub fills: Vec<Fill>,
+}
+
+fn bracket_order(side: &TradeSide, id: OrderId, order_type: OrderType, size: f64) -> Order {
+    Order::new(
+        id,
+        OrderRequest {
+            side: side.opposite(),
+            order_type,
+            size,
+            reduce_only: true,
+        },
+    )
+}
+
+impl Position {
+    // The entry order must already be filled
+    pub fn open(
+        entry_order: Order,
+        entry_time: i64,
+        entry_balance: f64,
+        stop_loss_id: OrderId,
+        stop_loss_price: f64,
+        take_profit_id: OrderId,
+        take_profit_price: f64,
+    ) -> Result<Self> {
+        if entry_order.status != OrderStatus::Filled {
+            bail!(
+                "position needs a filled entry order, got {:?}",
+                entry_order.status
+            );
+        }
+        let side = entry_order.side.clone();
+        let size = entry_order.filled_size;
+        let bracket = |id, order_type| bracket_order(&side, id, order_type, size);
+        let fill = Fill {
+            time: entry_time,
+            kind: FillKind::Entry,
+            side: side.value() as i64,
+            price: entry_order.avg_fill_price,
+            size,
+            fee: entry_order.fee,
+        };
+        Ok(Position {
+            side: entry_order.side.clone(),
+            size: entry_order.filled_size,
+            entry_price: entry_order.avg_fill_price,
+            entry_time,
+            entry_balance,
+            stop_loss: bracket(
+                stop_loss_id,
+                OrderType::StopMarket {
+                    stop_price: stop_loss_price,
+                },
+            ),
+            take_profit: bracket(
+                take_profit_id,
+                OrderType::TakeProfitMarket {
+                    stop_price: take_profit_price,
+                },
+            ),
+            entry_order,
+            exit_order: None,
+            scale_ins: Vec::new(),
+            partial_exits: Vec::new(),
+            fills: vec![fill],
+        })
+    }
+
+    // Adds a filled order at the average entry price, the brackets are replaced by ones
+    // covering the new size
+    pub fn scale_in(
+        &mut self,
+        order: Order,
+        time: i64,
+        order_ids: [OrderId; 2],
+        stop_loss_price: f64,
+        take_profit_price: f64,
+    ) -> Result<()> {
+        if order.status != OrderStatus::Filled || order.side != self.side {
+            bail!("scale-in needs a filled order on the position side");
+        }
+        self.entry_price = (self.entry_price * self.size
+            + order.avg_fill_price * order.filled_size)
+            / (self.size + order.filled_size);
+        self.size += order.filled_size;
+        self.fills.push(Fill {
+            time,
+            kind: FillKind::ScaleIn,
+            side: self.side.value() as i64,
+            price: order.avg_fill_price,
+            size: order.filled_size,
+            fee: order.fee,
+        });
+        self.scale_ins.push(order);
+        self.replace_brackets(order_ids, stop_loss_price, take_profit_price)
+    }
+
+    pub fn closes_all(&self, size: f64) -> bool {
+        size >= self.size - MIN_SIZE
+    }
+
+    // Fills the take profit for part of the size and moves it to the next level.
+    // Returns the exit price.
+    pub fn take_partial_profit(
+        &mut self,
+        size: f64,
+        fee: f64,
+        time: i64,
+        order_ids: [OrderId; 2],
+        take_profit_price: f64,
+    ) -> Result<f64> {
+        if self.closes_all(size) {
+            bail!("partial take profit of {} closes the whole position", size);
+        }
+        let exit_price = self.bracket_price(Bracket::TakeProfit);
+        self.take_profit.fill(size, exit_price, fee)?;
+        // The rest of the order is re-placed at the next level
+        self.take_profit.cancel()?;
+        self.partial_exits.push(self.take_profit.clone());
+        self.size -= size;
+        self.fills.push(Fill {
+            time,
+            kind: FillKind::TakeProfit,
+            side: self.side.opposite().value() as i64,
+            price: exit_price,
+            size,
+            fee,
+        });
+        let stop_loss_price = self.bracket_price(Bracket::StopLoss);
+        self.replace_brackets(order_ids, stop_loss_price, take_profit_price)?;
+        Ok(exit_price)
+    }
+
+    fn replace_brackets(
+        &mut self,
+        order_ids: [OrderId; 2],
+        stop_loss_price: f64,
+        take_profit_price: f64,
+    ) -> Result<()> {
+        if self.stop_loss.status.is_open() {
+            self.stop_loss.cancel()?;
+        }
+        if self.take_profit.status.is_open() {
+            self.take_profit.cancel()?;
+        }
+        self.stop_loss = bracket_order(
+            &self.side,
+            order_ids[0],
+            OrderType::StopMarket {
+                stop_price: stop_loss_price,
+            },
+            self.size,
+        );
+        self.take_profit = bracket_order(
+            &self.side,
+            order_ids[1],
+            OrderType::TakeProfitMarket {
+                stop_price: take_profit_price,
+            },
+            self.size,
+        );
+        Ok(())
+    }
+
+    // Largest size held, the first entry plus the scale-ins
+    pub fn max_size(&self) -> f64 {
+        self.entry_order.filled_size
+            + self
+                .scale_ins
+                .iter()
+                .map(|order| order.filled_size)
+                .sum::<f64>()
+    }
+
+    // Size weighted over every exit fill
+    pub fn exit_price(&self) -> Option<f64> {
+        let exits: Vec<&Fill> = self
+            .fills
+            .iter()
+            .filter(|fill| {
+                matches!(
+                    fill.kind,
+                    FillKind::TakeProfit | FillKind::StopLoss | FillKind::Exit
+                )
+            })
+            .collect();
+        match exits.as_slice() {
+            [] => None,
+            [exit] => Some(exit.price),
+            exits => Some(
+                exits.iter().map(|fill| fill.price * fill.size).sum::<f64>()
+                    / exits.iter().map(|fill| fill.size).sum::<f64>(),
+            ),
+        }
+    }
+
+    pub fn bracket(&self, bracket: Bracket) -> &Order {
+        match bracket {
+            Bracket::StopLoss => &self.stop_loss,
+            Bracket::TakeProfit => &self.take_profit,
+        }
+    }
+
+    pub fn bracket_price(&self, bracket: Bracket) -> f64 {
+        self.bracket(bracket).order_type.price().unwrap()
+    }
+
+    // Assume we will keep tracking the price, not just tracking 15m kline.
+    // The stop loss is checked first, so a kline touching both counts as a loss.
+    pub fn triggered_bracket(&self, kline: &Kline) -> Option<Bracket> {
+        let stop_loss_price = self.bracket_price(Bracket::StopLoss);
+        let take_profit_price = self.bracket_price(Bracket::TakeProfit);
+        let (stop_loss_hit, take_profit_hit) = if self.side == TradeSide::Buy {
+            (
+                kline.low <= stop_loss_price,
+                kline.high >= take_profit_price,
+            )
+        } else {
+            (
+                kline.high >= stop_loss_price,
+                kline.low <= take_profit_price,
+            )
+        };
+        if stop_loss_hit {
+            Some(Bracket::StopLoss)
+        } else if take_profit_hit {
+            Some(Bracket::TakeProfit)
+        } else {
+            None
+        }
+    }
+
+    // Fills the bracket at its price, cancels the other one and closes the entry
+    pub fn close(&mut self, bracket: Bracket, fee: f64, time: i64) -> Result<f64> {
+        let exit_price = self.bracket_price(bracket);
+        let (filled, cancelled) = match bracket {
+            Bracket::StopLoss => (&mut self.stop_loss, &mut self.take_profit),
+            Bracket::TakeProfit => (&mut self.take_profit, &mut self.stop_loss),
+        };
+        filled.fill(filled.remaining(), exit_price, fee)?;
+        cancelled.cancel()?;
+        self.close_entries()?;
+        self.fills.push(Fill {
+            time,
+            kind: match bracket {
+                Bracket::StopLoss => FillKind::StopLoss,
+                Bracket::TakeProfit => FillKind::TakeProfit,
+            },
+            side: self.side.opposite().value() as i64,
+            price: exit_price,
+            size: self.size,
+            fee,
+        });
+        Ok(exit_price)
+    }
+
+    // Exits with a market order, cancelling both brackets
+    pub fn flatten(
+        &mut self,
+        mut exit_order: Order,
+        price: f64,
+        fee: f64,
+        time: i64,
+    ) -> Result<()> {
+        exit_order.fill(exit_order.remaining(), price, fee)?;
+        self.stop_loss.cancel()?;
+        self.take_profit.cancel()?;
+        self.close_entries()?;
+        self.exit_order = Some(exit_order);
+        self.fills.push(Fill {
+            time,
+            kind: FillKind::Exit,
+            side: self.side.opposite().value() as i64,
+            price,
+            size: self.size,
+            fee,
+        });
+        Ok(())
+    }
+
+    fn close_entries(&mut self) -> Result<()> {
+        self.entry_order.close()?;
+        for order in self.scale_ins.iter_mut() {
+            order.close()?;
+        }
+        Ok(())
+    }
+
+    pub fn total_fee(&self) -> f64 {
+        let exit_fee = self.exit_order.as_ref().map_or(0., |order| order.fee);
+        let scale_fee: f64 = self
+            .scale_ins
+            .iter()
+            .chain(&self.partial_exits)
+            .map(|order| order.fee)
+            .sum();
+        self.entry_order.fee + self.stop_loss.fee + self.take_profit.fee + exit_fee + scale_fee
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(id: OrderId, side: TradeSide, order_type: OrderType, size: f64) -> Order {
+        Order::new(
+            id,
+            OrderRequest {
+                side,
+                order_type,
+                size,
+                reduce_only: false,
+            },
+        )
+    }
+
+    // Long 2 at 100 with the stop loss at 90 and the take profit at 110
+    fn position() -> Position {
+        let mut entry = order(1, TradeSide::Buy, OrderType::Market, 2.);
+        entry.fill(2., 100., 0.2).unwrap();
+        Position::open(entry, 0, 1000., 2, 90., 3, 110.).unwrap()
+    }
+
+    #[test]
+    fn fills_average_the_price_and_reject_overfills() {
+        let mut order = order(1, TradeSide::Buy, OrderType::Market, 2.);
+        order.fill(1., 100., 0.1).unwrap();
+        assert_eq!(order.status, OrderStatus::PartiallyFilled);
+        assert!(order.fill(1.5, 100., 0.1).is_err());
+
+        order.fill(1., 110., 0.1).unwrap();
+        assert_eq!(order.status, OrderStatus::Filled);
+        assert_eq!(order.avg_fill_price, 105.);
+        assert_eq!(order.fee, 0.2);
+        assert_eq!(order.remaining(), 0.);
+        assert!(order.fill(1e-6, 100., 0.).is_err());
+    }
+
+    #[test]
+    fn only_open_orders_cancel_and_only_pending_ones_reject() {
+        let mut pending = order(1, TradeSide::Buy, OrderType::Market, 2.);
+        pending.cancel().unwrap();
+        assert_eq!(pending.status, OrderStatus::Cancelled);
+        assert!(pending.cancel().is_err());
+        assert!(pending.reject().is_err());
+        assert!(pending.fill(1., 100., 0.).is_err());
+
+        let mut rejected = order(2, TradeSide::Buy, OrderType::Market, 2.);
+        rejected.reject().unwrap();
+        assert!(rejected.cancel().is_err());
+        assert!(rejected.close().is_err());
+
+        let mut partial = order(3, TradeSide::Buy, OrderType::Market, 2.);
+        partial.fill(1., 100., 0.).unwrap();
+        assert!(partial.reject().is_err());
+        assert!(partial.close().is_err());
+        partial.fill(1., 100., 0.).unwrap();
+        assert!(partial.cancel().is_err());
+        partial.close().unwrap();
+        assert_eq!(partial.status, OrderStatus::Closed);
+    }
+
+    #[test]
+    fn close_fills_one_bracket_and_cancels_the_other() {
+        let mut position = position();
+        let exit_price = position.close(Bracket::StopLoss, 0.18, 5).unwrap();
+        assert_eq!(exit_price, 90.);
+        assert_eq!(position.stop_loss.status, OrderStatus::Filled);
+        assert_eq!(position.stop_loss.filled_size, 2.);
+        assert_eq!(position.take_profit.status, OrderStatus::Cancelled);
+        assert_eq!(position.entry_order.status, OrderStatus::Closed);
+        assert_eq!(position.exit_price(), Some(90.));
+        assert!((position.total_fee() - 0.38).abs() < 1e-12);
+        assert!(position.close(Bracket::TakeProfit, 0., 6).is_err());
+    }
+
+    #[test]
+    fn partial_take_profit_moves_the_brackets_to_the_rest() {
+        let mut position = position();
+        assert!(position
+            .take_partial_profit(2., 0., 5, [4, 5], 120.)
+            .is_err());
+
+        let exit_price = position
+            .take_partial_profit(0.5, 0.05, 5, [4, 5], 120.)
+            .unwrap();
+        assert_eq!(exit_price, 110.);
+        assert_eq!(position.size, 1.5);
+        assert_eq!(position.max_size(), 2.);
+        let partial = &position.partial_exits[0];
+        assert_eq!(
+            (partial.id, partial.filled_size, partial.status),
+            (3, 0.5, OrderStatus::Cancelled)
+        );
+        assert_eq!((position.stop_loss.id, position.take_profit.id), (4, 5));
+        assert_eq!(position.bracket_price(Bracket::StopLoss), 90.);
+        assert_eq!(position.bracket_price(Bracket::TakeProfit), 120.);
+        assert_eq!(position.stop_loss.size, 1.5);
+        assert_eq!(position.take_profit.size, 1.5);
+        assert!(position.take_profit.status.is_open());
+
+        position.close(Bracket::TakeProfit, 0.18, 6).unwrap();
+        // 0.5 at 110 and 1.5 at 120
+        assert_eq!(position.exit_price(), Some(117.5));
+    }
+
+    #[test]
+    fn scale_in_replaces_the_brackets() {
+        let mut position = position();
+        let mut scale_in = order(4, TradeSide::Buy, OrderType::Market, 2.);
+        assert!(position
+            .scale_in(scale_in.clone(), 5, [5, 6], 85., 105.)
+            .is_err());
+
+        scale_in.fill(2., 90., 0.2).unwrap();
+        position.scale_in(scale_in, 5, [5, 6], 85., 105.).unwrap();
+        assert_eq!(position.size, 4.);
+        assert_eq!(position.entry_price, 95.);
+        assert_eq!((position.stop_loss.id, position.take_profit.id), (5, 6));
+        assert_eq!(position.stop_loss.size, 4.);
+        assert_eq!(position.take_profit.size, 4.);
+        assert_eq!(position.take_profit.side, TradeSide::Sell);
+        assert!(position.take_profit.reduce_only);
+    }
+
+    #[test]
+    fn stop_loss_wins_when_both_brackets_trigger() {
+        let position = position();
+        let kline = |low, high| Kline {
+            low,
+            high,
+            ..Default::default()
+        };
+        assert_eq!(position.triggered_bracket(&kline(95., 105.)), None);
+        assert_eq!(
+            position.triggered_bracket(&kline(95., 110.)),
+            Some(Bracket::TakeProfit)
+        );
+        assert_eq!(
+            position.triggered_bracket(&kline(85., 115.)),
+            Some(Bracket::StopLoss)
+        );
+    }
+}
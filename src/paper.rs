@@ -0,0 +1,195 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    notify::Notifier,
+    order::Position,
+    risk::RiskState,
+    strategy_pool::bb_swing::{BBSwing, SwingState, DAYS},
+    stream::KlineStream,
+    types::{BacktestMetric, BbBandConfig, Kline},
+    KLINE_INTERVAL_MS,
+};
+
+// Everything needed to resume after a restart without losing the open position
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PaperState {
+    pub metric: BacktestMetric,
+    #[serde(default)]
+    pub position: Option<Position>,
+    #[serde(default)]
+    pub risk: Option<RiskState>,
+    pub window: Vec<Kline>,
+    pub last_close_time: i64,
+    #[serde(default)]
+    pub swing: SwingState, // order ids, re-entry cooldown and resting limit quote
+}
+
+pub fn paper(config: &BbBandConfig, history: &[Kline]) -> Result<()> {
+    let paper_config = &config.paper;
+    let mut bb_swing = BBSwing::new(config);
+    let mut state = match load_state(&paper_config.state_path)? {
+        Some(state) => {
+            info!(
+                "restored paper state from {:?}: usd_balance: {:.4}, position: {}",
+                paper_config.state_path,
+                state.metric.usd_balance,
+                position_summary(&state.position)
+            );
+            state
+        }
+        None => {
+            // History only seeds the window when it runs right up to the live stream,
+            // otherwise the strategy warms up from the stream itself
+            let is_recent = history.last().is_some_and(|kline| {
+                Utc::now().timestamp_millis() - kline.close_time <= KLINE_INTERVAL_MS
+            });
+            let warm_up = if is_recent {
+                &history[history.len().saturating_sub(DAYS)..]
+            } else {
+                &[]
+            };
+            info!(
+                "new paper session, warming up with {} klines",
+                warm_up.len()
+            );
+            PaperState {
+                metric: BacktestMetric::new(config),
+                position: None,
+                risk: None,
+                window: warm_up.to_vec(),
+                swing: SwingState::default(),
+                last_close_time: warm_up.last().map_or(0, |kline| kline.close_time),
+            }
+        }
+    };
+    bb_swing.warm_up(&state.window);
+    bb_swing.set_position(state.position.clone());
+    bb_swing.set_risk_state(state.risk.clone());
+    bb_swing.restore(state.swing.clone());
+    bb_swing.record_events();
+
+    let mut notifier = Notifier::new(&config.notifier);
+    let mut stream = KlineStream::new(&paper_config.stream_url);
+    loop {
+        let kline = notifier.report(stream.next_closed_kline())?;
+        // Replays and reconnects can resend klines that were already processed
+        if kline.close_time <= state.last_close_time {
+            continue;
+        }
+        bb_swing.strategy(&mut state.metric, &kline);
+        notifier.notify_all(bb_swing.drain_events());
+        state.position = bb_swing.position().cloned();
+        state.risk = bb_swing.risk_state().cloned();
+        state.window = bb_swing.window();
+        state.swing = bb_swing.state();
+        state.last_close_time = kline.close_time;
+        notifier.report(save_state(&paper_config.state_path, &state))?;
+        info!(
+            "paper: date: {:?}, close: {:.4}, usd_balance: {:.4}, position: {}",
+            DateTime::from_timestamp_millis(kline.close_time)
+                .unwrap()
+                .naive_utc(),
+            kline.close,
+            state.metric.usd_balance,
+            position_summary(&state.position)
+        );
+    }
+}
+
+fn position_summary(position: &Option<Position>) -> String {
+    match position {
+        Some(position) => format!(
+            "{:?} {:.4} @ {:.4}",
+            position.side, position.size, position.entry_price
+        ),
+        None => "None".to_string(),
+    }
+}
+
+fn load_state(path: &Path) -> Result<Option<PaperState>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let state = serde_json::from_reader(fs::File::open(path)?)?;
+    Ok(Some(state))
+}
+
+fn save_state(path: &Path, state: &PaperState) -> Result<()> {
+    // Write then rename so a crash mid-write keeps the previous state
+    let tmp_path = path.with_extension("tmp");
+    serde_json::to_writer(fs::File::create(&tmp_path)?, state)?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::{config, klines};
+
+    fn restored(config: &BbBandConfig, state: &PaperState) -> BBSwing {
+        let mut bb_swing = BBSwing::new(config);
+        bb_swing.warm_up(&state.window);
+        bb_swing.set_position(state.position.clone());
+        bb_swing.set_risk_state(state.risk.clone());
+        bb_swing.restore(state.swing.clone());
+        bb_swing
+    }
+
+    #[test]
+    fn state_round_trip_resumes_the_session() {
+        let config = config(json!({
+            "entry": {"order_type": "Limit"},
+            "timing": {"cooldown_bars": 4},
+            "risk": {"max_consecutive_losses": 3},
+        }));
+        let klines = klines(1500);
+        let mut bb_swing = BBSwing::new(&config);
+        let mut metric = BacktestMetric::new(&config);
+        let mut split = None;
+        for (index, kline) in klines.iter().enumerate() {
+            bb_swing.strategy(&mut metric, kline);
+            if index > 500 && bb_swing.position().is_some() {
+                split = Some(index + 1);
+                break;
+            }
+        }
+        let split = split.expect("no position opened");
+        let state = PaperState {
+            metric: metric.clone(),
+            position: bb_swing.position().cloned(),
+            risk: bb_swing.risk_state().cloned(),
+            window: bb_swing.window(),
+            last_close_time: klines[split - 1].close_time,
+            swing: bb_swing.state(),
+        };
+
+        let path = std::env::temp_dir().join(format!("bb_band_paper_{}.json", process::id()));
+        assert!(load_state(&path).unwrap().is_none());
+        save_state(&path, &state).unwrap();
+        let loaded = load_state(&path).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&state).unwrap()
+        );
+
+        let mut resumed = restored(&config, &loaded);
+        let mut resumed_metric = loaded.metric;
+        for kline in &klines[split..] {
+            bb_swing.strategy(&mut metric, kline);
+            resumed.strategy(&mut resumed_metric, kline);
+        }
+        assert_eq!(resumed_metric.trades.len(), metric.trades.len());
+        assert_eq!(resumed_metric.usd_balance, metric.usd_balance);
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use resvg::{tiny_skia, usvg};
+
+use crate::types::PlotFormat;
+
+// Viridis, sampled at 5 stops
+const COLOR_STOPS: [(f64, f64, f64); 5] = [
+    (68., 1., 84.),
+    (59., 82., 139.),
+    (33., 145., 140.),
+    (94., 201., 98.),
+    (253., 231., 37.),
+];
+pub const MISSING_COLOR: &str = "#dddddd";
+
+// Minimal SVG document, plots are drawn with a handful of primitives
+pub struct Svg {
+    width: f64,
+    height: f64,
+    body: String,
+}
+
+impl Svg {
+    pub fn new(width: f64, height: f64) -> Self {
+        Svg {
+            width,
+            height,
+            body: String::new(),
+        }
+    }
+
+    pub fn rect(&mut self, x: f64, y: f64, width: f64, height: f64, fill: &str) {
+        let _ = writeln!(
+            self.body,
+            r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}" fill="{}"/>"#,
+            x, y, width, height, fill
+        );
+    }
+
+    pub fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64, stroke: &str) {
+        let _ = writeln!(
+            self.body,
+            r#"<line x1="{:.2}" y1="{:.2}" x2="{:.2}" y2="{:.2}" stroke="{}" stroke-width="1"/>"#,
+            x1, y1, x2, y2, stroke
+        );
+    }
+
+    pub fn polyline(&mut self, points: &[(f64, f64)], stroke: &str) {
+        let _ = writeln!(
+            self.body,
+            r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="2"/>"#,
+            points_attr(points),
+            stroke
+        );
+    }
+
+    pub fn polygon(&mut self, points: &[(f64, f64)], fill: &str, opacity: f64) {
+        let _ = writeln!(
+            self.body,
+            r#"<polygon points="{}" fill="{}" fill-opacity="{}"/>"#,
+            points_attr(points),
+            fill,
+            opacity
+        );
+    }
+
+    pub fn circle(&mut self, x: f64, y: f64, radius: f64, fill: &str) {
+        let _ = writeln!(
+            self.body,
+            r#"<circle cx="{:.2}" cy="{:.2}" r="{}" fill="{}"/>"#,
+            x, y, radius, fill
+        );
+    }
+
+    pub fn hollow_circle(&mut self, x: f64, y: f64, radius: f64, stroke: &str) {
+        let _ = writeln!(
+            self.body,
+            r#"<circle cx="{:.2}" cy="{:.2}" r="{}" fill="none" stroke="{}" stroke-width="1.5"/>"#,
+            x, y, radius, stroke
+        );
+    }
+
+    // anchor is start, middle or end; rotated text is turned 90 degrees counterclockwise
+    pub fn text(&mut self, x: f64, y: f64, size: f64, anchor: &str, rotated: bool, text: &str) {
+        self.colored_text(x, y, size, anchor, rotated, "black", text);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn colored_text(
+        &mut self,
+        x: f64,
+        y: f64,
+        size: f64,
+        anchor: &str,
+        rotated: bool,
+        fill: &str,
+        text: &str,
+    ) {
+        let transform = if rotated {
+            format!(r#" transform="rotate(-90 {:.2} {:.2})""#, x, y)
+        } else {
+            "".to_string()
+        };
+        let _ = writeln!(
+            self.body,
+            r#"<text x="{:.2}" y="{:.2}" font-family="sans-serif" font-size="{}" text-anchor="{}" fill="{}"{}>{}</text>"#,
+            x,
+            y,
+            size,
+            anchor,
+            fill,
+            transform,
+            escape(text)
+        );
+    }
+
+    pub fn to_svg(&self) -> String {
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n<rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n{}</svg>\n",
+            self.width, self.height, self.width, self.height, self.body
+        )
+    }
+
+    // Writes <base>.svg and/or <base>.png
+    pub fn save(&self, base: &Path, formats: &[PlotFormat]) -> Result<Vec<PathBuf>> {
+        let svg = self.to_svg();
+        let mut paths = Vec::new();
+        for format in formats {
+            let path = base.with_extension(format.extension());
+            match format {
+                PlotFormat::Svg => fs::write(&path, &svg)?,
+                PlotFormat::Png => render_png(&svg, &path)?,
+            }
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+// Rasterised in process, text needs a system font
+fn render_png(svg: &str, path: &Path) -> Result<()> {
+    let mut options = usvg::Options::default();
+    let fontdb = options.fontdb_mut();
+    fontdb.load_system_fonts();
+    // sans-serif maps to Arial, fall back to any installed sans font
+    let query = usvg::fontdb::Query {
+        families: &[usvg::fontdb::Family::SansSerif],
+        ..Default::default()
+    };
+    if fontdb.query(&query).is_none() {
+        let sans = fontdb
+            .faces()
+            .flat_map(|face| face.families.iter().map(|(family, _)| family.clone()))
+            .find(|family| family.contains("Sans"));
+        if let Some(sans) = sans {
+            fontdb.set_sans_serif_family(sans);
+        }
+    }
+    let tree = usvg::Tree::from_str(svg, &options)?;
+    let size = tree.size().to_int_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or_else(|| anyhow!("empty plot {:?}", path))?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    pixmap.save_png(path)?;
+    Ok(())
+}
+
+fn points_attr(points: &[(f64, f64)]) -> String {
+    points
+        .iter()
+        .map(|(x, y)| format!("{:.2},{:.2}", x, y))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// Colour for t in [0, 1]
+pub fn color(t: f64) -> String {
+    let t = t.clamp(0., 1.) * (COLOR_STOPS.len() - 1) as f64;
+    let index = (t.floor() as usize).min(COLOR_STOPS.len() - 2);
+    let fraction = t - index as f64;
+    let (r0, g0, b0) = COLOR_STOPS[index];
+    let (r1, g1, b1) = COLOR_STOPS[index + 1];
+    format!(
+        "rgb({:.0},{:.0},{:.0})",
+        r0 + (r1 - r0) * fraction,
+        g0 + (g1 - g0) * fraction,
+        b0 + (b1 - b0) * fraction
+    )
+}
+
+// Short axis label, rounding away float noise such as 0.020000000000000004
+pub fn tick_label(value: f64) -> String {
+    let rounded = (value * 1e6).round() / 1e6;
+    if rounded.abs() >= 1e3 {
+        format!("{:.0}", rounded)
+    } else {
+        format!("{}", rounded)
+    }
+}
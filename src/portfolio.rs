@@ -0,0 +1,509 @@
+use std::{collections::VecDeque, fs::File, path::Path, time::Instant};
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{
+    data_quality::validate_klines,
+    order::Position,
+    strategy_pool::bb_swing::BBSwing,
+    synthetic::get_synthetic_klines,
+    types::{Allocation, BacktestMetric, BbBandConfig, Kline, PortfolioConfig, SyntheticConfig},
+    utils::get_collection_klines_from_db,
+    DAY_MS, KLINE_INTERVAL_MS,
+};
+
+pub struct Stream {
+    pub symbol: String,
+    pub klines: Vec<Kline>,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct SymbolBreakdown {
+    pub symbol: String,
+    pub klines: usize,
+    pub trades: usize,
+    pub win: usize,
+    pub lose: usize,
+    pub total_profit: f64,
+    pub total_fee: f64,
+    pub contribution: f64,    // net profit / initial capital
+    pub exposure_time: f64,   // share of the symbol's klines with an open position
+    pub mean_allocation: f64, // over the symbol's klines
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PortfolioMetric {
+    #[serde(skip)]
+    pub account: BacktestMetric, // the shared balance, trades of every symbol
+    pub initial_captial: f64,
+    pub usd_balance: f64,
+    pub total_return: f64,
+    pub max_drawdown: f64, // on the equity marked at every step
+    pub sharpe: f64,       // annualised, daily marked equity
+    pub trades: usize,
+    pub win_rate: f64,
+    pub total_fee: f64,
+    pub max_exposure: f64, // gross notional / equity
+    pub mean_exposure: f64,
+    pub symbols: Vec<SymbolBreakdown>,
+}
+
+// Klines of every portfolio symbol, from mongo or generated from the synthetic config
+// with one seed per symbol
+pub fn load_streams(
+    config: &BbBandConfig,
+    synthetic_config: Option<&SyntheticConfig>,
+) -> Result<Vec<Stream>> {
+    if config.portfolio.symbols.is_empty() {
+        bail!("portfolio mode needs portfolio.symbols in the config");
+    }
+    let mut streams = Vec::new();
+    for (index, symbol) in config.portfolio.symbols.iter().enumerate() {
+        let klines = match synthetic_config {
+            Some(synthetic_config) => {
+                let mut synthetic_config = synthetic_config.clone();
+                synthetic_config.seed += index as u64;
+                if let Some(start_price) = symbol.start_price {
+                    synthetic_config.start_price = start_price;
+                }
+                get_synthetic_klines(config, &synthetic_config)?
+            }
+            None => get_collection_klines_from_db(config, &symbol.collection()),
+        };
+        let (klines, report) =
+            validate_klines(klines, KLINE_INTERVAL_MS, config.data_quality_policy)?;
+        if report.is_clean() {
+            info!("{}: {}", symbol.symbol, report);
+        } else {
+            warn!("{}: {}", symbol.symbol, report);
+        }
+        streams.push(Stream {
+            symbol: symbol.symbol.clone(),
+            klines,
+        });
+    }
+    Ok(streams)
+}
+
+// Log-return volatility over the last lookback klines
+struct RollingVolatility {
+    lookback: usize,
+    returns: VecDeque<f64>,
+    sum: f64,
+    sum_sq: f64,
+    prev_close: Option<f64>,
+}
+
+impl RollingVolatility {
+    fn new(lookback: usize) -> Self {
+        RollingVolatility {
+            lookback: lookback.max(2),
+            returns: VecDeque::new(),
+            sum: 0.,
+            sum_sq: 0.,
+            prev_close: None,
+        }
+    }
+
+    fn push(&mut self, close: f64) {
+        if let Some(prev_close) = self.prev_close {
+            let ret = (close / prev_close).ln();
+            self.returns.push_back(ret);
+            self.sum += ret;
+            self.sum_sq += ret * ret;
+            if self.returns.len() > self.lookback {
+                let old = self.returns.pop_front().unwrap();
+                self.sum -= old;
+                self.sum_sq -= old * old;
+            }
+        }
+        self.prev_close = Some(close);
+    }
+
+    fn value(&self) -> Option<f64> {
+        let len = self.returns.len() as f64;
+        if len < 2. {
+            return None;
+        }
+        let variance = (self.sum_sq - self.sum * self.sum / len) / (len - 1.);
+        (variance > 0.).then(|| variance.sqrt())
+    }
+}
+
+// Symbols without a volatility estimate yet get the mean inverse volatility of the others
+fn allocations(portfolio: &PortfolioConfig, volatilities: &[RollingVolatility]) -> Vec<f64> {
+    let symbols = volatilities.len();
+    match portfolio.allocation {
+        Allocation::EqualWeight => vec![1. / symbols as f64; symbols],
+        Allocation::VolatilityParity => {
+            let inverse: Vec<Option<f64>> = volatilities
+                .iter()
+                .map(|volatility| volatility.value().map(|value| 1. / value))
+                .collect();
+            let known: Vec<f64> = inverse.iter().flatten().cloned().collect();
+            let fallback = if known.is_empty() {
+                1.
+            } else {
+                known.iter().sum::<f64>() / known.len() as f64
+            };
+            let inverse: Vec<f64> = inverse
+                .iter()
+                .map(|inverse| inverse.unwrap_or(fallback))
+                .collect();
+            let total: f64 = inverse.iter().sum();
+            inverse.iter().map(|inverse| inverse / total).collect()
+        }
+    }
+}
+
+fn unrealised(position: &Position, price: f64, leverage: u64) -> f64 {
+    (price - position.entry_price) * position.size * position.side.value() * leverage as f64
+}
+
+fn notional(position: &Position, price: f64, leverage: u64) -> f64 {
+    position.size * price * leverage as f64
+}
+
+// Runs BBSwing on every stream against one account. Klines are merged by open time,
+// symbols sharing a timestamp trade in config order, and each entry is sized from the
+// shared balance times the symbol's allocation, then capped by the exposure limits.
+pub fn portfolio_backtest(config: &BbBandConfig, streams: &[Stream]) -> PortfolioMetric {
+    let timer = Instant::now();
+    let portfolio = &config.portfolio;
+    let leverage = config.leverage;
+    let mut account = BacktestMetric::new(config);
+    account.bb_width = config.bb_width;
+    let mut swings: Vec<BBSwing> = streams.iter().map(|_| BBSwing::new(config)).collect();
+    let mut volatilities: Vec<RollingVolatility> = streams
+        .iter()
+        .map(|_| RollingVolatility::new(portfolio.volatility_lookback))
+        .collect();
+    let mut breakdowns: Vec<SymbolBreakdown> = streams
+        .iter()
+        .map(|stream| SymbolBreakdown {
+            symbol: stream.symbol.clone(),
+            ..Default::default()
+        })
+        .collect();
+    let mut last_close: Vec<Option<f64>> = vec![None; streams.len()];
+    let mut heads = vec![0; streams.len()];
+
+    let mut peak_equity = config.initial_captial;
+    let mut max_drawdown: f64 = 0.;
+    let mut max_exposure: f64 = 0.;
+    let mut exposure_sum = 0.;
+    let mut steps = 0;
+    let mut daily_equity = Vec::new();
+    let mut day = None;
+    // Marked equity and gross notional of the open positions, skipping one symbol
+    let mark = |swings: &[BBSwing], last_close: &[Option<f64>], skip: Option<usize>| {
+        let mut unrealised_sum = 0.;
+        let mut notional_sum = 0.;
+        for (index, swing) in swings.iter().enumerate() {
+            if Some(index) == skip {
+                continue;
+            }
+            if let (Some(position), Some(price)) = (swing.position(), last_close[index]) {
+                unrealised_sum += unrealised(position, price, leverage);
+                notional_sum += notional(position, price, leverage);
+            }
+        }
+        (unrealised_sum, notional_sum)
+    };
+
+    while let Some(time) = streams
+        .iter()
+        .zip(&heads)
+        .filter_map(|(stream, head)| stream.klines.get(*head).map(|kline| kline.open_time))
+        .min()
+    {
+        let allocation = allocations(portfolio, &volatilities);
+        for index in 0..streams.len() {
+            let Some(kline) = streams[index].klines.get(heads[index]) else {
+                continue;
+            };
+            if kline.open_time != time {
+                continue;
+            }
+            let (unrealised_sum, _) = mark(&swings, &last_close, None);
+            let (other_unrealised, other_notional) = mark(&swings, &last_close, Some(index));
+            let equity = account.usd_balance + unrealised_sum;
+            let symbol_limit = portfolio.max_symbol_exposure.map(|limit| limit * equity);
+            let total_limit = portfolio
+                .max_total_exposure
+                .map(|limit| limit * equity - other_notional);
+            let max_entry_notional = match (symbol_limit, total_limit) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (limit, None) | (None, limit) => limit,
+            };
+            swings[index].set_allocation(allocation[index], max_entry_notional);
+            swings[index].set_other_positions(other_unrealised, other_notional);
+
+            let trades = account.trades.len();
+            swings[index].strategy(&mut account, kline);
+            let breakdown = &mut breakdowns[index];
+            for trade in &account.trades[trades..] {
+                breakdown.trades += 1;
+                if trade.profit - trade.fee >= 0. {
+                    breakdown.win += 1;
+                } else {
+                    breakdown.lose += 1;
+                }
+                breakdown.total_profit += trade.profit;
+                breakdown.total_fee += trade.fee;
+            }
+            breakdown.klines += 1;
+            breakdown.mean_allocation += allocation[index];
+            if swings[index].position().is_some() {
+                breakdown.exposure_time += 1.;
+            }
+            last_close[index] = Some(kline.close);
+            volatilities[index].push(kline.close);
+            heads[index] += 1;
+        }
+
+        let (unrealised_sum, notional_sum) = mark(&swings, &last_close, None);
+        let equity = account.usd_balance + unrealised_sum;
+        peak_equity = peak_equity.max(equity);
+        max_drawdown = max_drawdown.max((peak_equity - equity) / peak_equity);
+        let exposure = if equity > 0. {
+            notional_sum / equity
+        } else {
+            0.
+        };
+        max_exposure = max_exposure.max(exposure);
+        exposure_sum += exposure;
+        steps += 1;
+        let today = time.div_euclid(DAY_MS);
+        if day != Some(today) {
+            day = Some(today);
+            daily_equity.push(equity);
+        } else if let Some(last) = daily_equity.last_mut() {
+            *last = equity;
+        }
+    }
+
+    for breakdown in breakdowns.iter_mut() {
+        let klines = breakdown.klines.max(1) as f64;
+        breakdown.exposure_time /= klines;
+        breakdown.mean_allocation /= klines;
+        breakdown.contribution =
+            (breakdown.total_profit - breakdown.total_fee) / config.initial_captial;
+    }
+    let trades = account.trades.len();
+    let metric = PortfolioMetric {
+        initial_captial: config.initial_captial,
+        usd_balance: account.usd_balance,
+        total_return: account.usd_balance / config.initial_captial - 1.,
+        max_drawdown,
+        sharpe: daily_sharpe(config.initial_captial, &daily_equity),
+        trades,
+        win_rate: account.win as f64 / trades.max(1) as f64,
+        total_fee: account.total_fee,
+        max_exposure,
+        mean_exposure: exposure_sum / steps.max(1) as f64,
+        symbols: breakdowns,
+        account,
+    };
+    log_portfolio(&metric);
+    info!("elapsed: {}", timer.elapsed().as_secs());
+    metric
+}
+
+fn daily_sharpe(initial: f64, daily_equity: &[f64]) -> f64 {
+    let mut prev = initial;
+    let returns: Vec<f64> = daily_equity
+        .iter()
+        .map(|equity| {
+            let ret = equity / prev - 1.;
+            prev = *equity;
+            ret
+        })
+        .collect();
+    if returns.len() < 2 {
+        return 0.;
+    }
+    let len = returns.len() as f64;
+    let mean = returns.iter().sum::<f64>() / len;
+    let std = (returns.iter().map(|ret| (ret - mean).powi(2)).sum::<f64>() / (len - 1.)).sqrt();
+    if std > 0. {
+        mean / std * 365f64.sqrt()
+    } else {
+        0.
+    }
+}
+
+fn log_portfolio(metric: &PortfolioMetric) {
+    for breakdown in &metric.symbols {
+        let mut msg = "".to_string();
+        msg += &format!("symbol: {}, ", breakdown.symbol);
+        msg += &format!("klines: {}, ", breakdown.klines);
+        msg += &format!("trades: {}, ", breakdown.trades);
+        msg += &format!("win: {}, ", breakdown.win);
+        msg += &format!("lose: {}, ", breakdown.lose);
+        msg += &format!("total_profit: {:.4}, ", breakdown.total_profit);
+        msg += &format!("total_fee: {:.4}, ", breakdown.total_fee);
+        msg += &format!("contribution: {:.4}, ", breakdown.contribution);
+        msg += &format!("exposure_time: {:.4}, ", breakdown.exposure_time);
+        msg += &format!("mean_allocation: {:.4}", breakdown.mean_allocation);
+        info!("{}", msg);
+    }
+    let mut msg = "".to_string();
+    msg += &format!("portfolio usd_balance: {:.4}, ", metric.usd_balance);
+    msg += &format!("total_return: {:.4}, ", metric.total_return);
+    msg += &format!("max_drawdown: {:.4}, ", metric.max_drawdown);
+    msg += &format!("sharpe: {:.4}, ", metric.sharpe);
+    msg += &format!("trades: {}, ", metric.trades);
+    msg += &format!("win_rate: {:.4}, ", metric.win_rate);
+    msg += &format!("total_fee: {:.4}, ", metric.total_fee);
+    msg += &format!("max_exposure: {:.4}, ", metric.max_exposure);
+    msg += &format!("mean_exposure: {:.4}", metric.mean_exposure);
+    info!("{}", msg);
+}
+
+pub fn write_portfolio(metric: &PortfolioMetric, path: &Path) -> Result<()> {
+    serde_json::to_writer_pretty(File::create(path)?, metric)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        backtest::backtest,
+        test_utils::{config, klines},
+    };
+
+    fn streams(klines: &[Vec<Kline>]) -> Vec<Stream> {
+        klines
+            .iter()
+            .enumerate()
+            .map(|(index, klines)| Stream {
+                symbol: format!("S{}", index),
+                klines: klines.clone(),
+            })
+            .collect()
+    }
+
+    // A second symbol on the same path, at a different price level
+    fn scaled(klines: &[Kline], factor: f64) -> Vec<Kline> {
+        klines
+            .iter()
+            .map(|kline| Kline {
+                open: kline.open * factor,
+                high: kline.high * factor,
+                low: kline.low * factor,
+                close: kline.close * factor,
+                ..kline.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn halts_are_counted_per_symbol() {
+        let config = config(json!({"risk": {"max_consecutive_losses": 2, "reset": "Daily"}}));
+        let klines = klines(3000);
+        let single = backtest(&config, &klines);
+        assert!(single.risk_halts > 0);
+
+        // Two symbols on the same prices trade alike, so each guard halts as often as a
+        // single symbol run
+        let portfolio = portfolio_backtest(&config, &streams(&[klines.clone(), klines]));
+        assert_eq!(portfolio.account.risk_halts, 2 * single.risk_halts);
+    }
+
+    #[test]
+    fn volatility_parity_weights_the_calmer_symbol_up() {
+        let mut portfolio = PortfolioConfig {
+            volatility_lookback: 10,
+            ..Default::default()
+        };
+        let mut volatilities = vec![RollingVolatility::new(10), RollingVolatility::new(10)];
+        assert_eq!(allocations(&portfolio, &volatilities), [0.5, 0.5]);
+
+        for bar in 0..20 {
+            let sign = if bar % 2 == 0 { 1. } else { -1. };
+            volatilities[0].push(100. * (1. + 0.01 * sign));
+            volatilities[1].push(100. * (1. + 0.03 * sign));
+        }
+        portfolio.allocation = Allocation::VolatilityParity;
+        let weights = allocations(&portfolio, &volatilities);
+        assert!((weights[0] + weights[1] - 1.).abs() < 1e-12);
+        assert!((weights[0] / weights[1] - 3.).abs() < 0.1);
+
+        // Without an estimate yet a symbol gets the mean inverse volatility of the others
+        volatilities.push(RollingVolatility::new(10));
+        let weights = allocations(&portfolio, &volatilities);
+        assert!((weights[2] - (weights[0] + weights[1]) / 2.).abs() < 1e-12);
+
+        portfolio.allocation = Allocation::EqualWeight;
+        assert_eq!(allocations(&portfolio, &volatilities), [1. / 3.; 3]);
+    }
+
+    #[test]
+    fn entries_respect_the_exposure_limits() {
+        let klines = klines(3000);
+        let streams = streams(&[klines.clone(), scaled(&klines, 0.1), scaled(&klines, 2.)]);
+        let unlimited = portfolio_backtest(&config(json!({"entry_protion": 1.})), &streams);
+        assert!(unlimited.max_exposure > 0.9);
+
+        // Limits are set at entry, prices drift a little before the exposure is measured
+        let total = portfolio_backtest(
+            &config(json!({"entry_protion": 1., "portfolio": {"max_total_exposure": 0.6}})),
+            &streams,
+        );
+        assert!(total.trades > 0);
+        assert!(total.max_exposure < 0.6 * 1.05);
+
+        let symbol = portfolio_backtest(
+            &config(json!({"entry_protion": 1., "portfolio": {"max_symbol_exposure": 0.1}})),
+            &streams,
+        );
+        assert!(symbol.trades > 0);
+        assert!(symbol.max_exposure < 0.3 * 1.05);
+
+        // The risk guard's leverage cap counts the notional of the other symbols
+        let leverage = portfolio_backtest(
+            &config(json!({"entry_protion": 1., "risk": {"max_leverage": 0.5}})),
+            &streams,
+        );
+        assert!(leverage.trades > 0);
+        assert!(leverage.max_exposure < 0.5 * 1.05);
+    }
+
+    #[test]
+    fn streams_are_merged_by_open_time() {
+        let klines = klines(3000);
+        // The second symbol starts late and has a gap in the middle
+        let late: Vec<Kline> = klines[500..]
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !(1000..1200).contains(index))
+            .map(|(_, kline)| kline.clone())
+            .collect();
+        let portfolio = portfolio_backtest(
+            &config(json!({})),
+            &streams(&[klines.clone(), late.clone()]),
+        );
+        assert_eq!(portfolio.symbols[0].klines, klines.len());
+        assert_eq!(portfolio.symbols[1].klines, late.len());
+        assert!(portfolio.symbols.iter().all(|symbol| symbol.trades > 0));
+        assert_eq!(
+            portfolio.trades,
+            portfolio
+                .symbols
+                .iter()
+                .map(|symbol| symbol.trades)
+                .sum::<usize>()
+        );
+        assert!(portfolio
+            .account
+            .trades
+            .windows(2)
+            .all(|pair| pair[0].exit_time <= pair[1].exit_time));
+    }
+}
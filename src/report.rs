@@ -0,0 +1,585 @@
+use std::{collections::BTreeMap, collections::VecDeque, fmt::Write as _, fs, path::Path};
+
+use anyhow::Result;
+use chrono::{DateTime, Datelike};
+
+use crate::{
+    benchmark::{Comparison, EquityStats},
+    plot::{color, tick_label, Svg},
+    strategy_pool::bb_swing::DAYS,
+    types::{BacktestMetric, BbBandConfig, Kline, TradeLog},
+    utils,
+};
+
+const WIDTH: f64 = 1000.;
+const MAX_PRICE_POINTS: usize = 3000;
+const HISTOGRAM_BINS: usize = 30;
+const BUY_COLOR: &str = "#2ca02c";
+const SELL_COLOR: &str = "#d62728";
+const BENCHMARK_COLOR: &str = "#ff7f0e";
+
+// Plot area of a chart and the data range it shows
+struct Frame {
+    left: f64,
+    top: f64,
+    width: f64,
+    height: f64,
+    x_range: (f64, f64),
+    y_range: (f64, f64),
+}
+
+impl Frame {
+    fn new(height: f64, x_range: (f64, f64), y_range: (f64, f64)) -> Self {
+        // Flat ranges still get a visible axis
+        let widen = |(min, max): (f64, f64)| {
+            if max > min {
+                (min, max)
+            } else {
+                (min - 0.5, max + 0.5)
+            }
+        };
+        Frame {
+            left: 80.,
+            top: 20.,
+            width: WIDTH - 110.,
+            height: height - 60.,
+            x_range: widen(x_range),
+            y_range: widen(y_range),
+        }
+    }
+
+    fn x(&self, value: f64) -> f64 {
+        self.left + (value - self.x_range.0) / (self.x_range.1 - self.x_range.0) * self.width
+    }
+
+    fn y(&self, value: f64) -> f64 {
+        self.top + self.height
+            - (value - self.y_range.0) / (self.y_range.1 - self.y_range.0) * self.height
+    }
+
+    fn svg(&self) -> Svg {
+        Svg::new(WIDTH, self.top + self.height + 40.)
+    }
+
+    // Axes with value ticks on y and either date or value ticks on x
+    fn axes(&self, svg: &mut Svg, dates: bool) {
+        let bottom = self.top + self.height;
+        svg.line(self.left, bottom, self.left + self.width, bottom, "black");
+        svg.line(self.left, self.top, self.left, bottom, "black");
+        let ticks = 5;
+        for tick in 0..=ticks {
+            let value =
+                self.y_range.0 + (self.y_range.1 - self.y_range.0) * tick as f64 / ticks as f64;
+            let y = self.y(value);
+            svg.line(self.left, y, self.left + self.width, y, "#eeeeee");
+            svg.text(
+                self.left - 6.,
+                y + 4.,
+                11.,
+                "end",
+                false,
+                &tick_label(value),
+            );
+        }
+        let ticks = 8;
+        for tick in 0..=ticks {
+            let value =
+                self.x_range.0 + (self.x_range.1 - self.x_range.0) * tick as f64 / ticks as f64;
+            let label = if dates {
+                date(value as i64)
+            } else {
+                tick_label(value)
+            };
+            svg.text(self.x(value), bottom + 16., 11., "middle", false, &label);
+        }
+    }
+}
+
+fn date(time: i64) -> String {
+    DateTime::from_timestamp_millis(time)
+        .unwrap()
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+// Self-contained HTML report of a backtest: summary, equity curve, drawdowns, monthly
+// returns, trade distributions and the price with bands and trades
+pub fn write_report(
+    config: &BbBandConfig,
+    klines: &[Kline],
+    metric: &BacktestMetric,
+    comparison: &Comparison,
+    path: &Path,
+) -> Result<()> {
+    let start = klines.first().map_or(0, |kline| kline.open_time);
+    let end = klines.last().map_or(0, |kline| kline.close_time);
+    let mut equity = vec![(start as f64, metric.initial_captial)];
+    equity.extend(
+        metric
+            .trades
+            .iter()
+            .map(|trade| (trade.exit_time as f64, trade.balance_after())),
+    );
+    equity.push((end as f64, metric.usd_balance));
+
+    let mut html = String::new();
+    html +=
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>bb_band backtest</title>\n";
+    html += "<style>body{font-family:sans-serif;margin:24px;color:#222}table{border-collapse:collapse}td,th{padding:4px 8px;border:1px solid #ddd;text-align:right}h2{margin-top:32px}</style>\n";
+    html += "</head><body>\n";
+    let _ = writeln!(html, "<h1>Backtest {} to {}</h1>", date(start), date(end));
+    html += &summary(config, metric);
+    html += "<h2>Buy and hold</h2>\n";
+    html += &benchmark_table(comparison);
+    html += "<h2>Equity</h2>\n";
+    let _ = writeln!(
+        html,
+        "<p><span style=\"color:{}\">&#9473; strategy</span> <span style=\"color:{}\">&#9473; buy and hold</span></p>",
+        color(0.25),
+        BENCHMARK_COLOR
+    );
+    html += &equity_chart(&equity, comparison).to_svg();
+    html += "<h2>Drawdown</h2>\n";
+    html += &drawdown_chart(&equity).to_svg();
+    html += "<h2>Monthly returns</h2>\n";
+    html += &monthly_returns(metric);
+    html += "<h2>Trade returns (%)</h2>\n";
+    let returns: Vec<f64> = metric
+        .trades
+        .iter()
+        .map(|trade| trade.net_return() * 100.)
+        .collect();
+    html += &histogram(&returns).to_svg();
+    html += "<h2>Holding time (hours)</h2>\n";
+    let holding: Vec<f64> = metric
+        .trades
+        .iter()
+        .map(|trade| (trade.exit_time - trade.entry_time) as f64 / 3_600_000.)
+        .collect();
+    html += &histogram(&holding).to_svg();
+    html += "<h2>Price, Bollinger bands and trades</h2>\n";
+    let _ = writeln!(
+        html,
+        "<p><span style=\"color:{}\">&#9679; long entry</span> <span style=\"color:{}\">&#9679; short entry</span> &#9675; exit</p>",
+        BUY_COLOR, SELL_COLOR
+    );
+    html += &price_chart(klines, &metric.trades).to_svg();
+    html += "</body></html>\n";
+    fs::write(path, html)?;
+    Ok(())
+}
+
+fn summary(config: &BbBandConfig, metric: &BacktestMetric) -> String {
+    let trades = metric.win + metric.lose;
+    let rows = [
+        ("initial capital", format!("{:.2}", metric.initial_captial)),
+        ("final balance", format!("{:.2}", metric.usd_balance)),
+        (
+            "total return",
+            format!(
+                "{:.2}%",
+                (metric.usd_balance / metric.initial_captial - 1.) * 100.
+            ),
+        ),
+        (
+            "max drawdown",
+            format!("{:.2}%", metric.max_drawdown() * 100.),
+        ),
+        ("trades", trades.to_string()),
+        (
+            "win rate",
+            format!("{:.2}%", metric.win as f64 / trades.max(1) as f64 * 100.),
+        ),
+        ("total fee", format!("{:.2}", metric.total_fee)),
+        ("risk halts", metric.risk_halts.to_string()),
+        (
+            "take profit / stop loss",
+            format!(
+                "{} / {}",
+                config.take_profit_percentage, config.stop_loss_percentage
+            ),
+        ),
+        ("leverage", config.leverage.to_string()),
+    ];
+    let mut html = "<table>\n".to_string();
+    for (name, value) in rows {
+        let _ = writeln!(
+            html,
+            "<tr><th style=\"text-align:left\">{}</th><td>{}</td></tr>",
+            name, value
+        );
+    }
+    html += "</table>\n";
+    html
+}
+
+fn benchmark_table(comparison: &Comparison) -> String {
+    let column = |stats: &EquityStats| {
+        [
+            format!("{:.2}", stats.usd_balance),
+            format!("{:.2}%", stats.total_return * 100.),
+            format!("{:.2}%", stats.volatility * 100.),
+            format!("{:.2}", stats.sharpe),
+            format!("{:.2}%", stats.max_drawdown * 100.),
+            stats.trades.to_string(),
+            format!("{:.2}", stats.total_fee),
+        ]
+    };
+    let names = [
+        "final balance",
+        "total return",
+        "volatility",
+        "sharpe",
+        "max drawdown",
+        "trades",
+        "total fee",
+    ];
+    let mut html =
+        "<table>\n<tr><th></th><th>strategy</th><th>buy and hold</th></tr>\n".to_string();
+    for ((name, strategy), benchmark) in names
+        .iter()
+        .zip(column(&comparison.strategy))
+        .zip(column(&comparison.benchmark))
+    {
+        let _ = writeln!(
+            html,
+            "<tr><th style=\"text-align:left\">{}</th><td>{}</td><td>{}</td></tr>",
+            name, strategy, benchmark
+        );
+    }
+    let _ = writeln!(
+        html,
+        "<tr><th style=\"text-align:left\">alpha / beta / correlation</th><td colspan=\"2\">{:.4} / {:.4} / {:.4}</td></tr>",
+        comparison.alpha, comparison.beta, comparison.correlation
+    );
+    html += "</table>\n";
+    html
+}
+
+fn range(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    values.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| {
+        (min.min(value), max.max(value))
+    })
+}
+
+fn equity_chart(equity: &[(f64, f64)], comparison: &Comparison) -> Svg {
+    let benchmark: Vec<(f64, f64)> = comparison
+        .equity
+        .iter()
+        .map(|(time, _, benchmark)| (*time as f64, *benchmark))
+        .collect();
+    let frame = Frame::new(
+        320.,
+        range(equity.iter().map(|point| point.0)),
+        range(equity.iter().chain(&benchmark).map(|point| point.1)),
+    );
+    let mut svg = frame.svg();
+    frame.axes(&mut svg, true);
+    // Balance only changes when a trade closes
+    let mut points = Vec::new();
+    for window in equity.windows(2) {
+        points.push((frame.x(window[0].0), frame.y(window[0].1)));
+        points.push((frame.x(window[1].0), frame.y(window[0].1)));
+    }
+    if let Some(last) = equity.last() {
+        points.push((frame.x(last.0), frame.y(last.1)));
+    }
+    let benchmark: Vec<(f64, f64)> = benchmark
+        .iter()
+        .map(|(time, balance)| (frame.x(time.min(frame.x_range.1)), frame.y(*balance)))
+        .collect();
+    svg.polyline(&benchmark, BENCHMARK_COLOR);
+    svg.polyline(&points, &color(0.25));
+    svg
+}
+
+fn drawdown_chart(equity: &[(f64, f64)]) -> Svg {
+    let mut peak = f64::NEG_INFINITY;
+    let drawdowns: Vec<(f64, f64)> = equity
+        .iter()
+        .map(|(time, balance)| {
+            peak = peak.max(*balance);
+            (*time, (balance / peak - 1.) * 100.)
+        })
+        .collect();
+    let (min, _) = range(drawdowns.iter().map(|point| point.1));
+    let frame = Frame::new(
+        220.,
+        range(drawdowns.iter().map(|point| point.0)),
+        (min.min(-1.), 0.),
+    );
+    let mut svg = frame.svg();
+    frame.axes(&mut svg, true);
+    let mut area = vec![(frame.x(drawdowns[0].0), frame.y(0.))];
+    for window in drawdowns.windows(2) {
+        area.push((frame.x(window[0].0), frame.y(window[0].1)));
+        area.push((frame.x(window[1].0), frame.y(window[0].1)));
+    }
+    if let Some(last) = drawdowns.last() {
+        area.push((frame.x(last.0), frame.y(last.1)));
+        area.push((frame.x(last.0), frame.y(0.)));
+    }
+    svg.polygon(&area, SELL_COLOR, 0.4);
+    svg
+}
+
+// Year by month table, each month compounding the trades closed in it
+fn monthly_returns(metric: &BacktestMetric) -> String {
+    let mut months: BTreeMap<(i32, u32), (f64, f64)> = BTreeMap::new();
+    for trade in &metric.trades {
+        let time = DateTime::from_timestamp_millis(trade.exit_time).unwrap();
+        let month = months
+            .entry((time.year(), time.month()))
+            .or_insert((trade.entry_balance, trade.entry_balance));
+        month.1 = trade.balance_after();
+    }
+    let mut years: BTreeMap<i32, Vec<Option<f64>>> = BTreeMap::new();
+    for ((year, month), (start, end)) in &months {
+        years.entry(*year).or_insert_with(|| vec![None; 12])[*month as usize - 1] =
+            Some(end / start - 1.);
+    }
+
+    let mut html = "<table>\n<tr><th></th>".to_string();
+    for month in [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ] {
+        let _ = write!(html, "<th>{}</th>", month);
+    }
+    html += "<th>Year</th></tr>\n";
+    let cell = |value: Option<f64>| match value {
+        Some(value) => {
+            // Greener for gains, redder for losses, saturating at 10%
+            let alpha = (value.abs() / 0.1).min(1.) * 0.6;
+            let rgb = if value >= 0. {
+                "44,160,44"
+            } else {
+                "214,39,40"
+            };
+            format!(
+                "<td style=\"background:rgba({},{:.2})\">{:.2}%</td>",
+                rgb,
+                alpha,
+                value * 100.
+            )
+        }
+        None => "<td></td>".to_string(),
+    };
+    for (year, returns) in &years {
+        let _ = write!(html, "<tr><th>{}</th>", year);
+        for value in returns {
+            html += &cell(*value);
+        }
+        let total = returns
+            .iter()
+            .flatten()
+            .fold(1., |total, ret| total * (1. + ret))
+            - 1.;
+        html += &cell(Some(total));
+        html += "</tr>\n";
+    }
+    html += "</table>\n";
+    html
+}
+
+fn histogram(values: &[f64]) -> Svg {
+    let (min, max) = if values.is_empty() {
+        (0., 1.)
+    } else {
+        range(values.iter().cloned())
+    };
+    let bin_width = if max > min {
+        (max - min) / HISTOGRAM_BINS as f64
+    } else {
+        1.
+    };
+    let mut counts = vec![0usize; HISTOGRAM_BINS];
+    for value in values {
+        let bin = (((value - min) / bin_width) as usize).min(HISTOGRAM_BINS - 1);
+        counts[bin] += 1;
+    }
+    let frame = Frame::new(
+        260.,
+        (min, min + bin_width * HISTOGRAM_BINS as f64),
+        (0., *counts.iter().max().unwrap_or(&1) as f64),
+    );
+    let mut svg = frame.svg();
+    frame.axes(&mut svg, false);
+    for (bin, count) in counts.iter().enumerate() {
+        let low = min + bin as f64 * bin_width;
+        let x = frame.x(low);
+        let y = frame.y(*count as f64);
+        let fill = if low + bin_width / 2. < 0. {
+            SELL_COLOR
+        } else {
+            BUY_COLOR
+        };
+        svg.rect(
+            x,
+            y,
+            frame.x(low + bin_width) - x - 1.,
+            frame.y(0.) - y,
+            fill,
+        );
+    }
+    svg
+}
+
+fn price_chart(klines: &[Kline], trades: &[TradeLog]) -> Svg {
+    // Bands as the strategy computes them, then thinned out for drawing
+    let mut window = VecDeque::new();
+    let mut points = Vec::new();
+    let stride = klines.len().div_ceil(MAX_PRICE_POINTS).max(1);
+    for (index, kline) in klines.iter().enumerate() {
+        window.push_back(kline.clone());
+        if window.len() > DAYS {
+            window.pop_front();
+        }
+        if index % stride == 0 {
+            let band = utils::bollinger_band(DAYS, 2., &window);
+            points.push((kline.close_time as f64, kline.close, band));
+        }
+    }
+    let (low, high) = range(points.iter().flat_map(|(_, close, band)| {
+        let band = band.as_ref();
+        [
+            *close,
+            band.map_or(*close, |band| band.up),
+            band.map_or(*close, |band| band.down),
+        ]
+    }));
+    let frame = Frame::new(420., range(points.iter().map(|point| point.0)), (low, high));
+    let mut svg = frame.svg();
+    frame.axes(&mut svg, true);
+    let band_line = |pick: fn(&crate::types::BollingerBand) -> f64| -> Vec<(f64, f64)> {
+        points
+            .iter()
+            .filter_map(|(time, _, band)| {
+                band.as_ref()
+                    .map(|band| (frame.x(*time), frame.y(pick(band))))
+            })
+            .collect()
+    };
+    svg.polyline(&band_line(|band| band.up), "#bbbbbb");
+    svg.polyline(&band_line(|band| band.down), "#bbbbbb");
+    svg.polyline(&band_line(|band| band.sma), "#dddddd");
+    let closes: Vec<(f64, f64)> = points
+        .iter()
+        .map(|(time, close, _)| (frame.x(*time), frame.y(*close)))
+        .collect();
+    svg.polyline(&closes, &color(0.25));
+    for trade in trades {
+        let fill = if trade.entry_side > 0 {
+            BUY_COLOR
+        } else {
+            SELL_COLOR
+        };
+        svg.circle(
+            frame.x(trade.entry_time as f64),
+            frame.y(trade.entry_price),
+            4.,
+            fill,
+        );
+        svg.hollow_circle(
+            frame.x(trade.exit_time as f64),
+            frame.y(trade.exit_price),
+            4.,
+            "black",
+        );
+    }
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        backtest::backtest,
+        benchmark::{buy_and_hold, compare},
+        test_utils::{config, klines},
+        DAY_MS,
+    };
+
+    const JAN_2022: i64 = 1_640_995_200_000;
+    const FEB_2022: i64 = 1_643_673_600_000;
+
+    fn trade(exit_time: i64, entry_balance: f64, balance_after: f64) -> TradeLog {
+        TradeLog {
+            entry_time: exit_time - DAY_MS,
+            exit_time,
+            entry_balance,
+            profit: balance_after - entry_balance + 1.,
+            fee: 1.,
+            ..Default::default()
+        }
+    }
+
+    fn metric(trades: Vec<TradeLog>) -> BacktestMetric {
+        let mut metric = BacktestMetric::new(&config(json!({"initial_captial": 1000.})));
+        metric.usd_balance = trades.last().map_or(1000., TradeLog::balance_after);
+        metric.trades = trades;
+        metric
+    }
+
+    #[test]
+    fn months_compound_their_trades() {
+        let metric = metric(vec![
+            trade(JAN_2022 + DAY_MS, 1000., 1100.),
+            trade(JAN_2022 + 2 * DAY_MS, 1100., 1210.),
+            trade(FEB_2022 + DAY_MS, 1210., 1089.),
+        ]);
+        let html = monthly_returns(&metric);
+        assert!(html.contains("<tr><th>2022</th>"));
+        assert!(html.contains(">21.00%</td>"));
+        assert!(html.contains(">-10.00%</td>"));
+        // 1.21 * 0.9 for the year, months without trades stay empty
+        assert!(html.contains(">8.90%</td></tr>"));
+        assert_eq!(html.matches("<td></td>").count(), 10);
+    }
+
+    #[test]
+    fn summary_uses_the_balance_after_each_trade() {
+        let metric = metric(vec![
+            trade(JAN_2022 + DAY_MS, 1000., 1100.),
+            trade(JAN_2022 + 2 * DAY_MS, 1100., 990.),
+        ]);
+        assert!((metric.max_drawdown() - 0.1).abs() < 1e-12);
+        let html = summary(&config(json!({})), &metric);
+        assert!(html.contains("<th style=\"text-align:left\">final balance</th><td>990.00</td>"));
+        assert!(html.contains("<th style=\"text-align:left\">max drawdown</th><td>10.00%</td>"));
+        assert!(html.contains("<th style=\"text-align:left\">total return</th><td>-1.00%</td>"));
+    }
+
+    #[test]
+    fn report_has_every_section() {
+        let config = config(json!({}));
+        let klines = klines(3000);
+        let metric = backtest(&config, &klines);
+        let benchmark = buy_and_hold(&config, &klines);
+        let comparison = compare(&config, &klines, &metric, &benchmark);
+        let path = std::env::temp_dir().join(format!("bb_band_report_{}.html", std::process::id()));
+        write_report(&config, &klines, &metric, &comparison, &path).unwrap();
+        let html = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        for section in [
+            "Buy and hold",
+            "Equity",
+            "Drawdown",
+            "Monthly returns",
+            "Trade returns (%)",
+            "Holding time (hours)",
+            "Price, Bollinger bands and trades",
+        ] {
+            assert!(
+                html.contains(&format!("<h2>{}</h2>", section)),
+                "{}",
+                section
+            );
+        }
+        assert_eq!(html.matches("<svg").count(), 5);
+        assert!(html.ends_with("</body></html>\n"));
+        assert!(!html.contains("NaN"));
+    }
+}
@@ -0,0 +1,375 @@
+use std::{collections::BTreeMap, process::Command};
+
+use anyhow::Result;
+use async_std::task;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use mongodb::{
+    bson::{self, doc, oid::ObjectId, Bson, Document},
+    options::FindOptions,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    mongo_client::MongoClient,
+    types::{BacktestMetric, BbBandConfig, ResultsConfig, TradeLog},
+    LOCAL_MONGO_CONNECTION_STRING, RESULTS_DB,
+};
+
+// One document per backtest, or per trial of a hypertune run
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RunRecord {
+    pub run_id: String,
+    pub mode: String,
+    pub created_at: i64,
+    pub git_commit: Option<String>,
+    pub config_hash: String,
+    pub config: Document,
+    pub metrics: RunMetrics,
+    #[serde(default)]
+    pub trades: Option<Vec<TradeLog>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RunMetrics {
+    pub initial_captial: f64,
+    pub usd_balance: f64,
+    pub max_usd: f64,
+    pub min_usd: f64,
+    pub win: i64,
+    pub lose: i64,
+    pub win_rate: f64,
+    pub total_fee: f64,
+    pub total_profit: f64,
+    pub risk_halts: i64,
+}
+
+impl RunMetrics {
+    pub fn new(metric: &BacktestMetric) -> Self {
+        RunMetrics {
+            initial_captial: metric.initial_captial,
+            usd_balance: metric.usd_balance,
+            max_usd: metric.max_usd,
+            min_usd: metric.min_usd,
+            win: metric.win as i64,
+            lose: metric.lose as i64,
+            win_rate: metric.win as f64 / (metric.win + metric.lose).max(1) as f64,
+            total_fee: metric.total_fee,
+            total_profit: metric.total_profit,
+            risk_halts: metric.risk_halts as i64,
+        }
+    }
+}
+
+pub struct ResultStore {
+    mongo: MongoClient,
+    config: ResultsConfig,
+    run_id: String,
+    git_commit: Option<String>,
+}
+
+impl ResultStore {
+    // None when results are disabled in the config
+    pub fn new(config: &ResultsConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        let store = ResultStore {
+            mongo: task::block_on(MongoClient::new(LOCAL_MONGO_CONNECTION_STRING)),
+            config: config.clone(),
+            run_id: ObjectId::new().to_hex(),
+            git_commit: git_commit(),
+        };
+        info!("storing results as run {}", store.run_id);
+        Some(store)
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    // Continues an interrupted run under its original id
+    pub fn resume(&mut self, run_id: &str) {
+        if self.run_id != run_id {
+            info!("resuming results of run {}", run_id);
+            self.run_id = run_id.to_string();
+        }
+    }
+
+    pub fn store(
+        &self,
+        mode: &str,
+        trial: Option<usize>,
+        config: &BbBandConfig,
+        metric: &BacktestMetric,
+    ) -> Result<()> {
+        let config = config.redacted();
+        let record = RunRecord {
+            run_id: match trial {
+                Some(trial) => format!("{}-{:05}", self.run_id, trial),
+                None => self.run_id.clone(),
+            },
+            mode: mode.to_string(),
+            created_at: Utc::now().timestamp_millis(),
+            git_commit: self.git_commit.clone(),
+            config_hash: config_hash(&config)?,
+            config: bson::to_document(&config)?,
+            metrics: RunMetrics::new(metric),
+            trades: self.config.store_trades.then(|| metric.trades.clone()),
+        };
+        task::block_on(self.mongo.insert_documents(
+            RESULTS_DB,
+            &self.config.collection,
+            vec![bson::to_document(&record)?],
+        ))
+    }
+}
+
+pub fn config_hash(config: &BbBandConfig) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(config)?);
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// HEAD of the working directory, with -dirty for uncommitted changes
+fn git_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        warn!("not a git checkout, results are stored without a commit");
+        return None;
+    }
+    let mut commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let status = Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .output()
+        .ok()?;
+    if !status.stdout.is_empty() {
+        commit += "-dirty";
+    }
+    Some(commit)
+}
+
+// Lists the latest runs, or compares the runs whose id starts with one of run_ids
+pub fn query(config: &BbBandConfig, run_ids: &[String], limit: i64) -> Result<()> {
+    let mongo = task::block_on(MongoClient::new(LOCAL_MONGO_CONNECTION_STRING));
+    let filter = query_filter(run_ids);
+    let find_options = FindOptions::builder()
+        .sort(doc! { "created_at": -1, "run_id": 1 })
+        .projection(doc! { "trades": 0 })
+        .limit(run_ids.is_empty().then_some(limit))
+        .build();
+    let documents = task::block_on(mongo.find_documents(
+        RESULTS_DB,
+        &config.results.collection,
+        filter,
+        find_options,
+    ))?;
+    let records = documents
+        .into_iter()
+        .map(bson::from_document::<RunRecord>)
+        .collect::<Result<Vec<_>, _>>()?;
+    info!("{} runs", records.len());
+    for record in &records {
+        info!("{}", record_message(record));
+    }
+    if !run_ids.is_empty() && records.len() > 1 {
+        log_config_diff(&records);
+    }
+    Ok(())
+}
+
+fn query_filter(run_ids: &[String]) -> Document {
+    if run_ids.is_empty() {
+        return doc! {};
+    }
+    let prefixes: Vec<Document> = run_ids
+        .iter()
+        .map(|run_id| doc! { "run_id": { "$regex": format!("^{}", run_id) } })
+        .collect();
+    doc! { "$or": prefixes }
+}
+
+fn record_message(record: &RunRecord) -> String {
+    let created_at = DateTime::from_timestamp_millis(record.created_at)
+        .unwrap()
+        .naive_utc();
+    let get_f64 = |key: &str| record.config.get_f64(key).unwrap_or(f64::NAN);
+    let metrics = &record.metrics;
+    let mut msg = "".to_string();
+    msg += &format!("run_id: {}, ", record.run_id);
+    msg += &format!("created_at: {:?}, ", created_at);
+    msg += &format!("mode: {}, ", record.mode);
+    msg += &format!(
+        "git: {}, ",
+        record
+            .git_commit
+            .as_deref()
+            .map_or("-", |commit| commit.get(..7).unwrap_or(commit))
+    );
+    msg += &format!(
+        "config_hash: {}, ",
+        record.config_hash.get(..12).unwrap_or(&record.config_hash)
+    );
+    msg += &format!("usd_balance: {:.4}, ", metrics.usd_balance);
+    msg += &format!("win_rate: {:.4}, ", metrics.win_rate);
+    msg += &format!("total_fee: {:.4}, ", metrics.total_fee);
+    msg += &format!("risk_halts: {}, ", metrics.risk_halts);
+    msg += &format!(
+        "take_profit_percentage: {}, ",
+        get_f64("take_profit_percentage")
+    );
+    msg += &format!(
+        "stop_loss_percentage: {}, ",
+        get_f64("stop_loss_percentage")
+    );
+    msg += &format!("bb_width: {}", get_f64("bb_width"));
+    msg
+}
+
+// Config values that are not the same in every compared run
+fn log_config_diff(records: &[RunRecord]) {
+    let flattened: Vec<BTreeMap<String, String>> = records
+        .iter()
+        .map(|record| {
+            let mut values = BTreeMap::new();
+            flatten("", &Bson::Document(record.config.clone()), &mut values);
+            values
+        })
+        .collect();
+    let mut keys: Vec<&String> = flattened.iter().flat_map(|values| values.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    let mut is_identical = true;
+    for key in keys {
+        let values: Vec<&str> = flattened
+            .iter()
+            .map(|values| values.get(key).map_or("-", |value| value.as_str()))
+            .collect();
+        if values.iter().all(|value| *value == values[0]) {
+            continue;
+        }
+        is_identical = false;
+        info!("config diff {}: {}", key, values.join(" | "));
+    }
+    if is_identical {
+        info!("configs are identical");
+    }
+}
+
+fn flatten(prefix: &str, value: &Bson, values: &mut BTreeMap<String, String>) {
+    match value {
+        Bson::Document(document) => {
+            for (key, value) in document {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&path, value, values);
+            }
+        }
+        _ => {
+            values.insert(prefix.to_string(), value.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::types::{SinkConfig, WebhookFormat};
+
+    fn config() -> BbBandConfig {
+        crate::test_utils::config(json!({}))
+    }
+
+    #[test]
+    fn config_hash_follows_the_parameters() {
+        let config = config();
+        let hash = config_hash(&config).unwrap();
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, config_hash(&config.clone()).unwrap());
+
+        let mut changed = config.clone();
+        changed.take_profit_percentage = 0.02;
+        assert_ne!(hash, config_hash(&changed).unwrap());
+    }
+
+    #[test]
+    fn redaction_masks_secrets_and_keeps_the_hash() {
+        let mut config = config();
+        config.binance.api_key = "key-1".to_string();
+        config.binance.api_secret = "secret-1".to_string();
+        config.notifier.sinks = vec![SinkConfig::Webhook {
+            url: "https://hooks.slack.com/services/T/B/token".to_string(),
+            format: WebhookFormat::Slack,
+        }];
+        let redacted = config.redacted();
+        assert_eq!(redacted.binance.api_key, "***");
+        assert_eq!(redacted.binance.api_secret, "***");
+        let SinkConfig::Webhook { url, .. } = &redacted.notifier.sinks[0] else {
+            panic!("expected a webhook sink");
+        };
+        assert_eq!(url, "***");
+        let stored = serde_json::to_string(&redacted).unwrap();
+        assert!(!stored.contains("secret-1") && !stored.contains("token"));
+
+        // Runs that only differ in credentials share a config hash
+        let mut rotated = config.clone();
+        rotated.binance.api_secret = "secret-2".to_string();
+        assert_eq!(
+            config_hash(&config.redacted()).unwrap(),
+            config_hash(&rotated.redacted()).unwrap()
+        );
+    }
+
+    #[test]
+    fn query_filter_matches_run_id_prefixes() {
+        assert_eq!(query_filter(&[]), doc! {});
+        let run_ids = vec!["65a1".to_string(), "65b2-00003".to_string()];
+        assert_eq!(
+            query_filter(&run_ids),
+            doc! { "$or": [
+                { "run_id": { "$regex": "^65a1" } },
+                { "run_id": { "$regex": "^65b2-00003" } },
+            ] }
+        );
+    }
+
+    #[test]
+    fn win_rate_without_trades_is_zero() {
+        let metrics = RunMetrics::new(&BacktestMetric::new(&config()));
+        assert_eq!(metrics.win_rate, 0.);
+    }
+
+    #[test]
+    fn commit_and_hash_are_shortened_when_logged() {
+        let mut record = RunRecord {
+            run_id: "run".to_string(),
+            mode: "backtest".to_string(),
+            created_at: 0,
+            git_commit: Some("abc".to_string()),
+            config_hash: "1234".to_string(),
+            config: Document::new(),
+            metrics: RunMetrics::default(),
+            trades: None,
+        };
+        let message = record_message(&record);
+        assert!(message.contains("git: abc, config_hash: 1234, "));
+
+        record.git_commit = Some("0123456789abcdef".to_string());
+        record.config_hash = "0123456789abcdef".to_string();
+        let message = record_message(&record);
+        assert!(message.contains("git: 0123456, config_hash: 0123456789ab, "));
+
+        record.git_commit = None;
+        assert!(record_message(&record).contains("git: -, "));
+    }
+}
@@ -0,0 +1,312 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    types::{RiskAction, RiskConfig, RiskReset},
+    DAY_MS,
+};
+
+// Everything that has to survive a restart, persisted by paper mode
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RiskState {
+    pub period: i64,
+    pub period_start_equity: f64,
+    pub peak_equity: f64,
+    pub consecutive_losses: usize,
+    pub halted: Option<String>, // reason, entries stay blocked until the next reset
+    pub halts: usize,
+}
+
+// Account-level guardrails shared by every mode through BBSwing
+pub struct RiskGuard {
+    config: RiskConfig,
+    state: Option<RiskState>, // None until the first kline is seen
+}
+
+impl RiskGuard {
+    pub fn new(config: &RiskConfig) -> Self {
+        RiskGuard {
+            config: config.clone(),
+            state: None,
+        }
+    }
+
+    pub fn state(&self) -> Option<&RiskState> {
+        self.state.as_ref()
+    }
+
+    pub fn set_state(&mut self, state: Option<RiskState>) {
+        self.state = state;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.state
+            .as_ref()
+            .is_some_and(|state| state.halted.is_some())
+    }
+
+    pub fn halts(&self) -> usize {
+        self.state.as_ref().map_or(0, |state| state.halts)
+    }
+
+    pub fn halt_reason(&self) -> Option<String> {
+        self.state.as_ref().and_then(|state| state.halted.clone())
+    }
+
+    pub fn action(&self) -> RiskAction {
+        self.config.action
+    }
+
+    pub fn flatten_on_halt(&self) -> bool {
+        self.config.action == RiskAction::Flatten && self.is_halted()
+    }
+
+    // Starts a new period when the schedule says so, clearing the period loss and any halt
+    // other than a drawdown that is still beyond max_drawdown
+    pub fn roll_period(&mut self, time: i64, equity: f64) {
+        let period = match self.config.reset {
+            RiskReset::Daily => time.div_euclid(DAY_MS),
+            // 1970-01-01 was a Thursday
+            RiskReset::Weekly => (time.div_euclid(DAY_MS) + 3).div_euclid(7),
+            RiskReset::Never => 0,
+        };
+        let state = self.state.get_or_insert_with(|| RiskState {
+            period,
+            period_start_equity: equity,
+            peak_equity: equity,
+            ..Default::default()
+        });
+        if state.period == period {
+            return;
+        }
+        state.period = period;
+        state.period_start_equity = equity;
+        // The peak spans periods, so a drawdown halt holds until equity is back within
+        // max_drawdown of it
+        let drawdown = 1. - equity / state.peak_equity;
+        if self
+            .config
+            .max_drawdown
+            .is_some_and(|max_drawdown| drawdown >= max_drawdown)
+        {
+            return;
+        }
+        // A losing streak carries over into the new period unless it is long enough to
+        // halt entries by itself, then it starts again from zero
+        if let Some(reason) = state.halted.take() {
+            info!("risk guard reset, entries resume (was halted: {})", reason);
+            if self
+                .config
+                .max_consecutive_losses
+                .is_some_and(|max_consecutive_losses| {
+                    state.consecutive_losses >= max_consecutive_losses
+                })
+            {
+                state.consecutive_losses = 0;
+            }
+        }
+    }
+
+    // Equity is the balance plus the unrealised profit at the kline close
+    pub fn check_equity(&mut self, equity: f64) {
+        let Some(state) = self.state.as_mut() else {
+            return;
+        };
+        state.peak_equity = state.peak_equity.max(equity);
+        let loss = 1. - equity / state.period_start_equity;
+        let drawdown = 1. - equity / state.peak_equity;
+        if let Some(max_daily_loss) = self.config.max_daily_loss {
+            if loss >= max_daily_loss {
+                self.halt(format!(
+                    "period loss {:.2}% >= max_daily_loss {:.2}%",
+                    loss * 100.,
+                    max_daily_loss * 100.
+                ));
+            }
+        }
+        if let Some(max_drawdown) = self.config.max_drawdown {
+            if drawdown >= max_drawdown {
+                self.halt(format!(
+                    "drawdown {:.2}% >= max_drawdown {:.2}%",
+                    drawdown * 100.,
+                    max_drawdown * 100.
+                ));
+            }
+        }
+    }
+
+    pub fn record_trade(&mut self, net_profit: f64) {
+        let Some(state) = self.state.as_mut() else {
+            return;
+        };
+        if net_profit >= 0. {
+            state.consecutive_losses = 0;
+            return;
+        }
+        state.consecutive_losses += 1;
+        let consecutive_losses = state.consecutive_losses;
+        if let Some(max_consecutive_losses) = self.config.max_consecutive_losses {
+            if consecutive_losses >= max_consecutive_losses {
+                self.halt(format!(
+                    "{} consecutive losses >= max_consecutive_losses {}",
+                    consecutive_losses, max_consecutive_losses
+                ));
+            }
+        }
+    }
+
+    // Scales an entry down to the notional and leverage limits. Both count the notional
+    // the account already holds elsewhere, the other symbols in portfolio mode.
+    pub fn cap_entry_size(
+        &self,
+        size: f64,
+        price: f64,
+        leverage: u64,
+        equity: f64,
+        other_notional: f64,
+    ) -> f64 {
+        let unit_notional = price * leverage as f64;
+        let mut max_notional = f64::INFINITY;
+        if let Some(max_position_notional) = self.config.max_position_notional {
+            max_notional = max_notional.min(max_position_notional);
+        }
+        if let Some(max_leverage) = self.config.max_leverage {
+            max_notional = max_notional.min(max_leverage * equity.max(0.));
+        }
+        max_notional = (max_notional - other_notional).max(0.);
+        if size * unit_notional <= max_notional {
+            return size;
+        }
+        let capped = max_notional / unit_notional;
+        info!(
+            "risk guard capped entry size {:.4} -> {:.4} (notional {:.2} -> {:.2})",
+            size,
+            capped,
+            size * unit_notional,
+            max_notional
+        );
+        capped
+    }
+
+    fn halt(&mut self, reason: String) {
+        let Some(state) = self.state.as_mut() else {
+            return;
+        };
+        if state.halted.is_some() {
+            return;
+        }
+        warn!(
+            "risk guard halted, action: {:?}, reason: {}",
+            self.config.action, reason
+        );
+        state.halted = Some(reason);
+        state.halts += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(config: RiskConfig) -> RiskGuard {
+        let mut guard = RiskGuard::new(&config);
+        guard.roll_period(0, 1000.);
+        guard
+    }
+
+    #[test]
+    fn drawdown_spans_daily_resets() {
+        let mut guard = guard(RiskConfig {
+            max_drawdown: Some(0.1),
+            ..Default::default()
+        });
+        // A slow bleed of 3% a day never trips a daily limit but does trip the peak one
+        let mut equity = 1000.;
+        for day in 1..=4 {
+            equity *= 0.97;
+            guard.roll_period(day * DAY_MS, equity);
+            guard.check_equity(equity);
+        }
+        assert!(guard.is_halted());
+        assert_eq!(guard.halts(), 1);
+
+        // Still beyond the limit on the next day, the halt holds without counting again
+        guard.roll_period(5 * DAY_MS, equity);
+        guard.check_equity(equity);
+        assert!(guard.is_halted());
+        assert_eq!(guard.halts(), 1);
+
+        guard.roll_period(6 * DAY_MS, 950.);
+        assert!(!guard.is_halted());
+    }
+
+    #[test]
+    fn period_loss_resets_daily() {
+        let mut guard = guard(RiskConfig {
+            max_daily_loss: Some(0.05),
+            ..Default::default()
+        });
+        guard.check_equity(940.);
+        assert!(guard.is_halted());
+        guard.roll_period(DAY_MS, 940.);
+        assert!(!guard.is_halted());
+        guard.check_equity(920.);
+        assert!(!guard.is_halted());
+    }
+
+    #[test]
+    fn losing_streak_carries_over_until_it_halts() {
+        let mut guard = guard(RiskConfig {
+            max_consecutive_losses: Some(3),
+            ..Default::default()
+        });
+        guard.record_trade(-1.);
+        guard.record_trade(-1.);
+        guard.roll_period(DAY_MS, 998.);
+        guard.record_trade(-1.);
+        assert!(guard.is_halted());
+
+        guard.roll_period(2 * DAY_MS, 997.);
+        assert!(!guard.is_halted());
+        guard.record_trade(-1.);
+        assert!(!guard.is_halted());
+    }
+
+    #[test]
+    fn other_halts_keep_the_losing_streak() {
+        let mut guard = guard(RiskConfig {
+            max_daily_loss: Some(0.05),
+            max_consecutive_losses: Some(3),
+            ..Default::default()
+        });
+        guard.record_trade(-30.);
+        guard.record_trade(-30.);
+        guard.check_equity(940.);
+        assert!(guard.halt_reason().unwrap().contains("max_daily_loss"));
+
+        guard.roll_period(DAY_MS, 940.);
+        assert!(!guard.is_halted());
+        assert_eq!(guard.state().unwrap().consecutive_losses, 2);
+        guard.record_trade(-1.);
+        assert!(guard
+            .halt_reason()
+            .unwrap()
+            .contains("max_consecutive_losses"));
+    }
+
+    #[test]
+    fn entry_caps_count_the_other_notional() {
+        let guard = guard(RiskConfig {
+            max_position_notional: Some(5000.),
+            max_leverage: Some(2.),
+            ..Default::default()
+        });
+        // 1 at 1000 with 2x leverage is 2000 of notional
+        assert_eq!(guard.cap_entry_size(1., 1000., 2, 10000., 0.), 1.);
+        assert_eq!(guard.cap_entry_size(5., 1000., 2, 10000., 0.), 2.5);
+        assert_eq!(guard.cap_entry_size(5., 1000., 2, 10000., 4000.), 0.5);
+        // The leverage cap binds on a small account
+        assert_eq!(guard.cap_entry_size(5., 1000., 2, 2000., 1000.), 1.5);
+        assert_eq!(guard.cap_entry_size(5., 1000., 2, 2000., 6000.), 0.);
+    }
+}
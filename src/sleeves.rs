@@ -0,0 +1,394 @@
+use std::{fs::File, path::Path, time::Instant};
+
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    strategy_pool::bb_swing::BBSwing,
+    types::{BacktestMetric, BbBandConfig, Fill, Kline, SleeveConfig, TradeLog},
+};
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SleeveResult {
+    pub name: String,
+    pub allocation: f64,
+    pub initial_captial: f64,
+    pub usd_balance: f64,
+    pub total_return: f64, // on the sleeve's own capital
+    pub max_drawdown: f64,
+    pub trades: usize,
+    pub win: usize,
+    pub lose: usize,
+    pub total_profit: f64,
+    pub total_fee: f64,    // net of the fee saving
+    pub fee_saving: f64,   // its share of the account's netting saving
+    pub contribution: f64, // net profit / account capital
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SleevesMetric {
+    #[serde(skip)]
+    pub account: BacktestMetric, // sleeve balances summed, trades of every sleeve
+    pub initial_captial: f64,
+    pub usd_balance: f64,
+    pub total_return: f64,
+    pub max_drawdown: f64, // on the equity marked at every kline
+    pub trades: usize,
+    pub total_fee: f64,    // paid on the net volume
+    pub gross_volume: f64, // leveraged size traded by the sleeves
+    pub net_volume: f64,   // leveraged size the netted account would trade
+    pub fee_saving: f64,   // fees charged on the fills netted away, credited to the sleeves
+    pub max_net_notional: f64,
+    pub max_gross_notional: f64,
+    pub sleeves: Vec<SleeveResult>,
+}
+
+// Config of every sleeve: the base config with the sleeve's overrides merged in and
+// its share of the initial capital
+pub fn sleeve_configs(config: &BbBandConfig) -> Result<Vec<BbBandConfig>> {
+    if config.sleeves.is_empty() {
+        bail!("sleeves mode needs sleeves in the config");
+    }
+    let allocation: f64 = config.sleeves.iter().map(|sleeve| sleeve.allocation).sum();
+    if allocation > 1. + 1e-9 {
+        bail!("sleeve allocations sum to {}, more than 1", allocation);
+    }
+    let mut base = serde_json::to_value(config)?;
+    if let Value::Object(base) = &mut base {
+        base.remove("sleeves");
+    }
+    config
+        .sleeves
+        .iter()
+        .map(|sleeve| {
+            let mut value = base.clone();
+            merge(&mut value, &Value::Object(sleeve.overrides.clone()), "")
+                .map_err(|err| anyhow!("sleeve {}: {}", sleeve.name, err))?;
+            let mut sleeve_config: BbBandConfig = serde_json::from_value(value)?;
+            sleeve_config.initial_captial = config.initial_captial * sleeve.allocation;
+            Ok(sleeve_config)
+        })
+        .collect()
+}
+
+// Objects merge key by key, anything else replaces the base value. Keys the base config
+// does not have are typos, serde would silently ignore them.
+fn merge(base: &mut Value, overrides: &Value, path: &str) -> Result<()> {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                match base.get_mut(key) {
+                    Some(slot) => merge(slot, value, &path)?,
+                    None => bail!("unknown override {}", path),
+                }
+            }
+        }
+        (base, overrides) => *base = overrides.clone(),
+    }
+    Ok(())
+}
+
+fn signed_size(swing: &BBSwing) -> f64 {
+    swing
+        .position()
+        .map_or(0., |position| position.size * position.side.value())
+}
+
+// Fills of the kline: those of the trades closed in it and of the open position
+fn kline_fills<'a>(swing: &'a BBSwing, closed: &'a [TradeLog], kline: &Kline) -> Vec<&'a Fill> {
+    let open = swing
+        .position()
+        .map_or(&[][..], |position| &position.fills[..]);
+    closed
+        .iter()
+        .flat_map(|trade| &trade.fills)
+        .chain(open)
+        .filter(|fill| fill.time >= kline.open_time && fill.time <= kline.close_time)
+        .collect()
+}
+
+// Crosses a kline's buys and sells, each side pro rata to the fill sizes. Fills are
+// (sleeve, signed leveraged size, fee). Returns the volume bought and sold and the fee
+// each fill saved.
+fn cross(fills: &[(usize, f64, f64)]) -> (f64, f64, Vec<f64>) {
+    let bought: f64 = fills.iter().map(|fill| fill.1.max(0.)).sum();
+    let sold: f64 = fills.iter().map(|fill| (-fill.1).max(0.)).sum();
+    let crossed = bought.min(sold);
+    let savings = fills
+        .iter()
+        .map(|(_, size, fee)| {
+            if crossed > 0. {
+                fee * crossed / if *size > 0. { bought } else { sold }
+            } else {
+                0.
+            }
+        })
+        .collect();
+    (bought, sold, savings)
+}
+
+// Each sleeve trades its own BBSwing on its own capital and positions. The account holds
+// the net of the sleeve positions, so opposite fills in the same kline cross internally
+// and only the net volume pays fees. Each side's fills are crossed pro rata, and the fees
+// they were charged go back to the sleeves that paid them.
+pub fn sleeves_backtest(config: &BbBandConfig, klines: &[Kline]) -> Result<SleevesMetric> {
+    let timer = Instant::now();
+    let configs = sleeve_configs(config)?;
+    let mut swings: Vec<BBSwing> = configs.iter().map(BBSwing::new).collect();
+    let mut metrics: Vec<BacktestMetric> = configs
+        .iter()
+        .map(|sleeve_config| {
+            let mut metric = BacktestMetric::new(sleeve_config);
+            metric.bb_width = sleeve_config.bb_width;
+            metric
+        })
+        .collect();
+    // Cash the sleeves leave unallocated stays in the account
+    let idle = config.initial_captial
+        - configs
+            .iter()
+            .map(|sleeve_config| sleeve_config.initial_captial)
+            .sum::<f64>();
+
+    let mut account = BacktestMetric::new(config);
+    account.bb_width = config.bb_width;
+    let mut gross_volume = 0.;
+    let mut net_volume = 0.;
+    let mut fee_saving = 0.;
+    let mut fee_savings = vec![0.; swings.len()];
+    let mut max_net_notional: f64 = 0.;
+    let mut max_gross_notional: f64 = 0.;
+    let mut peak_equity = config.initial_captial;
+    let mut max_drawdown: f64 = 0.;
+    for kline in klines {
+        // Sizes are leveraged so sleeves with different leverage net correctly
+        let mut fills = Vec::new(); // sleeve, leveraged size, fee
+        let mut net = 0.;
+        let mut gross = 0.;
+        let mut equity = idle;
+        for (index, swing) in swings.iter_mut().enumerate() {
+            let trades = metrics[index].trades.len();
+            swing.strategy(&mut metrics[index], kline);
+            let leverage = configs[index].leverage as f64;
+            for fill in kline_fills(swing, &metrics[index].trades[trades..], kline) {
+                fills.push((index, fill.side as f64 * fill.size * leverage, fill.fee));
+            }
+            let size = signed_size(swing) * leverage;
+            net += size;
+            gross += size.abs();
+            equity += metrics[index].usd_balance;
+            if let Some(position) = swing.position() {
+                equity += (kline.close - position.entry_price)
+                    * position.size
+                    * position.side.value()
+                    * leverage;
+            }
+        }
+        let (bought, sold, savings) = cross(&fills);
+        gross_volume += bought + sold;
+        net_volume += (bought - sold).abs();
+        for ((index, _, _), saving) in fills.iter().zip(savings) {
+            fee_saving += saving;
+            fee_savings[*index] += saving;
+        }
+        equity += fee_saving;
+        max_net_notional = max_net_notional.max(net.abs() * kline.close);
+        max_gross_notional = max_gross_notional.max(gross * kline.close);
+        peak_equity = peak_equity.max(equity);
+        max_drawdown = max_drawdown.max((peak_equity - equity) / peak_equity);
+        let balance =
+            idle + fee_saving + metrics.iter().map(|metric| metric.usd_balance).sum::<f64>();
+        account.max_usd = account.max_usd.max(balance);
+        account.min_usd = account.min_usd.min(balance);
+    }
+
+    let mut sleeves = Vec::new();
+    for (((sleeve, sleeve_config), metric), fee_saving) in config
+        .sleeves
+        .iter()
+        .zip(&configs)
+        .zip(&metrics)
+        .zip(fee_savings)
+    {
+        sleeves.push(sleeve_result(
+            config,
+            sleeve,
+            sleeve_config,
+            metric,
+            fee_saving,
+        ));
+        account.usd_balance += metric.usd_balance + fee_saving - sleeve_config.initial_captial;
+        account.win += metric.win;
+        account.lose += metric.lose;
+        account.total_fee += metric.total_fee - fee_saving;
+        account.total_profit += metric.total_profit;
+        account.risk_halts += metric.risk_halts;
+        account.limit_quotes += metric.limit_quotes;
+        account.limit_fills += metric.limit_fills;
+        account.trades.extend(metric.trades.iter().cloned());
+    }
+    account.trades.sort_by_key(|trade| trade.exit_time);
+
+    let result = SleevesMetric {
+        initial_captial: config.initial_captial,
+        usd_balance: account.usd_balance,
+        total_return: account.usd_balance / config.initial_captial - 1.,
+        max_drawdown,
+        trades: account.trades.len(),
+        total_fee: account.total_fee,
+        gross_volume,
+        net_volume,
+        fee_saving,
+        max_net_notional,
+        max_gross_notional,
+        sleeves,
+        account,
+    };
+    log_sleeves(&result);
+    info!("elapsed: {}", timer.elapsed().as_secs());
+    Ok(result)
+}
+
+fn sleeve_result(
+    config: &BbBandConfig,
+    sleeve: &SleeveConfig,
+    sleeve_config: &BbBandConfig,
+    metric: &BacktestMetric,
+    fee_saving: f64,
+) -> SleeveResult {
+    let initial_captial = sleeve_config.initial_captial;
+    let usd_balance = metric.usd_balance + fee_saving;
+    SleeveResult {
+        name: sleeve.name.clone(),
+        allocation: sleeve.allocation,
+        initial_captial,
+        usd_balance,
+        total_return: if initial_captial > 0. {
+            usd_balance / initial_captial - 1.
+        } else {
+            0.
+        },
+        max_drawdown: metric.max_drawdown(),
+        trades: metric.trades.len(),
+        win: metric.win,
+        lose: metric.lose,
+        total_profit: metric.total_profit,
+        total_fee: metric.total_fee - fee_saving,
+        fee_saving,
+        contribution: (usd_balance - initial_captial) / config.initial_captial,
+    }
+}
+
+fn log_sleeves(metric: &SleevesMetric) {
+    for sleeve in &metric.sleeves {
+        let mut msg = "".to_string();
+        msg += &format!("sleeve: {}, ", sleeve.name);
+        msg += &format!("allocation: {:.4}, ", sleeve.allocation);
+        msg += &format!("usd_balance: {:.4}, ", sleeve.usd_balance);
+        msg += &format!("total_return: {:.4}, ", sleeve.total_return);
+        msg += &format!("max_drawdown: {:.4}, ", sleeve.max_drawdown);
+        msg += &format!("trades: {}, ", sleeve.trades);
+        msg += &format!("win: {}, ", sleeve.win);
+        msg += &format!("lose: {}, ", sleeve.lose);
+        msg += &format!("total_fee: {:.4}, ", sleeve.total_fee);
+        msg += &format!("fee_saving: {:.4}, ", sleeve.fee_saving);
+        msg += &format!("contribution: {:.4}", sleeve.contribution);
+        info!("{}", msg);
+    }
+    let mut msg = "".to_string();
+    msg += &format!("account usd_balance: {:.4}, ", metric.usd_balance);
+    msg += &format!("total_return: {:.4}, ", metric.total_return);
+    msg += &format!("max_drawdown: {:.4}, ", metric.max_drawdown);
+    msg += &format!("trades: {}, ", metric.trades);
+    msg += &format!("total_fee: {:.4}, ", metric.total_fee);
+    msg += &format!("gross_volume: {:.4}, ", metric.gross_volume);
+    msg += &format!("net_volume: {:.4}, ", metric.net_volume);
+    msg += &format!("fee_saving: {:.4}, ", metric.fee_saving);
+    msg += &format!("max_net_notional: {:.4}, ", metric.max_net_notional);
+    msg += &format!("max_gross_notional: {:.4}", metric.max_gross_notional);
+    info!("{}", msg);
+}
+
+pub fn write_sleeves(metric: &SleevesMetric, path: &Path) -> Result<()> {
+    serde_json::to_writer_pretty(File::create(path)?, metric)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::klines;
+
+    fn config(sleeves: Value) -> BbBandConfig {
+        crate::test_utils::config(json!({"sleeves": sleeves}))
+    }
+
+    #[test]
+    fn overrides_merge_into_the_base() {
+        let config = config(json!([
+            {"name": "a", "allocation": 0.4, "overrides": {"take_profit_percentage": 0.03}},
+            {"name": "b", "allocation": 0.6, "overrides": {"risk": {"max_drawdown": 0.1}}},
+        ]));
+        let configs = sleeve_configs(&config).unwrap();
+        assert_eq!(configs[0].take_profit_percentage, 0.03);
+        assert_eq!(configs[0].initial_captial, 4000.);
+        assert_eq!(configs[1].take_profit_percentage, 0.01);
+        assert_eq!(configs[1].risk.max_drawdown, Some(0.1));
+    }
+
+    #[test]
+    fn unknown_overrides_are_rejected() {
+        for overrides in [
+            json!({"take_profit_percentag": 0.03}),
+            json!({"risk": {"max_draw_down": 0.1}}),
+        ] {
+            let config = config(json!([
+                {"name": "a", "allocation": 0.5, "overrides": overrides},
+            ]));
+            assert!(sleeve_configs(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn netting_saving_goes_back_to_the_sleeves() {
+        let config = config(json!([
+            {"name": "a", "allocation": 0.4, "overrides": {}},
+            {"name": "b", "allocation": 0.4, "overrides": {"take_profit_percentage": 0.005, "stop_loss_percentage": 0.02}},
+            {"name": "c", "allocation": 0.2, "overrides": {"fee_rate": 0.001, "entry": {"order_type": "Limit"}}},
+        ]));
+        let metric = sleeves_backtest(&config, &klines(3000)).unwrap();
+        assert!(metric.fee_saving > 0.);
+        // A sleeve can't save more than it was charged
+        assert!(metric.sleeves.iter().all(|sleeve| sleeve.total_fee >= 0.));
+        assert!(metric.sleeves[2].trades > 0 && metric.sleeves[2].fee_saving > 0.);
+        assert!(metric.net_volume < metric.gross_volume);
+        let sleeve_saving: f64 = metric.sleeves.iter().map(|sleeve| sleeve.fee_saving).sum();
+        assert!((sleeve_saving - metric.fee_saving).abs() < 1e-9);
+        let sleeve_balance: f64 = metric.sleeves.iter().map(|sleeve| sleeve.usd_balance).sum();
+        assert!((sleeve_balance - metric.usd_balance).abs() < 1e-6);
+        let sleeve_fee: f64 = metric.sleeves.iter().map(|sleeve| sleeve.total_fee).sum();
+        assert!((sleeve_fee - metric.total_fee).abs() < 1e-6);
+    }
+
+    #[test]
+    fn crossed_fills_save_the_fees_they_were_charged() {
+        // A buys 3 as a maker, B sells 1 and C sells 1 as takers: 2 of the 3 bought cross
+        let fills = [(0, 3., 0.3), (1, -1., 0.5), (2, -1., 0.4)];
+        let (bought, sold, savings) = cross(&fills);
+        assert_eq!((bought, sold), (3., 2.));
+        assert!((savings[0] - 0.2).abs() < 1e-12);
+        assert_eq!(savings[1..], [0.5, 0.4]);
+
+        let (_, _, savings) = cross(&[(0, 1., 0.1), (1, 2., 0.2)]);
+        assert_eq!(savings, [0., 0.]);
+    }
+}
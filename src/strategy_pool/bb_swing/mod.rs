@@ -1,15 +1,22 @@
 use std::collections::VecDeque;
 
-use chrono::NaiveDateTime;
+use chrono::DateTime;
 use log::{info, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    types::{BacktestMetric, BbBandConfig, BollingerBand, Kline, OrderSpec, StrategyType},
-    utils::{self, calculate_fee, init_trade},
-    TradeSide,
+    notify::Event,
+    order::{Bracket, Order, OrderId, OrderRequest, OrderType, Position},
+    risk::{RiskGuard, RiskState},
+    types::{
+        BacktestMetric, BbBandConfig, BollingerBand, EntryConfig, EntryOrderType, FillKind,
+        FillRule, Kline, OrderSpec, ScalingConfig, StrategyType, TimingConfig, TradeLog,
+    },
+    utils::{self, calculate_fee},
+    TradeSide, KLINE_INTERVAL_MS,
 };
 
-const DAYS: usize = 20;
+pub const DAYS: usize = 20;
 
 // bb down buy, bb up sell
 pub struct BBSwing {
@@ -22,6 +29,58 @@ pub struct BBSwing {
     fee_rate: f64,
     leverage: u64,
     entry_protion: f64,
+    position: Option<Position>,
+    order_id: OrderId,
+    risk: RiskGuard,
+    reported_halts: usize,      // halts already counted in the metric
+    events: Option<Vec<Event>>, // only collected once a notifier asks for them
+    allocation: f64,
+    max_entry_notional: Option<f64>,
+    other_unrealised: f64, // of the other portfolio symbols, seen by the guardrails
+    other_notional: f64,   // of the other portfolio symbols, counted by the entry caps
+    scaling: ScalingConfig,
+    timing: TimingConfig,
+    cooldown: usize, // bars left before the next entry
+    entry: EntryConfig,
+    resting: Option<RestingEntry>,
+}
+
+// Limit entries quoted at both bands, the first one to fill cancels the other
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RestingEntry {
+    buy: f64,
+    sell: f64,
+    expires: i64, // close time of the last kline the quote rests
+}
+
+// Strategy state besides the window, position and risk that a restart has to restore
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SwingState {
+    pub order_id: OrderId,
+    pub cooldown: usize,
+    pub resting: Option<RestingEntry>,
+}
+
+impl RestingEntry {
+    fn fill(&self, kline: &Kline, entry: &EntryConfig) -> Option<(TradeSide, f64)> {
+        let (buy, sell) = match entry.fill_rule {
+            FillRule::Touch => (kline.low <= self.buy, kline.high >= self.sell),
+            FillRule::TradeThrough => (
+                kline.low < self.buy * (1. - entry.trade_through),
+                kline.high > self.sell * (1. + entry.trade_through),
+            ),
+        };
+        // A kline that gaps past a quote fills it at the open, a kline crossing both
+        // fills the one nearer the open first
+        let buy_first = kline.open - self.buy <= self.sell - kline.open;
+        match (buy, sell) {
+            (true, false) => Some((TradeSide::Buy, self.buy.min(kline.open))),
+            (false, true) => Some((TradeSide::Sell, self.sell.max(kline.open))),
+            (true, true) if buy_first => Some((TradeSide::Buy, self.buy.min(kline.open))),
+            (true, true) => Some((TradeSide::Sell, self.sell.max(kline.open))),
+            (false, false) => None,
+        }
+    }
 }
 
 impl BBSwing {
@@ -36,122 +95,536 @@ impl BBSwing {
             fee_rate: config.fee_rate,
             leverage: config.leverage,
             entry_protion: config.entry_protion,
+            position: None,
+            order_id: 0,
+            risk: RiskGuard::new(&config.risk),
+            reported_halts: 0,
+            events: None,
+            allocation: 1.,
+            max_entry_notional: None,
+            other_unrealised: 0.,
+            other_notional: 0.,
+            scaling: config.scaling.clone(),
+            timing: config.timing.clone(),
+            cooldown: 0,
+            entry: config.entry.clone(),
+            resting: None,
         }
     }
 
+    // Fills the indicator window without trading, e.g. from history or a persisted state
+    pub fn warm_up(&mut self, klines: &[Kline]) {
+        for kline in klines {
+            self.klines.push_back(kline.clone());
+            let bb_band = utils::bollinger_band(DAYS, 2., &self.klines);
+            self.bb_bands.push_back(bb_band);
+            if self.klines.len() > DAYS {
+                self.bb_bands.pop_front();
+                self.klines.pop_front();
+            }
+        }
+    }
+
+    pub fn window(&self) -> Vec<Kline> {
+        self.klines.iter().cloned().collect()
+    }
+
+    pub fn position(&self) -> Option<&Position> {
+        self.position.as_ref()
+    }
+
+    // Restores a persisted position, or drops the current one when the exchange went flat
+    pub fn set_position(&mut self, position: Option<Position>) {
+        self.position = position;
+    }
+
+    // Starts collecting entry, exit and guardrail events for drain_events. Backtest loops
+    // never call it, so they don't keep a second copy of the trade ledger.
+    pub fn record_events(&mut self) {
+        self.events.get_or_insert_with(Vec::new);
+    }
+
+    // Events since the last call, for the notifier
+    pub fn drain_events(&mut self) -> Vec<Event> {
+        self.events.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn emit(&mut self, event: Event) {
+        if let Some(events) = self.events.as_mut() {
+            events.push(event);
+        }
+    }
+
+    pub fn state(&self) -> SwingState {
+        SwingState {
+            order_id: self.order_id,
+            cooldown: self.cooldown,
+            resting: self.resting.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, state: SwingState) {
+        self.order_id = state.order_id;
+        self.cooldown = state.cooldown;
+        self.resting = state.resting;
+    }
+
+    pub fn risk_state(&self) -> Option<&RiskState> {
+        self.risk.state()
+    }
+
+    pub fn set_risk_state(&mut self, state: Option<RiskState>) {
+        self.risk.set_state(state);
+        self.reported_halts = self.risk.halts();
+    }
+
+    // Portfolio mode: scales entries by the symbol's capital share and caps their notional
+    pub fn set_allocation(&mut self, allocation: f64, max_entry_notional: Option<f64>) {
+        self.allocation = allocation;
+        self.max_entry_notional = max_entry_notional;
+    }
+
+    // Portfolio mode: the guardrails measure the portfolio's marked equity, the shared
+    // balance plus the unrealised profit of every symbol, and the risk caps on entry
+    // sizes count the notional of every symbol
+    pub fn set_other_positions(&mut self, other_unrealised: f64, other_notional: f64) {
+        self.other_unrealised = other_unrealised;
+        self.other_notional = other_notional;
+    }
+
     pub fn strategy(&mut self, metric: &mut BacktestMetric, kline: &Kline) {
         self.klines.push_back(kline.clone());
         let bb_band = utils::bollinger_band(DAYS, 2., &self.klines);
         self.bb_bands.push_back(bb_band);
         if self.klines.len() > DAYS {
             let index = self.klines.len() - 1;
-            let curr_kline = &self.klines[index];
+            let curr_kline = self.klines[index].clone();
             let curr_price = (curr_kline.high + curr_kline.low) / 2.;
-            let prev_kline = &self.klines[index - 1];
-            let prev_bb_band = self.bb_bands[index - 1].as_ref().unwrap();
-            if metric.entry_side == TradeSide::None {
-                let entry_size = if self.strategy_type == StrategyType::Single {
-                    self.initial_captial.min(metric.usd_balance) * self.entry_protion / curr_price
-                } else {
-                    metric.usd_balance * self.entry_protion / curr_price
-                };
-
-                if let Some(order) = prev_bb_band_entry(prev_kline, prev_bb_band, entry_size) {
-                    metric.position = order.position;
-                    metric.entry_price = curr_price;
-                    metric.entry_side = order.side;
-                    let fee = calculate_fee(
-                        self.fee_rate,
-                        metric.entry_price,
-                        metric.position,
-                        self.leverage,
-                    );
-                    metric.take_profit_price = metric.entry_price
-                        * (1. + self.take_profit_percentage * metric.entry_side.value());
-                    metric.stop_loss_price = metric.entry_price
-                        * (1. - self.stop_loss_percentage * metric.entry_side.value());
-                    metric.total_fee += fee;
-                    metric.usd_balance -= fee;
+            match self.position.take() {
+                None if self.risk.is_halted() => self.resting = None,
+                None if self.cooldown > 0 => {
+                    self.cooldown -= 1;
+                    self.resting = None;
                 }
-            } else if metric.entry_side == TradeSide::Buy {
-                // Assume we will keep tracking the price, not just tracking 15m kline
-                let exit_price = if curr_kline.low <= metric.stop_loss_price {
-                    Some(metric.stop_loss_price)
-                } else if curr_kline.high >= metric.take_profit_price {
-                    Some(metric.take_profit_price)
-                } else {
-                    None
-                };
-                if exit_price.is_some() {
-                    // Calculate profit
-                    let fee = calculate_fee(
-                        self.fee_rate,
-                        exit_price.unwrap(),
-                        metric.position,
-                        self.leverage,
-                    );
-                    let profit = (exit_price.unwrap() - metric.entry_price)
-                        * metric.position
-                        * self.leverage as f64;
-                    metric.usd_balance -= fee;
-                    metric.usd_balance += profit;
-                    metric.total_fee += fee;
-                    metric.total_profit += profit;
-                    if profit - fee >= 0. {
-                        metric.win += 1;
-                    } else {
-                        metric.lose += 1;
-                    }
-                    metric.max_usd = metric.max_usd.max(metric.usd_balance);
-                    metric.min_usd = metric.min_usd.min(metric.usd_balance);
-                    metric.profit = profit;
-                    metric.fee = fee;
-                    metric.exit_price = exit_price.unwrap();
-                    trade_log(&metric, &self.klines[index]);
-                    init_trade(metric);
+                None if self.timing.blocks_entry(curr_kline.open_time) => self.resting = None,
+                None if self.entry.order_type == EntryOrderType::Limit => {
+                    self.limit_entry(metric, index, &curr_kline);
                 }
-            } else if metric.entry_side == TradeSide::Sell {
-                // Assume we will keep tracking the price, not just tracking 15m kline
-                let exit_price = if curr_kline.high >= metric.stop_loss_price {
-                    Some(metric.stop_loss_price)
-                } else if curr_kline.low <= metric.take_profit_price {
-                    Some(metric.take_profit_price)
-                } else {
-                    None
-                };
-                if exit_price.is_some() {
-                    // Calculate profit
-                    let fee = calculate_fee(
-                        self.fee_rate,
-                        exit_price.unwrap(),
-                        metric.position,
-                        self.leverage,
-                    );
-                    let profit = (metric.entry_price - exit_price.unwrap())
-                        * metric.position
-                        * self.leverage as f64;
-                    metric.usd_balance -= fee;
-                    metric.usd_balance += profit;
-                    metric.total_fee += fee;
-                    metric.total_profit += profit;
-                    if profit - fee >= 0. {
-                        metric.win += 1;
-                    } else {
-                        metric.lose += 1;
+                None => {
+                    let entry_size = self.entry_size(metric, curr_price, 0.);
+                    let prev_kline = &self.klines[index - 1];
+                    let prev_bb_band = self.bb_bands[index - 1].as_ref().unwrap();
+                    if let Some(order) = prev_bb_band_entry(prev_kline, prev_bb_band, entry_size) {
+                        if order.position > 0. {
+                            self.position = Some(self.open(
+                                metric,
+                                order,
+                                OrderType::Market,
+                                curr_price,
+                                &curr_kline,
+                            ));
+                        }
                     }
-                    metric.max_usd = metric.max_usd.max(metric.usd_balance);
-                    metric.min_usd = metric.min_usd.min(metric.usd_balance);
-                    metric.profit = profit;
-                    metric.fee = fee;
-                    metric.exit_price = exit_price.unwrap();
-                    trade_log(&metric, &self.klines[index]);
-                    init_trade(metric);
                 }
+                Some(mut position) => match position.triggered_bracket(&curr_kline) {
+                    Some(Bracket::TakeProfit) if self.is_partial_exit(&position) => {
+                        self.take_partial_profit(metric, &mut position, &curr_kline);
+                        self.position = Some(position);
+                    }
+                    Some(bracket) => self.close(metric, &mut position, bracket, &curr_kline),
+                    None if self.is_stale(&position, &curr_kline) => {
+                        info!(
+                            "closing position held {} bars at max_holding_bars",
+                            (curr_kline.close_time - position.entry_time) / KLINE_INTERVAL_MS
+                        );
+                        self.flatten(metric, &mut position, &curr_kline);
+                    }
+                    None => {
+                        self.scale_in(metric, &mut position, index, curr_price, &curr_kline);
+                        self.position = Some(position);
+                    }
+                },
             }
+            self.check_risk(metric, &curr_kline);
             self.bb_bands.pop_front();
             self.klines.pop_front();
         }
     }
+
+    // Size of a fresh entry, or of an add to a position of held size, within the risk
+    // and portfolio limits
+    fn entry_size(&self, metric: &BacktestMetric, curr_price: f64, held: f64) -> f64 {
+        let entry_size = if self.strategy_type == StrategyType::Single {
+            self.initial_captial.min(metric.usd_balance) * self.entry_protion / curr_price
+        } else {
+            metric.usd_balance * self.entry_protion / curr_price
+        };
+        let scale = if held > 0. { self.scaling.add_size } else { 1. };
+        let mut size = self.risk.cap_entry_size(
+            held + entry_size * scale * self.allocation,
+            curr_price,
+            self.leverage,
+            metric.usd_balance,
+            self.other_notional,
+        );
+        if let Some(max_entry_notional) = self.max_entry_notional {
+            size = size.min(max_entry_notional.max(0.) / (curr_price * self.leverage as f64));
+        }
+        size - held
+    }
+
+    // Fills the quote resting from earlier klines, otherwise keeps it until it expires and
+    // then quotes again at the bands of the current kline
+    fn limit_entry(&mut self, metric: &mut BacktestMetric, index: usize, curr_kline: &Kline) {
+        if let Some(resting) = self.resting.take() {
+            if let Some((side, price)) = resting.fill(curr_kline, &self.entry) {
+                let position = self.entry_size(metric, price, 0.);
+                if position > 0. {
+                    metric.limit_fills += 1;
+                    let order = OrderSpec { position, side };
+                    let order_type = OrderType::Limit { price };
+                    self.position = Some(self.open(metric, order, order_type, price, curr_kline));
+                    return;
+                }
+            }
+            if curr_kline.close_time < resting.expires {
+                self.resting = Some(resting);
+                return;
+            }
+        }
+        let bb_band = self.bb_bands[index].as_ref().unwrap();
+        self.resting = Some(RestingEntry {
+            buy: bb_band.down * (1. - self.entry.offset),
+            sell: bb_band.up * (1. + self.entry.offset),
+            expires: curr_kline.close_time
+                + self.entry.expiry_bars.max(1) as i64 * KLINE_INTERVAL_MS,
+        });
+        metric.limit_quotes += 1;
+    }
+
+    fn is_stale(&self, position: &Position, curr_kline: &Kline) -> bool {
+        self.timing.max_holding_bars > 0
+            && curr_kline.close_time - position.entry_time
+                >= self.timing.max_holding_bars as i64 * KLINE_INTERVAL_MS
+    }
+
+    fn take_profit_percentage(&self, level: usize) -> f64 {
+        self.scaling
+            .take_profits
+            .get(level)
+            .map_or(self.take_profit_percentage, |level| level.percentage)
+    }
+
+    fn is_last_level(&self, position: &Position) -> bool {
+        position.partial_exits.len() + 1 >= self.scaling.take_profits.len()
+    }
+
+    // A take profit level with a fraction of 1 or more closes everything, like the last one
+    fn is_partial_exit(&self, position: &Position) -> bool {
+        !self.is_last_level(position) && !position.closes_all(self.partial_size(position))
+    }
+
+    fn partial_size(&self, position: &Position) -> f64 {
+        let level = position.partial_exits.len();
+        position.size * self.scaling.take_profits[level].fraction.min(1.)
+    }
+
+    // Adds to a position while the previous close keeps moving beyond the band, until
+    // max_entries or the first partial take profit
+    fn scale_in(
+        &mut self,
+        metric: &mut BacktestMetric,
+        position: &mut Position,
+        index: usize,
+        curr_price: f64,
+        curr_kline: &Kline,
+    ) {
+        if position.scale_ins.len() + 1 >= self.scaling.max_entries
+            || !position.partial_exits.is_empty()
+            || self.risk.is_halted()
+            || self.timing.blocks_entry(curr_kline.open_time)
+        {
+            return;
+        }
+        let Some(last_fill) = position
+            .fills
+            .iter()
+            .rev()
+            .find(|fill| matches!(fill.kind, FillKind::Entry | FillKind::ScaleIn))
+        else {
+            return;
+        };
+        let side = position.side.value();
+        let prev_close = self.klines[index - 1].close;
+        let Some(prev_bb_band) = self.bb_bands[index - 1].as_ref() else {
+            return;
+        };
+        let beyond_band = if side > 0. {
+            prev_close < prev_bb_band.down
+        } else {
+            prev_close > prev_bb_band.up
+        };
+        if !beyond_band
+            || (last_fill.price - prev_close) * side < last_fill.price * self.scaling.add_step
+        {
+            return;
+        }
+        let size = self.entry_size(metric, curr_price, position.size);
+        if size <= 0. {
+            return;
+        }
+        let mut order = Order::new(
+            self.next_order_id(),
+            OrderRequest {
+                side: position.side.clone(),
+                order_type: OrderType::Market,
+                size,
+                reduce_only: false,
+            },
+        );
+        let fee = calculate_fee(self.fee_rate, curr_price, size, self.leverage);
+        order.fill(size, curr_price, fee).unwrap();
+        let entry_price =
+            (position.entry_price * position.size + curr_price * size) / (position.size + size);
+        let order_ids = [self.next_order_id(), self.next_order_id()];
+        position
+            .scale_in(
+                order,
+                curr_kline.close_time,
+                order_ids,
+                entry_price * (1. - self.stop_loss_percentage * side),
+                entry_price * (1. + self.take_profit_percentage(0) * side),
+            )
+            .unwrap();
+        metric.total_fee += fee;
+        metric.usd_balance -= fee;
+        self.emit(Event::ScaleIn {
+            time: curr_kline.close_time,
+            side: position.side.clone(),
+            size,
+            price: curr_price,
+            entry_price: position.entry_price,
+            stop_loss: position.bracket_price(Bracket::StopLoss),
+            take_profit: position.bracket_price(Bracket::TakeProfit),
+        });
+    }
+
+    // Closes the level's fraction of the size and moves the take profit to the next level
+    fn take_partial_profit(
+        &mut self,
+        metric: &mut BacktestMetric,
+        position: &mut Position,
+        curr_kline: &Kline,
+    ) {
+        let level = position.partial_exits.len();
+        let size = self.partial_size(position);
+        let exit_price = position.bracket_price(Bracket::TakeProfit);
+        let fee = calculate_fee(self.fee_rate, exit_price, size, self.leverage);
+        let side = position.side.value();
+        let order_ids = [self.next_order_id(), self.next_order_id()];
+        let next_price =
+            position.entry_price * (1. + self.take_profit_percentage(level + 1) * side);
+        position
+            .take_partial_profit(size, fee, curr_kline.close_time, order_ids, next_price)
+            .unwrap();
+        let profit = (exit_price - position.entry_price) * size * side * self.leverage as f64;
+        metric.usd_balance += profit - fee;
+        metric.total_fee += fee;
+        metric.total_profit += profit;
+        metric.max_usd = metric.max_usd.max(metric.usd_balance);
+        metric.min_usd = metric.min_usd.min(metric.usd_balance);
+        self.emit(Event::PartialExit {
+            time: curr_kline.close_time,
+            side: position.side.clone(),
+            size,
+            price: exit_price,
+            profit,
+            fee,
+            remaining: position.size,
+        });
+    }
+
+    fn check_risk(&mut self, metric: &mut BacktestMetric, curr_kline: &Kline) {
+        let unrealised = self.position.as_ref().map_or(0., |position| {
+            (curr_kline.close - position.entry_price)
+                * position.size
+                * position.side.value()
+                * self.leverage as f64
+        });
+        let equity = metric.usd_balance + unrealised + self.other_unrealised;
+        self.risk.roll_period(curr_kline.close_time, equity);
+        self.risk.check_equity(equity);
+        // The metric may be shared with other symbols, so only this guard's new halts count
+        if self.risk.halts() > self.reported_halts {
+            metric.risk_halts += self.risk.halts() - self.reported_halts;
+            self.reported_halts = self.risk.halts();
+            self.emit(Event::Guardrail {
+                time: curr_kline.close_time,
+                reason: self.risk.halt_reason().unwrap_or_default(),
+                action: format!("{:?}", self.risk.action()),
+            });
+        }
+        if self.risk.flatten_on_halt() {
+            if let Some(mut position) = self.position.take() {
+                self.flatten(metric, &mut position, curr_kline);
+            }
+        }
+    }
+
+    fn next_order_id(&mut self) -> OrderId {
+        self.order_id += 1;
+        self.order_id
+    }
+
+    fn open(
+        &mut self,
+        metric: &mut BacktestMetric,
+        order: OrderSpec,
+        order_type: OrderType,
+        entry_price: f64,
+        curr_kline: &Kline,
+    ) -> Position {
+        let side = order.side.value();
+        // Limit fills add liquidity and pay the maker fee
+        let fee_rate = match order_type {
+            OrderType::Limit { .. } => self.entry.maker_fee_rate,
+            _ => self.fee_rate,
+        };
+        let mut entry_order = Order::new(
+            self.next_order_id(),
+            OrderRequest {
+                side: order.side,
+                order_type,
+                size: order.position,
+                reduce_only: false,
+            },
+        );
+        let fee = calculate_fee(fee_rate, entry_price, order.position, self.leverage);
+        entry_order.fill(order.position, entry_price, fee).unwrap();
+        let position = Position::open(
+            entry_order,
+            curr_kline.close_time,
+            metric.usd_balance,
+            self.next_order_id(),
+            entry_price * (1. - self.stop_loss_percentage * side),
+            self.next_order_id(),
+            entry_price * (1. + self.take_profit_percentage * side),
+        )
+        .unwrap();
+        metric.total_fee += fee;
+        metric.usd_balance -= fee;
+        self.emit(Event::Entry {
+            time: curr_kline.close_time,
+            side: position.side.clone(),
+            size: position.size,
+            price: position.entry_price,
+            stop_loss: position.bracket_price(Bracket::StopLoss),
+            take_profit: position.bracket_price(Bracket::TakeProfit),
+        });
+        position
+    }
+
+    fn close(
+        &mut self,
+        metric: &mut BacktestMetric,
+        position: &mut Position,
+        bracket: Bracket,
+        curr_kline: &Kline,
+    ) {
+        let exit_price = position.bracket_price(bracket);
+        let fee = calculate_fee(self.fee_rate, exit_price, position.size, self.leverage);
+        position.close(bracket, fee, curr_kline.close_time).unwrap();
+        let stop_out = bracket == Bracket::StopLoss;
+        self.settle(metric, position, exit_price, fee, stop_out, curr_kline);
+    }
+
+    // Market exit at the kline close
+    fn flatten(
+        &mut self,
+        metric: &mut BacktestMetric,
+        position: &mut Position,
+        curr_kline: &Kline,
+    ) {
+        let exit_price = curr_kline.close;
+        let fee = calculate_fee(self.fee_rate, exit_price, position.size, self.leverage);
+        let exit_order = Order::new(
+            self.next_order_id(),
+            OrderRequest {
+                side: position.side.opposite(),
+                order_type: OrderType::Market,
+                size: position.size,
+                reduce_only: true,
+            },
+        );
+        position
+            .flatten(exit_order, exit_price, fee, curr_kline.close_time)
+            .unwrap();
+        self.settle(metric, position, exit_price, fee, false, curr_kline);
+    }
+
+    fn settle(
+        &mut self,
+        metric: &mut BacktestMetric,
+        position: &Position,
+        exit_price: f64,
+        fee: f64,
+        stop_out: bool,
+        curr_kline: &Kline,
+    ) {
+        // Calculate profit
+        let profit = (exit_price - position.entry_price)
+            * position.size
+            * position.side.value()
+            * self.leverage as f64;
+        // Partial take profits were booked when they filled, the trade covers all of it
+        let (partial_profit, partial_fee) =
+            position
+                .partial_exits
+                .iter()
+                .fold((0., 0.), |(profit, fee), order| {
+                    (
+                        profit
+                            + (order.avg_fill_price - position.entry_price)
+                                * order.filled_size
+                                * position.side.value()
+                                * self.leverage as f64,
+                        fee + order.fee,
+                    )
+                });
+        metric.usd_balance -= fee;
+        metric.usd_balance += profit;
+        metric.total_fee += fee;
+        metric.total_profit += profit;
+        let (trade_profit, trade_fee) = (partial_profit + profit, partial_fee + fee);
+        if trade_profit - trade_fee >= 0. {
+            metric.win += 1;
+        } else {
+            metric.lose += 1;
+            self.cooldown = self.timing.cooldown_bars;
+        }
+        metric.max_usd = metric.max_usd.max(metric.usd_balance);
+        metric.min_usd = metric.min_usd.min(metric.usd_balance);
+        let trade = TradeLog {
+            entry_side: position.side.value() as i64,
+            entry_price: position.entry_price,
+            entry_size: position.max_size(),
+            exit_price: position.exit_price().unwrap_or(exit_price),
+            entry_time: position.entry_time,
+            exit_time: curr_kline.close_time,
+            entry_balance: position.entry_balance,
+            profit: trade_profit,
+            fee: position.total_fee(),
+            fills: position.fills.clone(),
+        };
+        trade_log(metric, position, &trade, fee);
+        self.emit(if stop_out {
+            Event::StopOut(trade.clone())
+        } else {
+            Event::Exit(trade.clone())
+        });
+        metric.trades.push(trade);
+        self.risk.record_trade(trade_profit - trade_fee);
+    }
 }
 
 fn prev_bb_band_entry(
@@ -173,23 +646,212 @@ fn prev_bb_band_entry(
     }
 }
 
-fn trade_log(metric: &BacktestMetric, curr_kline: &Kline) {
-    let curr_date = NaiveDateTime::from_timestamp_millis(curr_kline.close_time).unwrap();
+fn trade_log(metric: &BacktestMetric, position: &Position, trade: &TradeLog, exit_fee: f64) {
+    let curr_date = DateTime::from_timestamp_millis(trade.exit_time)
+        .unwrap()
+        .naive_utc();
     let mut msg = "".to_string();
     msg += &format!("date: {:?}, ", curr_date);
     msg += &format!("win: {:?}, ", metric.win);
     msg += &format!("lose: {:?}, ", metric.lose);
     msg += &format!("usd_balance: {:.4}, ", metric.usd_balance);
-    msg += &format!("position: {:.4}, ", metric.position);
-    msg += &format!("entry_side: {:?}, ", metric.entry_side);
-    msg += &format!("entry_price: {:.4}, ", metric.entry_price);
-    msg += &format!("exit_price: {:.4}, ", metric.exit_price);
-    msg += &format!("profit: {:.4}, ", metric.profit);
-    msg += &format!("fee: {:.4}, ", metric.fee);
-
-    if metric.profit >= 0. {
+    msg += &format!("position: {:.4}, ", position.size);
+    msg += &format!("entry_side: {:?}, ", position.side);
+    msg += &format!("entry_price: {:.4}, ", position.entry_price);
+    msg += &format!("exit_price: {:.4}, ", trade.exit_price);
+    msg += &format!("profit: {:.4}, ", trade.profit);
+    msg += &format!("fee: {:.4}, ", exit_fee);
+
+    if trade.profit >= 0. {
         info!("{}", msg);
     } else {
         warn!("{}", msg);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        backtest::backtest,
+        test_utils::{config, klines},
+    };
+
+    #[test]
+    fn take_profit_fraction_must_be_positive() {
+        for fraction in [0., -0.5] {
+            let config = json!({"take_profits": [{"percentage": 0.01, "fraction": fraction}]});
+            assert!(serde_json::from_value::<ScalingConfig>(config).is_err());
+        }
+    }
+
+    #[test]
+    fn full_fraction_closes_the_position() {
+        let klines = klines(2000);
+        let plain = backtest(&config(json!({})), &klines);
+        let scaled = backtest(
+            &config(json!({"scaling": {"take_profits": [
+                {"percentage": 0.01, "fraction": 1.},
+                {"percentage": 0.02, "fraction": 1.},
+            ]}})),
+            &klines,
+        );
+        assert!(!plain.trades.is_empty());
+        assert_eq!(scaled.trades.len(), plain.trades.len());
+        assert!((scaled.usd_balance - plain.usd_balance).abs() < 1e-6);
+        assert!(scaled.usd_balance.is_finite());
+    }
+
+    fn bar(open: f64, high: f64, low: f64) -> Kline {
+        Kline {
+            open_time: 0,
+            close_time: KLINE_INTERVAL_MS - 1,
+            open,
+            high,
+            low,
+            close: open,
+            ..Default::default()
+        }
+    }
+
+    fn entry(fill_rule: FillRule) -> EntryConfig {
+        EntryConfig {
+            order_type: EntryOrderType::Limit,
+            fill_rule,
+            trade_through: 0.001,
+            ..Default::default()
+        }
+    }
+
+    const QUOTE: RestingEntry = RestingEntry {
+        buy: 100.,
+        sell: 110.,
+        expires: 0,
+    };
+
+    #[test]
+    fn touch_fills_at_the_limit() {
+        let touch = entry(FillRule::Touch);
+        assert_eq!(
+            QUOTE.fill(&bar(105., 106., 100.), &touch),
+            Some((TradeSide::Buy, 100.))
+        );
+        assert_eq!(
+            QUOTE.fill(&bar(105., 110., 104.), &touch),
+            Some((TradeSide::Sell, 110.))
+        );
+        assert_eq!(QUOTE.fill(&bar(105., 109.9, 100.1), &touch), None);
+    }
+
+    #[test]
+    fn trade_through_needs_the_price_past_the_limit() {
+        let trade_through = entry(FillRule::TradeThrough);
+        assert_eq!(QUOTE.fill(&bar(105., 106., 100.), &trade_through), None);
+        assert_eq!(QUOTE.fill(&bar(105., 106., 99.9), &trade_through), None);
+        assert_eq!(
+            QUOTE.fill(&bar(105., 106., 99.8), &trade_through),
+            Some((TradeSide::Buy, 100.))
+        );
+        assert_eq!(QUOTE.fill(&bar(105., 110.1, 104.), &trade_through), None);
+        assert_eq!(
+            QUOTE.fill(&bar(105., 110.2, 104.), &trade_through),
+            Some((TradeSide::Sell, 110.))
+        );
+    }
+
+    #[test]
+    fn gaps_fill_at_the_open() {
+        let touch = entry(FillRule::Touch);
+        assert_eq!(
+            QUOTE.fill(&bar(95., 97., 94.), &touch),
+            Some((TradeSide::Buy, 95.))
+        );
+        assert_eq!(
+            QUOTE.fill(&bar(115., 116., 112.), &touch),
+            Some((TradeSide::Sell, 115.))
+        );
+        // Both sides crossed, the one nearer the open fills
+        assert_eq!(
+            QUOTE.fill(&bar(108., 111., 99.), &touch),
+            Some((TradeSide::Sell, 110.))
+        );
+        assert_eq!(
+            QUOTE.fill(&bar(102., 111., 99.), &touch),
+            Some((TradeSide::Buy, 100.))
+        );
+    }
+
+    // expires is a close time, so a data gap counts the missing klines towards the expiry
+    #[test]
+    fn quote_expires_by_close_time_across_gaps() {
+        let config = config(json!({"entry": {"order_type": "Limit", "expiry_bars": 4}}));
+        let klines = klines(2000);
+        let (history, rest) = klines.split_at(DAYS);
+        let last = history.last().unwrap();
+        let far = RestingEntry {
+            buy: 1.,
+            sell: 1e9,
+            expires: last.close_time + 4 * KLINE_INTERVAL_MS,
+        };
+        let restored = |far: &RestingEntry| {
+            let mut swing = BBSwing::new(&config);
+            swing.warm_up(history);
+            swing.restore(SwingState {
+                order_id: 0,
+                cooldown: 0,
+                resting: Some(far.clone()),
+            });
+            swing
+        };
+
+        // Two klines missing, the next one closes before the quote expires
+        let mut swing = restored(&far);
+        let mut metric = BacktestMetric::new(&config);
+        swing.strategy(&mut metric, &rest[2]);
+        assert_eq!(swing.state().resting.unwrap().expires, far.expires);
+        assert_eq!(metric.limit_quotes, 0);
+
+        // Three or more missing, the first kline after the gap is the quote's last one and
+        // quotes again at its bands
+        for next in [3, 6] {
+            let mut swing = restored(&far);
+            let mut metric = BacktestMetric::new(&config);
+            swing.strategy(&mut metric, &rest[next]);
+            let requoted = swing.state().resting.unwrap();
+            assert_eq!(
+                requoted.expires,
+                rest[next].close_time + 4 * KLINE_INTERVAL_MS
+            );
+            assert!(requoted.buy > 1. && requoted.sell < 1e9);
+            assert_eq!(metric.limit_quotes, 1);
+        }
+    }
+
+    #[test]
+    fn swing_state_survives_a_restart() {
+        let config = config(json!({"entry": {"order_type": "Limit"}}));
+        let klines = klines(2000);
+        let mut swing = BBSwing::new(&config);
+        let mut metric = BacktestMetric::new(&config);
+        for kline in &klines[..DAYS + 1] {
+            swing.strategy(&mut metric, kline);
+        }
+        let state: SwingState =
+            serde_json::from_str(&serde_json::to_string(&swing.state()).unwrap()).unwrap();
+        assert!(state.resting.is_some());
+
+        let mut restarted = BBSwing::new(&config);
+        restarted.warm_up(&swing.window());
+        restarted.restore(state);
+        let mut restarted_metric = metric.clone();
+        for kline in &klines[DAYS + 1..] {
+            swing.strategy(&mut metric, kline);
+            restarted.strategy(&mut restarted_metric, kline);
+        }
+        assert!(metric.limit_fills > 0);
+        assert_eq!(restarted_metric.limit_fills, metric.limit_fills);
+        assert_eq!(restarted_metric.usd_balance, metric.usd_balance);
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+use crate::types::Kline;
+
+// Binance kline stream message, e.g. wss://fstream.binance.com/ws/btcusdt@kline_15m
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BinanceKlineEvent {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: i64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "k")]
+    pub kline: BinanceKline,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BinanceKline {
+    #[serde(rename = "t")]
+    pub open_time: i64,
+    #[serde(rename = "T")]
+    pub close_time: i64,
+    #[serde(rename = "i")]
+    pub interval: String,
+    #[serde(rename = "o")]
+    pub open: String,
+    #[serde(rename = "c")]
+    pub close: String,
+    #[serde(rename = "h")]
+    pub high: String,
+    #[serde(rename = "l")]
+    pub low: String,
+    #[serde(rename = "v")]
+    pub volume: String,
+    #[serde(rename = "n")]
+    pub trade_count: u64,
+    #[serde(rename = "x")]
+    pub is_closed: bool,
+    #[serde(rename = "q")]
+    pub quote_volume: String,
+    #[serde(rename = "V")]
+    pub taker_buy_volume: String,
+    #[serde(rename = "Q")]
+    pub taker_buy_quote_volume: String,
+}
+
+impl BinanceKline {
+    pub fn to_kline(&self) -> Result<Kline> {
+        Ok(Kline {
+            open_time: self.open_time,
+            close_time: self.close_time,
+            open: self.open.parse()?,
+            high: self.high.parse()?,
+            low: self.low.parse()?,
+            close: self.close.parse()?,
+            volume: self.volume.parse().ok(),
+            quote_volume: self.quote_volume.parse().ok(),
+            trade_count: Some(self.trade_count),
+            taker_buy_volume: self.taker_buy_volume.parse().ok(),
+            taker_buy_quote_volume: self.taker_buy_quote_volume.parse().ok(),
+        })
+    }
+
+    pub fn from_kline(kline: &Kline, interval: &str, is_closed: bool) -> Self {
+        BinanceKline {
+            open_time: kline.open_time,
+            close_time: kline.close_time,
+            interval: interval.to_string(),
+            open: kline.open.to_string(),
+            close: kline.close.to_string(),
+            high: kline.high.to_string(),
+            low: kline.low.to_string(),
+            volume: kline.volume.unwrap_or(0.).to_string(),
+            trade_count: kline.trade_count.unwrap_or(0),
+            is_closed,
+            quote_volume: kline.quote_volume.unwrap_or(0.).to_string(),
+            taker_buy_volume: kline.taker_buy_volume.unwrap_or(0.).to_string(),
+            taker_buy_quote_volume: kline.taker_buy_quote_volume.unwrap_or(0.).to_string(),
+        }
+    }
+}
+
+pub struct KlineStream {
+    url: String,
+    socket: Option<WebSocket<MaybeTlsStream<TcpStream>>>,
+}
+
+impl KlineStream {
+    pub fn new(url: &str) -> Self {
+        KlineStream {
+            url: url.to_string(),
+            socket: None,
+        }
+    }
+
+    // Blocks until the next closed kline, reconnecting whenever the connection drops
+    pub fn next_closed_kline(&mut self) -> Result<Kline> {
+        loop {
+            if self.socket.is_none() {
+                match tungstenite::connect(self.url.as_str()) {
+                    Ok((socket, _)) => {
+                        info!("connected to kline stream {}", self.url);
+                        self.socket = Some(socket);
+                    }
+                    Err(err) => {
+                        warn!("kline stream connect failed: {}, retrying", err);
+                        thread::sleep(Duration::from_secs(5));
+                        continue;
+                    }
+                }
+            }
+            let socket = self.socket.as_mut().unwrap();
+            match socket.read() {
+                Ok(Message::Text(text)) => {
+                    let event: BinanceKlineEvent = match serde_json::from_str(&text) {
+                        Ok(event) => event,
+                        Err(err) => {
+                            warn!("skipping unexpected stream message: {}", err);
+                            continue;
+                        }
+                    };
+                    if event.kline.is_closed {
+                        return event.kline.to_kline();
+                    }
+                }
+                Ok(Message::Close(_)) => {
+                    warn!("kline stream closed by server, reconnecting");
+                    self.socket = None;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!("kline stream error: {}, reconnecting", err);
+                    self.socket = None;
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        }
+    }
+}
+
+// Local WebSocket server replaying klines in Binance format, for testing paper mode offline.
+// Each kline is sent once as an open update and once closed, delay_ms apart.
+pub fn serve_replay(addr: &str, klines: &[Kline], interval: &str, delay_ms: u64) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("replay server listening on ws://{}", addr);
+    let (stream, peer) = listener.accept()?;
+    info!("replay client connected: {}", peer);
+    let mut socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        Err(err) => bail!("replay handshake failed: {}", err),
+    };
+    for kline in klines {
+        for is_closed in [false, true] {
+            let event = BinanceKlineEvent {
+                event_type: "kline".to_string(),
+                event_time: kline.close_time,
+                symbol: "BTCUSDT".to_string(),
+                kline: BinanceKline::from_kline(kline, interval, is_closed),
+            };
+            socket.send(Message::Text(serde_json::to_string(&event)?))?;
+            thread::sleep(Duration::from_millis(delay_ms));
+        }
+    }
+    socket.close(None)?;
+    // Drain until the client acknowledges the close
+    while socket.read().is_ok() {}
+    info!("replay finished: {} klines", klines.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    // Synthetic klines with every field the stream carries
+    fn klines(bars: usize) -> Vec<Kline> {
+        test_utils::klines(bars)
+            .into_iter()
+            .map(|kline| Kline {
+                trade_count: Some(42),
+                taker_buy_volume: kline.volume.map(|volume| volume / 2.),
+                taker_buy_quote_volume: kline.quote_volume.map(|volume| volume / 2.),
+                ..kline
+            })
+            .collect()
+    }
+
+    #[test]
+    fn binance_kline_round_trips() {
+        for kline in klines(3) {
+            let binance = BinanceKline::from_kline(&kline, "15m", true);
+            assert_eq!(binance.to_kline().unwrap(), kline);
+        }
+        // Fields the stream always sends come back even when the source had none
+        let kline = Kline {
+            open: 1.,
+            high: 2.,
+            low: 0.5,
+            close: 1.5,
+            ..Default::default()
+        };
+        let converted = BinanceKline::from_kline(&kline, "15m", false)
+            .to_kline()
+            .unwrap();
+        assert_eq!(converted.volume, Some(0.));
+        assert_eq!(converted.trade_count, Some(0));
+    }
+
+    #[test]
+    fn bad_prices_are_rejected() {
+        let mut binance = BinanceKline::from_kline(&klines(1)[0], "15m", true);
+        binance.close = "n/a".to_string();
+        assert!(binance.to_kline().is_err());
+    }
+
+    #[test]
+    fn replay_sends_closed_klines_in_order() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let klines = klines(5);
+        let server = {
+            let (addr, klines) = (addr.clone(), klines.clone());
+            thread::spawn(move || serve_replay(&addr, &klines, "15m", 0))
+        };
+        thread::sleep(Duration::from_millis(100));
+        let mut stream = KlineStream::new(&format!("ws://{}", addr));
+        for kline in &klines {
+            assert_eq!(&stream.next_closed_kline().unwrap(), kline);
+        }
+        drop(stream);
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,544 @@
+use anyhow::{bail, Result};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, Normal, Poisson, StandardNormal};
+
+use crate::{
+    types::{BbBandConfig, Kline, PriceProcess, Regime, Scenario, SyntheticConfig},
+    utils::datetime_to_ts_ms,
+    KLINE_INTERVAL_MS,
+};
+
+// Each bar is built from a few sub-steps so high/low are consistent with the path
+const SUB_STEPS: usize = 4;
+const BASE_VOLUME: f64 = 100.;
+
+struct BarShock {
+    drift: f64,
+    volatility: f64,
+    jump: f64,
+}
+
+// Synthetic replacement for utils::get_klines_from_db, starting at the config's from date
+pub fn get_synthetic_klines(
+    config: &BbBandConfig,
+    synthetic_config: &SyntheticConfig,
+) -> Result<Vec<Kline>> {
+    let from_ts_ms = datetime_to_ts_ms(config.from.0, config.from.1, config.from.2);
+    let to_ts_ms = datetime_to_ts_ms(config.to.0, config.to.1, config.to.2);
+    let bars = synthetic_config
+        .bars
+        .unwrap_or(((to_ts_ms - from_ts_ms) / KLINE_INTERVAL_MS).max(0) as usize);
+    generate_klines(synthetic_config, from_ts_ms, KLINE_INTERVAL_MS, bars)
+}
+
+pub fn generate_klines(
+    config: &SyntheticConfig,
+    start_ts: i64,
+    interval_ms: i64,
+    bars: usize,
+) -> Result<Vec<Kline>> {
+    validate(config)?;
+    let mut rng = ChaCha8Rng::seed_from_u64(config.seed);
+    let mut process = ProcessState::new(&config.process)?;
+    let mut klines = Vec::with_capacity(bars);
+    let mut prev_close = config.start_price;
+
+    for bar in 0..bars {
+        let mut shock = process.next_bar(&mut rng);
+        let mut open = prev_close;
+        for scenario in &config.scenarios {
+            apply_scenario(scenario, bar, &mut shock, &mut open);
+        }
+
+        let jump_step = rng.gen_range(0..SUB_STEPS);
+        let step_drift = shock.drift / SUB_STEPS as f64;
+        let step_volatility = shock.volatility / (SUB_STEPS as f64).sqrt();
+        let mut price = open;
+        let mut high = open;
+        let mut low = open;
+        for step in 0..SUB_STEPS {
+            let z: f64 = StandardNormal.sample(&mut rng);
+            let mut log_return = step_drift + step_volatility * z;
+            if step == jump_step {
+                log_return += shock.jump;
+            }
+            price *= log_return.exp();
+            high = high.max(price);
+            low = low.min(price);
+        }
+
+        let bar_return = (price / prev_close).ln();
+        process.observe(bar_return - shock.drift);
+
+        let open_time = start_ts + bar as i64 * interval_ms;
+        let bar_return = bar_return.abs();
+        let volume = BASE_VOLUME * (1. + bar_return / shock.volatility.max(1e-12));
+        klines.push(Kline {
+            open_time,
+            close_time: open_time + interval_ms - 1,
+            open,
+            high,
+            low,
+            close: price,
+            volume: Some(volume),
+            quote_volume: Some(volume * (open + price) / 2.),
+            ..Default::default()
+        });
+        prev_close = price;
+    }
+    Ok(klines)
+}
+
+// Rejects parameters the distributions or the price path cannot use
+fn validate(config: &SyntheticConfig) -> Result<()> {
+    if !config.start_price.is_finite() || config.start_price <= 0. {
+        bail!(
+            "synthetic start_price must be positive, got {}",
+            config.start_price
+        );
+    }
+    let (drifts, volatilities) = match &config.process {
+        PriceProcess::Gbm { drift, volatility } => (vec![*drift], vec![*volatility]),
+        PriceProcess::Garch {
+            drift,
+            omega,
+            alpha,
+            beta,
+        } => (vec![*drift], vec![*omega, *alpha, *beta]),
+        PriceProcess::JumpDiffusion {
+            drift,
+            volatility,
+            jump_intensity,
+            jump_mean,
+            jump_std,
+        } => (
+            vec![*drift, *jump_mean],
+            vec![*volatility, *jump_intensity, *jump_std],
+        ),
+        PriceProcess::RegimeSwitching {
+            regimes,
+            switch_probability,
+        } => {
+            if regimes.is_empty() {
+                bail!("regime switching needs at least one regime");
+            }
+            if !(0. ..=1.).contains(switch_probability) {
+                bail!(
+                    "regime switch_probability must be within 0 and 1, got {}",
+                    switch_probability
+                );
+            }
+            regimes
+                .iter()
+                .map(|regime| (regime.drift, regime.volatility))
+                .unzip()
+        }
+    };
+    if drifts.iter().any(|drift| !drift.is_finite()) {
+        bail!(
+            "synthetic process drifts must be finite: {:?}",
+            config.process
+        );
+    }
+    // Volatilities, GARCH coefficients, jump intensity and size
+    if volatilities
+        .iter()
+        .any(|value| !value.is_finite() || *value < 0.)
+    {
+        bail!(
+            "synthetic process volatilities must be finite and not negative: {:?}",
+            config.process
+        );
+    }
+    for scenario in &config.scenarios {
+        match *scenario {
+            Scenario::FlashCrash { depth, .. } if !(0. ..1.).contains(&depth) => {
+                bail!("flash crash depth must be within 0 and 1, got {}", depth);
+            }
+            Scenario::Gap { size, .. } if !size.is_finite() || size <= -1. => {
+                bail!("gap size must be above -1, got {}", size);
+            }
+            Scenario::Trend { drift, .. } if !drift.is_finite() => {
+                bail!("trend drift must be finite, got {}", drift);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn apply_scenario(scenario: &Scenario, bar: usize, shock: &mut BarShock, open: &mut f64) {
+    match *scenario {
+        Scenario::FlashCrash {
+            at_bar,
+            depth,
+            recovery_bars,
+        } => {
+            let crash = (1. - depth).ln();
+            if bar == at_bar {
+                shock.jump += crash;
+            } else if bar > at_bar && bar <= at_bar + recovery_bars {
+                shock.drift -= crash / recovery_bars as f64;
+            }
+        }
+        Scenario::Gap { at_bar, size } => {
+            if bar == at_bar {
+                *open *= 1. + size;
+            }
+        }
+        Scenario::Trend {
+            start_bar,
+            bars,
+            drift,
+        } => {
+            if bar >= start_bar && bar < start_bar + bars {
+                shock.drift += drift;
+            }
+        }
+    }
+}
+
+enum ProcessState<'a> {
+    Gbm {
+        drift: f64,
+        volatility: f64,
+    },
+    Garch {
+        drift: f64,
+        omega: f64,
+        alpha: f64,
+        beta: f64,
+        variance: f64,
+        prev_residual: f64,
+    },
+    JumpDiffusion {
+        drift: f64,
+        volatility: f64,
+        jumps: Poisson<f64>,
+        jump_size: Normal<f64>,
+    },
+    RegimeSwitching {
+        regimes: &'a [Regime],
+        switch_probability: f64,
+        current: usize,
+    },
+}
+
+impl<'a> ProcessState<'a> {
+    fn new(process: &'a PriceProcess) -> Result<Self> {
+        let state = match process {
+            PriceProcess::Gbm { drift, volatility } => ProcessState::Gbm {
+                drift: *drift,
+                volatility: *volatility,
+            },
+            PriceProcess::Garch {
+                drift,
+                omega,
+                alpha,
+                beta,
+            } => {
+                // Start from the unconditional variance when the process is stationary
+                let persistence = alpha + beta;
+                let variance = if persistence < 1. {
+                    omega / (1. - persistence)
+                } else {
+                    *omega
+                };
+                ProcessState::Garch {
+                    drift: *drift,
+                    omega: *omega,
+                    alpha: *alpha,
+                    beta: *beta,
+                    variance,
+                    prev_residual: 0.,
+                }
+            }
+            PriceProcess::JumpDiffusion {
+                drift,
+                volatility,
+                jump_intensity,
+                jump_mean,
+                jump_std,
+            } => ProcessState::JumpDiffusion {
+                drift: *drift,
+                volatility: *volatility,
+                jumps: Poisson::new(jump_intensity.max(f64::MIN_POSITIVE))?,
+                jump_size: Normal::new(*jump_mean, *jump_std)?,
+            },
+            PriceProcess::RegimeSwitching {
+                regimes,
+                switch_probability,
+            } => ProcessState::RegimeSwitching {
+                regimes,
+                switch_probability: *switch_probability,
+                current: 0,
+            },
+        };
+        Ok(state)
+    }
+
+    // Feeds the realised residual back, so shocks from scenarios also cluster volatility
+    fn observe(&mut self, residual: f64) {
+        if let ProcessState::Garch { prev_residual, .. } = self {
+            *prev_residual = residual;
+        }
+    }
+
+    fn next_bar(&mut self, rng: &mut ChaCha8Rng) -> BarShock {
+        match self {
+            ProcessState::Gbm { drift, volatility } => BarShock {
+                drift: *drift,
+                volatility: *volatility,
+                jump: 0.,
+            },
+            ProcessState::Garch {
+                drift,
+                omega,
+                alpha,
+                beta,
+                variance,
+                prev_residual,
+            } => {
+                *variance = *omega + *alpha * *prev_residual * *prev_residual + *beta * *variance;
+                BarShock {
+                    drift: *drift,
+                    volatility: variance.sqrt(),
+                    jump: 0.,
+                }
+            }
+            ProcessState::JumpDiffusion {
+                drift,
+                volatility,
+                jumps,
+                jump_size,
+            } => {
+                let count = jumps.sample(rng) as usize;
+                let jump = (0..count).map(|_| jump_size.sample(rng)).sum();
+                BarShock {
+                    drift: *drift,
+                    volatility: *volatility,
+                    jump,
+                }
+            }
+            ProcessState::RegimeSwitching {
+                regimes,
+                switch_probability,
+                current,
+            } => {
+                if regimes.len() > 1 && rng.gen_bool(*switch_probability) {
+                    let offset = rng.gen_range(1..regimes.len());
+                    *current = (*current + offset) % regimes.len();
+                }
+                let regime = &regimes[*current];
+                BarShock {
+                    drift: regime.drift,
+                    volatility: regime.volatility,
+                    jump: 0.,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: i64 = 60_000;
+
+    fn config(process: PriceProcess, scenarios: Vec<Scenario>) -> SyntheticConfig {
+        SyntheticConfig {
+            seed: 7,
+            start_price: 100.,
+            bars: None,
+            process,
+            scenarios,
+        }
+    }
+
+    fn flat() -> PriceProcess {
+        PriceProcess::Gbm {
+            drift: 0.,
+            volatility: 0.,
+        }
+    }
+
+    fn processes() -> Vec<PriceProcess> {
+        vec![
+            PriceProcess::Gbm {
+                drift: 0.0001,
+                volatility: 0.01,
+            },
+            PriceProcess::Garch {
+                drift: 0.,
+                omega: 0.00001,
+                alpha: 0.1,
+                beta: 0.85,
+            },
+            PriceProcess::JumpDiffusion {
+                drift: 0.,
+                volatility: 0.005,
+                jump_intensity: 0.05,
+                jump_mean: -0.01,
+                jump_std: 0.02,
+            },
+            PriceProcess::RegimeSwitching {
+                regimes: vec![
+                    Regime {
+                        drift: 0.001,
+                        volatility: 0.005,
+                    },
+                    Regime {
+                        drift: -0.001,
+                        volatility: 0.02,
+                    },
+                ],
+                switch_probability: 0.02,
+            },
+        ]
+    }
+
+    fn close_to(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9 * b.abs().max(1.)
+    }
+
+    #[test]
+    fn same_seed_gives_same_klines() {
+        for process in processes() {
+            let config = config(process, Vec::new());
+            let first = generate_klines(&config, 0, INTERVAL, 500).unwrap();
+            let second = generate_klines(&config, 0, INTERVAL, 500).unwrap();
+            let closes =
+                |klines: &[Kline]| klines.iter().map(|kline| kline.close).collect::<Vec<_>>();
+            assert_eq!(closes(&first), closes(&second));
+
+            let mut other_seed = config.clone();
+            other_seed.seed += 1;
+            let third = generate_klines(&other_seed, 0, INTERVAL, 500).unwrap();
+            assert_ne!(closes(&first), closes(&third));
+        }
+    }
+
+    #[test]
+    fn ohlc_invariants_hold() {
+        let scenarios = vec![
+            Scenario::FlashCrash {
+                at_bar: 100,
+                depth: 0.3,
+                recovery_bars: 20,
+            },
+            Scenario::Gap {
+                at_bar: 200,
+                size: -0.05,
+            },
+        ];
+        for process in processes() {
+            let config = config(process, scenarios.clone());
+            let klines = generate_klines(&config, 0, INTERVAL, 1000).unwrap();
+            assert_eq!(klines.len(), 1000);
+            for (bar, kline) in klines.iter().enumerate() {
+                assert_eq!(kline.open_time, bar as i64 * INTERVAL);
+                assert_eq!(kline.close_time, kline.open_time + INTERVAL - 1);
+                assert!(kline.low > 0.);
+                assert!(kline.high >= kline.open.max(kline.close));
+                assert!(kline.low <= kline.open.min(kline.close));
+                assert!(kline.volume.unwrap() > 0.);
+            }
+        }
+    }
+
+    #[test]
+    fn scenarios_land_at_their_bars() {
+        let scenarios = vec![
+            Scenario::Gap {
+                at_bar: 10,
+                size: -0.05,
+            },
+            Scenario::FlashCrash {
+                at_bar: 20,
+                depth: 0.3,
+                recovery_bars: 10,
+            },
+            Scenario::Trend {
+                start_bar: 40,
+                bars: 5,
+                drift: 0.01,
+            },
+        ];
+        let klines = generate_klines(&config(flat(), scenarios), 0, INTERVAL, 60).unwrap();
+
+        assert!(close_to(klines[9].close, 100.));
+        assert!(close_to(klines[10].open, 95.));
+        assert!(close_to(klines[19].close, 95.));
+        assert!(close_to(klines[20].close, 95. * 0.7));
+        // The crash is recovered in log terms over recovery_bars
+        assert!(close_to(klines[30].close, 95.));
+        assert!(close_to(klines[39].close, 95.));
+        for bar in 40..45 {
+            assert!(close_to(
+                klines[bar].close,
+                klines[bar - 1].close * 0.01f64.exp()
+            ));
+        }
+        assert!(close_to(klines[59].close, klines[44].close));
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        let invalid = [
+            config(
+                PriceProcess::RegimeSwitching {
+                    regimes: Vec::new(),
+                    switch_probability: 0.1,
+                },
+                Vec::new(),
+            ),
+            config(
+                PriceProcess::RegimeSwitching {
+                    regimes: vec![Regime {
+                        drift: 0.,
+                        volatility: 0.01,
+                    }],
+                    switch_probability: f64::NAN,
+                },
+                Vec::new(),
+            ),
+            config(
+                PriceProcess::JumpDiffusion {
+                    drift: 0.,
+                    volatility: 0.01,
+                    jump_intensity: 0.1,
+                    jump_mean: 0.,
+                    jump_std: -1.,
+                },
+                Vec::new(),
+            ),
+            config(
+                PriceProcess::Gbm {
+                    drift: 0.,
+                    volatility: f64::INFINITY,
+                },
+                Vec::new(),
+            ),
+            config(
+                flat(),
+                vec![Scenario::FlashCrash {
+                    at_bar: 1,
+                    depth: 1.,
+                    recovery_bars: 1,
+                }],
+            ),
+            config(
+                flat(),
+                vec![Scenario::Gap {
+                    at_bar: 1,
+                    size: -1.,
+                }],
+            ),
+        ];
+        for config in invalid {
+            assert!(generate_klines(&config, 0, INTERVAL, 10).is_err());
+        }
+    }
+}
@@ -0,0 +1,63 @@
+// Fixtures shared by the unit tests
+use serde_json::{json, Value};
+
+use crate::{
+    synthetic::generate_klines,
+    types::{BbBandConfig, HypertuneConfig, Kline, PriceProcess, SyntheticConfig},
+    KLINE_INTERVAL_MS,
+};
+
+// A one-month single-entry config, with the fields in extra replacing the defaults
+pub fn config(extra: Value) -> BbBandConfig {
+    let mut config = json!({
+        "from": [2022, 1, 1],
+        "to": [2022, 2, 1],
+        "initial_captial": 10000.,
+        "take_profit_percentage": 0.01,
+        "stop_loss_percentage": 0.01,
+        "fee_rate": 0.0004,
+        "leverage": 1,
+        "strategy_type": "Single",
+        "entry_protion": 0.5,
+        "bb_width": 2.,
+        "kline_cache_dir": null,
+    });
+    if let (Value::Object(config), Value::Object(extra)) = (&mut config, extra) {
+        config.extend(extra);
+    }
+    serde_json::from_value(config).unwrap()
+}
+
+// A small grid over the three swept params, with the fields in extra replacing the defaults
+pub fn hypertune_config(extra: Value) -> HypertuneConfig {
+    let mut config = json!({
+        "take_profit_percentage_step": 0.01,
+        "take_profit_percentage_min": 0.01,
+        "take_profit_percentage_max": 0.03,
+        "stop_loss_percentage_step": 0.01,
+        "stop_loss_percentage_min": 0.01,
+        "stop_loss_percentage_max": 0.03,
+        "bb_width_step": 0.5,
+        "bb_width_min": 1.,
+        "bb_width_max": 2.,
+    });
+    if let (Value::Object(config), Value::Object(extra)) = (&mut config, extra) {
+        config.extend(extra);
+    }
+    serde_json::from_value(config).unwrap()
+}
+
+// Driftless 15m GBM klines from 1970-01-01, enough volatility to trade the bands
+pub fn klines(bars: usize) -> Vec<Kline> {
+    let synthetic = SyntheticConfig {
+        seed: 3,
+        start_price: 30000.,
+        bars: None,
+        process: PriceProcess::Gbm {
+            drift: 0.,
+            volatility: 0.004,
+        },
+        scenarios: Vec::new(),
+    };
+    generate_klines(&synthetic, 0, KLINE_INTERVAL_MS, bars).unwrap()
+}
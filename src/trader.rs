@@ -0,0 +1,656 @@
+use std::{thread, time::Duration};
+
+use anyhow::{bail, Result};
+use chrono::Utc;
+use log::{info, warn};
+
+use crate::{
+    backtest::backtest,
+    exchange::{binance::BinanceFutures, mock::MockExchange, Exchange},
+    notify::{Event, Notifier},
+    order::{OrderId, OrderRequest, OrderStatus, OrderType, Position},
+    strategy_pool::bb_swing::{BBSwing, DAYS},
+    stream::KlineStream,
+    types::{BacktestMetric, BbBandConfig, EntryOrderType, Kline},
+    TradeSide, KLINE_INTERVAL_MS,
+};
+
+const EXIT_ATTEMPTS: usize = 3;
+const EXIT_BACKOFF: Duration = Duration::from_secs(1);
+
+// Drives BBSwing against an exchange. The strategy runs exactly as in backtest on its own
+// metric; the trader turns entries into a market order plus reduce-only TP/SL brackets
+// and keeps the strategy in sync with what actually happened on the exchange.
+pub struct Trader<E: Exchange> {
+    pub exchange: E,
+    pub metric: BacktestMetric,
+    pub notifier: Notifier,
+    bb_swing: BBSwing,
+    entry_order: Option<OrderId>,
+    bracket_orders: Vec<OrderId>,
+    exit_pending: bool, // the exit was rejected, the exchange still holds the position
+    exit_backoff: Duration,
+}
+
+impl<E: Exchange> Trader<E> {
+    pub fn new(config: &BbBandConfig, exchange: E) -> Self {
+        let mut bb_swing = BBSwing::new(config);
+        bb_swing.record_events();
+        Trader {
+            exchange,
+            metric: BacktestMetric::new(config),
+            notifier: Notifier::new(&config.notifier),
+            bb_swing,
+            entry_order: None,
+            bracket_orders: Vec::new(),
+            exit_pending: false,
+            exit_backoff: EXIT_BACKOFF,
+        }
+    }
+
+    pub fn warm_up(&mut self, klines: &[Kline]) {
+        self.bb_swing.warm_up(klines);
+    }
+
+    // Call once per closed kline
+    pub fn on_kline(&mut self, kline: &Kline) -> Result<()> {
+        let result = self.step(kline);
+        let events = self.bb_swing.drain_events();
+        self.notifier.notify_all(events);
+        self.notifier.report(result)
+    }
+
+    fn step(&mut self, kline: &Kline) -> Result<()> {
+        self.protect_entry()?;
+        if self.exit_pending {
+            self.close()?;
+        }
+        self.metric.usd_balance = self.exchange.balance()?;
+
+        let was_open = self.bb_swing.position().is_some();
+        self.bb_swing.strategy(&mut self.metric, kline);
+        match self.bb_swing.position().cloned() {
+            Some(_) if !was_open && self.exit_pending => {
+                let message = "entry skipped, the previous exit is still pending".to_string();
+                warn!("{}", message);
+                self.notifier.notify(&Event::Error { message });
+                self.bb_swing.set_position(None);
+            }
+            Some(position) if !was_open => self.open(&position)?,
+            None if was_open => self.close()?,
+            // A bracket can trigger on the exchange before the strategy sees its exit
+            Some(_)
+                if !self.bracket_orders.is_empty()
+                    && self.exchange.position()?.side == TradeSide::None =>
+            {
+                info!("position closed on exchange, resetting strategy state");
+                self.cancel_all()?;
+                self.bb_swing.set_position(None);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, position: &Position) -> Result<()> {
+        let order = self.exchange.place_order(position.entry_order.request())?;
+        if order.status == OrderStatus::Rejected {
+            let message = format!("entry order rejected: {:?}", order.request());
+            warn!("{}", message);
+            self.notifier.notify(&Event::Error { message });
+            self.bb_swing.set_position(None);
+            return Ok(());
+        }
+        self.entry_order = Some(order.id);
+        Ok(())
+    }
+
+    // Reduce-only orders are rejected while flat, so brackets go in once the entry fills.
+    // An entry the exchange cancels or expires without a fill leaves nothing to manage.
+    fn protect_entry(&mut self) -> Result<()> {
+        let Some(entry_id) = self.entry_order else {
+            return Ok(());
+        };
+        let entry = self.exchange.order(entry_id)?;
+        if !entry.status.is_open() {
+            self.entry_order = None;
+            if entry.filled_size <= 0. {
+                let message = format!(
+                    "entry order {} ended {:?} without a fill, resetting strategy state",
+                    entry_id, entry.status
+                );
+                warn!("{}", message);
+                self.notifier.notify(&Event::Error { message });
+                self.bb_swing.set_position(None);
+                return Ok(());
+            }
+        }
+        if entry.filled_size > 0. && self.bracket_orders.is_empty() {
+            if self.exchange.position()?.side == TradeSide::None {
+                return Ok(());
+            }
+            self.place_brackets()?;
+        }
+        Ok(())
+    }
+
+    fn place_brackets(&mut self) -> Result<()> {
+        let Some(position) = self.bb_swing.position() else {
+            return Ok(());
+        };
+        // Stop loss first so it wins when both trigger on the same kline, as in backtest
+        let brackets = [position.stop_loss.request(), position.take_profit.request()];
+        for request in brackets {
+            let order = self.exchange.place_order(request)?;
+            if order.status == OrderStatus::Rejected {
+                let message = format!("bracket order rejected, flattening: {:?}", order.request());
+                warn!("{}", message);
+                self.notifier.notify(&Event::Error { message });
+                self.close()?;
+                self.bb_swing.set_position(None);
+                return Ok(());
+            }
+            self.bracket_orders.push(order.id);
+        }
+        Ok(())
+    }
+
+    // Brackets are only cancelled once the exit is accepted, a rejected exit keeps them
+    // protecting the position and is retried on the next kline. Every retry backs off and
+    // re-reads the position first, and an exit that failed without an answer is not resent
+    // until the next kline, when its outcome shows in the position.
+    fn close(&mut self) -> Result<()> {
+        if let Some(entry_id) = self.entry_order.take() {
+            if self.exchange.order(entry_id)?.status.is_open() {
+                self.exchange.cancel_order(entry_id)?;
+            }
+        }
+        let mut is_accepted = false;
+        for attempt in 0..EXIT_ATTEMPTS {
+            if attempt > 0 {
+                thread::sleep(self.exit_backoff * attempt as u32);
+            }
+            let position = self.exchange.position()?;
+            if position.side == TradeSide::None {
+                is_accepted = true;
+                break;
+            }
+            let request = OrderRequest {
+                side: position.side.opposite(),
+                order_type: OrderType::Market,
+                size: position.size,
+                reduce_only: true,
+            };
+            match self.exchange.place_order(request.clone()) {
+                Ok(order) if order.status != OrderStatus::Rejected => {
+                    is_accepted = true;
+                    break;
+                }
+                Ok(_) if attempt + 1 < EXIT_ATTEMPTS => {}
+                Ok(_) => {
+                    let message = format!(
+                        "exit order rejected {} times, keeping brackets: {:?}",
+                        EXIT_ATTEMPTS, request
+                    );
+                    warn!("{}", message);
+                    self.notifier.notify(&Event::Error { message });
+                }
+                Err(err) => {
+                    let message = format!(
+                        "exit order failed, keeping brackets: {:?}: {:#}",
+                        request, err
+                    );
+                    warn!("{}", message);
+                    self.notifier.notify(&Event::Error { message });
+                    break;
+                }
+            }
+        }
+        if !is_accepted {
+            self.exit_pending = true;
+            return Ok(());
+        }
+        self.exit_pending = false;
+        self.cancel_all()
+    }
+
+    fn cancel_all(&mut self) -> Result<()> {
+        let order_ids = self.entry_order.take().into_iter();
+        for id in order_ids.chain(self.bracket_orders.drain(..)) {
+            if self.exchange.order(id)?.status.is_open() {
+                self.exchange.cancel_order(id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The trader mirrors one market entry and one pair of brackets per position
+fn check_strategy(config: &BbBandConfig) -> Result<()> {
+    if config.scaling.is_enabled() {
+        bail!("scale-in and partial take profits are not supported when trading on an exchange");
+    }
+    if config.entry.order_type == EntryOrderType::Limit {
+        bail!("limit entries are not supported when trading on an exchange");
+    }
+    Ok(())
+}
+
+// Runs the trader on a MockExchange over the klines and compares it with the backtest
+pub fn mock_trade(config: &BbBandConfig, klines: &[Kline]) -> Result<f64> {
+    check_strategy(config)?;
+    let exchange = MockExchange::new(
+        klines.to_vec(),
+        &config.mock_exchange,
+        config.initial_captial,
+        config.fee_rate,
+        config.leverage,
+    );
+    let mut trader = Trader::new(config, exchange);
+    while let Some(kline) = trader.exchange.advance() {
+        trader.on_kline(&kline)?;
+    }
+    let balance = trader.exchange.balance()?;
+    let position = trader.exchange.position()?;
+    let metric = backtest(config, klines);
+    info!(
+        "mock exchange: usd_balance: {:.4}, total_fee: {:.4}, open position: {:?} {:.4}, backtest usd_balance: {:.4}",
+        balance, trader.exchange.total_fee, position.side, position.size, metric.usd_balance
+    );
+    Ok(balance)
+}
+
+// Runs the trader on Binance futures, driven by the exchange's own kline stream
+pub fn live_trade(config: &BbBandConfig) -> Result<()> {
+    check_strategy(config)?;
+    let mut exchange = BinanceFutures::new(&config.binance);
+    // The trader only manages positions it opened itself
+    let position = exchange.position()?;
+    if position.side != TradeSide::None {
+        bail!(
+            "account already holds a {:?} position of {}, close it before starting",
+            position.side,
+            position.size
+        );
+    }
+    let now = Utc::now().timestamp_millis();
+    let history: Vec<Kline> = exchange
+        .fetch_klines(now - (DAYS as i64 + 1) * KLINE_INTERVAL_MS, now)?
+        .into_iter()
+        .filter(|kline| kline.close_time < now)
+        .collect();
+    let mut last_close_time = history.last().map_or(0, |kline| kline.close_time);
+    let mut trader = Trader::new(config, exchange);
+    trader.warm_up(&history);
+
+    // Order and account updates are only logged, the trader queries order state itself
+    let mut user_data_client = BinanceFutures::new(&config.binance);
+    thread::spawn(move || {
+        let mut user_data = match user_data_client.start_user_data_stream() {
+            Ok(user_data) => user_data,
+            Err(err) => {
+                warn!("user data stream unavailable: {}", err);
+                return;
+            }
+        };
+        loop {
+            match user_data.next_event(&mut user_data_client) {
+                Ok(event) => info!("user data: {:?}", event),
+                Err(err) => {
+                    warn!("user data stream stopped: {}", err);
+                    return;
+                }
+            }
+        }
+    });
+
+    let stream_url = format!(
+        "{}/ws/{}@kline_15m",
+        config.binance.ws_url,
+        config.binance.symbol.to_lowercase()
+    );
+    let mut stream = KlineStream::new(&stream_url);
+    loop {
+        let kline = trader.notifier.report(stream.next_closed_kline())?;
+        if kline.close_time <= last_close_time {
+            continue;
+        }
+        trader.on_kline(&kline)?;
+        last_close_time = kline.close_time;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{order::Order, test_utils::klines, types::MockExchangeConfig};
+
+    type RejectRule = Box<dyn FnMut(&OrderRequest) -> bool>;
+
+    // Rejects the placements the rule picks, everything else goes to the mock. Placements
+    // `fail` picks reach the mock but answer with an error, like a timeout or a 5xx.
+    struct Scripted {
+        mock: MockExchange,
+        reject: RejectRule,
+        fail: RejectRule,
+        placed: Vec<(OrderRequest, Option<OrderId>)>, // None when rejected
+    }
+
+    impl Exchange for Scripted {
+        fn place_order(&mut self, request: OrderRequest) -> Result<Order> {
+            if !(self.reject)(&request) {
+                let order = self.mock.place_order(request.clone())?;
+                self.placed.push((request.clone(), Some(order.id)));
+                if (self.fail)(&request) {
+                    bail!("no answer for {:?}", request);
+                }
+                return Ok(order);
+            }
+            self.placed.push((request.clone(), None));
+            let mut order = Order::new(0, request);
+            order.reject()?;
+            Ok(order)
+        }
+
+        fn cancel_order(&mut self, id: OrderId) -> Result<Order> {
+            self.mock.cancel_order(id)
+        }
+
+        fn order(&mut self, id: OrderId) -> Result<Order> {
+            self.mock.order(id)
+        }
+
+        fn position(&mut self) -> Result<crate::exchange::ExchangePosition> {
+            self.mock.position()
+        }
+
+        fn balance(&mut self) -> Result<f64> {
+            self.mock.balance()
+        }
+
+        fn fetch_klines(&mut self, from_ts: i64, to_ts: i64) -> Result<Vec<Kline>> {
+            self.mock.fetch_klines(from_ts, to_ts)
+        }
+    }
+
+    fn config(bracket_percentage: f64) -> BbBandConfig {
+        crate::test_utils::config(json!({
+            "take_profit_percentage": bracket_percentage,
+            "stop_loss_percentage": bracket_percentage,
+        }))
+    }
+
+    fn trader(
+        config: &BbBandConfig,
+        mock_config: &MockExchangeConfig,
+        reject: RejectRule,
+    ) -> Trader<Scripted> {
+        let mock = MockExchange::new(
+            klines(2000),
+            mock_config,
+            config.initial_captial,
+            config.fee_rate,
+            config.leverage,
+        );
+        let exchange = Scripted {
+            mock,
+            reject,
+            fail: Box::new(|_| false),
+            placed: Vec::new(),
+        };
+        let mut trader = Trader::new(config, exchange);
+        trader.exit_backoff = Duration::ZERO;
+        trader
+    }
+
+    // Feeds klines until the condition holds after a kline, false when they run out
+    fn run_until(trader: &mut Trader<Scripted>, done: impl Fn(&Trader<Scripted>) -> bool) -> bool {
+        while let Some(kline) = trader.exchange.mock.advance() {
+            trader.on_kline(&kline).unwrap();
+            if done(trader) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn step(trader: &mut Trader<Scripted>) {
+        let kline = trader.exchange.mock.advance().unwrap();
+        trader.on_kline(&kline).unwrap();
+    }
+
+    fn is_flat(trader: &mut Trader<Scripted>) -> bool {
+        trader.exchange.position().unwrap().side == TradeSide::None
+    }
+
+    #[test]
+    fn clean_run_matches_backtest() {
+        let config = config(0.01);
+        let mut trader = trader(&config, &MockExchangeConfig::default(), Box::new(|_| false));
+        run_until(&mut trader, |_| false);
+        let metric = backtest(&config, &klines(2000));
+
+        let entries = trader
+            .exchange
+            .placed
+            .iter()
+            .filter(|(request, _)| !request.reduce_only)
+            .count();
+        assert!(metric.trades.len() > 20);
+        assert_eq!(
+            entries,
+            metric.trades.len() + trader.bb_swing.position().is_some() as usize
+        );
+        assert!(trader.exchange.placed.iter().all(|(_, id)| id.is_some()));
+        // Market entries fill at the next open instead of the backtest's bar midpoint
+        let balance = trader.exchange.balance().unwrap();
+        assert!(
+            (balance / metric.usd_balance - 1.).abs() < 0.02,
+            "mock {} vs backtest {}",
+            balance,
+            metric.usd_balance
+        );
+    }
+
+    #[test]
+    fn rejected_entry_leaves_strategy_flat() {
+        let config = config(0.01);
+        let mut first = true;
+        let reject = Box::new(move |_: &OrderRequest| std::mem::replace(&mut first, false));
+        let mut trader = trader(&config, &MockExchangeConfig::default(), reject);
+        assert!(run_until(&mut trader, |trader| !trader
+            .exchange
+            .placed
+            .is_empty()));
+
+        assert!(trader.exchange.placed[0].1.is_none());
+        assert!(trader.bb_swing.position().is_none());
+        assert!(trader.entry_order.is_none());
+        step(&mut trader);
+        assert!(is_flat(&mut trader));
+        assert!(trader.bracket_orders.is_empty());
+    }
+
+    #[test]
+    fn rejected_bracket_flattens_position() {
+        let config = config(0.05);
+        // The stop loss goes in first
+        let reject = Box::new(|request: &OrderRequest| {
+            matches!(request.order_type, OrderType::StopMarket { .. })
+        });
+        let mut trader = trader(&config, &MockExchangeConfig::default(), reject);
+        assert!(run_until(&mut trader, |trader| trader
+            .exchange
+            .placed
+            .len()
+            >= 3));
+
+        let (exit, exit_id) = trader.exchange.placed[2].clone();
+        assert!(trader.exchange.placed[1].1.is_none());
+        assert!(exit.reduce_only && exit.order_type == OrderType::Market);
+        // The strategy may enter again on the same kline, so check the exit order itself
+        assert_eq!(
+            trader.bb_swing.position().is_some(),
+            trader.exchange.placed.len() > 3
+        );
+        step(&mut trader);
+        let exit_order = trader.exchange.order(exit_id.unwrap()).unwrap();
+        assert_eq!(exit_order.status, OrderStatus::Filled);
+    }
+
+    #[test]
+    fn rejected_exit_keeps_brackets_and_retries() {
+        let mut config = config(0.2);
+        config.timing.max_holding_bars = 3;
+        let mut exits = 0;
+        let reject = Box::new(move |request: &OrderRequest| {
+            let is_exit = request.reduce_only && request.order_type == OrderType::Market;
+            exits += is_exit as usize;
+            is_exit && exits <= EXIT_ATTEMPTS
+        });
+        let mut trader = trader(&config, &MockExchangeConfig::default(), reject);
+        assert!(run_until(&mut trader, |trader| trader.exit_pending));
+
+        let rejected = trader.exchange.placed.iter().filter(|(_, id)| id.is_none());
+        assert_eq!(rejected.count(), EXIT_ATTEMPTS);
+        assert!(trader.bb_swing.position().is_none());
+        assert!(!is_flat(&mut trader));
+        assert_eq!(trader.bracket_orders.len(), 2);
+        for id in trader.bracket_orders.clone() {
+            assert!(trader.exchange.order(id).unwrap().status.is_open());
+        }
+
+        let brackets = trader.bracket_orders.clone();
+
+        // The next kline retries the exit and only then drops the brackets
+        step(&mut trader);
+        assert!(!trader.exit_pending);
+        assert!(trader.bracket_orders.is_empty());
+        for id in brackets {
+            let bracket = trader.exchange.order(id).unwrap();
+            assert_eq!(bracket.status, OrderStatus::Cancelled);
+        }
+        let (_, exit_id) = trader
+            .exchange
+            .placed
+            .iter()
+            .rfind(|(request, _)| request.reduce_only && request.order_type == OrderType::Market)
+            .cloned()
+            .unwrap();
+        step(&mut trader);
+        let exit_order = trader.exchange.order(exit_id.unwrap()).unwrap();
+        assert_eq!(exit_order.status, OrderStatus::Filled);
+    }
+
+    #[test]
+    fn failed_exit_is_not_resent_once_it_went_through() {
+        let mut config = config(0.2);
+        config.timing.max_holding_bars = 3;
+        let mut trader = trader(&config, &MockExchangeConfig::default(), Box::new(|_| false));
+        trader.exchange.fail =
+            Box::new(|request| request.reduce_only && request.order_type == OrderType::Market);
+        assert!(run_until(&mut trader, |trader| trader.exit_pending));
+
+        let is_exit = |(request, _): &&(OrderRequest, Option<OrderId>)| {
+            request.reduce_only && request.order_type == OrderType::Market
+        };
+        assert_eq!(trader.exchange.placed.iter().filter(is_exit).count(), 1);
+        assert_eq!(trader.bracket_orders.len(), 2);
+
+        // The exit filled on the next kline, so the retry only cleans up
+        step(&mut trader);
+        assert!(is_flat(&mut trader));
+        assert!(!trader.exit_pending);
+        assert!(trader.bracket_orders.is_empty());
+        assert_eq!(trader.exchange.placed.iter().filter(is_exit).count(), 1);
+    }
+
+    #[test]
+    fn unfilled_entry_resets_strategy() {
+        let config = config(0.2);
+        let mock_config = MockExchangeConfig {
+            latency_bars: 1,
+            ..Default::default()
+        };
+        let mut trader = trader(&config, &mock_config, Box::new(|_| false));
+        assert!(run_until(&mut trader, |trader| trader
+            .entry_order
+            .is_some()));
+        assert!(trader.bb_swing.position().is_some());
+
+        // The exchange cancels the entry before it goes live
+        let entry_id = trader.entry_order.unwrap();
+        trader.exchange.mock.cancel_order(entry_id).unwrap();
+        step(&mut trader);
+
+        let entries = trader
+            .exchange
+            .placed
+            .iter()
+            .filter(|(request, _)| !request.reduce_only)
+            .count();
+        assert_ne!(trader.entry_order, Some(entry_id));
+        // The strategy may enter again on the same kline
+        assert_eq!(trader.bb_swing.position().is_some(), entries > 1);
+        assert!(trader.bracket_orders.is_empty());
+        assert!(is_flat(&mut trader));
+    }
+
+    #[test]
+    fn partial_fill_is_protected_before_it_completes() {
+        let config = config(0.2);
+        let mock_config = MockExchangeConfig {
+            partial_fill_ratio: 0.5,
+            ..Default::default()
+        };
+        let mut trader = trader(&config, &mock_config, Box::new(|_| false));
+        assert!(run_until(&mut trader, |trader| !trader
+            .exchange
+            .placed
+            .is_empty()));
+        let entry_size = trader.exchange.placed[0].0.size;
+
+        step(&mut trader);
+        let position = trader.exchange.position().unwrap();
+        assert!((position.size - entry_size * 0.5).abs() < 1e-9);
+        assert_eq!(trader.bracket_orders.len(), 2);
+        assert!(trader.entry_order.is_some());
+
+        step(&mut trader);
+        let position = trader.exchange.position().unwrap();
+        assert!((position.size - entry_size).abs() < 1e-9);
+        assert!(trader.entry_order.is_none());
+        assert_eq!(trader.bracket_orders.len(), 2);
+    }
+
+    #[test]
+    fn bracket_firing_first_resets_strategy() {
+        let config = config(0.01);
+        let mut trader = trader(&config, &MockExchangeConfig::default(), Box::new(|_| false));
+        assert!(run_until(&mut trader, |trader| trader.bracket_orders.len() == 2));
+
+        // Move the strategy's own brackets out of reach so only the exchange can exit
+        let mut position = trader.bb_swing.position().cloned().unwrap();
+        position.stop_loss.order_type = OrderType::StopMarket {
+            stop_price: position.entry_price * (1. - position.side.value()),
+        };
+        position.take_profit.order_type = OrderType::TakeProfitMarket {
+            stop_price: position.entry_price * (1. + position.side.value()),
+        };
+        trader.bb_swing.set_position(Some(position));
+
+        assert!(run_until(&mut trader, |trader| trader
+            .bb_swing
+            .position()
+            .is_none()));
+        assert!(is_flat(&mut trader));
+        assert!(trader.bracket_orders.is_empty());
+        let exits =
+            trader.exchange.placed.iter().filter(|(request, _)| {
+                request.order_type == OrderType::Market && request.reduce_only
+            });
+        assert_eq!(exits.count(), 0);
+    }
+}
@@ -1,8 +1,10 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{collections::BTreeMap, fmt, path::PathBuf, str::FromStr};
 
-use crate::TradeSide;
+use crate::{TradeSide, KLINE_CACHE_DIR};
+use chrono::{DateTime, Datelike, Timelike, Weekday};
 use clap::Parser;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 #[derive(Parser)]
 #[command(arg_required_else_help = false)]
@@ -13,12 +15,33 @@ pub struct Cli {
     pub mode: Mode,
     #[arg(short = 't', required = false)]
     pub hypertune_config: Option<PathBuf>,
+    #[arg(short = 's', required = false)]
+    pub synthetic_config: Option<PathBuf>,
+    #[arg(short = 'o', required = false)]
+    pub output: Option<PathBuf>,
+    #[arg(short = 'p', required = false)]
+    pub plot_config: Option<PathBuf>,
+    #[arg(long = "report", required = false)]
+    pub report: Option<PathBuf>, // backtest mode: html report
+    #[arg(short = 'r', required = false)]
+    pub run_ids: Vec<String>, // query mode: runs to compare, prefixes match
+    #[arg(short = 'n', default_value_t = 20)]
+    pub limit: i64, // query mode: runs to list
 }
 
 #[derive(Clone, Debug)]
 pub enum Mode {
     Backtest,
     Hypertune,
+    MonteCarlo,
+    Paper,
+    Replay,
+    Mock,
+    Live,
+    Query,
+    Plot,
+    Portfolio,
+    Sleeves,
 }
 
 impl FromStr for Mode {
@@ -28,8 +51,26 @@ impl FromStr for Mode {
         match s {
             "backtest" => Ok(Mode::Backtest),
             "hypertune" => Ok(Mode::Hypertune),
+            "monte_carlo" => Ok(Mode::MonteCarlo),
+            "paper" => Ok(Mode::Paper),
+            "replay" => Ok(Mode::Replay),
+            "mock" => Ok(Mode::Mock),
+            "live" => Ok(Mode::Live),
+            "query" => Ok(Mode::Query),
+            "plot" => Ok(Mode::Plot),
+            "portfolio" => Ok(Mode::Portfolio),
+            "sleeves" => Ok(Mode::Sleeves),
             "b" => Ok(Mode::Backtest),
             "h" => Ok(Mode::Hypertune),
+            "mc" => Ok(Mode::MonteCarlo),
+            "p" => Ok(Mode::Paper),
+            "rp" => Ok(Mode::Replay),
+            "mk" => Ok(Mode::Mock),
+            "l" => Ok(Mode::Live),
+            "q" => Ok(Mode::Query),
+            "pl" => Ok(Mode::Plot),
+            "pf" => Ok(Mode::Portfolio),
+            "sv" => Ok(Mode::Sleeves),
             _ => Err(format!("Invalid mode: {}", s)),
         }
     }
@@ -47,35 +88,91 @@ pub struct BbBandConfig {
     pub strategy_type: StrategyType,
     pub entry_protion: f64,
     pub bb_width: f64,
+    #[serde(default)]
+    pub data_quality_policy: DataQualityPolicy,
+    #[serde(default = "default_kline_cache_dir")]
+    pub kline_cache_dir: Option<PathBuf>, // null disables the cache
+    #[serde(default)]
+    pub monte_carlo: MonteCarloConfig,
+    #[serde(default)]
+    pub paper: PaperConfig,
+    #[serde(default)]
+    pub mock_exchange: MockExchangeConfig,
+    #[serde(default)]
+    pub binance: BinanceConfig,
+    #[serde(default)]
+    pub risk: RiskConfig,
+    #[serde(default)]
+    pub notifier: NotifierConfig,
+    #[serde(default)]
+    pub results: ResultsConfig,
+    #[serde(default)]
+    pub portfolio: PortfolioConfig,
+    #[serde(default)]
+    pub sleeves: Vec<SleeveConfig>,
+    #[serde(default)]
+    pub scaling: ScalingConfig,
+    #[serde(default)]
+    pub timing: TimingConfig,
+    #[serde(default)]
+    pub entry: EntryConfig,
 }
 
-#[derive(Debug, Clone, Default)]
+impl BbBandConfig {
+    // Copy that is safe to store or share, secrets are masked
+    pub fn redacted(&self) -> BbBandConfig {
+        let mut config = self.clone();
+        config.binance.api_key = redact(&config.binance.api_key).to_string();
+        config.binance.api_secret = redact(&config.binance.api_secret).to_string();
+        for sink in config.notifier.sinks.iter_mut() {
+            if let SinkConfig::Webhook { url, .. } = sink {
+                *url = redact(url).to_string();
+            }
+        }
+        config
+    }
+}
+
+fn default_kline_cache_dir() -> Option<PathBuf> {
+    Some(PathBuf::from(KLINE_CACHE_DIR))
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BacktestMetric {
     pub initial_captial: f64,
     pub usd_balance: f64,
-    pub position: f64,
-    pub entry_price: f64,
-    pub entry_side: TradeSide,
-    pub take_profit_price: f64,
-    pub stop_loss_price: f64,
     pub win: usize,
     pub lose: usize,
     pub total_fee: f64,
     pub total_profit: f64,
     pub max_usd: f64,
     pub min_usd: f64,
-    pub fee: f64,
-    pub profit: f64,
-    pub exit_price: f64,
     pub bb_width: f64,
+    pub risk_halts: usize,
+    #[serde(default)]
+    pub limit_quotes: usize, // buy and sell limit pairs quoted at the bands
+    #[serde(default)]
+    pub limit_fills: usize,
+    pub trades: Vec<TradeLog>,
 }
 
 impl BacktestMetric {
+    // Largest fall of the balance from its peak, measured on closed trades
+    pub fn max_drawdown(&self) -> f64 {
+        let mut peak = self.initial_captial;
+        let mut max_drawdown: f64 = 0.;
+        for trade in &self.trades {
+            let balance = trade.balance_after();
+            peak = peak.max(balance);
+            max_drawdown = max_drawdown.max((peak - balance) / peak);
+        }
+        max_drawdown
+    }
+
     pub fn new(config: &BbBandConfig) -> BacktestMetric {
         BacktestMetric {
             initial_captial: config.initial_captial,
             usd_balance: config.initial_captial,
-            entry_side: TradeSide::None,
             max_usd: config.initial_captial,
             min_usd: config.initial_captial,
             ..Default::default()
@@ -94,9 +191,736 @@ pub struct HypertuneConfig {
     pub bb_width_step: f64,
     pub bb_width_min: f64,
     pub bb_width_max: f64,
+    #[serde(default)]
+    pub output: Option<PathBuf>, // directory or file base name, -o overrides it
+    #[serde(default = "default_output_formats")]
+    pub formats: Vec<OutputFormat>,
+    #[serde(default)]
+    pub search: SearchConfig,
+    #[serde(default)]
+    pub analysis: AnalysisConfig,
+}
+
+// Overfitting diagnostics run over the trials once a hypertune run completes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct AnalysisConfig {
+    pub enabled: bool,
+    pub cscv_blocks: usize, // even number of blocks the daily returns are split into
+    pub fragile_threshold: f64, // relative drop to the grid neighbours that marks a peak fragile
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        AnalysisConfig {
+            enabled: true,
+            cscv_blocks: 16,
+            fragile_threshold: 0.5,
+        }
+    }
+}
+
+// Heatmaps and sensitivity plots of a hypertune CSV
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PlotConfig {
+    pub input: PathBuf,
+    pub output_dir: PathBuf, // -o overrides it
+    pub metric: String,
+    pub params: Vec<String>,          // empty plots every swept param
+    pub fixed: BTreeMap<String, f64>, // params held at a value, the others are marginalised
+    pub aggregate: Aggregate,
+    pub formats: Vec<PlotFormat>,
+}
+
+impl Default for PlotConfig {
+    fn default() -> Self {
+        PlotConfig {
+            input: PathBuf::from("output.csv"),
+            output_dir: PathBuf::from("plots"),
+            metric: "usd_balance".to_string(),
+            params: Vec::new(),
+            fixed: BTreeMap::new(),
+            aggregate: Aggregate::Mean,
+            formats: vec![PlotFormat::Svg],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Mean,
+    Median,
+    Max,
+    Min,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum PlotFormat {
+    Svg,
+    Png,
+}
+
+impl PlotFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PlotFormat::Svg => "svg",
+            PlotFormat::Png => "png",
+        }
+    }
+}
+
+fn default_output_formats() -> Vec<OutputFormat> {
+    vec![OutputFormat::Csv]
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Parquet => "parquet",
+        }
+    }
+}
+
+// Grid walks every step of the ranges, Nsga2 searches the same ranges for a Pareto front,
+// Genetic evolves any numeric config fields towards one objective
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub enum SearchConfig {
+    #[default]
+    Grid,
+    Nsga2(Nsga2Config),
+    Genetic(GeneticConfig),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Nsga2Config {
+    pub population: usize,
+    pub generations: usize,
+    pub seed: u64,
+    pub crossover_probability: f64,
+    pub crossover_eta: f64, // SBX distribution index
+    pub mutation_eta: f64,  // polynomial mutation distribution index
+    pub objectives: Vec<Objective>,
+    pub constraints: HypertuneConstraints,
+}
+
+impl Default for Nsga2Config {
+    fn default() -> Self {
+        Nsga2Config {
+            population: 40,
+            generations: 20,
+            seed: 0,
+            crossover_probability: 0.9,
+            crossover_eta: 15.,
+            mutation_eta: 20.,
+            objectives: vec![
+                Objective::UsdBalance,
+                Objective::MaxDrawdown,
+                Objective::Trades,
+            ],
+            constraints: HypertuneConstraints::default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct GeneticConfig {
+    pub population: usize,
+    pub generations: usize,
+    pub stall_generations: Option<usize>, // stop early when the best trial stops improving
+    pub seed: u64,
+    pub tournament_size: usize,
+    pub crossover_probability: f64,
+    pub mutation_probability: f64, // per gene
+    pub mutation_scale: f64,       // standard deviation as a fraction of the range
+    pub elitism: usize,
+    pub objective: Objective,
+    pub constraints: HypertuneConstraints,
+    pub params: Vec<ParamRange>, // empty searches the take profit, stop loss and bb width ranges
+}
+
+impl Default for GeneticConfig {
+    fn default() -> Self {
+        GeneticConfig {
+            population: 30,
+            generations: 30,
+            stall_generations: None,
+            seed: 0,
+            tournament_size: 3,
+            crossover_probability: 0.9,
+            mutation_probability: 0.2,
+            mutation_scale: 0.1,
+            elitism: 2,
+            objective: Objective::UsdBalance,
+            constraints: HypertuneConstraints::default(),
+            params: Vec::new(),
+        }
+    }
+}
+
+// A numeric BbBandConfig field, by its dotted path such as "risk.max_drawdown".
+// Integer fields are rounded.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ParamRange {
+    pub field: String,
+    pub min: f64,
+    pub max: f64,
+    #[serde(default)]
+    pub step: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Objective {
+    UsdBalance,
+    MaxDrawdown,
+    Trades,
+    WinRate,
+    TotalFee,
+}
+
+impl Objective {
+    // Trial metric column the objective is read from
+    pub fn metric(&self) -> &'static str {
+        match self {
+            Objective::UsdBalance => "usd_balance",
+            Objective::MaxDrawdown => "max_drawdown",
+            Objective::Trades => "trades",
+            Objective::WinRate => "win_rate",
+            Objective::TotalFee => "total_fee",
+        }
+    }
+
+    pub fn maximize(&self) -> bool {
+        match self {
+            Objective::UsdBalance | Objective::Trades | Objective::WinRate => true,
+            Objective::MaxDrawdown | Objective::TotalFee => false,
+        }
+    }
+}
+
+// Trials breaking a constraint never dominate one that keeps them
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HypertuneConstraints {
+    pub min_trades: Option<usize>,
+    pub max_drawdown: Option<f64>, // fraction of peak balance
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MonteCarloConfig {
+    pub iterations: usize,
+    pub seed: u64,
+    pub methods: Vec<ResampleMethod>,
+    pub block_size: usize,
+    pub ruin_threshold: f64, // fraction of initial capital lost
+    pub confidence: f64,
+    pub output_path: PathBuf,
+}
+
+impl Default for MonteCarloConfig {
+    fn default() -> Self {
+        MonteCarloConfig {
+            iterations: 5000,
+            seed: 0,
+            methods: vec![
+                ResampleMethod::Shuffle,
+                ResampleMethod::Bootstrap,
+                ResampleMethod::BlockBootstrap,
+            ],
+            block_size: 10,
+            ruin_threshold: 0.5,
+            confidence: 0.95,
+            output_path: PathBuf::from("monte_carlo.csv"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PaperConfig {
+    pub stream_url: String,
+    pub state_path: PathBuf,
+    pub replay_addr: String,
+    pub replay_delay_ms: u64,
+}
+
+impl Default for PaperConfig {
+    fn default() -> Self {
+        PaperConfig {
+            stream_url: "wss://fstream.binance.com/ws/btcusdt@kline_15m".to_string(),
+            state_path: PathBuf::from("paper_state.json"),
+            replay_addr: "127.0.0.1:9001".to_string(),
+            replay_delay_ms: 100,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MockExchangeConfig {
+    pub latency_bars: usize, // orders become active this many bars after the next one
+    pub partial_fill_ratio: f64, // share of the order size that can fill per bar
+    pub rejection_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for MockExchangeConfig {
+    fn default() -> Self {
+        MockExchangeConfig {
+            latency_bars: 0,
+            partial_fill_ratio: 1.,
+            rejection_rate: 0.,
+            seed: 0,
+        }
+    }
+}
+
+// Scale-in and partial take-profit, the defaults keep one entry and one exit
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ScalingConfig {
+    pub max_entries: usize,                 // including the first entry
+    pub add_step: f64, // adds once the close is this fraction beyond the band and the last fill
+    pub add_size: f64, // multiple of the size a fresh entry would get
+    pub take_profits: Vec<TakeProfitLevel>, // empty: take_profit_percentage for the whole size
+}
+
+impl Default for ScalingConfig {
+    fn default() -> Self {
+        ScalingConfig {
+            max_entries: 1,
+            add_step: 0.005,
+            add_size: 1.,
+            take_profits: Vec::new(),
+        }
+    }
+}
+
+impl ScalingConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.max_entries > 1 || !self.take_profits.is_empty()
+    }
+}
+
+// Percentages are from the average entry price, the last level closes what remains
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(try_from = "RawTakeProfitLevel")]
+pub struct TakeProfitLevel {
+    pub percentage: f64,
+    pub fraction: f64, // of the size held when the level is hit, above 0
+}
+
+#[derive(Deserialize)]
+struct RawTakeProfitLevel {
+    percentage: f64,
+    fraction: f64,
+}
+
+// Checked on every load, including sleeve overrides and hypertune trials
+impl TryFrom<RawTakeProfitLevel> for TakeProfitLevel {
+    type Error = String;
+
+    fn try_from(raw: RawTakeProfitLevel) -> Result<Self, Self::Error> {
+        if raw.fraction.is_nan() || raw.fraction <= 0. {
+            return Err(format!(
+                "take profit fraction {} must be above 0",
+                raw.fraction
+            ));
+        }
+        Ok(TakeProfitLevel {
+            percentage: raw.percentage,
+            fraction: raw.fraction,
+        })
+    }
 }
 
+// How BBSwing enters after a band break
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct EntryConfig {
+    pub order_type: EntryOrderType,
+    pub offset: f64,        // limit price this fraction beyond the band
+    pub expiry_bars: usize, // klines a limit order rests before it is re-quoted at the band
+    pub fill_rule: FillRule,
+    pub trade_through: f64, // FillRule::TradeThrough: fraction the price must trade past the limit
+    pub maker_fee_rate: f64, // limit fills pay this instead of fee_rate
+}
+
+impl Default for EntryConfig {
+    fn default() -> Self {
+        EntryConfig {
+            order_type: EntryOrderType::Market,
+            offset: 0.,
+            expiry_bars: 1,
+            fill_rule: FillRule::TradeThrough,
+            trade_through: 0.0005,
+            maker_fee_rate: 0.0002,
+        }
+    }
+}
+
+// Market enters at the next kline after the close breaks the band, Limit rests a buy at
+// the lower and a sell at the upper band
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum EntryOrderType {
+    Market,
+    Limit,
+}
+
+// Touch assumes the order is first in the queue, TradeThrough that the whole queue at the
+// price has to trade before it fills
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum FillRule {
+    Touch,
+    TradeThrough,
+}
+
+// When BBSwing may enter and how long it holds, 0 disables a bar count
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TimingConfig {
+    pub cooldown_bars: usize, // no entries for this many bars after a losing trade
+    pub max_holding_bars: usize, // positions are closed at the kline close once this old
+    pub blocked: Vec<BlockedWindow>, // no entries or scale-ins inside these windows
+}
+
+impl TimingConfig {
+    pub fn blocks_entry(&self, time: i64) -> bool {
+        let Some(date_time) = DateTime::from_timestamp_millis(time) else {
+            return false;
+        };
+        let day = Day::from_weekday(date_time.weekday());
+        let hour = date_time.hour() as f64 + date_time.minute() as f64 / 60.;
+        self.blocked.iter().any(|window| window.contains(day, hour))
+    }
+}
+
+// UTC hours, end before start wraps past midnight, e.g. 23.75 to 0.25 around funding.
+// Without days the window applies every day, a wrapped window belongs to its start day.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockedWindow {
+    #[serde(default)]
+    pub days: Vec<Day>,
+    pub start_hour: f64,
+    pub end_hour: f64,
+}
+
+impl BlockedWindow {
+    fn contains(&self, day: Day, hour: f64) -> bool {
+        let on = |day: Day| self.days.is_empty() || self.days.contains(&day);
+        if self.start_hour <= self.end_hour {
+            on(day) && hour >= self.start_hour && hour < self.end_hour
+        } else {
+            (on(day) && hour >= self.start_hour) || (on(day.previous()) && hour < self.end_hour)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Day {
+    const ALL: [Day; 7] = [
+        Day::Mon,
+        Day::Tue,
+        Day::Wed,
+        Day::Thu,
+        Day::Fri,
+        Day::Sat,
+        Day::Sun,
+    ];
+
+    fn from_weekday(weekday: Weekday) -> Day {
+        Day::ALL[weekday.num_days_from_monday() as usize]
+    }
+
+    fn previous(self) -> Day {
+        Day::ALL[(self as usize + 6) % 7]
+    }
+}
+
+// Every limit is optional, null disables it
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RiskConfig {
+    pub max_daily_loss: Option<f64>, // fraction of the equity at the start of the period
+    pub max_drawdown: Option<f64>,   // fraction of the all-time peak equity
+    pub max_consecutive_losses: Option<usize>,
+    pub max_position_notional: Option<f64>, // usd, size * price * leverage
+    pub max_leverage: Option<f64>,          // notional / equity
+    pub action: RiskAction,
+    pub reset: RiskReset,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum RiskAction {
+    #[default]
+    HaltEntries,
+    Flatten, // also closes the open position at the kline close
+}
+
+// Periods are UTC days or weeks starting on Monday
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum RiskReset {
+    #[default]
+    Daily,
+    Weekly,
+    Never,
+}
+
+// Portfolio mode: one BBSwing per symbol on a shared account balance
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PortfolioConfig {
+    pub symbols: Vec<PortfolioSymbol>,
+    pub allocation: Allocation,
+    pub volatility_lookback: usize, // klines, for VolatilityParity
+    pub max_symbol_exposure: Option<f64>, // notional / equity of one symbol
+    pub max_total_exposure: Option<f64>, // notional / equity over all symbols
+}
+
+impl Default for PortfolioConfig {
+    fn default() -> Self {
+        PortfolioConfig {
+            symbols: Vec::new(),
+            allocation: Allocation::EqualWeight,
+            volatility_lookback: 96 * 7,
+            max_symbol_exposure: None,
+            max_total_exposure: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortfolioSymbol {
+    pub symbol: String,
+    #[serde(default)]
+    pub collection: Option<String>, // defaults to <symbol>_15m
+    #[serde(default)]
+    pub start_price: Option<f64>, // synthetic klines only
+}
+
+impl PortfolioSymbol {
+    pub fn collection(&self) -> String {
+        self.collection
+            .clone()
+            .unwrap_or_else(|| format!("{}_15m", self.symbol))
+    }
+}
+
+// Share of the entry_protion sizing each symbol gets, weights sum to 1
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum Allocation {
+    #[default]
+    EqualWeight,
+    VolatilityParity, // inverse to the recent volatility of each symbol
+}
+
+// Sleeves mode: one BBSwing per sleeve on its share of the capital, netted in one account
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SleeveConfig {
+    pub name: String,
+    pub allocation: f64, // share of initial_captial, sleeves sum to at most 1
+    #[serde(default)]
+    pub overrides: Map<String, Value>, // merged into the base config, e.g. {"take_profit_percentage": 0.02}
+}
+
+// Backtest and hypertune results, stored in RESULTS_DB on the local mongod
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ResultsConfig {
+    pub enabled: bool,
+    pub collection: String,
+    pub store_trades: bool,
+}
+
+impl Default for ResultsConfig {
+    fn default() -> Self {
+        ResultsConfig {
+            enabled: false,
+            collection: "results".to_string(),
+            store_trades: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct NotifierConfig {
+    pub sinks: Vec<SinkConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum SinkConfig {
+    Stdout,
+    Jsonl { path: PathBuf },
+    Webhook { url: String, format: WebhookFormat },
+}
+
+// Webhook urls carry their token, keep them out of the startup log
+impl fmt::Debug for SinkConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkConfig::Stdout => f.write_str("Stdout"),
+            SinkConfig::Jsonl { path } => f.debug_struct("Jsonl").field("path", path).finish(),
+            SinkConfig::Webhook { url, format } => f
+                .debug_struct("Webhook")
+                .field("url", &redact(url))
+                .field("format", format)
+                .finish(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum WebhookFormat {
+    Generic, // the event itself as JSON
+    Slack,
+    Discord,
+    Telegram { chat_id: String }, // url is https://api.telegram.org/bot<token>/sendMessage
+}
+
+// Empty api_key/api_secret are read from BINANCE_API_KEY/BINANCE_API_SECRET
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct BinanceConfig {
+    pub base_url: String,
+    pub ws_url: String,
+    pub api_key: String,
+    pub api_secret: String,
+    pub symbol: String,
+    pub quantity_precision: usize,
+    pub price_precision: usize,
+    pub recv_window: u64,
+}
+
+impl Default for BinanceConfig {
+    fn default() -> Self {
+        BinanceConfig {
+            base_url: "https://fapi.binance.com".to_string(),
+            ws_url: "wss://fstream.binance.com".to_string(),
+            api_key: "".to_string(),
+            api_secret: "".to_string(),
+            symbol: "BTCUSDT".to_string(),
+            quantity_precision: 3,
+            price_precision: 1,
+            recv_window: 5000,
+        }
+    }
+}
+
+// The config is logged at startup, keep the credentials out of it
+impl fmt::Debug for BinanceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BinanceConfig")
+            .field("base_url", &self.base_url)
+            .field("ws_url", &self.ws_url)
+            .field("api_key", &redact(&self.api_key))
+            .field("api_secret", &redact(&self.api_secret))
+            .field("symbol", &self.symbol)
+            .field("quantity_precision", &self.quantity_precision)
+            .field("price_precision", &self.price_precision)
+            .field("recv_window", &self.recv_window)
+            .finish()
+    }
+}
+
+fn redact(secret: &str) -> &'static str {
+    if secret.is_empty() {
+        ""
+    } else {
+        "***"
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ResampleMethod {
+    Shuffle,
+    Bootstrap,
+    BlockBootstrap,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyntheticConfig {
+    pub seed: u64,
+    pub start_price: f64,
+    pub bars: Option<usize>, // defaults to the config from/to range
+    pub process: PriceProcess,
+    #[serde(default)]
+    pub scenarios: Vec<Scenario>,
+}
+
+// Drift and volatility are per bar, in log-return terms
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum PriceProcess {
+    Gbm {
+        drift: f64,
+        volatility: f64,
+    },
+    Garch {
+        drift: f64,
+        omega: f64,
+        alpha: f64,
+        beta: f64,
+    },
+    JumpDiffusion {
+        drift: f64,
+        volatility: f64,
+        jump_intensity: f64, // expected jumps per bar
+        jump_mean: f64,
+        jump_std: f64,
+    },
+    RegimeSwitching {
+        regimes: Vec<Regime>,
+        switch_probability: f64, // per bar
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Regime {
+    pub drift: f64,
+    pub volatility: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Scenario {
+    FlashCrash {
+        at_bar: usize,
+        depth: f64, // fraction of price lost within the bar
+        recovery_bars: usize,
+    },
+    Gap {
+        at_bar: usize,
+        size: f64, // open vs previous close, e.g. -0.05
+    },
+    Trend {
+        start_bar: usize,
+        bars: usize,
+        drift: f64, // extra log-return per bar
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
 pub struct Kline {
     pub open_time: i64,
     pub close_time: i64,
@@ -104,6 +928,17 @@ pub struct Kline {
     pub high: f64,
     pub low: f64,
     pub close: f64,
+    // Older collections only store OHLC and times
+    #[serde(default)]
+    pub volume: Option<f64>,
+    #[serde(default)]
+    pub quote_volume: Option<f64>,
+    #[serde(default)]
+    pub trade_count: Option<u64>,
+    #[serde(default)]
+    pub taker_buy_volume: Option<f64>,
+    #[serde(default)]
+    pub taker_buy_quote_volume: Option<f64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -115,6 +950,15 @@ pub struct BollingerBand {
     pub date_time: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Copy, Default)]
+pub enum DataQualityPolicy {
+    Fail,
+    Drop,
+    ForwardFill,
+    #[default]
+    Warn,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Copy)]
 pub enum StrategyType {
     Single,
@@ -126,9 +970,49 @@ pub struct OrderSpec {
     pub side: TradeSide,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct TradeLog {
     pub entry_side: i64,
-    pub entry_price: f64,
-    pub entry_size: f64,
-    pub exit_price: f64,
+    pub entry_price: f64, // average over the scale-ins
+    pub entry_size: f64,  // largest size held
+    pub exit_price: f64,  // average over the partial exits
+    pub entry_time: i64,
+    pub exit_time: i64,
+    pub entry_balance: f64, // usd_balance before the entry fee
+    pub profit: f64,
+    pub fee: f64, // entry + exit
+    #[serde(default)]
+    pub fills: Vec<Fill>,
+}
+
+// One execution of a logical trade
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Fill {
+    pub time: i64,
+    pub kind: FillKind,
+    pub side: i64,
+    pub price: f64,
+    pub size: f64,
+    pub fee: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum FillKind {
+    Entry,
+    ScaleIn,
+    TakeProfit,
+    StopLoss,
+    Exit, // market exit outside the brackets
+}
+
+impl TradeLog {
+    // Net return on the balance at entry, used to resample trades independently of sizing
+    pub fn net_return(&self) -> f64 {
+        (self.profit - self.fee) / self.entry_balance
+    }
+
+    // usd_balance once the trade is closed and its fees are paid
+    pub fn balance_after(&self) -> f64 {
+        self.entry_balance + self.profit - self.fee
+    }
 }
@@ -1,20 +1,21 @@
 use std::collections::VecDeque;
 
 use crate::{
+    kline_cache::KlineCache,
     mongo_client::MongoClient,
-    types::{BacktestMetric, BbBandConfig, BollingerBand, Kline},
-    TradeSide, BTCUSDT_15M, KLINE_DB, LOCAL_MONGO_CONNECTION_STRING,
+    types::{BbBandConfig, BollingerBand, Kline},
+    BTCUSDT_15M, KLINE_DB, LOCAL_MONGO_CONNECTION_STRING,
 };
 use async_std::task;
-use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use chrono::{DateTime, NaiveDate};
+use log::warn;
 
 pub fn sma(days: usize, klines: &VecDeque<Kline>) -> Option<f64> {
     if klines.len() >= days {
         let start_index = klines.len() - days;
-        let end_index = klines.len() - 1;
         let mut close_sum = 0.;
-        for index in start_index..=end_index {
-            close_sum += &klines[index].close;
+        for kline in klines.iter().skip(start_index) {
+            close_sum += kline.close;
         }
         let sma = close_sum / days as f64;
         Some(sma)
@@ -26,10 +27,9 @@ pub fn sma(days: usize, klines: &VecDeque<Kline>) -> Option<f64> {
 pub fn deviation(days: usize, klines: &VecDeque<Kline>) -> Option<f64> {
     if let Some(sma) = sma(days, klines) {
         let start_index = klines.len() - days;
-        let end_index = klines.len() - 1;
         let mut diff_sum = 0.;
-        for index in start_index..=end_index {
-            let diff = klines[index].close - sma;
+        for kline in klines.iter().skip(start_index) {
+            let diff = kline.close - sma;
             diff_sum += diff * diff;
         }
         let dev = f64::sqrt(diff_sum / days as f64);
@@ -42,12 +42,9 @@ pub fn deviation(days: usize, klines: &VecDeque<Kline>) -> Option<f64> {
 pub fn bollinger_band(days: usize, width: f64, klines: &VecDeque<Kline>) -> Option<BollingerBand> {
     let sma_opt = sma(days, klines);
     let dev_opt = deviation(days, klines);
-    if sma_opt.is_some() && dev_opt.is_some() {
-        let sma = sma_opt.unwrap();
-        let dev = dev_opt.unwrap();
+    if let (Some(sma), Some(dev)) = (sma_opt, dev_opt) {
         let timestamp_sec = klines[days - 1].close_time / 1000;
-        let naive = NaiveDateTime::from_timestamp_opt(timestamp_sec, 0).unwrap();
-        let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
+        let datetime = DateTime::from_timestamp(timestamp_sec, 0).unwrap();
         Some(BollingerBand {
             up: sma + width * dev,
             sma,
@@ -60,12 +57,87 @@ pub fn bollinger_band(days: usize, width: f64, klines: &VecDeque<Kline>) -> Opti
     }
 }
 
+// Mean and sample standard deviation, a single sample has no spread
+pub fn mean_std(returns: &[f64]) -> (f64, f64) {
+    let len = returns.len() as f64;
+    let mean = returns.iter().sum::<f64>() / len;
+    let variance = returns.iter().map(|ret| (ret - mean).powi(2)).sum::<f64>() / (len - 1.).max(1.);
+    (mean, variance.sqrt())
+}
+
+// Standard normal CDF through the complementary error function (Numerical Recipes erfcc)
+pub fn normal_cdf(x: f64) -> f64 {
+    let z = x.abs() / std::f64::consts::SQRT_2;
+    let t = 1. / (1. + 0.5 * z);
+    let erfc = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+    if x >= 0. {
+        1. - 0.5 * erfc
+    } else {
+        0.5 * erfc
+    }
+}
+
+// Acklam's rational approximation of the standard normal inverse CDF
+pub fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e+01,
+        2.209460984245205e+02,
+        -2.759285104469687e+02,
+        1.38357751867269e+02,
+        -3.066479806614716e+01,
+        2.506628277459239e+00,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e+01,
+        1.615858368580409e+02,
+        -1.556989798598866e+02,
+        6.680131188771972e+01,
+        -1.328068155288572e+01,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-03,
+        -3.223964580411365e-01,
+        -2.400758277161838e+00,
+        -2.549671010379318e+00,
+        4.374664141464968e+00,
+        2.938163982698783e+00,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-03,
+        3.224671290700398e-01,
+        2.445134137142996e+00,
+        3.754408661907416e+00,
+    ];
+    let p_low = 0.02425;
+    if p < p_low {
+        let q = (-2. * p.ln()).sqrt();
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.)
+    } else if p <= 1. - p_low {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.)
+    } else {
+        -normal_quantile(1. - p)
+    }
+}
+
 pub fn datetime_to_ts_ms(year: i32, month: u32, day: u32) -> i64 {
     let naive_date = NaiveDate::from_ymd_opt(year, month, day)
         .unwrap()
         .and_hms_opt(0, 0, 0)
         .unwrap();
-    DateTime::<Utc>::from_utc(naive_date, Utc).timestamp_millis()
+    naive_date.and_utc().timestamp_millis()
 }
 
 pub fn calculate_fee(fee_rate: f64, price: f64, size: f64, leverage: u64) -> f64 {
@@ -73,21 +145,20 @@ pub fn calculate_fee(fee_rate: f64, price: f64, size: f64, leverage: u64) -> f64
 }
 
 pub fn get_klines_from_db(config: &BbBandConfig) -> Vec<Kline> {
+    get_collection_klines_from_db(config, BTCUSDT_15M)
+}
+
+pub fn get_collection_klines_from_db(config: &BbBandConfig, collection: &str) -> Vec<Kline> {
     let from_ts_ms = datetime_to_ts_ms(config.from.0, config.from.1, config.from.2);
     let to_ts_ms = datetime_to_ts_ms(config.to.0, config.to.1, config.to.2);
 
     let mongo_clinet = task::block_on(MongoClient::new(LOCAL_MONGO_CONNECTION_STRING));
-    let klines =
-        task::block_on(mongo_clinet.get_klines(KLINE_DB, BTCUSDT_15M, from_ts_ms, Some(to_ts_ms)));
-    klines
-}
-
-pub fn init_trade(metric: &mut BacktestMetric) {
-    metric.position = 0.;
-    metric.entry_price = 0.;
-    metric.entry_side = TradeSide::None;
-    metric.take_profit_price = 0.;
-    metric.stop_loss_price = 0.;
-    metric.fee = 0.;
-    metric.profit = 0.;
+    if let Some(cache_dir) = &config.kline_cache_dir {
+        let cache = KlineCache::new(cache_dir);
+        match cache.get_klines(&mongo_clinet, KLINE_DB, collection, from_ts_ms, to_ts_ms) {
+            Ok(klines) => return klines,
+            Err(err) => warn!("kline cache unavailable, querying mongo: {}", err),
+        }
+    }
+    task::block_on(mongo_clinet.get_klines(KLINE_DB, collection, from_ts_ms, Some(to_ts_ms)))
 }